@@ -0,0 +1,244 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Scans the encoded protobuf fields without copying their values, since the other fields of the
+// CodeGeneratorRequest (i.e. proto_file) can be tens of megabytes.
+
+use ::anyhow::{Result, anyhow};
+use ::protobuf_core::{FieldNumber, ReadExtTag, ReadExtVarint, WireType};
+
+// Returns the value of the last occurrence of the given top-level string field. The fields with
+// the other wire types, including the groups which legacy proto2 messages can contain, are
+// skipped, and so is the target field with a non-Len wire type.
+//
+// If stop_field_number is given, stops scanning at the first top-level field of that number once
+// the target field is found. Since protoc writes the fields in the field number order, the rest
+// (i.e. the proto_file entries) can't contain the target field in practice. If the target field
+// is not found before that, falls back to scanning the whole input.
+pub(crate) fn find_last_string_field(
+    input: &[u8],
+    target_field_number: u32,
+    stop_field_number: Option<u32>,
+) -> Result<Option<String>> {
+    let mut result: Option<&[u8]> = None;
+    let mut rest = input;
+    // The field numbers of the groups being skipped, innermost last.
+    let mut groups: Vec<FieldNumber> = Vec::new();
+
+    while !rest.is_empty() {
+        let offset = input.len() - rest.len();
+        let corrupt = |message: String| anyhow!("corrupt protobuf at byte {}: {}", offset, message);
+        let tag = rest
+            .read_tag()
+            .map_err(|e| corrupt(format!("invalid tag: {}", e)))?
+            .ok_or_else(|| corrupt("truncated tag".to_string()))?;
+        if groups.is_empty()
+            && result.is_some()
+            && Some(tag.field_number.as_u32()) == stop_field_number
+        {
+            break;
+        }
+        let truncated = || {
+            corrupt(format!(
+                "truncated {:?} value of field {}",
+                tag.wire_type,
+                tag.field_number.as_u32()
+            ))
+        };
+        let len = match tag.wire_type {
+            WireType::Varint => {
+                read_varint(&mut rest).ok_or_else(truncated)?;
+                0
+            }
+            WireType::Int64 => 8,
+            WireType::Int32 => 4,
+            WireType::Len => read_varint(&mut rest)
+                .and_then(|len| usize::try_from(len).ok())
+                .ok_or_else(truncated)?,
+            WireType::SGroup => {
+                groups.push(tag.field_number);
+                continue;
+            }
+            WireType::EGroup => {
+                if groups.pop() != Some(tag.field_number) {
+                    return Err(corrupt(format!(
+                        "unmatched end of group {}",
+                        tag.field_number.as_u32()
+                    )));
+                }
+                continue;
+            }
+        };
+        if rest.len() < len {
+            return Err(truncated());
+        }
+        let (value, remaining) = rest.split_at(len);
+        if groups.is_empty()
+            && tag.field_number.as_u32() == target_field_number
+            && tag.wire_type == WireType::Len
+        {
+            result = Some(value);
+        }
+        rest = remaining;
+    }
+    if let Some(field_number) = groups.last() {
+        return Err(anyhow!(
+            "corrupt protobuf: group {} is not terminated",
+            field_number.as_u32()
+        ));
+    }
+
+    Ok(result
+        .map(|bytes| String::from_utf8(bytes.to_vec()))
+        .transpose()?)
+}
+
+// Returns None if the varint is truncated or malformed.
+fn read_varint(input: &mut &[u8]) -> Option<u64> {
+    input.read_varint().ok()?.map(|varint| varint.to_uint64())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_last_string_field() {
+        // field 1 "a", field 3 varint 150, field 2 "first", field 4 fixed32, field 5 fixed64,
+        // field 2 "second".
+        let mut input = vec![0x0a, 1, b'a', 0x18, 0x96, 0x01, 0x12, 5];
+        input.extend(b"first");
+        input.extend([0x25, 0, 0, 0, 0, 0x29, 0, 0, 0, 0, 0, 0, 0, 0, 0x12, 6]);
+        input.extend(b"second");
+        assert_eq!(
+            find_last_string_field(&input, 2, None).unwrap().as_deref(),
+            Some("second")
+        );
+        assert_eq!(find_last_string_field(&input, 6, None).unwrap(), None);
+        assert_eq!(find_last_string_field(&[], 2, None).unwrap(), None);
+    }
+
+    #[test]
+    fn test_find_last_string_field_stop() {
+        // field 2 "first", field 15 "x", field 2 "second".
+        let mut input = vec![0x12, 5];
+        input.extend(b"first");
+        input.extend([0x7a, 1, b'x', 0x12, 6]);
+        input.extend(b"second");
+        assert_eq!(
+            find_last_string_field(&input, 2, Some(15))
+                .unwrap()
+                .as_deref(),
+            Some("first")
+        );
+        // The rest after the stop field is not even parsed.
+        assert_eq!(
+            find_last_string_field(&input[..10], 2, Some(15))
+                .unwrap()
+                .as_deref(),
+            Some("first")
+        );
+        // Without the target field before the stop field, the whole input is scanned.
+        assert_eq!(
+            find_last_string_field(&input[7..], 2, Some(15))
+                .unwrap()
+                .as_deref(),
+            Some("second")
+        );
+    }
+
+    #[test]
+    fn test_find_last_string_field_skips_groups() {
+        // field 2 "top", then group 7 { field 2 "inner", group 8 { field 2 varint 1 } }.
+        let mut input = vec![0x12, 3];
+        input.extend(b"top");
+        input.extend([0x3b, 0x12, 5]);
+        input.extend(b"inner");
+        input.extend([0x43, 0x10, 1, 0x44, 0x3c]);
+        assert_eq!(
+            find_last_string_field(&input, 2, None).unwrap().as_deref(),
+            Some("top")
+        );
+    }
+
+    #[test]
+    fn test_find_last_string_field_ignores_non_len_target() {
+        // field 2 "str", then field 2 as varint 1.
+        let mut input = vec![0x12, 3];
+        input.extend(b"str");
+        input.extend([0x10, 1]);
+        assert_eq!(
+            find_last_string_field(&input, 2, None).unwrap().as_deref(),
+            Some("str")
+        );
+    }
+
+    #[test]
+    fn test_find_last_string_field_corrupt() {
+        // The value of field 2 is truncated at byte 3.
+        let err = find_last_string_field(&[0x0a, 0, 0x12, 5, b'a'], 2, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "corrupt protobuf at byte 2: truncated Len value of field 2"
+        );
+        // The wire type 7 is invalid.
+        let err = find_last_string_field(&[0x0f], 2, None).unwrap_err();
+        assert!(
+            err.to_string()
+                .starts_with("corrupt protobuf at byte 0: invalid tag"),
+            "{}",
+            err
+        );
+        // The group 7 is ended as 8.
+        let err = find_last_string_field(&[0x3b, 0x44], 2, None).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "corrupt protobuf at byte 1: unmatched end of group 8"
+        );
+        assert!(find_last_string_field(&[0x3b], 2, None).is_err());
+    }
+}
+
+// Run with `cargo +nightly bench -p protoc-plugin-bin --features bench`.
+#[cfg(all(test, feature = "bench"))]
+mod benches {
+    extern crate test;
+
+    use super::*;
+    use test::{Bencher, black_box};
+
+    /// A synthetic 50 MB request of 100,000 proto_file entries after the parameter.
+    fn large_request() -> Vec<u8> {
+        let mut input = vec![0x12, 3];
+        input.extend(b"key");
+        for _ in 0..100_000 {
+            // field 15 with 500 bytes.
+            input.extend([0x7a, 0xf4, 0x03]);
+            input.extend([b'x'; 500]);
+        }
+        input
+    }
+
+    #[bench]
+    fn bench_find_last_string_field(b: &mut Bencher) {
+        let input = large_request();
+        b.iter(|| find_last_string_field(black_box(&input), 2, None).unwrap());
+    }
+
+    #[bench]
+    fn bench_find_last_string_field_stopped(b: &mut Bencher) {
+        let input = large_request();
+        b.iter(|| find_last_string_field(black_box(&input), 2, Some(15)).unwrap());
+    }
+}
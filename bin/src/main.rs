@@ -12,53 +12,611 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
+#![cfg_attr(all(test, feature = "bench"), feature(test))]
+
 use ::anyhow::{Result, anyhow};
-use ::ipc_channel::ipc::{IpcSender, bytes_channel};
-use ::protobuf_core::{FieldValue, ReadExtProtobuf};
+use ::ipc_channel::ipc::{IpcBytesReceiver, IpcBytesSender, IpcSender, bytes_channel};
+use ::protobuf_core::{Field, FieldNumber, FieldValue, WriteExtProtobuf};
+use ::std::env;
 use ::std::io::{Read, Write, stdin, stdout};
+use ::std::thread;
+use ::std::time::Duration;
+
+mod fields;
+
+use self::fields::find_last_string_field;
 
 // Field number for CodeGeneratorRequest.parameter field
 // See: google/protobuf/compiler/plugin.proto in the Google Protobuf repository.
 //
-// The parameter field contains the IPC initialization key passed via --rust-ppbc_opt.
+// The parameter field contains the protocol version and the IPC initialization key passed via
+// --rust-ppbc_opt, separated by a colon.
 // We use protobuf-core to parse this single field without deserializing the entire message.
 const CODE_GENERATOR_REQUEST_PARAMETER_FIELD_NUMBER: u32 = 2;
+// The proto_file field, which protoc writes after the parameter field. It's where the scan for
+// the parameter field stops, since it's most of the request.
+const CODE_GENERATOR_REQUEST_PROTO_FILE_FIELD_NUMBER: u32 = 15;
+const CODE_GENERATOR_RESPONSE_ERROR_FIELD_NUMBER: u32 = 1;
+
+// The version of the wire contract between the library and this binary, which this binary sends
+// first. Bump this whenever the contract changes. Must be in sync with the library.
+const PROTOCOL_VERSION: u32 = 1;
+// Overrides PROTOCOL_VERSION, only for testing the version mismatch.
+const PROTOCOL_VERSION_OVERRIDE_ENV_VAR: &str =
+    "PROTOC_PLUGIN_BY_CLOSURE_PROTOCOL_VERSION_OVERRIDE";
+
+// The start of the error response sent to protoc when the connection back to the library
+// process fails, which the library looks for in protoc's stderr.
+// Must be in sync with the library.
+const CONNECT_FAILURE_MESSAGE: &str = "failed to connect back to the host process";
+
+// Connecting back to the library process can transiently fail on heavily loaded machines,
+// so we retry a few times with an exponential backoff (10ms, 20ms, 40ms, 80ms).
+const IPC_CONNECT_ATTEMPTS: u32 = 5;
+const IPC_CONNECT_INITIAL_BACKOFF: Duration = Duration::from_millis(10);
+
+// The maximum size of the CodeGeneratorRequest read from stdin, set by the library process.
+// Must be in sync with the one in the library. Unset or 0 means no limit.
+const MAX_REQUEST_SIZE_ENV_VAR: &str = "PROTOC_PLUGIN_BY_CLOSURE_MAX_REQUEST_SIZE";
+
+// The expected size of the CodeGeneratorRequest, optionally set by the library process to
+// reserve the input buffer up front. It's only a hint, so an invalid value is ignored.
+// It can't be in the parameter field, which is read from the same input.
+// Must be in sync with the one in the library.
+const REQUEST_SIZE_HINT_ENV_VAR: &str = "PROTOC_PLUGIN_BY_CLOSURE_REQUEST_SIZE_HINT";
+
+// The tag of the run in a batch of protoc runs sharing one server in the library process,
+// optionally set by the library process. It's sent back after the protocol version in the
+// handshake, so that the library can tell which run this binary belongs to.
+// Must be in sync with the one in the library.
+const RUN_TAG_ENV_VAR: &str = "PROTOC_PLUGIN_BY_CLOSURE_RUN_TAG";
+
+// The init key prefix for the Unix domain socket transport, followed by the socket path.
+// The request and the response are framed by a little-endian u64 length prefix.
+// Must be in sync with the library.
+const UNIX_SOCKET_INIT_KEY_PREFIX: &str = "unix:";
+
+// The batch mode, in which a batch driver (not protoc) pushes multiple requests over one
+// session. Each request is preceded by a control byte, and the end is marked by another.
+// Must be in sync with the library.
+const BATCH_MODE_ARG: &str = "--batch";
+const CONTROL_REQUEST_FOLLOWS: u8 = 1;
+const CONTROL_END: u8 = 0;
+
+// The maximum buffer size reserved up front by a frame's length prefix or the request size hint.
+// The larger inputs grow the buffer as the bytes arrive, so a corrupt prefix can't make us
+// allocate a huge buffer.
+const MAX_PREALLOCATED_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+fn max_request_size() -> Result<usize> {
+    match env::var(MAX_REQUEST_SIZE_ENV_VAR) {
+        Ok(value) => value
+            .parse()
+            .map_err(|e| anyhow!("invalid {}={}: {}", MAX_REQUEST_SIZE_ENV_VAR, value, e)),
+        Err(env::VarError::NotPresent) => Ok(0),
+        Err(e) => Err(anyhow!("invalid {}: {}", MAX_REQUEST_SIZE_ENV_VAR, e)),
+    }
+}
+
+fn run_tag() -> Result<Option<u64>> {
+    match env::var(RUN_TAG_ENV_VAR) {
+        Ok(value) => value
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow!("invalid {}={}: {}", RUN_TAG_ENV_VAR, value, e)),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(e) => Err(anyhow!("invalid {}: {}", RUN_TAG_ENV_VAR, e)),
+    }
+}
 
-fn find_last_string_field(input: &[u8], target_field_number: u32) -> Result<Option<String>> {
-    let mut result: Option<String> = None;
+fn request_size_hint() -> usize {
+    env::var(REQUEST_SIZE_HINT_ENV_VAR)
+        .ok()
+        .and_then(|value| value.parse().ok())
+        .unwrap_or(0)
+}
 
-    for field_result in input.read_protobuf_fields() {
-        let field = field_result.map_err(|e| anyhow!("Failed to parse protobuf field: {}", e))?;
+// Reads the whole input, but stops reading as soon as it exceeds the limit (if non-zero)
+// so that an oversized request is never buffered as a whole.
+// The buffer is reserved by the size hint (if non-zero) up to the limit, and grows as usual if
+// the input is larger.
+fn read_input(mut input: impl Read, limit: usize, size_hint: usize) -> Result<Vec<u8>> {
+    let mut reserved = size_hint.min(MAX_PREALLOCATED_FRAME_LEN);
+    if limit != 0 {
+        // One more byte for detecting an oversized input.
+        reserved = reserved.min(limit + 1);
+    }
+    let mut buffer = Vec::with_capacity(reserved);
+    if limit == 0 {
+        input.read_to_end(&mut buffer)?;
+        return Ok(buffer);
+    }
+    input.take(limit as u64 + 1).read_to_end(&mut buffer)?;
+    if buffer.len() > limit {
+        return Err(anyhow!(
+            "RequestTooLarge: the request exceeds the limit of {} bytes",
+            limit
+        ));
+    }
+    Ok(buffer)
+}
 
-        // Extract string field if it matches the target field number
-        if field.field_number.as_u32() == target_field_number {
-            if let FieldValue::Len(bytes) = field.value {
-                result = Some(String::from_utf8(bytes)?);
+fn retry_with_backoff<T, E>(
+    attempts: u32,
+    initial_backoff: Duration,
+    mut op: impl FnMut() -> std::result::Result<T, E>,
+) -> std::result::Result<T, E> {
+    let mut backoff = initial_backoff;
+    let mut attempt = 1;
+    loop {
+        match op() {
+            Err(_) if attempt < attempts => {
+                thread::sleep(backoff);
+                backoff *= 2;
+                attempt += 1;
             }
+            result => return result,
         }
     }
+}
 
-    Ok(result)
+// Writes the CodeGeneratorResponse with only the error field set, which protoc shows to the user.
+fn error_response(message: &str) -> Result<Vec<u8>> {
+    let field = Field::new(
+        FieldNumber::try_from(CODE_GENERATOR_RESPONSE_ERROR_FIELD_NUMBER)
+            .map_err(|e| anyhow!("invalid field number: {}", e))?,
+        FieldValue::from_string(message.to_string()),
+    );
+    let mut response = Vec::new();
+    response
+        .write_protobuf_field(&field)
+        .map_err(|e| anyhow!("failed to write the error response: {}", e))?;
+    Ok(response)
 }
 
-fn main() -> Result<()> {
-    let mut input_buffer = Vec::new();
-    stdin().read_to_end(&mut input_buffer)?;
+// The error response for a failure of the connection back to the library process, so that protoc
+// shows it to the user rather than only that the plugin failed.
+fn connect_failure_response(error: &anyhow::Error) -> Result<Vec<u8>> {
+    error_response(&format!(
+        "{}: {:#}; the controlling library may have exited",
+        CONNECT_FAILURE_MESSAGE, error
+    ))
+}
+
+fn version_mismatch_response(lib_version: u32, bin_version: u32) -> Result<Vec<u8>> {
+    error_response(&format!(
+        "protoc-plugin-by-closure speaks the protocol version {} but its plugin binary \
+         (protoc-plugin-bin) speaks {}. Make sure both come from the same release and rebuild.",
+        lib_version, bin_version
+    ))
+}
 
-    let ipc_init_key = find_last_string_field(&input_buffer, CODE_GENERATOR_REQUEST_PARAMETER_FIELD_NUMBER)?.ok_or_else(|| {
+fn protocol_version() -> Result<u32> {
+    match env::var(PROTOCOL_VERSION_OVERRIDE_ENV_VAR) {
+        Ok(value) => value.parse().map_err(|e| {
+            anyhow!(
+                "invalid {}={}: {}",
+                PROTOCOL_VERSION_OVERRIDE_ENV_VAR,
+                value,
+                e
+            )
+        }),
+        Err(_) => Ok(PROTOCOL_VERSION),
+    }
+}
+
+// Splits the parameter field into the library's protocol version and the init key.
+fn parse_parameter(parameter: &str) -> Result<(u32, &str)> {
+    parameter
+        .split_once(':')
+        .and_then(|(version, init_key)| Some((version.parse().ok()?, init_key)))
+        .ok_or_else(|| {
+            anyhow!(
+                "the parameter field (2) does not start with the protocol version. \
+                 protoc-plugin-by-closure may be older than its plugin binary (protoc-plugin-bin)."
+            )
+        })
+}
+
+// The connection back to the library process.
+trait Session {
+    fn send(&mut self, bytes: &[u8]) -> Result<()>;
+    fn recv(&mut self) -> Result<Vec<u8>>;
+}
+
+#[cfg(unix)]
+impl Session for ::std::os::unix::net::UnixStream {
+    fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        write_frame(self, bytes)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        read_frame(self)?.ok_or_else(|| anyhow!("the socket is closed by the library process"))
+    }
+}
+
+impl Session for (IpcBytesSender, IpcBytesReceiver) {
+    fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        Ok(self.0.send(bytes)?)
+    }
+
+    fn recv(&mut self) -> Result<Vec<u8>> {
+        Ok(self.1.recv()?)
+    }
+}
+
+#[cfg(unix)]
+fn connect_unix_socket(socket_path: &str) -> Result<Box<dyn Session>> {
+    let stream = retry_with_backoff(IPC_CONNECT_ATTEMPTS, IPC_CONNECT_INITIAL_BACKOFF, || {
+        ::std::os::unix::net::UnixStream::connect(socket_path)
+    })
+    .map_err(|e| {
         anyhow!(
-            "input CodeGeneratorRequest proto does not contain a parameter field (2) (IPC init key)."
+            "failed to connect to the socket {} after {} attempts: {}",
+            socket_path,
+            IPC_CONNECT_ATTEMPTS,
+            e
         )
     })?;
-    let ipc_init_send = IpcSender::connect(ipc_init_key)?;
+    Ok(Box::new(stream))
+}
+
+#[cfg(not(unix))]
+fn connect_unix_socket(_socket_path: &str) -> Result<Box<dyn Session>> {
+    Err(anyhow!(
+        "the Unix domain socket transport is not supported on this platform"
+    ))
+}
+
+fn connect_ipc_channel(ipc_init_key: &str) -> Result<Box<dyn Session>> {
+    let ipc_init_send =
+        retry_with_backoff(IPC_CONNECT_ATTEMPTS, IPC_CONNECT_INITIAL_BACKOFF, || {
+            IpcSender::connect(ipc_init_key.to_string())
+        })
+        .map_err(|e| {
+            anyhow!(
+                "failed to connect to the IPC server after {} attempts: {}",
+                IPC_CONNECT_ATTEMPTS,
+                e
+            )
+        })?;
     let (req_send, req_recv) = bytes_channel()?;
     let (res_send, res_recv) = bytes_channel()?;
     ipc_init_send.send((req_recv, res_send))?;
+    Ok(Box::new((req_send, res_recv)))
+}
 
-    req_send.send(&input_buffer)?;
-    let response = res_recv.recv()?;
+// Connects to the library process by the init key in the request's parameter field, and sends
+// this binary's protocol version. Returns the library's protocol version along with the session.
+fn connect(request: &[u8]) -> Result<(u32, Box<dyn Session>)> {
+    let (lib_version, init_key) = read_parameter(request)?;
+    Ok((lib_version, open_session(&init_key, protocol_version()?)?))
+}
 
-    stdout().write_all(&response)?;
+// Reads the library's protocol version and the init key in the request's parameter field.
+fn read_parameter(request: &[u8]) -> Result<(u32, String)> {
+    let parameter = find_last_string_field(
+        request,
+        CODE_GENERATOR_REQUEST_PARAMETER_FIELD_NUMBER,
+        Some(CODE_GENERATOR_REQUEST_PROTO_FILE_FIELD_NUMBER),
+    )?
+    .ok_or_else(|| {
+        anyhow!(
+            "input CodeGeneratorRequest proto does not contain a parameter field (2) (IPC init key)."
+        )
+    })?;
+    let (lib_version, init_key) = parse_parameter(&parameter)?;
+    Ok((lib_version, init_key.to_string()))
+}
+
+// The handshake message: the little-endian u32 protocol version, followed by the little-endian
+// u64 run tag if any.
+fn handshake(bin_version: u32, run_tag: Option<u64>) -> Vec<u8> {
+    let mut message = bin_version.to_le_bytes().to_vec();
+    if let Some(run_tag) = run_tag {
+        message.extend(run_tag.to_le_bytes());
+    }
+    message
+}
 
+// Connects to the library process by the init key, and sends the handshake.
+fn open_session(init_key: &str, bin_version: u32) -> Result<Box<dyn Session>> {
+    let run_tag = run_tag()?;
+    let mut session = match init_key.strip_prefix(UNIX_SOCKET_INIT_KEY_PREFIX) {
+        Some(socket_path) => connect_unix_socket(socket_path)?,
+        None => connect_ipc_channel(init_key)?,
+    };
+    session.send(&handshake(bin_version, run_tag))?;
+    Ok(session)
+}
+
+// Passes the request to the library process and returns its response, or the error response if
+// the protocol versions don't match.
+fn exchange(request: &[u8], lib_version: u32, bin_version: u32, init_key: &str) -> Result<Vec<u8>> {
+    let mut session = open_session(init_key, bin_version)?;
+    if lib_version != bin_version {
+        return version_mismatch_response(lib_version, bin_version);
+    }
+    session.send(request)?;
+    session.recv()
+}
+
+// Writes a frame with a little-endian u64 length prefix.
+fn write_frame(mut output: impl Write, bytes: &[u8]) -> Result<()> {
+    output.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    output.write_all(bytes)?;
+    output.flush()?;
     Ok(())
 }
+
+// Reads a frame with a little-endian u64 length prefix. Returns None on EOF before the frame.
+fn read_frame(mut input: impl Read) -> Result<Option<Vec<u8>>> {
+    let mut len = [0u8; 8];
+    match input.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    let len = usize::try_from(u64::from_le_bytes(len))?;
+    let mut bytes = Vec::with_capacity(len.min(MAX_PREALLOCATED_FRAME_LEN));
+    input.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(anyhow!("the frame is truncated"));
+    }
+    Ok(Some(bytes))
+}
+
+// Reads the next request in the batch mode, which is preceded by a control byte.
+// Returns None at the end marker or EOF.
+fn read_batch_request(mut input: impl Read, limit: usize) -> Result<Option<Vec<u8>>> {
+    let mut control = [0u8; 1];
+    match input.read_exact(&mut control) {
+        Ok(()) => (),
+        Err(e) if e.kind() == ::std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    }
+    match control[0] {
+        CONTROL_END => return Ok(None),
+        CONTROL_REQUEST_FOLLOWS => (),
+        other => return Err(anyhow!("invalid control header: {}", other)),
+    }
+    let request = read_frame(input)?.ok_or_else(|| anyhow!("the request is truncated"))?;
+    if limit != 0 && request.len() > limit {
+        return Err(anyhow!(
+            "RequestTooLarge: the request exceeds the limit of {} bytes",
+            limit
+        ));
+    }
+    Ok(Some(request))
+}
+
+// Forwards the requests read from stdin to the library process over one session, and writes
+// the responses to stdout. Used by a batch driver, never by protoc.
+fn run_batch() -> Result<()> {
+    let limit = max_request_size()?;
+    let mut stdin = stdin().lock();
+    let mut stdout = stdout().lock();
+    let Some(first_request) = read_batch_request(&mut stdin, limit)? else {
+        return Ok(());
+    };
+    let (lib_version, mut session) = connect(&first_request)?;
+    let bin_version = protocol_version()?;
+    if lib_version != bin_version {
+        return Err(anyhow!(
+            "the library speaks the protocol version {} but this binary speaks {}",
+            lib_version,
+            bin_version
+        ));
+    }
+
+    let mut request = Some(first_request);
+    while let Some(req) = request {
+        session.send(&[CONTROL_REQUEST_FOLLOWS])?;
+        session.send(&req)?;
+        write_frame(&mut stdout, &session.recv()?)?;
+        request = read_batch_request(&mut stdin, limit)?;
+    }
+    session.send(&[CONTROL_END])?;
+    Ok(())
+}
+
+fn main() -> Result<()> {
+    if env::args().nth(1).as_deref() == Some(BATCH_MODE_ARG) {
+        return run_batch();
+    }
+
+    // The std's stdin and stdout read and write the raw handles on Windows too, without the C
+    // runtime's text mode translation, so the protobuf bytes are passed through as they are.
+    let input_buffer = read_input(stdin().lock(), max_request_size()?, request_size_hint())?;
+
+    let (lib_version, init_key) = read_parameter(&input_buffer)?;
+    let bin_version = protocol_version()?;
+    // The connection failures are reported through protoc, which otherwise only says that the
+    // plugin failed.
+    let response = match exchange(&input_buffer, lib_version, bin_version, &init_key) {
+        Ok(response) => response,
+        Err(e) => connect_failure_response(&e)?,
+    };
+
+    let mut stdout = stdout().lock();
+    stdout.write_all(&response)?;
+    stdout.flush()?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ::std::time::Instant;
+
+    #[test]
+    fn test_read_input_within_limit() {
+        let input = vec![1u8; 16];
+        assert_eq!(read_input(&input[..], 16, 0).unwrap(), input);
+        assert_eq!(read_input(&input[..], 0, 0).unwrap(), input);
+    }
+
+    #[test]
+    fn test_read_input_exceeds_limit() {
+        let input = [1u8; 17];
+        let err = read_input(&input[..], 16, 0).unwrap_err();
+        assert!(err.to_string().starts_with("RequestTooLarge"));
+        let err = read_input(&input[..], 16, 1000).unwrap_err();
+        assert!(err.to_string().starts_with("RequestTooLarge"));
+    }
+
+    #[test]
+    fn test_read_input_size_hint() {
+        let input = vec![7u8; 1000];
+        // The hint doesn't change the input read, whether it's accurate or not.
+        for limit in [0, 1000] {
+            for hint in [0, 1, 999, 1000, 1001, usize::MAX] {
+                assert_eq!(read_input(&input[..], limit, hint).unwrap(), input);
+            }
+        }
+        // An accurate hint reserves the buffer for the whole input, without growing it.
+        // `read_to_end` still grows it to probe the EOF in the worst case, so one more byte.
+        let buffer = read_input(&input[..], 0, 1001).unwrap();
+        assert_eq!(buffer.capacity(), 1001);
+        // A huge hint is capped by the limit.
+        let buffer = read_input(&input[..], 2000, usize::MAX).unwrap();
+        assert_eq!(buffer.capacity(), 2001);
+    }
+
+    #[test]
+    fn test_read_batch_request() {
+        let mut input = vec![CONTROL_REQUEST_FOLLOWS];
+        write_frame(&mut input, b"first").unwrap();
+        input.push(CONTROL_REQUEST_FOLLOWS);
+        write_frame(&mut input, b"second").unwrap();
+        input.push(CONTROL_END);
+        let mut input = &input[..];
+        assert_eq!(
+            read_batch_request(&mut input, 0).unwrap().unwrap(),
+            b"first"
+        );
+        assert_eq!(
+            read_batch_request(&mut input, 0).unwrap().unwrap(),
+            b"second"
+        );
+        assert!(read_batch_request(&mut input, 0).unwrap().is_none());
+        assert!(read_batch_request(&mut input, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_read_frame_presized() {
+        let mut input = Vec::new();
+        write_frame(&mut input, &[7u8; 1000]).unwrap();
+        let frame = read_frame(&input[..]).unwrap().unwrap();
+        assert_eq!(frame, [7u8; 1000]);
+        // Read into the buffer sized by the length prefix, without growing it.
+        assert_eq!(frame.capacity(), 1000);
+
+        // A truncated frame, and a corrupt length prefix which must not be allocated.
+        assert!(read_frame(&input[..500]).is_err());
+        let mut input = (1u64 << 40).to_le_bytes().to_vec();
+        input.extend(b"abc");
+        assert!(read_frame(&input[..]).is_err());
+    }
+
+    #[test]
+    fn test_handshake() {
+        assert_eq!(handshake(1, None), 1u32.to_le_bytes());
+        let message = handshake(1, Some(3));
+        assert_eq!(message.len(), 12);
+        assert_eq!(message[..4], 1u32.to_le_bytes());
+        assert_eq!(message[4..], 3u64.to_le_bytes());
+    }
+
+    #[test]
+    fn test_parse_parameter() {
+        assert_eq!(
+            parse_parameter("1:unix:/tmp/a").unwrap(),
+            (1, "unix:/tmp/a")
+        );
+        assert!(parse_parameter("no-version").is_err());
+        assert!(parse_parameter("x:key").is_err());
+    }
+
+    #[test]
+    fn test_retry_with_backoff_delayed_acceptor() {
+        // An acceptor which becomes ready only after a short delay.
+        let ready_at = Instant::now() + Duration::from_millis(25);
+        let mut calls = 0;
+        let result = retry_with_backoff(5, Duration::from_millis(10), || {
+            calls += 1;
+            if Instant::now() >= ready_at {
+                Ok(calls)
+            } else {
+                Err("not ready")
+            }
+        });
+        assert!(result.is_ok());
+        assert!(calls > 1);
+    }
+
+    #[test]
+    fn test_retry_with_backoff_gives_up() {
+        let mut calls = 0;
+        let result: std::result::Result<(), _> =
+            retry_with_backoff(3, Duration::from_millis(1), || {
+                calls += 1;
+                Err("never ready")
+            });
+        assert_eq!(result, Err("never ready"));
+        assert_eq!(calls, 3);
+    }
+}
+
+// Run with `cargo +nightly bench -p protoc-plugin-bin --features bench`.
+#[cfg(all(test, feature = "bench"))]
+mod benches {
+    extern crate test;
+
+    use super::*;
+    use test::Bencher;
+
+    const LEN: usize = 50 * 1024 * 1024;
+
+    /// Reads a 50 MB input from a pipe written by another thread.
+    #[cfg(unix)]
+    fn bench_read_input(b: &mut Bencher, hint: usize) {
+        let bytes = vec![b'x'; LEN];
+        b.iter(|| {
+            let (reader, mut writer) = ::std::io::pipe().unwrap();
+            let bytes = bytes.clone();
+            let writer = thread::spawn(move || writer.write_all(&bytes));
+            let buffer = read_input(reader, 0, hint).unwrap();
+            writer.join().unwrap().unwrap();
+            buffer
+        });
+    }
+
+    #[cfg(unix)]
+    #[bench]
+    fn bench_read_input_without_hint(b: &mut Bencher) {
+        bench_read_input(b, 0);
+    }
+
+    #[cfg(unix)]
+    #[bench]
+    fn bench_read_input_with_hint(b: &mut Bencher) {
+        bench_read_input(b, LEN);
+    }
+
+    /// Reads a 50 MB frame from a Unix domain socket written by another thread.
+    #[cfg(unix)]
+    #[bench]
+    fn bench_read_frame(b: &mut Bencher) {
+        let bytes = vec![b'x'; LEN];
+        b.iter(|| {
+            let (mut reader, writer) = ::std::os::unix::net::UnixStream::pair().unwrap();
+            let bytes = bytes.clone();
+            let writer = thread::spawn(move || write_frame(writer, &bytes));
+            let frame = read_frame(&mut reader).unwrap().unwrap();
+            writer.join().unwrap().unwrap();
+            frame
+        });
+    }
+}
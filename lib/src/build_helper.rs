@@ -0,0 +1,132 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Helpers for running [`Protoc`] from cargo build scripts (`build.rs`).
+//!
+//! # Example
+//! ```no_run
+//! // build.rs
+//! use protoc_plugin_by_closure::{Protoc, build_helper};
+//! use std::time::Duration;
+//!
+//! fn main() {
+//!     let protoc = Protoc::new()
+//!         .proto_file("proto/my_protobuf_file.proto")
+//!         .proto_path("proto/");
+//!     build_helper::compile_with(protoc, Duration::from_secs(3), |request_bytes| {
+//!         // Your plugin logic here.
+//! #       unimplemented!()
+//!     })
+//!     .unwrap();
+//! }
+//! ```
+
+use crate::{ErrorKind, Protoc, Result};
+use ::std::env;
+use ::std::io::{Write, stdout};
+use ::std::time::Duration;
+
+/// Runs the given [`Protoc`] in a cargo build script.
+///
+/// In addition to [`Protoc::run`], this:
+/// - Sets the output directory to `$OUT_DIR` unless [`Protoc::out_dir`] is explicitly set.
+///   Returns [`ErrorKind::OutDirNotSetError`] if neither of them is available.
+/// - Prints `cargo:rerun-if-changed=<path>` for each configured proto file and proto path,
+///   and after the run, for each transitively imported proto file. See [`Protoc::run_with_deps`].
+/// - Prints `cargo:warning=<line>` for each line of the warnings `protoc` printed.
+///   See [`Protoc::run_with_warnings`].
+pub fn compile_with<F>(protoc: Protoc, timeout: Duration, body: F) -> Result<()>
+where
+    F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+{
+    compile_with_writer(protoc, timeout, body, &mut stdout().lock())
+}
+
+/// Same as [`compile_with`], but writes the cargo directives into the given writer
+/// instead of the stdout.
+pub fn compile_with_writer<F, W>(
+    mut protoc: Protoc,
+    timeout: Duration,
+    body: F,
+    writer: &mut W,
+) -> Result<()>
+where
+    F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    W: Write,
+{
+    protoc = with_default_out_dir(protoc)?;
+
+    let paths = protoc
+        .proto_files
+        .iter()
+        .chain(&protoc.proto_paths)
+        .cloned()
+        .collect::<Vec<_>>();
+    for path in &paths {
+        writeln!(writer, "cargo:rerun-if-changed={}", path.display())?;
+    }
+
+    let (output, deps) = protoc.run_with_output_and_deps(timeout, body)?;
+    for dep in deps {
+        if !paths.contains(&dep) {
+            writeln!(writer, "cargo:rerun-if-changed={}", dep.display())?;
+        }
+    }
+    for warning in output.warnings {
+        for line in warning.to_string().lines() {
+            writeln!(writer, "cargo:warning={}", line)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(feature = "build")]
+impl Protoc {
+    /// Runs `protoc` in a cargo build script, printing the cargo directives for its results.
+    /// Requires the `build` feature.
+    ///
+    /// Same as [`compile_with`], i.e. the output directory defaults to `$OUT_DIR`, and this prints
+    /// `cargo:rerun-if-changed=<path>` for the inputs and the transitively imported proto files,
+    /// and `cargo:warning=<line>` for each line of the warnings `protoc` printed.
+    pub fn run_in_build_script<F>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        compile_with(self, timeout, body)
+    }
+
+    /// Same as [`Self::run_in_build_script`], but writes the cargo directives into the given
+    /// writer instead of the stdout. See [`compile_with_writer`].
+    pub fn run_in_build_script_with_writer<F, W>(
+        self,
+        timeout: Duration,
+        body: F,
+        writer: &mut W,
+    ) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+        W: Write,
+    {
+        compile_with_writer(self, timeout, body, writer)
+    }
+}
+
+/// Sets the output directory to `$OUT_DIR` unless [`Protoc::out_dir`] is explicitly set.
+fn with_default_out_dir(protoc: Protoc) -> Result<Protoc> {
+    if protoc.out_dir.is_some() {
+        return Ok(protoc);
+    }
+    let out_dir = env::var_os("OUT_DIR").ok_or(ErrorKind::OutDirNotSetError)?;
+    Ok(protoc.out_dir(out_dir))
+}
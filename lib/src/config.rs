@@ -0,0 +1,269 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The declarative configuration of [`Protoc`], e.g. loaded from a TOML file. Enabled by the
+//! `serde` feature.
+
+use crate::{ErrorFormat, ExtraPlugin, Protoc, RetryPolicy, Transport};
+use ::serde::{Deserialize, Serialize};
+use ::std::path::{Path, PathBuf};
+use ::std::time::Duration;
+
+/// The settings of [`Protoc`] except the closures, e.g. [`Protoc::on_event`], and the
+/// [`CancellationToken`](crate::CancellationToken), converted by [`Protoc::from_config`] and
+/// [`Protoc::to_config`].
+///
+/// The missing fields are the defaults of [`Protoc::new`], and the unknown fields are rejected.
+/// The durations are in milliseconds. The relative paths are relative to the current directory
+/// of the process running `protoc`, unless resolved by [`Self::resolve_paths`].
+///
+/// # Example
+/// ```
+/// # use protoc_plugin_by_closure::{Protoc, ProtocConfig};
+/// let config: ProtocConfig = serde_json::from_str(
+///     r#"{ "proto_files": ["foo.proto"], "proto_paths": ["proto"], "out_dir": "gen" }"#,
+/// )
+/// .unwrap();
+/// let protoc = Protoc::from_config(config.resolve_paths("path/to/package"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProtocConfig {
+    /// See [`Protoc::protoc_path`].
+    pub protoc_path: PathBuf,
+    /// See [`Protoc::plugin_path`].
+    pub plugin_path: Option<PathBuf>,
+    /// See [`Protoc::plugin_name`].
+    pub plugin_name: String,
+    /// See [`Protoc::with_plugin`].
+    pub plugins: Vec<PluginConfig>,
+    /// See [`Protoc::out_dir`].
+    pub out_dir: Option<PathBuf>,
+    /// See [`Protoc::create_out_dir`].
+    pub create_out_dir: bool,
+    /// See [`Protoc::out_zip`].
+    #[cfg(feature = "zip")]
+    pub out_zip: Option<PathBuf>,
+    /// See [`Protoc::manifest_out`].
+    pub manifest_out: Option<PathBuf>,
+    /// See [`Protoc::proto_file`].
+    pub proto_files: Vec<PathBuf>,
+    /// See [`Protoc::proto_dir_recursive`].
+    pub proto_dirs_recursive: Vec<PathBuf>,
+    /// See [`Protoc::proto_path`].
+    pub proto_paths: Vec<PathBuf>,
+    /// See [`Protoc::include_imports`].
+    pub include_imports: bool,
+    /// See [`Protoc::include_source_info`].
+    pub include_source_info: bool,
+    /// See [`Protoc::include_std_types`].
+    pub include_std_types: bool,
+    /// See [`Protoc::dependency_out`].
+    pub dependency_out: Option<PathBuf>,
+    /// See [`Protoc::max_request_size`].
+    pub max_request_size: usize,
+    /// See [`Protoc::max_response_size`].
+    pub max_response_size: usize,
+    /// See [`Protoc::request_size_hint`].
+    pub request_size_hint: usize,
+    /// See [`Protoc::transport`].
+    pub transport: Option<Transport>,
+    /// See [`Protoc::fatal_warnings`].
+    pub fatal_warnings: bool,
+    /// See [`Protoc::protoc_parse_timeout`]. Rounded up to whole milliseconds.
+    pub protoc_parse_timeout_ms: Option<u64>,
+    /// See [`Protoc::error_format`].
+    pub error_format: Option<ErrorFormat>,
+    /// See [`Protoc::direct_dependencies`].
+    pub direct_dependencies: Option<Vec<String>>,
+    /// See [`Protoc::direct_dependencies_violation_msg`].
+    pub direct_dependencies_violation_msg: Option<String>,
+    /// See [`Protoc::handle_signals`].
+    pub handle_signals: bool,
+    /// See [`RetryPolicy::max_attempts`].
+    pub retry_max_attempts: u32,
+    /// See [`RetryPolicy::backoff`]. Rounded up to whole milliseconds.
+    pub retry_backoff_ms: u64,
+    /// See [`Protoc::reproducible`].
+    pub reproducible: bool,
+    /// See [`Protoc::fail_fast`].
+    pub fail_fast: bool,
+    /// See [`Protoc::temp_dir`].
+    pub temp_dir: Option<PathBuf>,
+    /// See [`Protoc::args`]. The non-UTF-8 arguments are converted lossily by
+    /// [`Protoc::to_config`].
+    pub args: Vec<String>,
+}
+
+/// An external plugin of [`ProtocConfig::plugins`]. See [`Protoc::with_plugin`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PluginConfig {
+    /// The plugin name.
+    pub name: String,
+    /// The path to the plugin executable.
+    pub path: PathBuf,
+    /// The output directory of the plugin.
+    pub out_dir: PathBuf,
+}
+
+impl Default for ProtocConfig {
+    fn default() -> Self {
+        Protoc::new().to_config()
+    }
+}
+
+impl ProtocConfig {
+    /// Resolves the relative paths against `base_dir`, e.g. the directory of the config file.
+    ///
+    /// [`Self::protoc_path`] is resolved only if it has a directory, so that a bare `protoc`
+    /// is still searched in the `PATH`.
+    pub fn resolve_paths(mut self, base_dir: impl AsRef<Path>) -> Self {
+        let base_dir = base_dir.as_ref();
+        // `join` keeps the absolute paths as they are.
+        let resolve = |path: &mut PathBuf| *path = base_dir.join(&*path);
+        if self.protoc_path.components().count() > 1 {
+            resolve(&mut self.protoc_path);
+        }
+        #[cfg(feature = "zip")]
+        self.out_zip.iter_mut().for_each(resolve);
+        self.plugin_path
+            .iter_mut()
+            .chain(&mut self.out_dir)
+            .chain(&mut self.manifest_out)
+            .chain(&mut self.proto_files)
+            .chain(&mut self.proto_dirs_recursive)
+            .chain(&mut self.proto_paths)
+            .chain(&mut self.dependency_out)
+            .chain(&mut self.temp_dir)
+            .for_each(resolve);
+        for plugin in &mut self.plugins {
+            resolve(&mut plugin.path);
+            resolve(&mut plugin.out_dir);
+        }
+        self
+    }
+}
+
+impl Protoc {
+    /// Creates a `Protoc` instance with the given settings, e.g. loaded from a TOML file.
+    /// See [`ProtocConfig`].
+    pub fn from_config(config: ProtocConfig) -> Self {
+        Self {
+            protoc_path: config.protoc_path,
+            plugin_path: config.plugin_path,
+            plugin_name: config.plugin_name,
+            extra_plugins: config
+                .plugins
+                .into_iter()
+                .map(|plugin| ExtraPlugin {
+                    name: plugin.name,
+                    path: plugin.path,
+                    out_dir: plugin.out_dir,
+                })
+                .collect(),
+            out_dir: config.out_dir,
+            create_out_dir: config.create_out_dir,
+            #[cfg(feature = "zip")]
+            out_zip: config.out_zip,
+            manifest_out: config.manifest_out,
+            proto_files: config.proto_files,
+            proto_dirs: config.proto_dirs_recursive,
+            proto_paths: config.proto_paths,
+            include_imports: config.include_imports,
+            include_source_info: config.include_source_info,
+            include_std_types: config.include_std_types,
+            dependency_out: config.dependency_out,
+            max_request_size: config.max_request_size,
+            max_response_size: config.max_response_size,
+            request_size_hint: config.request_size_hint,
+            transport: config.transport,
+            fatal_warnings: config.fatal_warnings,
+            protoc_parse_timeout: config.protoc_parse_timeout_ms.map(Duration::from_millis),
+            error_format: config.error_format,
+            direct_dependencies: config.direct_dependencies,
+            direct_dependencies_violation_msg: config.direct_dependencies_violation_msg,
+            handle_signals: config.handle_signals,
+            retry_policy: RetryPolicy {
+                max_attempts: config.retry_max_attempts,
+                backoff: Duration::from_millis(config.retry_backoff_ms),
+            },
+            reproducible: config.reproducible,
+            fail_fast: config.fail_fast,
+            temp_dir: config.temp_dir,
+            extra_args: config.args.into_iter().map(Into::into).collect(),
+            ..Self::new()
+        }
+    }
+
+    /// Returns the settings of this instance, e.g. for saving them into a TOML file.
+    /// The closures are not included. See [`ProtocConfig`].
+    ///
+    /// The durations are rounded up to whole milliseconds, so that a short non-zero timeout does
+    /// not become zero.
+    pub fn to_config(&self) -> ProtocConfig {
+        ProtocConfig {
+            protoc_path: self.protoc_path.clone(),
+            plugin_path: self.plugin_path.clone(),
+            plugin_name: self.plugin_name.clone(),
+            plugins: self
+                .extra_plugins
+                .iter()
+                .map(|plugin| PluginConfig {
+                    name: plugin.name.clone(),
+                    path: plugin.path.clone(),
+                    out_dir: plugin.out_dir.clone(),
+                })
+                .collect(),
+            out_dir: self.out_dir.clone(),
+            create_out_dir: self.create_out_dir,
+            #[cfg(feature = "zip")]
+            out_zip: self.out_zip.clone(),
+            manifest_out: self.manifest_out.clone(),
+            proto_files: self.proto_files.clone(),
+            proto_dirs_recursive: self.proto_dirs.clone(),
+            proto_paths: self.proto_paths.clone(),
+            include_imports: self.include_imports,
+            include_source_info: self.include_source_info,
+            include_std_types: self.include_std_types,
+            dependency_out: self.dependency_out.clone(),
+            max_request_size: self.max_request_size,
+            max_response_size: self.max_response_size,
+            request_size_hint: self.request_size_hint,
+            transport: self.transport,
+            fatal_warnings: self.fatal_warnings,
+            protoc_parse_timeout_ms: self.protoc_parse_timeout.map(millis_rounded_up),
+            error_format: self.error_format,
+            direct_dependencies: self.direct_dependencies.clone(),
+            direct_dependencies_violation_msg: self.direct_dependencies_violation_msg.clone(),
+            handle_signals: self.handle_signals,
+            retry_max_attempts: self.retry_policy.max_attempts,
+            retry_backoff_ms: millis_rounded_up(self.retry_policy.backoff),
+            reproducible: self.reproducible,
+            fail_fast: self.fail_fast,
+            temp_dir: self.temp_dir.clone(),
+            args: self
+                .extra_args
+                .iter()
+                .map(|arg| arg.to_string_lossy().into_owned())
+                .collect(),
+        }
+    }
+}
+
+/// Returns the duration in milliseconds, rounding up the sub-millisecond part and saturating at
+/// [`u64::MAX`].
+fn millis_rounded_up(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos().div_ceil(1_000_000)).unwrap_or(u64::MAX)
+}
@@ -0,0 +1,158 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The plugin binary embedded into this library, extracted into a cache directory on the first
+//! use. Enabled by the `embed-plugin` feature.
+//!
+//! This makes the library self-contained, e.g. when it's shipped inside another tool, since
+//! the plugin binary built alongside it doesn't need to stay at its build-time path.
+
+use crate::{ErrorKind, Result};
+use ::sha2::{Digest, Sha256};
+use ::std::env;
+use ::std::io::{self, Write};
+use ::std::path::{Path, PathBuf};
+use ::std::sync::OnceLock;
+use ::tempfile::NamedTempFile;
+
+/// The plugin binary, embedded at the build time.
+static PLUGIN_BYTES: &[u8] = include_bytes!(env!("CARGO_BIN_FILE_PROTOC_PLUGIN_BIN"));
+
+/// The env var to override the cache directory to extract the plugin binary into.
+const CACHE_DIR_ENV_VAR: &str = "PROTOC_PLUGIN_BY_CLOSURE_CACHE_DIR";
+
+/// The subdirectory of the cache directory for this crate.
+const CACHE_SUBDIR_NAME: &str = "protoc-plugin-by-closure";
+
+/// The path to extract the plugin binary into and the SHA-256 hash of the binary, computed once
+/// per process.
+static EXTRACTED_PATH: OnceLock<(PathBuf, [u8; 32])> = OnceLock::new();
+
+/// Returns the path to the extracted plugin binary, extracting it first if it's not there,
+/// e.g. on the first use or after the cache is cleaned.
+///
+/// The path is `{cache}/protoc-plugin-by-closure/{version}-{hash}/protoc-plugin-bin`, where
+/// `{cache}` is the `PROTOC_PLUGIN_BY_CLOSURE_CACHE_DIR` env var, or the user's cache
+/// directory (`$XDG_CACHE_HOME`, `~/.cache` or `%LOCALAPPDATA%`), or the temp directory, and
+/// `{hash}` is the SHA-256 hash of the plugin binary. The binary is written into a temp file and
+/// renamed into place, so the processes extracting it concurrently never run a partially written
+/// one.
+///
+/// A cached file is run only if its size and hash match the embedded binary, and is extracted
+/// again otherwise. On Unix, the `protoc-plugin-by-closure` directory is created with the mode
+/// `0700`, and the extraction fails with [`ErrorKind::PluginExtractError`] if it's owned by
+/// another user or writable by others, e.g. when it's planted in a shared temp directory.
+pub fn extracted_plugin_path() -> Result<PathBuf> {
+    let (path, hash) = EXTRACTED_PATH.get_or_init(|| {
+        let hash: [u8; 32] = Sha256::digest(PLUGIN_BYTES).into();
+        let hex = hash
+            .iter()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        let path = cache_root()
+            .join(CACHE_SUBDIR_NAME)
+            .join(format!("{}-{}", env!("CARGO_PKG_VERSION"), hex))
+            .join(format!("protoc-plugin-bin{}", env::consts::EXE_SUFFIX));
+        (path, hash)
+    });
+    let extract_error = |source| ErrorKind::PluginExtractError {
+        path: path.clone(),
+        source,
+    };
+    let dir = path.parent().expect("the path has the parent directory");
+    create_private_dir(dir).map_err(extract_error)?;
+    if !is_extracted(path, hash) {
+        extract(path, hash).map_err(extract_error)?;
+    }
+    Ok(path.clone())
+}
+
+/// Returns whether the file at the path is the embedded plugin binary.
+fn is_extracted(path: &Path, hash: &[u8; 32]) -> bool {
+    match ::std::fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() && metadata.len() == PLUGIN_BYTES.len() as u64 => {
+            ::std::fs::read(path).is_ok_and(|bytes| Sha256::digest(bytes)[..] == hash[..])
+        }
+        _ => false,
+    }
+}
+
+/// Creates the directory of the extracted binary and its `protoc-plugin-by-closure` parent with
+/// the mode `0700` on Unix, and checks that the latter is private to this user.
+fn create_private_dir(dir: &Path) -> io::Result<()> {
+    let mut builder = ::std::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    {
+        use ::std::os::unix::fs::DirBuilderExt;
+        builder.mode(0o700);
+    }
+    builder.create(dir)?;
+    #[cfg(unix)]
+    {
+        use ::std::os::unix::fs::MetadataExt;
+        let root = dir.parent().expect("the dir is in the cache subdir");
+        let metadata = ::std::fs::symlink_metadata(root)?;
+        // SAFETY: `geteuid` has no preconditions and never fails.
+        let uid = unsafe { ::libc::geteuid() };
+        if !metadata.is_dir() || metadata.uid() != uid || metadata.mode() & 0o022 != 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("{} is not a directory private to this user", root.display()),
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Returns the root cache directory.
+fn cache_root() -> PathBuf {
+    let non_empty = |name| env::var_os(name).filter(|value| !value.is_empty());
+    if let Some(dir) = non_empty(CACHE_DIR_ENV_VAR) {
+        return dir.into();
+    }
+    if cfg!(windows) {
+        if let Some(dir) = non_empty("LOCALAPPDATA") {
+            return dir.into();
+        }
+    } else if let Some(dir) = non_empty("XDG_CACHE_HOME") {
+        return dir.into();
+    } else if let Some(home) = non_empty("HOME") {
+        return Path::new(&home).join(".cache");
+    }
+    env::temp_dir()
+}
+
+/// Writes the plugin binary to the path, atomically.
+fn extract(path: &Path, hash: &[u8; 32]) -> io::Result<()> {
+    let dir = path.parent().expect("the path has the parent directory");
+    let mut file = NamedTempFile::new_in(dir)?;
+    file.write_all(PLUGIN_BYTES)?;
+    #[cfg(unix)]
+    {
+        use ::std::os::unix::fs::PermissionsExt;
+        file.as_file()
+            .set_permissions(::std::fs::Permissions::from_mode(0o755))?;
+    }
+    // Close the file before renaming it, since running a file opened for writing fails with
+    // `ETXTBSY`.
+    let temp_path = file.into_temp_path();
+    match temp_path.persist(path) {
+        Ok(()) => Ok(()),
+        // Another process won the race, e.g. on Windows where a running binary can't be
+        // replaced.
+        Err(_) if is_extracted(path, hash) => Ok(()),
+        Err(e) => Err(e.error),
+    }
+}
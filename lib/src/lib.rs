@@ -14,18 +14,126 @@
 
 #![doc = include_str!("../readme.md")]
 
-use ::ipc_channel::ipc::{IpcBytesReceiver, IpcBytesSender, IpcOneShotServer};
+pub mod build_helper;
+#[cfg(feature = "serde")]
+mod config;
+#[cfg(feature = "embed-plugin")]
+mod embedded_plugin;
+#[cfg(feature = "serde")]
+mod manifest;
+pub mod plugin_proto;
+#[cfg(feature = "prost")]
+mod prost_plugin;
+#[cfg(feature = "protobuf")]
+mod protobuf_plugin;
+pub mod request;
+mod response_bytes;
+mod signals;
+#[cfg(any(feature = "prost", feature = "protobuf"))]
+mod typed_plugin;
+#[cfg(unix)]
+mod unix_socket;
+#[cfg(feature = "well-known-types")]
+mod well_known_types;
+
+use ::ipc_channel::ipc::{
+    IpcBytesReceiver, IpcBytesSender, IpcError, IpcOneShotServer, IpcSender, TryRecvError,
+    bytes_channel,
+};
+#[cfg(feature = "on-memory")]
+use ::std::borrow::Cow;
+#[cfg(feature = "on-memory")]
+use ::std::collections::BTreeMap;
 use ::std::env;
-use ::std::path::PathBuf;
-use ::std::process::{Command, ExitStatus};
-use ::std::time::Duration;
+use ::std::ffi::OsString;
+use ::std::io::{self, BufRead, BufReader, Read};
 #[cfg(feature = "on-memory")]
+use ::std::path::Component;
+use ::std::path::{Path, PathBuf};
+use ::std::process::{Child, Command, ExitStatus, Stdio};
+use ::std::sync::atomic::{AtomicBool, Ordering};
+use ::std::sync::{Arc, Mutex, MutexGuard, PoisonError, mpsc};
+use ::std::thread;
+use ::std::time::{Duration, Instant};
 use ::tempfile::TempDir;
 use ::thiserror::Error;
 use ::wait_timeout::ChildExt;
 
+use self::plugin_proto::CodeGeneratorResponse;
+use self::signals::SignalGuard;
+
 const PLUGIN_PATH: &str = env!("CARGO_BIN_FILE_PROTOC_PLUGIN_BIN");
 
+/// The default plugin name, i.e. `protoc` is run with `--plugin=protoc-gen-rust-ppbc=...`.
+const DEFAULT_PLUGIN_NAME: &str = "rust-ppbc";
+
+/// The placeholder of the init key in the command lines shown by [`Protoc::dry_run`] and
+/// attached to the errors.
+const INIT_KEY_PLACEHOLDER: &str = "<init-key>";
+
+/// The total length of the `protoc` arguments above which we pass them by a response file
+/// (`@argfile`) instead, keeping well below the Windows' 32K characters command line limit.
+const MAX_COMMAND_LINE_ARGS_LEN: usize = 8 * 1024;
+
+/// The subdirectory names in the `ProtocOnMemory`'s temp dir for the input and the output files.
+#[cfg(feature = "on-memory")]
+const TEMP_PROTO_DIR_NAME: &str = "proto";
+#[cfg(feature = "on-memory")]
+const TEMP_OUT_DIR_NAME: &str = "out";
+
+/// The env var which cargo sets only for the build scripts, used by [`Transport::detect`].
+const CARGO_CFG_TARGET_OS_ENV_VAR: &str = "CARGO_CFG_TARGET_OS";
+
+/// The file listing the paths to exclude from [`Protoc::proto_dir_recursive`].
+const PROTOC_IGNORE_FILE_NAME: &str = ".protocignore";
+
+/// The default limit of the `CodeGeneratorRequest` and the `CodeGeneratorResponse` sizes in bytes.
+const DEFAULT_MAX_REQUEST_SIZE: usize = 512 * 1024 * 1024;
+
+/// The environment variable to pass the request size limit to the plugin binary.
+/// Must be in sync with the one in the plugin binary.
+const MAX_REQUEST_SIZE_ENV_VAR: &str = "PROTOC_PLUGIN_BY_CLOSURE_MAX_REQUEST_SIZE";
+
+/// The start of the plugin binary's error response when it fails to connect back to this
+/// process, which `protoc` prints to its stderr. Must be in sync with the one in the plugin binary.
+const CONNECT_FAILURE_MESSAGE: &str = "failed to connect back to the host process";
+
+/// The environment variable to pass [`Protoc::request_size_hint`] to the plugin binary.
+/// Must be in sync with the one in the plugin binary.
+const REQUEST_SIZE_HINT_ENV_VAR: &str = "PROTOC_PLUGIN_BY_CLOSURE_REQUEST_SIZE_HINT";
+
+/// The environment variable to pass the tag of a [`Protoc::run_many`] run to the plugin binary,
+/// which sends it back in the handshake. Must be in sync with the one in the plugin binary.
+const RUN_TAG_ENV_VAR: &str = "PROTOC_PLUGIN_BY_CLOSURE_RUN_TAG";
+
+/// The version of the wire contract between this library and the plugin binary, which the both
+/// sides exchange first. Bump this whenever the contract changes. Must be in sync with the one in
+/// the plugin binary.
+const PROTOCOL_VERSION: u32 = 1;
+
+/// The env var for the reproducible builds, see <https://reproducible-builds.org/specs/source-date-epoch/>.
+const SOURCE_DATE_EPOCH_ENV_VAR: &str = "SOURCE_DATE_EPOCH";
+
+/// The env var to point to `protoc`'s include directory. See [`Protoc::include_std_types`].
+const PROTOC_INCLUDE_ENV_VAR: &str = "PROTOC_INCLUDE";
+
+/// The interval to check the `protoc` process state while waiting for the plugin binary.
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// The timeout used by the `run_untimed` methods, i.e. waiting for `protoc` forever.
+const NO_TIMEOUT: Duration = Duration::MAX;
+
+/// The deadline used for [`NO_TIMEOUT`], which `Instant` can't represent.
+const FAR_FUTURE: Duration = Duration::from_secs(100 * 365 * 24 * 60 * 60);
+
+/// The pair of the IPC channels which the plugin binary sends back to us.
+type PluginChannels = (IpcBytesReceiver, IpcBytesSender);
+
+/// The control headers which the plugin binary in the batch mode sends before each request,
+/// and after the last one. Must be in sync with the plugin binary.
+const CONTROL_REQUEST_FOLLOWS: u8 = 1;
+const CONTROL_END: u8 = 0;
+
 /// Error type for this crate.
 #[derive(Error, Debug)]
 pub enum ErrorKind {
@@ -35,23 +143,1208 @@ pub enum ErrorKind {
     IpcError(#[from] ::ipc_channel::Error),
     #[error("IoError: {0}")]
     IoError(#[from] ::std::io::Error),
+    #[error("ProtobufError: {0}")]
+    ProtobufError(#[from] ::protobuf_core::ProtobufError),
+    /// A message of [`plugin_proto`] failed to parse. `offset` is the byte offset of the broken
+    /// field in the bytes passed to `from_bytes`, including the nested messages', and
+    /// `last_field_number` is the last field successfully parsed before it in the innermost
+    /// message.
+    #[error(
+        "MalformedMessage: corrupt protobuf at byte {offset}{}: {source}",
+        .last_field_number.map(|n| format!(" after field {}", n)).unwrap_or_default()
+    )]
+    MalformedMessage {
+        offset: usize,
+        last_field_number: Option<u32>,
+        source: ::protobuf_core::ProtobufError,
+    },
     #[error("CallbackError: {0}")]
     CallbackError(String),
-    #[error("ProtocTimeoutError")]
-    ProtocTimeoutError,
+    /// The run didn't finish within its timeout. `elapsed` is measured from spawning `protoc`.
+    ///
+    /// This replaces the former `ProtocTimeoutError`, adding the [`Phase`] which stalled.
+    #[error(
+        "Timeout: timed out after {elapsed:?} while {phase}{}",
+        timeout_details(stderr, command)
+    )]
+    Timeout {
+        phase: Phase,
+        elapsed: Duration,
+        stderr: String,
+        command: String,
+    },
+    #[error(
+        "ProtocParseTimeout: protoc didn't send the request to the plugin binary within \
+         {elapsed:?}{}",
+        timeout_details(stderr, command)
+    )]
+    ProtocParseTimeout {
+        elapsed: Duration,
+        stderr: String,
+        command: String,
+    },
+    /// `protoc` exited unsuccessfully.
+    ///
+    /// The payload used to be the bare [`ExitStatus`], which is now available by
+    /// [`ExitStatusInfo::status`], next to the outputs and the command line of `protoc`.
     #[error("ProtocProcessError: {0}")]
-    ProtocProcessError(ExitStatus),
+    ProtocProcessError(ExitStatusInfo),
+    #[error("CheckError: {}", check_error_details(.0))]
+    CheckError(Vec<Diagnostic>),
     #[error("FileNameError")]
     FileNameError,
+    #[error("PluginNotConnectedError: the plugin binary could not connect back")]
+    PluginNotConnectedError,
+    #[error("PluginConnectError: {0}")]
+    PluginConnectError(String),
+    #[error("PluginNotInvoked: protoc succeeded without invoking the plugin")]
+    PluginNotInvoked,
+    #[error("PluginNoRequestError: the plugin binary connected but did not send the request")]
+    PluginNoRequestError,
+    #[error("OutDirNotSetError: neither the out_dir nor the OUT_DIR env var is set")]
+    OutDirNotSetError,
+    #[error("UnsafeOutputFileNameError: {0} points outside of the output directory")]
+    UnsafeOutputFileNameError(String),
+    #[error(
+        "ResponseTooLarge: the response is {actual} bytes, exceeding the limit of {limit} bytes"
+    )]
+    ResponseTooLarge { limit: usize, actual: usize },
+    #[error("RequestTooLarge: the request is {actual} bytes, exceeding the limit of {limit} bytes")]
+    RequestTooLarge { limit: usize, actual: usize },
+    #[error(
+        "PluginVersionMismatch: the library speaks the protocol version {lib} but the plugin binary speaks {bin}. Rebuild the plugin binary"
+    )]
+    PluginVersionMismatch { lib: u32, bin: u32 },
+    /// The plugin binary which connected in a run of [`Protoc::run_many`] was not invoked for
+    /// that run, e.g. a late one of an earlier run. `actual` is the run tag it sent, if any.
+    #[error(
+        "PluginRunMismatch: the plugin binary connected for the run {actual:?} instead of the run {expected}"
+    )]
+    PluginRunMismatch { expected: u64, actual: Option<u64> },
+    #[error(
+        "NoInput: no input proto files are given. Add them by `proto_file` (or `add_file` for `ProtocOnMemory`)"
+    )]
+    NoInput,
+    #[error("UnsupportedPathError: {0} is a UNC path, which protoc does not support")]
+    UnsupportedPathError(String),
+    #[error("Interrupted: the run was interrupted by a signal")]
+    Interrupted,
+    #[error("Cancelled: the run was cancelled by the cancellation token")]
+    Cancelled,
+    #[error("DuplicateOutputFileError: {0} is generated more than once")]
+    DuplicateOutputFileError(String),
+    #[cfg(feature = "on-memory")]
+    #[error("DuplicateInputFileError: {0} is added more than once to ProtocOnMemory")]
+    DuplicateInputFileError(String),
+    #[cfg(feature = "on-memory")]
+    #[error(
+        "UnknownGenerateOnlyFileError: {0} is given to generate_only but is not added to ProtocOnMemory"
+    )]
+    UnknownGenerateOnlyFileError(String),
+    #[error("InsertionPointError: cannot insert into {file} at {insertion_point}: {reason}")]
+    InsertionPointError {
+        file: String,
+        insertion_point: String,
+        reason: String,
+    },
+    #[cfg(feature = "zip")]
+    #[error("OutZipConflictError: both out_dir and out_zip are set")]
+    OutZipConflictError,
+    #[cfg(feature = "zip")]
+    #[error("ZipError: {0}")]
+    ZipError(#[from] ::zip::result::ZipError),
+    #[cfg(feature = "serde")]
+    #[error("ManifestError: failed to serialize the manifest: {0}")]
+    ManifestError(#[from] ::serde_json::Error),
+    #[cfg(feature = "prost")]
+    #[error("ProstDecodeError: failed to decode {message_name}: {source}")]
+    ProstDecodeError {
+        message_name: &'static str,
+        source: ::prost::DecodeError,
+    },
+    #[cfg(feature = "protobuf")]
+    #[error("ProtobufCodecError: failed to parse or serialize {message_name}: {source}")]
+    ProtobufCodecError {
+        message_name: &'static str,
+        source: ::protobuf::Error,
+    },
+    #[error("ProtocNotFound: {0} is not found")]
+    ProtocNotFound(String),
+    #[error(
+        "StdTypesNotFound: protoc's include directory with the well-known types is not found in {}. Set the PROTOC_INCLUDE env var to it",
+        .0.iter().map(|dir| dir.display().to_string()).collect::<Vec<_>>().join(", ")
+    )]
+    StdTypesNotFound(Vec<PathBuf>),
+    #[error(
+        "PluginNotFound: the plugin binary {} is not found. Rebuild this crate, e.g. after `cargo clean`, or fix the path given to `Protoc::plugin_path`",
+        .0.display()
+    )]
+    PluginNotFound(PathBuf),
+    #[error("PluginNotExecutable: the plugin binary {} is not executable", .0.display())]
+    PluginNotExecutable(PathBuf),
+    #[error(
+        "PluginNotFoundInRunner: the plugin binary is not found or not executable at the mapped path {}. Make it reachable at the same path both here and in the runner, e.g. by a bind mount",
+        .0.display()
+    )]
+    PluginNotFoundInRunner(PathBuf),
+    #[cfg(feature = "embed-plugin")]
+    #[error("PluginExtractError: failed to extract the plugin binary to {}: {source}", .path.display())]
+    PluginExtractError {
+        path: PathBuf,
+        source: ::std::io::Error,
+    },
+    #[error("Rejected: the request is rejected by the request guard: {0}")]
+    Rejected(String),
+    #[error("VerificationFailed: {}: {message}", file.display())]
+    VerificationFailed { file: PathBuf, message: String },
+    #[error("RetryError: failed after {attempts} attempts, the last error: {error}")]
+    RetryError {
+        attempts: u32,
+        error: Box<ErrorKind>,
+    },
+    #[error("PluginNameConflict: the plugin name {0} is used more than once")]
+    PluginNameConflict(String),
+    #[error(
+        "InvalidFileName: the name of the file #{index} in the response is not valid UTF-8: {}",
+        String::from_utf8_lossy(bytes)
+    )]
+    InvalidFileName { index: usize, bytes: Vec<u8> },
+    #[error("GeneratorReportedError: {0}")]
+    GeneratorReportedError(String),
+    #[error("OutDirNotFound: the output directory {} does not exist", .0.display())]
+    OutDirNotFound(PathBuf),
+    #[error("OutDirCreateError: failed to create the output directory {}: {source}", .path.display())]
+    OutDirCreateError {
+        path: PathBuf,
+        source: ::std::io::Error,
+    },
+    #[error("TempDirError: failed to create a temp dir under {}: {source}", .path.display())]
+    TempDirError {
+        path: PathBuf,
+        source: ::std::io::Error,
+    },
+    /// An error after `protoc` was spawned, with the command line of the `protoc` run.
+    /// [`ErrorKind::ProtocProcessError`] and [`ErrorKind::Timeout`] carry the command line by
+    /// themselves and are never wrapped. Use [`ErrorKind::into_inner`] to match the error.
+    #[error("{error}{}", timeout_details("", command))]
+    WithCommand {
+        command: String,
+        error: Box<ErrorKind>,
+    },
+}
+
+impl ErrorKind {
+    /// Returns [`ErrorKind::Timeout`] of the wait in the given phase started at `started`,
+    /// without the stderr and the command line of `protoc` which only the caller owning
+    /// the process can fill.
+    pub(crate) fn timeout(phase: Phase, started: Instant) -> Self {
+        Self::Timeout {
+            phase,
+            elapsed: started.elapsed(),
+            stderr: String::new(),
+            command: String::new(),
+        }
+    }
+
+    /// Wraps an error after `protoc` was spawned by [`ErrorKind::WithCommand`], unless it
+    /// already has the command line.
+    pub(crate) fn with_command(self, command: &str) -> Self {
+        match self {
+            Self::ProtocProcessError(_)
+            | Self::Timeout { .. }
+            | Self::ProtocParseTimeout { .. }
+            | Self::WithCommand { .. } => self,
+            error => Self::WithCommand {
+                command: command.to_string(),
+                error: Box::new(error),
+            },
+        }
+    }
+
+    /// Returns the error wrapped by [`ErrorKind::WithCommand`], or this error itself.
+    pub fn into_inner(self) -> Self {
+        match self {
+            Self::WithCommand { error, .. } => *error,
+            error => error,
+        }
+    }
+
+    /// Returns the diagnostics parsed from `protoc`'s stderr attached to this error, e.g. of
+    /// [`ErrorKind::ProtocProcessError`], or an empty list if there is none.
+    /// See [`parse_protoc_diagnostics`].
+    pub fn diagnostics(&self) -> Vec<Diagnostic> {
+        match self {
+            Self::ProtocProcessError(info) => parse_protoc_diagnostics(&info.stderr),
+            Self::CheckError(diagnostics) => diagnostics.clone(),
+            Self::Timeout { stderr, .. } | Self::ProtocParseTimeout { stderr, .. } => {
+                parse_protoc_diagnostics(stderr)
+            }
+            Self::RetryError { error, .. } | Self::WithCommand { error, .. } => error.diagnostics(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Wraps the error message of the user's closure into [`ErrorKind::CallbackError`].
+fn callback<F>(body: F) -> impl FnOnce(&[u8]) -> Result<Vec<u8>>
+where
+    F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+{
+    |req| body(req).map_err(ErrorKind::CallbackError)
+}
+
+/// Formats the stderr and the command line of `protoc` attached to [`ErrorKind::Timeout`],
+/// if any.
+fn timeout_details(stderr: &str, command: &str) -> String {
+    let mut details = String::new();
+    if !stderr.is_empty() {
+        details.push_str(&format!("\nstderr:\n{}", stderr.trim_end()));
+    }
+    if !command.is_empty() {
+        details.push_str(&format!("\ncommand: {}", command));
+    }
+    details
+}
+
+/// The phase of a run which stalled, reported by [`ErrorKind::Timeout`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for `protoc` to invoke the plugin binary, and for the plugin binary to connect
+    /// back and send its protocol version.
+    AwaitingPluginHandshake,
+    /// Waiting for the plugin binary to send the `CodeGeneratorRequest`.
+    AwaitingRequest,
+    /// Running the closure. The closure is not interrupted, but the run fails after it returns.
+    RunningClosure,
+    /// Waiting for `protoc` to exit after the `CodeGeneratorResponse` is sent.
+    AwaitingProtocExit,
+}
+
+impl ::std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        f.write_str(match self {
+            Phase::AwaitingPluginHandshake => "waiting for the plugin binary to connect back",
+            Phase::AwaitingRequest => "waiting for the plugin binary to send the request",
+            Phase::RunningClosure => "running the closure",
+            Phase::AwaitingProtocExit => "waiting for protoc to exit",
+        })
+    }
+}
+
+/// A token to cancel an in-flight run from another thread. See [`Protoc::run_cancellable`].
+///
+/// The clones share the same state, so keep one and pass another to the run.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancellationToken {
+    /// Creates a new token, which is not cancelled yet.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Cancels the runs watching this token.
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    /// Returns whether [`Self::cancel`] has been called.
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+/// The progress of a run, reported to the handler set by [`Protoc::on_event`].
+///
+/// For a successful run, the events are reported in the order of the variants, except
+/// [`Self::ProtocStdout`] and [`Self::ProtocStderr`] which are reported whenever `protoc` prints
+/// a line. On errors, the events up to the failure point are reported.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RunEvent {
+    /// The temp dir of [`ProtocOnMemory`] is kept after running by [`ProtocOnMemory::keep_temp`],
+    /// at the given path. Reported before `protoc` is spawned, so also for the failed runs.
+    TempDirKept(PathBuf),
+    /// The `protoc` process is spawned.
+    Spawned,
+    /// The plugin binary connected back, and its protocol version is checked.
+    PluginConnected,
+    /// The `CodeGeneratorRequest` is received from the plugin binary.
+    RequestReceived {
+        /// The size of the request in bytes.
+        bytes: usize,
+        /// The number of the proto files in the request, including the imported ones.
+        proto_files: usize,
+    },
+    /// The closure is called.
+    ClosureStarted,
+    /// The closure returned, either successfully or not.
+    ClosureFinished {
+        /// How long the closure ran.
+        duration: Duration,
+    },
+    /// The `CodeGeneratorResponse` is sent to the plugin binary.
+    ResponseSent {
+        /// The size of the response in bytes.
+        bytes: usize,
+    },
+    /// A line printed to the stdout by `protoc`, including the trailing newline if any.
+    ///
+    /// `protoc`'s outputs are captured silently and attached to the errors, so handle this to
+    /// forward them e.g. to the console. Reported from a background thread reading the pipe.
+    ProtocStdout(String),
+    /// A line printed to the stderr by `protoc`, including the trailing newline if any.
+    /// See [`Self::ProtocStdout`].
+    ProtocStderr(String),
+    /// The `protoc` process exited.
+    ProtocExited(ExitStatus),
+}
+
+/// The handler set by [`Protoc::on_event`].
+type EventHandler = Arc<dyn Fn(RunEvent) + Send + Sync>;
+
+/// The summary of a `CodeGeneratorRequest`, passed to the guard set by
+/// [`Protoc::request_guard`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestInfo {
+    /// The number of the proto files in the request, including the imported ones.
+    pub proto_file_count: usize,
+    /// The size of the request in bytes.
+    pub byte_len: usize,
+}
+
+/// The context of a run, passed to the closure of [`Protoc::run_with_context`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunContext<'a> {
+    /// The output directory set by [`Protoc::out_dir`], where the generated files are written.
+    /// `None` if it's not set, e.g. when writing into `Protoc::out_zip`.
+    pub out_dir: Option<&'a Path>,
+    /// The absolute path of the `protoc` executable. See [`Protoc::resolved_protoc_path`].
+    pub protoc_path: &'a Path,
+}
+
+/// The guard set by [`Protoc::request_guard`].
+type RequestGuard = Arc<dyn Fn(&RequestInfo) -> ::std::result::Result<(), String> + Send + Sync>;
+
+/// The verifier set by [`Protoc::verify_output`].
+type OutputVerifier = Arc<dyn Fn(&Path, &[u8]) -> ::std::result::Result<(), String> + Send + Sync>;
+
+/// The runner set by [`Protoc::runner`].
+type Runner = Arc<dyn Fn(&PreparedCommand) -> Command + Send + Sync>;
+
+/// The path mapping set by [`Protoc::path_mapper`].
+type PathMapper = Arc<dyn Fn(&Path) -> PathBuf + Send + Sync>;
+
+/// An external plugin run alongside the closure. See [`Protoc::with_plugin`].
+#[derive(Debug, Clone)]
+struct ExtraPlugin {
+    name: String,
+    path: PathBuf,
+    out_dir: PathBuf,
+}
+
+/// The policy to re-run `protoc` on the transient failures. See [`Protoc::retry_policy`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RetryPolicy {
+    /// The maximum number of the attempts including the first one. `1` disables the retries.
+    pub max_attempts: u32,
+    /// The wait before the first retry, which is doubled for each further retry.
+    pub backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 1,
+            backoff: Duration::from_millis(100),
+        }
+    }
+}
+
+/// The exit status and the outputs of the `protoc` process, attached to the errors about it
+/// so that the logs are self-contained.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExitStatusInfo {
+    /// The exit code, or `None` if `protoc` was terminated by a signal.
+    pub code: Option<i32>,
+    /// The signal which terminated `protoc`. Always `None` on the non-Unix platforms.
+    pub signal: Option<i32>,
+    /// The stdout of `protoc`.
+    pub stdout: String,
+    /// The stderr of `protoc`, which contains its diagnostics.
+    pub stderr: String,
+    /// The `protoc` command line in the `sh` syntax, with the init key redacted.
+    /// See [`PreparedCommand::to_shell_string`].
+    pub command: String,
+    status: Option<ExitStatus>,
+}
+
+impl ExitStatusInfo {
+    fn new(status: Option<ExitStatus>) -> Self {
+        #[cfg(unix)]
+        let signal = status.and_then(|status| {
+            use ::std::os::unix::process::ExitStatusExt;
+            status.signal()
+        });
+        #[cfg(not(unix))]
+        let signal = None;
+        Self {
+            code: status.and_then(|status| status.code()),
+            signal,
+            stdout: String::new(),
+            stderr: String::new(),
+            command: String::new(),
+            status,
+        }
+    }
+
+    /// Returns the exit status, or `None` if it's not known.
+    pub fn status(&self) -> Option<ExitStatus> {
+        self.status
+    }
+}
+
+impl ::std::fmt::Display for ExitStatusInfo {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        match (self.status, self.signal) {
+            (_, Some(signal)) => write!(f, "terminated by signal {}", signal)?,
+            (Some(status), None) => write!(f, "{}", status)?,
+            (None, None) => write!(f, "no exit status")?,
+        }
+        if !self.stderr.is_empty() {
+            write!(f, "\nstderr:\n{}", self.stderr.trim_end())?;
+        }
+        if !self.stdout.is_empty() {
+            write!(f, "\nstdout:\n{}", self.stdout.trim_end())?;
+        }
+        if !self.command.is_empty() {
+            write!(f, "\ncommand: {}", self.command)?;
+        }
+        Ok(())
+    }
+}
+
+/// The format of `protoc`'s error and warning messages. See [`Protoc::error_format`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(::serde::Serialize, ::serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum ErrorFormat {
+    /// `foo.proto:3:25: Expected ";".`, which is `protoc`'s default.
+    #[default]
+    Gcc,
+    /// `foo.proto(3) : error in column=25: Expected ";".`, which Visual Studio recognizes.
+    Msvs,
+}
+
+impl ErrorFormat {
+    /// Returns the value of `protoc`'s `--error_format` option.
+    fn as_arg(self) -> &'static str {
+        match self {
+            Self::Gcc => "gcc",
+            Self::Msvs => "msvs",
+        }
+    }
+}
+
+/// The transport between this library and the plugin binary which `protoc` runs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[cfg_attr(
+    feature = "serde",
+    derive(::serde::Serialize, ::serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum Transport {
+    /// Uses `ipc-channel`. This is the [`Default`], but see [`Transport::detect`] for what
+    /// [`Protoc`] uses by default.
+    #[default]
+    IpcChannel,
+    /// Uses a Unix domain socket in a temp dir. Useful in the sandboxed environments where
+    /// the shared memory mechanisms which `ipc-channel` relies on are not available.
+    #[cfg(unix)]
+    UnixSocket,
+}
+
+impl Transport {
+    /// Returns the transport which [`Protoc`] uses when none is set by [`Protoc::transport`].
+    ///
+    /// In a cargo build script (detected by the `CARGO_CFG_TARGET_OS` env var which cargo sets
+    /// only for build scripts) on Unix, this is [`Transport::UnixSocket`], since the build
+    /// sandboxes (e.g. on macOS, where `ipc-channel` relies on Mach ports) often block
+    /// the mechanisms of `ipc-channel`. Otherwise, this is [`Transport::IpcChannel`].
+    ///
+    /// Note that `protoc` and the plugin binary run on the host, so the host platform decides
+    /// the transport even when cross-compiling for another target OS.
+    pub fn detect() -> Self {
+        #[cfg(unix)]
+        if env::var_os(CARGO_CFG_TARGET_OS_ENV_VAR).is_some() {
+            return Self::UnixSocket;
+        }
+        Self::IpcChannel
+    }
+}
+
+/// The server side of the [`Transport`], waiting for the plugin binary to connect.
+enum PluginServer {
+    /// The `ipc-channel` server and its name. It accepts only one connection, so it's taken on
+    /// accepting, and a new one is armed by [`IpcServer::init_key`] for the next connection.
+    IpcChannel(Option<(IpcOneShotServer<PluginChannels>, String)>),
+    /// The socket, which is kept for all the connections.
+    #[cfg(unix)]
+    UnixSocket {
+        listener: ::std::os::unix::net::UnixListener,
+        // Declared after `listener` so that the socket is closed before it's removed.
+        _socket_dir: TempDir,
+        init_key: String,
+    },
+}
+
+/// The low-level server which the plugin binary connects back to, for running `protoc`
+/// by yourself (e.g. in a custom sandbox) instead of by [`Protoc`].
+///
+/// [`Self::start`] returns the init key along with the server. Run `protoc` with
+/// `--plugin=protoc-gen-{name}={plugin_path}` (see [`plugin_path`]), `--{name}_out=...` and
+/// `--{name}_opt={init_key}`, and then call [`Self::serve`] with your closure.
+///
+/// # Example
+/// ```no_run
+/// # fn run_protoc() {
+///     use protoc_plugin_by_closure::{IpcServer, plugin_path};
+///     use std::process::Command;
+///     use std::time::Duration;
+///     let (init_key, server) = IpcServer::start().unwrap();
+///     let mut protoc = Command::new("protoc")
+///         .arg(format!("--plugin=protoc-gen-my={}", plugin_path().display()))
+///         .arg("--my_out=path/to/my/output_dir/")
+///         .arg(format!("--my_opt={}", init_key))
+///         .arg("my_protobuf_file.proto")
+///         .spawn()
+///         .unwrap();
+///     server
+///         .serve(Duration::from_secs(3), |request_bytes| {
+///             // Your plugin logic here.
+/// #           unimplemented!()
+///         })
+///         .unwrap();
+///     assert!(protoc.wait().unwrap().success());
+/// # }
+/// ```
+pub struct IpcServer {
+    server: PluginServer,
+    /// The tag which the next plugin binary must send back in the handshake, set for each run
+    /// of [`Protoc::run_many`].
+    tag: Option<u64>,
+}
+
+impl IpcServer {
+    /// Starts a new server with the default [`Transport`]. Returns the init key to pass to
+    /// the plugin binary as the plugin parameter, and the server.
+    pub fn start() -> Result<(String, Self)> {
+        Self::start_with_transport(Transport::default())
+    }
+
+    /// Same as [`Self::start`], but with the given [`Transport`].
+    pub fn start_with_transport(transport: Transport) -> Result<(String, Self)> {
+        let server = match transport {
+            Transport::IpcChannel => PluginServer::IpcChannel(None),
+            #[cfg(unix)]
+            Transport::UnixSocket => {
+                let (temp_dir, listener, init_key) = unix_socket::bind()?;
+                PluginServer::UnixSocket {
+                    listener,
+                    _socket_dir: temp_dir,
+                    init_key,
+                }
+            }
+        };
+        let mut server = Self { server, tag: None };
+        Ok((server.init_key()?, server))
+    }
+
+    /// Returns the init key for the next plugin binary to connect, arming a new `ipc-channel`
+    /// server if the last one has already accepted its connection.
+    fn init_key(&mut self) -> Result<String> {
+        let init_key = match &mut self.server {
+            PluginServer::IpcChannel(armed) => {
+                if armed.is_none() {
+                    *armed = Some(IpcOneShotServer::<PluginChannels>::new()?);
+                }
+                let (_, name) = armed.as_ref().expect("armed above");
+                name.clone()
+            }
+            #[cfg(unix)]
+            PluginServer::UnixSocket { init_key, .. } => init_key.clone(),
+        };
+        Ok(format!("{}:{}", PROTOCOL_VERSION, init_key))
+    }
+
+    /// Waits for the plugin binary to connect, and calls the given closure with the
+    /// `CodeGeneratorRequest` bytes, and sends the returned `CodeGeneratorResponse` bytes back.
+    ///
+    /// Returns [`ErrorKind::Timeout`] if the plugin binary doesn't connect and send the request
+    /// within the `timeout`. Since the `protoc` process is not known to the server, it's not
+    /// watched nor killed on errors.
+    pub fn serve<F>(mut self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.exchange(None, None, timeout, Interrupts::default(), &|_| (), |req| {
+            body(req).map_err(ErrorKind::CallbackError)
+        })
+        .map(|_| ())
+    }
+
+    /// Same as [`Self::serve`], but serves multiple requests sent by the plugin binary running
+    /// in the batch mode (`protoc-plugin-bin --batch`) over one session, calling the closure
+    /// for each request. Returns the number of the served requests.
+    ///
+    /// In the batch mode, the plugin binary reads the `CodeGeneratorRequest`s from its stdin,
+    /// each preceded by a control byte `1` and framed by a little-endian `u64` length prefix,
+    /// until a control byte `0` or EOF. It writes the `CodeGeneratorResponse`s to its stdout,
+    /// each framed by the same length prefix. The init key is read from the first request.
+    /// `protoc` itself never runs the plugin binary in this mode.
+    ///
+    /// The `timeout` applies to waiting for each request.
+    pub fn serve_many<F>(mut self, timeout: Duration, mut body: F) -> Result<usize>
+    where
+        F: FnMut(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        let mut session = self.connect(None, timeout, Interrupts::default())?;
+        let mut count = 0;
+        loop {
+            match session
+                .recv(None, Phase::AwaitingRequest, timeout, Interrupts::default())?
+                .as_slice()
+            {
+                [CONTROL_REQUEST_FOLLOWS] => (),
+                [CONTROL_END] => return Ok(count),
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "invalid control header from the plugin binary",
+                    )
+                    .into());
+                }
+            }
+            let req = session.recv(None, Phase::AwaitingRequest, timeout, Interrupts::default())?;
+            let res = body(&req).map_err(ErrorKind::CallbackError)?;
+            session.send(&res)?;
+            count += 1;
+        }
+    }
+
+    /// Waits for the plugin binary to connect and exchanges the request and the response.
+    /// Returns the response which the closure returned.
+    ///
+    /// If the `protoc` process is given, it is watched while waiting, and killed on errors.
+    /// The progress is reported to `on_event`.
+    ///
+    /// The `timeout`, or `parse_timeout` if given, applies to the handshake and the request
+    /// together. The closure is not timed here, the caller checks its own deadline in it.
+    fn exchange<F>(
+        &mut self,
+        mut process: Option<&mut Child>,
+        parse_timeout: Option<Duration>,
+        timeout: Duration,
+        interrupts: Interrupts<'_>,
+        on_event: &dyn Fn(RunEvent),
+        body: F,
+    ) -> Result<Vec<u8>>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let clock = RunClock::start();
+        let timeout = parse_timeout.unwrap_or(timeout);
+        let mut session = self.connect(process.as_deref_mut(), timeout, interrupts)?;
+        on_event(RunEvent::PluginConnected);
+        let req = session.recv(
+            process.as_deref_mut(),
+            Phase::AwaitingRequest,
+            clock.remaining(timeout),
+            interrupts,
+        )?;
+        on_event(RunEvent::RequestReceived {
+            bytes: req.len(),
+            proto_files: request::proto_file_names(&req).map_or(0, |names| names.len()),
+        });
+        let res = body(&req)
+            .and_then(|res| interrupts.check().map(|()| res))
+            .inspect_err(|_| kill_if_any(process))?;
+        session.send(&res)?;
+        on_event(RunEvent::ResponseSent { bytes: res.len() });
+        Ok(res)
+    }
+
+    /// Waits for the plugin binary to connect, and checks its handshake. The `timeout`
+    /// applies to both.
+    fn connect(
+        &mut self,
+        mut process: Option<&mut Child>,
+        timeout: Duration,
+        interrupts: Interrupts<'_>,
+    ) -> Result<PluginSession> {
+        let clock = RunClock::start();
+        let mut session = match &mut self.server {
+            PluginServer::IpcChannel(armed) => {
+                let (ipc_init_server, ipc_init_name) =
+                    armed.take().expect("armed by IpcServer::init_key");
+                // receive the ipc channels from the plugin exe.
+                let (req_recv, res_send) = accept_plugin(
+                    ipc_init_server,
+                    ipc_init_name,
+                    process.as_deref_mut(),
+                    timeout,
+                    interrupts,
+                )?;
+                PluginSession::IpcChannel(req_recv, res_send)
+            }
+            #[cfg(unix)]
+            PluginServer::UnixSocket { listener, .. } => {
+                let stream = unix_socket::accept_plugin(
+                    listener,
+                    process.as_deref_mut(),
+                    timeout,
+                    interrupts,
+                )?;
+                PluginSession::UnixSocket(stream)
+            }
+        };
+        let handshake = session.recv(
+            process.as_deref_mut(),
+            Phase::AwaitingPluginHandshake,
+            clock.remaining(timeout),
+            interrupts,
+        )?;
+        check_handshake(&handshake, self.tag).inspect_err(|_| kill_if_any(process))?;
+        Ok(session)
+    }
+}
+
+/// The connection to the plugin binary, after it connected back to the [`IpcServer`].
+enum PluginSession {
+    IpcChannel(IpcBytesReceiver, IpcBytesSender),
+    #[cfg(unix)]
+    UnixSocket(::std::os::unix::net::UnixStream),
+}
+
+impl PluginSession {
+    /// Waits for the plugin binary to send a message, while watching the deadline and
+    /// the `protoc` process. The `phase` is reported on timeout.
+    fn recv(
+        &mut self,
+        process: Option<&mut Child>,
+        phase: Phase,
+        timeout: Duration,
+        interrupts: Interrupts<'_>,
+    ) -> Result<Vec<u8>> {
+        match self {
+            PluginSession::IpcChannel(req_recv, _) => {
+                recv_message(req_recv, process, phase, timeout, interrupts)
+            }
+            #[cfg(unix)]
+            PluginSession::UnixSocket(stream) => {
+                unix_socket::recv_message(stream, process, phase, timeout, interrupts)
+            }
+        }
+    }
+
+    /// Sends a message to the plugin binary.
+    fn send(&mut self, bytes: &[u8]) -> Result<()> {
+        match self {
+            PluginSession::IpcChannel(_, res_send) => res_send.send(bytes)?,
+            #[cfg(unix)]
+            PluginSession::UnixSocket(stream) => unix_socket::write_frame(stream, bytes)?,
+        }
+        Ok(())
+    }
+}
+
+/// Returns the path to the plugin binary, for running `protoc` by yourself with [`IpcServer`].
+///
+/// This is the build-time path. With the `embed-plugin` feature, [`Protoc`] runs the
+/// `extracted_plugin_path` binary instead.
+pub fn plugin_path() -> &'static Path {
+    Path::new(PLUGIN_PATH)
+}
+
+/// Combines two closures for [`Protoc::run`] into one, e.g. to run the independent generators
+/// for the messages and for the services in a single `protoc` run.
+///
+/// Both closures take the same `CodeGeneratorRequest`, and their `CodeGeneratorResponse` files
+/// are concatenated in order. The run fails with [`ErrorKind::CallbackError`] if both generate
+/// a file with the same name, while inserting into the other's file is fine. If either response
+/// has an error, the combined response has the errors joined by newlines and no files. The
+/// supported features are the ones which both support.
+///
+/// # Example
+/// ```no_run
+/// # use protoc_plugin_by_closure::{Protoc, chain};
+/// # use std::time::Duration;
+/// # fn messages(_: &[u8]) -> Result<Vec<u8>, String> { unimplemented!() }
+/// # fn services(_: &[u8]) -> Result<Vec<u8>, String> { unimplemented!() }
+/// Protoc::new()
+///     .proto_file("my_protobuf_file.proto")
+///     .run(Duration::from_secs(3), chain(messages, services))
+///     .unwrap();
+/// ```
+pub fn chain<A, B>(a: A, b: B) -> impl FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>
+where
+    A: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    B: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+{
+    move |req| {
+        let parse =
+            |res: Vec<u8>| CodeGeneratorResponse::from_bytes(&res).map_err(|e| e.to_string());
+        let mut res = parse(a(req)?)?;
+        let other = parse(b(req)?)?;
+        if res.error.is_some() || other.error.is_some() {
+            let errors: Vec<_> = res.error.into_iter().chain(other.error).collect();
+            res = CodeGeneratorResponse {
+                error: Some(errors.join("\n")),
+                ..Default::default()
+            };
+        } else {
+            let names: ::std::collections::HashSet<_> = res
+                .files
+                .iter()
+                .filter(|file| file.insertion_point.is_none())
+                .map(|file| &file.name)
+                .collect();
+            if let Some(file) = other
+                .files
+                .iter()
+                .find(|file| file.insertion_point.is_none() && names.contains(&file.name))
+            {
+                return Err(format!("{} is generated by both closures", file.name));
+            }
+            res.supported_features = res
+                .supported_features
+                .zip(other.supported_features)
+                .map(|(a, b)| a & b);
+            // The editions which both closures support.
+            res.minimum_edition = res
+                .minimum_edition
+                .zip(other.minimum_edition)
+                .map(|(a, b)| a.max(b));
+            res.maximum_edition = res
+                .maximum_edition
+                .zip(other.maximum_edition)
+                .map(|(a, b)| a.min(b));
+            res.files.extend(other.files);
+        }
+        res.to_bytes().map_err(|e| e.to_string())
+    }
+}
+
+#[cfg(feature = "serde")]
+pub use config::{PluginConfig, ProtocConfig};
+#[cfg(feature = "embed-plugin")]
+pub use embedded_plugin::extracted_plugin_path;
+#[cfg(feature = "serde")]
+pub use manifest::{Manifest, ManifestFile};
+pub use response_bytes::IntoResponseBytes;
+
+/// Checks the plugin binary given to [`Protoc::plugin_path`] exists and is executable, since
+/// `protoc`'s errors about it don't tell which plugin is wrong.
+fn check_plugin_binary(path: &Path) -> Result<()> {
+    let metadata = ::std::fs::metadata(path)
+        .ok()
+        .filter(|metadata| metadata.is_file())
+        .ok_or_else(|| ErrorKind::PluginNotFound(path.to_path_buf()))?;
+    #[cfg(unix)]
+    {
+        use ::std::os::unix::fs::PermissionsExt;
+        if metadata.permissions().mode() & 0o111 == 0 {
+            return Err(ErrorKind::PluginNotExecutable(path.to_path_buf()));
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = metadata;
+    Ok(())
+}
+
+/// Returns the plugin binary to run, i.e. the extracted one with the `embed-plugin` feature,
+/// or else the build-time one.
+///
+/// The build-time path is never resolved at run time. It's only checked to still exist, since
+/// `protoc`'s error about a missing plugin doesn't tell what to do.
+fn plugin_binary() -> Result<PathBuf> {
+    #[cfg(feature = "embed-plugin")]
+    {
+        extracted_plugin_path()
+    }
+    #[cfg(not(feature = "embed-plugin"))]
+    {
+        if !Path::new(PLUGIN_PATH).is_file() {
+            return Err(ErrorKind::PluginNotFound(PLUGIN_PATH.into()));
+        }
+        Ok(PLUGIN_PATH.into())
+    }
 }
 
 /// Result type for this crate.
 pub type Result<T> = ::std::result::Result<T, ErrorKind>;
 
+/// The result of [`ProtocOnMemory::run_with_warnings`].
+#[cfg(feature = "on-memory")]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OnMemoryOutput {
+    /// The generated files, same as the ones returned by [`ProtocOnMemory::run`].
+    pub files: Vec<(String, String)>,
+    /// The warnings which `protoc` printed, same as [`RunOutput::warnings`].
+    pub warnings: Vec<Diagnostic>,
+    /// The lines of the `protoc`'s stderr which are not a part of the warnings, as they are.
+    pub stderr: String,
+}
+
+/// The outputs of [`ProtocOnMemory::run_and_read`].
+#[cfg(feature = "on-memory")]
+struct ReadOutput<C> {
+    /// The generated files read from the temp dir.
+    files: Vec<(String, C)>,
+    /// The encoded `CodeGeneratorResponse` bytes sent to `protoc`.
+    response: Vec<u8>,
+    /// The `protoc`'s stderr.
+    stderr: String,
+}
+
+/// A file generated by the plugin closure, i.e. a `CodeGeneratorResponse.File` entry with
+/// the Unix permission bits, which `CodeGeneratorResponse` has no field for.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GeneratedFile {
+    /// The `CodeGeneratorResponse.File` entry. Its insertion point is always `None` in the files
+    /// returned by [`Protoc::run_to_memory`], which applies the insertions.
+    pub file: plugin_proto::File,
+    /// The Unix permission bits of the file, e.g. `0o755` for an executable script.
+    ///
+    /// This is only honored by [`Protoc::run_files`], and is ignored on non-Unix platforms.
+    pub mode: Option<u32>,
+}
+
+impl From<plugin_proto::File> for GeneratedFile {
+    fn from(file: plugin_proto::File) -> Self {
+        Self { file, mode: None }
+    }
+}
+
+impl From<GeneratedFile> for plugin_proto::File {
+    fn from(generated: GeneratedFile) -> Self {
+        generated.file
+    }
+}
+
+/// Whether a file entry of the `CodeGeneratorResponse` created a new file or edited an existing
+/// one, reported by [`Protoc::run_with_summary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(::serde::Serialize, ::serde::Deserialize),
+    serde(tag = "type", rename_all = "snake_case")
+)]
+pub enum FileKind {
+    /// A new file, overwriting the existing one if any.
+    New,
+    /// An insertion into a file generated by the same `protoc` run.
+    Insertion {
+        /// The insertion point name, i.e. `NAME` in `@@protoc_insertion_point(NAME)`.
+        point: String,
+        /// The name of the file inserted into, relative to the output directory.
+        target: String,
+    },
+}
+
+/// A file entry of the `CodeGeneratorResponse` which `protoc` wrote, reported by
+/// [`Protoc::run_with_summary`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileSummary {
+    /// The path of the created or edited file, under [`Protoc::out_dir`] if it's set.
+    pub path: PathBuf,
+    /// Whether the file is created or edited.
+    pub kind: FileKind,
+}
+
+impl FileSummary {
+    fn new(out_dir: &Path, file: plugin_proto::File) -> Self {
+        let path = out_dir.join(&file.name);
+        let kind = match file.insertion_point {
+            Some(point) => FileKind::Insertion {
+                point,
+                target: file.name,
+            },
+            None => FileKind::New,
+        };
+        Self { path, kind }
+    }
+}
+
+/// The per-run settings of [`Protoc::run_many`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunSpec {
+    /// The input proto files of the run, replacing the ones set by [`Protoc::proto_file`] and
+    /// [`Protoc::proto_dir_recursive`].
+    pub proto_files: Vec<PathBuf>,
+    /// The output directory of the run, or `None` to use [`Protoc::out_dir`].
+    pub out_dir: Option<PathBuf>,
+}
+
+/// The result of a successful run of [`Protoc::run_with_output`] and [`Protoc::run_many`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RunOutput {
+    /// The files which `protoc` wrote. See [`Protoc::run_with_summary`].
+    pub files: Vec<FileSummary>,
+    /// The warnings which `protoc` printed, parsed from [`Self::stderr`].
+    pub warnings: Vec<Diagnostic>,
+    /// The whole stderr of `protoc`.
+    pub stderr: String,
+    /// The encoded `CodeGeneratorResponse` bytes sent to `protoc`.
+    pub response: Vec<u8>,
+    /// The `protoc` version in the `CodeGeneratorRequest`, e.g. `6.32.0`, if `protoc` sent it.
+    pub protoc_version: Option<String>,
+}
+
+#[cfg(feature = "serde")]
+impl RunOutput {
+    /// Computes the manifest of the generated files, the same as written by
+    /// [`Protoc::manifest_out`].
+    pub fn manifest(&self) -> Result<Manifest> {
+        Manifest::new(&self.response, self.protoc_version.clone())
+    }
+}
+
+/// A warning or an error which `protoc` printed, parsed from its stderr in either
+/// [`ErrorFormat`].
+///
+/// The [`Display`](::std::fmt::Display) output is in the [`ErrorFormat::Gcc`] format, e.g. for
+/// `cargo:warning=` lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    /// Whether this is a warning or an error.
+    pub severity: Severity,
+    /// The proto file name as `protoc` printed it, or empty if the diagnostic has no location.
+    pub file: String,
+    /// The 1-based line number, if any.
+    pub line: Option<u32>,
+    /// The 1-based column number, if any.
+    pub column: Option<u32>,
+    /// The message, without the `warning:` prefix.
+    pub message: String,
+    /// The lines following the diagnostic which belong to it, i.e. the indented lines and the
+    /// `note:`s.
+    pub notes: Vec<String>,
+}
+
+impl ::std::fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter<'_>) -> ::std::fmt::Result {
+        if !self.file.is_empty() {
+            write!(f, "{}:", self.file)?;
+            for number in [self.line, self.column].into_iter().flatten() {
+                write!(f, "{}:", number)?;
+            }
+            write!(f, " ")?;
+        }
+        if self.severity == Severity::Warning {
+            write!(f, "warning: ")?;
+        }
+        write!(f, "{}", self.message)?;
+        for note in &self.notes {
+            write!(f, "\n{}", note)?;
+        }
+        Ok(())
+    }
+}
+
+/// The severity of a [`Diagnostic`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// A warning, which fails `protoc` only with [`Protoc::fatal_warnings`].
+    Warning,
+    /// An error, which fails `protoc`.
+    Error,
+    /// A line which is not recognized as a warning or an error, e.g. a plugin's failure, kept as
+    /// is in the message. The file is always empty.
+    Raw,
+}
+
+/// The `protoc` command which [`Protoc::run`] spawns, returned by [`Protoc::dry_run`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PreparedCommand {
+    /// The path to the `protoc` command.
+    pub program: PathBuf,
+    /// The arguments. The init key for the plugin binary is a placeholder.
+    pub args: Vec<OsString>,
+    /// The env vars set in addition to the ones of this process.
+    pub env: Vec<(OsString, OsString)>,
+    /// The working directory, which is the current directory of this process.
+    pub cwd: PathBuf,
+}
+
+impl PreparedCommand {
+    /// Returns the command line in the `sh` syntax, e.g. for pasting it into a bug report.
+    ///
+    /// The arguments are single-quoted if needed, and the non-UTF-8 bytes are replaced with
+    /// `U+FFFD`. Note that running it standalone fails in the plugin binary, since the init key
+    /// is a placeholder.
+    pub fn to_shell_string(&self) -> String {
+        let mut words = Vec::new();
+        for (key, value) in &self.env {
+            words.push(format!(
+                "{}={}",
+                key.to_string_lossy(),
+                shell_quote(&value.to_string_lossy())
+            ));
+        }
+        words.push(shell_quote(&self.program.to_string_lossy()));
+        words.extend(
+            self.args
+                .iter()
+                .map(|arg| shell_quote(&arg.to_string_lossy())),
+        );
+        let command = words.join(" ");
+        if self.cwd.as_os_str().is_empty() {
+            return command;
+        }
+        format!(
+            "cd {} && {}",
+            shell_quote(&self.cwd.to_string_lossy()),
+            command
+        )
+    }
+
+    /// Returns the command with the given init key replaced by the placeholder.
+    fn redacted(&self, init_key: &str) -> Self {
+        let args = self
+            .args
+            .iter()
+            .map(|arg| match arg.to_str() {
+                Some(arg) => arg.replace(init_key, INIT_KEY_PLACEHOLDER).into(),
+                None => arg.clone(),
+            })
+            .collect();
+        Self {
+            args,
+            ..self.clone()
+        }
+    }
+}
+
+/// Quotes the word for `sh` if it contains any character other than the safe ones.
+fn shell_quote(word: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || "_@%+=:,./-".contains(c);
+    if !word.is_empty() && word.chars().all(is_safe) {
+        return word.to_string();
+    }
+    format!("'{}'", word.replace('\'', r"'\''"))
+}
+
 /// A convenient wrapper for running protoc command with your own plugin code as a closure.
 ///
 /// See the [crate level documentation](crate) for the basic explanation.
 ///
+/// `Protoc` is [`Clone`], so you can configure a base instance once and tweak each copy, e.g.
+/// to compile the same proto files with slightly different options. Cloning only copies the
+/// configuration; nothing is started until one of the `run` methods is called.
+///
 /// # Example
 /// ```no_run
 /// # fn run_protoc() {
@@ -75,112 +1368,2551 @@ pub type Result<T> = ::std::result::Result<T, ErrorKind>;
 ///     assert!(std::path::Path("path/to/my/output_dir/my_protobuf_file2.rs").exists());
 /// # }
 /// ```
+#[derive(Clone)]
 pub struct Protoc {
     protoc_path: PathBuf,
+    plugin_name: String,
     out_dir: Option<PathBuf>,
+    create_out_dir: bool,
+    #[cfg(feature = "zip")]
+    out_zip: Option<PathBuf>,
+    #[cfg(feature = "serde")]
+    manifest_out: Option<PathBuf>,
     proto_files: Vec<PathBuf>,
+    proto_dirs: Vec<PathBuf>,
     proto_paths: Vec<PathBuf>,
+    include_imports: bool,
+    include_source_info: bool,
+    include_std_types: bool,
+    dependency_out: Option<PathBuf>,
+    max_request_size: usize,
+    max_response_size: usize,
+    #[cfg(any(feature = "prost", feature = "protobuf"))]
+    supported_features: u64,
+    request_size_hint: usize,
+    transport: Option<Transport>,
+    fatal_warnings: bool,
+    protoc_parse_timeout: Option<Duration>,
+    error_format: Option<ErrorFormat>,
+    direct_dependencies: Option<Vec<String>>,
+    direct_dependencies_violation_msg: Option<String>,
+    handle_signals: bool,
+    retry_policy: RetryPolicy,
+    cancellation: Option<CancellationToken>,
+    reproducible: bool,
+    on_event: Option<EventHandler>,
+    request_guard: Option<RequestGuard>,
+    verify_output: Option<OutputVerifier>,
+    extra_plugins: Vec<ExtraPlugin>,
+    fail_fast: bool,
+    temp_dir: Option<PathBuf>,
+    plugin_path: Option<PathBuf>,
+    extra_args: Vec<OsString>,
+    runner: Option<Runner>,
+    path_mapper: Option<PathMapper>,
+    shared_server: Option<SharedServer>,
+}
+
+impl Protoc {
+    /// Creates a new `Protoc` instance.
+    pub fn new() -> Self {
+        Self {
+            protoc_path: "protoc".into(),
+            plugin_name: DEFAULT_PLUGIN_NAME.to_string(),
+            out_dir: None,
+            create_out_dir: true,
+            #[cfg(feature = "zip")]
+            out_zip: None,
+            #[cfg(feature = "serde")]
+            manifest_out: None,
+            proto_files: Vec::new(),
+            proto_dirs: Vec::new(),
+            proto_paths: Vec::new(),
+            include_imports: false,
+            include_source_info: false,
+            include_std_types: false,
+            dependency_out: None,
+            max_request_size: DEFAULT_MAX_REQUEST_SIZE,
+            max_response_size: DEFAULT_MAX_REQUEST_SIZE,
+            #[cfg(any(feature = "prost", feature = "protobuf"))]
+            supported_features: 0,
+            request_size_hint: 0,
+            transport: None,
+            fatal_warnings: false,
+            protoc_parse_timeout: None,
+            error_format: None,
+            direct_dependencies: None,
+            direct_dependencies_violation_msg: None,
+            handle_signals: false,
+            retry_policy: RetryPolicy::default(),
+            cancellation: None,
+            reproducible: false,
+            on_event: None,
+            request_guard: None,
+            verify_output: None,
+            extra_plugins: Vec::new(),
+            fail_fast: false,
+            temp_dir: None,
+            plugin_path: None,
+            extra_args: Vec::new(),
+            runner: None,
+            path_mapper: None,
+            shared_server: None,
+        }
+    }
+    /// Sets the path to the `protoc` command. Default is `"protoc"`.
+    pub fn protoc_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.protoc_path = path.into();
+        self
+    }
+    /// Sets the path to the plugin binary to run, instead of the one built with this crate (see
+    /// [`plugin_path`]), e.g. when a hermetic build system provides it as a declared input.
+    ///
+    /// The binary must be the `protoc-plugin-bin` of the same version. The run fails with
+    /// [`ErrorKind::PluginNotFound`] if it doesn't exist, or with
+    /// [`ErrorKind::PluginNotExecutable`] if it's not executable.
+    pub fn plugin_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.plugin_path = Some(path.into());
+        self
+    }
+    /// Sets the name of the plugin, which is used for the `protoc` options of the plugin:
+    /// `--plugin=protoc-gen-{name}=...`, `--{name}_out` and `--{name}_opt`.
+    /// Default is `"rust-ppbc"`.
+    ///
+    /// Change this if the default name collides with other plugins in the same `protoc` run.
+    pub fn plugin_name(mut self, name: &str) -> Self {
+        self.plugin_name = name.to_string();
+        self
+    }
+    /// Adds an external plugin for `protoc` to run alongside the closure, e.g. a gRPC plugin.
+    /// Corresponds to `--plugin=protoc-gen-{name}={path}` and `--{name}_out={out_dir}` options of
+    /// `protoc`.
+    ///
+    /// The name must differ from [`Self::plugin_name`] and the other plugins' names, otherwise
+    /// the run fails with [`ErrorKind::PluginNameConflict`].
+    pub fn with_plugin(
+        mut self,
+        name: &str,
+        path: impl Into<PathBuf>,
+        out_dir: impl Into<PathBuf>,
+    ) -> Self {
+        self.extra_plugins.push(ExtraPlugin {
+            name: name.to_string(),
+            path: path.into(),
+            out_dir: out_dir.into(),
+        });
+        self
+    }
+    /// Sets the output directory for the generated files. Corresponds to `--{plugin_name}_out` option of `protoc`.
+    pub fn out_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.out_dir = Some(path.into());
+        self
+    }
+    /// Creates the [`Self::out_dir`] and its parents before running `protoc` if they don't
+    /// exist. Default is `true`.
+    ///
+    /// If `false`, a missing output directory fails the run with [`ErrorKind::OutDirNotFound`]
+    /// before running `protoc`.
+    pub fn create_out_dir(mut self, create_out_dir: bool) -> Self {
+        self.create_out_dir = create_out_dir;
+        self
+    }
+    /// Sets the directory to create the temp dirs in, e.g. the output directory of
+    /// [`Self::run_to_memory`] and the argument file for the long command lines. Default is
+    /// [`::std::env::temp_dir`].
+    ///
+    /// The directory is created if it doesn't exist, and the run fails with
+    /// [`ErrorKind::TempDirError`] if it can't be created or written. The Unix domain socket of
+    /// [`Transport::UnixSocket`] is still created in the default temp directory, since its path
+    /// length is limited.
+    pub fn temp_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.temp_dir = Some(path.into());
+        self
+    }
+    /// Writes the generated files into a zip archive at the given path instead of a directory.
+    /// The nested file names are kept as the entry names. Can't be used with [`Self::out_dir`],
+    /// which makes the run fail with [`ErrorKind::OutZipConflictError`].
+    ///
+    /// `protoc` still writes into a temporary directory, so the insertion points are applied.
+    /// Requires the `zip` feature.
+    #[cfg(feature = "zip")]
+    pub fn out_zip(mut self, path: impl Into<PathBuf>) -> Self {
+        self.out_zip = Some(path.into());
+        self
+    }
+    /// Writes the [`Manifest`] of the generated files as JSON to the given path after a
+    /// successful run, e.g. for the tools verifying or vendoring them. The sizes and the SHA-256
+    /// hashes are computed from the closure's response rather than read back from the disk.
+    #[cfg(feature = "serde")]
+    pub fn manifest_out(mut self, path: impl Into<PathBuf>) -> Self {
+        self.manifest_out = Some(path.into());
+        self
+    }
+    /// Sets the path to the input proto file. Corresponds to the unnamed argument of `protoc`.
+    pub fn proto_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.proto_files.push(path.into());
+        self
+    }
+    /// Sets the paths to the input proto files. Corresponds to the unnamed arguments of `protoc`.
+    pub fn proto_files<I>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<PathBuf>,
+    {
+        self.proto_files.extend(paths.into_iter().map(|p| p.into()));
+        self
+    }
+    /// Adds all the `.proto` files under the directory, recursively, as the input proto files,
+    /// and the directory itself as a [`Self::proto_path`].
+    ///
+    /// The directory is walked when `protoc` is run, and the files are passed in the sorted
+    /// order. If the directory contains a `.protocignore` file, each of its lines is a path
+    /// relative to the directory (with `/` separators) of a file or a directory to exclude.
+    /// The empty lines and the lines starting with `#` are ignored.
+    pub fn proto_dir_recursive(mut self, dir: impl Into<PathBuf>) -> Self {
+        let dir = dir.into();
+        self.proto_paths.push(dir.clone());
+        self.proto_dirs.push(dir);
+        self
+    }
+    /// Sets the path to the input proto file directory. Corresponds to `--proto_path` option of `protoc`.
+    pub fn proto_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.proto_paths.push(path.into());
+        self
+    }
+    /// Adds a raw argument for `protoc`, for the options which this builder doesn't cover.
+    ///
+    /// The proto paths given as `-I`, `--proto_path` (either followed by the path as the next
+    /// argument, or joined to it like `-Ipath` and `--proto_path=path`) are merged after the
+    /// [`Self::proto_path`] ones, skipping the duplicates. The other arguments are passed as they
+    /// are, after the options set by this builder and before the proto paths.
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.extra_args.push(arg.into());
+        self
+    }
+    /// Adds raw arguments for `protoc`. See [`Self::arg`].
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.extra_args.extend(args.into_iter().map(Into::into));
+        self
+    }
+    /// Adds the proto paths listed in the given environment variable, e.g. `PROTO_INCLUDE`,
+    /// separated by the platform's path separator (`:` on Unix, `;` on Windows) like `PATH`.
+    /// Does nothing if the variable is not set. The empty entries are skipped.
+    pub fn proto_paths_from_env(mut self, var_name: &str) -> Self {
+        if let Some(value) = ::std::env::var_os(var_name) {
+            self.proto_paths.extend(
+                ::std::env::split_paths(&value).filter(|path| !path.as_os_str().is_empty()),
+            );
+        }
+        self
+    }
+    /// Includes all the transitive dependencies in the output `FileDescriptorSet`.
+    /// Corresponds to `--include_imports` option of `protoc`. Default is `false`.
+    ///
+    /// Only affects [`Self::compile_descriptor_set`]. `protoc` ignores this option for the plugins,
+    /// since the `CodeGeneratorRequest` passed to the closure always has all the transitive
+    /// dependencies in its `proto_file`.
+    pub fn include_imports(mut self, include_imports: bool) -> Self {
+        self.include_imports = include_imports;
+        self
+    }
+    /// Includes the source code info in the output `FileDescriptorSet`.
+    /// Corresponds to `--include_source_info` option of `protoc`. Default is `false`.
+    ///
+    /// Only affects [`Self::compile_descriptor_set`]. `protoc` ignores this option for the plugins,
+    /// since the `CodeGeneratorRequest` passed to the closure always has the source code info of
+    /// the files to generate.
+    pub fn include_source_info(mut self, include_source_info: bool) -> Self {
+        self.include_source_info = include_source_info;
+        self
+    }
+    /// Adds the include directory installed with `protoc`, which has the well-known type protos
+    /// like `google/protobuf/empty.proto`, as the last proto path. Default is `false`.
+    ///
+    /// The first directory having `google/protobuf/descriptor.proto` is used among the
+    /// `PROTOC_INCLUDE` env var, `../include` relative to the [`Self::resolved_protoc_path`]
+    /// (and to its symlink target), and `/usr/local/include` and `/usr/include` on Unix.
+    /// The run fails with [`ErrorKind::StdTypesNotFound`] listing them if none has it.
+    ///
+    /// Usually `protoc` finds its own include directory by itself, but not when it's not next
+    /// to the binary, e.g. with a symlinked or a copied `protoc`.
+    pub fn include_std_types(mut self, include_std_types: bool) -> Self {
+        self.include_std_types = include_std_types;
+        self
+    }
+    /// Sets the path to write the make-style dependency file listing all the proto files which
+    /// `protoc` read, including the transitively imported ones.
+    /// Corresponds to `--dependency_out` option of `protoc`.
+    pub fn dependency_out(mut self, path: impl Into<PathBuf>) -> Self {
+        self.dependency_out = Some(path.into());
+        self
+    }
+    /// Sets the maximum size in bytes of the `CodeGeneratorRequest` which `protoc` can send.
+    /// Default is 512 MiB. `0` disables the check.
+    ///
+    /// The plugin binary stops reading a larger request and fails, so it is never buffered
+    /// as a whole. [`Self::run_offline`] returns [`ErrorKind::RequestTooLarge`] for it instead.
+    pub fn max_request_size(mut self, limit: usize) -> Self {
+        self.max_request_size = limit;
+        self
+    }
+    /// Sets the expected size in bytes of the `CodeGeneratorRequest`, e.g. from
+    /// [`RunEvent::RequestReceived`] of a previous run with the same inputs. Default is `0`,
+    /// i.e. no hint.
+    ///
+    /// The plugin binary reserves its input buffer by this (up to 64 MiB and
+    /// [`Self::max_request_size`]) instead of growing it while reading the request. It's only a
+    /// hint, so an inaccurate one doesn't change the result.
+    pub fn request_size_hint(mut self, bytes: usize) -> Self {
+        self.request_size_hint = bytes;
+        self
+    }
+    /// Sets the maximum size in bytes of the `CodeGeneratorResponse` which the closure can return.
+    /// Default is 512 MiB, the same as [`Self::max_request_size`]. `0` disables the check.
+    ///
+    /// If the closure returns a larger response, the run is aborted with
+    /// [`ErrorKind::ResponseTooLarge`] before the response is sent to `protoc`.
+    pub fn max_response_size(mut self, limit: usize) -> Self {
+        self.max_response_size = limit;
+        self
+    }
+    /// Adds the given `CodeGeneratorResponse.Feature` bits, e.g.
+    /// [`FEATURE_PROTO3_OPTIONAL`](plugin_proto::FEATURE_PROTO3_OPTIONAL), to the
+    /// `supported_features` of the response which the closure of `run_prost` or `run_protobuf`
+    /// returns, on top of the ones the closure sets. Default is `0`.
+    #[cfg(any(feature = "prost", feature = "protobuf"))]
+    pub fn supported_features(mut self, features: u64) -> Self {
+        self.supported_features |= features;
+        self
+    }
+    /// Sets the transport between this library and the plugin binary.
+    /// Default is chosen by [`Transport::detect`] for the platform when `protoc` is run.
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.transport = Some(transport);
+        self
+    }
+    /// Sets the timeout of `protoc`'s parsing of the proto files, measured from spawning `protoc`
+    /// until the plugin binary sends the `CodeGeneratorRequest`. Its expiry is reported as
+    /// [`ErrorKind::ProtocParseTimeout`], which is not retried by [`Self::retry_policy`].
+    ///
+    /// The `timeout` of the run methods then applies only to the rest of the run, measured from
+    /// the request. Default is none, i.e. the `timeout` covers the parsing as well.
+    pub fn protoc_parse_timeout(mut self, timeout: Duration) -> Self {
+        self.protoc_parse_timeout = Some(timeout);
+        self
+    }
+    /// Makes `protoc` fail if any warnings are generated.
+    /// Corresponds to `--fatal_warnings` option of `protoc`. Default is `false`.
+    pub fn fatal_warnings(mut self, fatal_warnings: bool) -> Self {
+        self.fatal_warnings = fatal_warnings;
+        self
+    }
+    /// Sets the format of `protoc`'s error and warning messages in its stderr, e.g. attached to
+    /// [`ErrorKind::ProtocProcessError`]. Corresponds to `--error_format` option of `protoc`.
+    /// Default is `protoc`'s default, i.e. [`ErrorFormat::Gcc`].
+    pub fn error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.error_format = Some(error_format);
+        self
+    }
+    /// Restricts the imports of the input proto files to the given files, named as in the
+    /// `import` statements. An empty list allows no imports at all.
+    /// Corresponds to `--direct_dependencies` option of `protoc`.
+    ///
+    /// Importing any other file makes `protoc` fail with [`ErrorKind::ProtocProcessError`],
+    /// whose stderr has the violation message. See [`Self::direct_dependencies_violation_msg`].
+    pub fn direct_dependencies<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.direct_dependencies = Some(names.into_iter().map(|n| n.into()).collect());
+        self
+    }
+    /// Sets the error message for the imports violating [`Self::direct_dependencies`], in which
+    /// `%s` is replaced with the imported file name.
+    /// Corresponds to `--direct_dependencies_violation_msg` option of `protoc`.
+    pub fn direct_dependencies_violation_msg(mut self, message: &str) -> Self {
+        self.direct_dependencies_violation_msg = Some(message.to_string());
+        self
+    }
+    /// Catches `SIGINT` and `SIGTERM` while running. Default is `false`.
+    ///
+    /// If one is caught, the `protoc` process is killed, the temporary files are removed, and
+    /// [`ErrorKind::Interrupted`] is returned instead of terminating this process. The plugin
+    /// binary exits by itself once the connection to this process is dropped. Note that
+    /// a running closure is not interrupted, so the run returns after the closure returns.
+    ///
+    /// The signal handlers are process-wide, and are only registered during the run.
+    ///
+    /// On Unix, `protoc` is spawned in its own process group, and the whole group is killed, so
+    /// the plugins `protoc` has spawned are killed too. Hence the signals from the terminal
+    /// reach `protoc` only through this process.
+    ///
+    /// On Windows, only Ctrl-C is caught (as `SIGINT`), while Ctrl-Break and closing the console
+    /// are not handled. Also only `protoc` itself is killed, and the plugins it has spawned are
+    /// left to exit by themselves.
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.handle_signals = handle_signals;
+        self
+    }
+    /// Sets the policy to re-run the whole `protoc` run on the transient failures.
+    /// Default is no retries.
+    ///
+    /// The transient failures are the IPC errors, the plugin binary not connecting back in time,
+    /// and `protoc` exiting unsuccessfully without printing any diagnostics (e.g. killed by
+    /// the OOM killer). The closure errors and the proto syntax errors are never retried. Since
+    /// the closure is `FnOnce`, the run is not retried either once the closure has been called.
+    ///
+    /// Each attempt uses a fresh IPC server and temporary files. If the run failed after
+    /// any retries, the last error is wrapped by [`ErrorKind::RetryError`] with the number of
+    /// the attempts.
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.retry_policy = retry_policy;
+        self
+    }
+    /// Runs `protoc` and the plugin binary for the reproducible builds. Default is `false`.
+    ///
+    /// This sets `SOURCE_DATE_EPOCH=0` in their environment unless it's already set in the
+    /// environment of this process, so that any tool honoring it embeds no build timestamps.
+    /// `protoc` has no flags for the deterministic plugin runs (`--deterministic_output` is only
+    /// for `--encode`), and its outputs are already byte-stable for the same inputs. Make sure
+    /// the closure is deterministic as well, e.g. not iterating a `HashMap`.
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.reproducible = reproducible;
+        self
+    }
+    /// Sets the handler to be called with the progress of the run, e.g. for showing it in a UI.
+    ///
+    /// The handler is called on the thread calling the run method, except for
+    /// [`RunEvent::ProtocStdout`] and [`RunEvent::ProtocStderr`]. See [`RunEvent`] for the
+    /// reported events.
+    pub fn on_event(mut self, handler: impl Fn(RunEvent) + Send + Sync + 'static) -> Self {
+        self.on_event = Some(Arc::new(handler));
+        self
+    }
+    /// Sets the runner to build the command which runs `protoc`, e.g. through a wrapper like
+    /// `docker run` or `ssh`. By default, `protoc` is run directly.
+    ///
+    /// The runner gets the `protoc` command, and returns the command to spawn instead. Its
+    /// stdout and stderr are piped to this library and the env vars of the `protoc` command are
+    /// set on it as well, but the wrapper needs to pass the env vars through by itself (e.g.
+    /// `docker run -e`). Use [`Self::path_mapper`] if the paths differ inside the wrapper.
+    ///
+    /// The plugin binary, which is run by `protoc` inside the wrapper, still connects back to
+    /// this process, so the wrapper must let it reach the IPC channel (e.g. by sharing the temp
+    /// directory and the network namespace), and the plugin binary must be executable at the
+    /// mapped path. The mapped path is checked on this host before running `protoc`, so it must
+    /// point to the plugin binary here too, e.g. by a bind mount at the same path. The run fails
+    /// with [`ErrorKind::PluginNotFoundInRunner`] if it doesn't. [`Self::dry_run`] still returns
+    /// the `protoc` command without the runner.
+    pub fn runner(
+        mut self,
+        runner: impl Fn(&PreparedCommand) -> Command + Send + Sync + 'static,
+    ) -> Self {
+        self.runner = Some(Arc::new(runner));
+        self
+    }
+    /// Sets the mapping applied to every path in the `protoc` arguments, including the plugin
+    /// binary, e.g. from the host paths to the ones in the container of [`Self::runner`].
+    ///
+    /// The files are still read and written by this library at the original paths, so the
+    /// mapped paths must point to the same files, e.g. by the bind mounts.
+    pub fn path_mapper(
+        mut self,
+        mapper: impl Fn(&Path) -> PathBuf + Send + Sync + 'static,
+    ) -> Self {
+        self.path_mapper = Some(Arc::new(mapper));
+        self
+    }
+    /// Sets the guard to check the request before the closure is called, e.g. for rejecting
+    /// an accidentally huge request.
+    ///
+    /// If the guard returns `Err`, the run fails with [`ErrorKind::Rejected`] without calling
+    /// the closure.
+    pub fn request_guard(
+        mut self,
+        guard: impl Fn(&RequestInfo) -> ::std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.request_guard = Some(Arc::new(guard));
+        self
+    }
+    /// Sets the verifier to be called with the path and the content of each generated file after
+    /// `protoc` wrote them, e.g. for checking that they're formatted.
+    ///
+    /// The files are verified in the order of the closure's `CodeGeneratorResponse`, once each
+    /// and after the insertions are applied. With `Self::out_zip`, the paths are in the temp
+    /// dir which the archive is written from. If the verifier returns `Err`, the run fails with
+    /// [`ErrorKind::VerificationFailed`] at the first such file. The files of the response are
+    /// then removed from the output directory, and the archive and `Self::manifest_out` are
+    /// not written. [`Self::dry_run`] doesn't verify anything.
+    pub fn verify_output(
+        mut self,
+        verifier: impl Fn(&Path, &[u8]) -> ::std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.verify_output = Some(Arc::new(verifier));
+        self
+    }
+    /// Stops [`Self::run_many`] at the first failed run instead of running the rest.
+    /// Default is `false`.
+    ///
+    /// Only affects [`Self::run_many`].
+    pub fn fail_fast(mut self, fail_fast: bool) -> Self {
+        self.fail_fast = fail_fast;
+        self
+    }
+
+    /// Returns the absolute path of the `protoc` command which will be run, without running it.
+    ///
+    /// A bare command name like the default `"protoc"` is looked up in the `PATH` env var
+    /// (with the `PATHEXT` extensions on Windows) like `which`, and a relative path is resolved
+    /// against the current directory. Returns [`ErrorKind::ProtocNotFound`] if it's not found.
+    pub fn resolved_protoc_path(&self) -> Result<PathBuf> {
+        let not_found = || ErrorKind::ProtocNotFound(self.protoc_path.display().to_string());
+        if self.protoc_path.components().count() > 1 || self.protoc_path.is_absolute() {
+            let path = ::std::path::absolute(&self.protoc_path)?;
+            return path.is_file().then_some(path).ok_or_else(not_found);
+        }
+
+        let extensions: Vec<OsString> = if cfg!(windows) {
+            let path_ext = env::var_os("PATHEXT").unwrap_or_else(|| ".EXE;.CMD;.BAT".into());
+            ::std::iter::once(OsString::new())
+                .chain(
+                    path_ext
+                        .to_string_lossy()
+                        .split(';')
+                        .filter(|ext| !ext.is_empty())
+                        .map(OsString::from),
+                )
+                .collect()
+        } else {
+            vec![OsString::new()]
+        };
+        let path_var = env::var_os("PATH").unwrap_or_default();
+        env::split_paths(&path_var)
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .flat_map(|dir| {
+                extensions.iter().map(move |ext| {
+                    let mut name = self.protoc_path.as_os_str().to_owned();
+                    name.push(ext);
+                    dir.join(name)
+                })
+            })
+            .find(|candidate| candidate.is_file())
+            .map(::std::path::absolute)
+            .transpose()?
+            .ok_or_else(not_found)
+    }
+
+    /// Runs the `protoc` command with the given closure as a plugin code.
+    ///
+    /// The `body` param can be any `FnOnce` closure which takes the encoded `CodeGeneratorRequest` bytes
+    /// and returns the `Result` of encoded `CodeGeneratorResponse` bytes.
+    ///
+    /// Set the `timeout` to the maximum duration of the `protoc` command execution.
+    ///
+    /// Returns [`ErrorKind::DuplicateOutputFileError`] if the closure's `CodeGeneratorResponse`
+    /// contains more than one file with the same name, which is almost always a generator bug.
+    /// The insertion point entries are not counted as duplicates of the file they insert into.
+    ///
+    /// Returns [`ErrorKind::PluginNotInvoked`] if `protoc` exits successfully without invoking
+    /// the plugin, so the closure is never called.
+    ///
+    /// Returns [`ErrorKind::Timeout`] with the [`Phase`] which stalled if the run doesn't finish
+    /// within the `timeout`, which is one deadline for the whole run including the closure. The
+    /// closure is not interrupted, but the run fails with [`Phase::RunningClosure`] if the
+    /// deadline passed when it returns.
+    /// [`Self::protoc_parse_timeout`] sets a separate timeout for `protoc`'s parsing.
+    ///
+    /// An empty `CodeGeneratorResponse`, i.e. with no files and no error, is a success which
+    /// writes nothing, e.g. when there is nothing to generate for the inputs.
+    pub fn run<F>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run_and_get_response(timeout, &mut |_| (), callback(body))
+            .map(|_| ())
+    }
+
+    /// Same as [`Self::run`], but calls `on_spawn` with the `protoc` process right after it's
+    /// spawned and before the closure is called, e.g. to set its priority or to monitor its
+    /// resource usage by [`Child::id`].
+    ///
+    /// `on_spawn` is called for each attempt if [`Self::retry_policy`] re-runs `protoc`. Don't
+    /// take or replace the child's stdio, which this library reads, nor wait for or kill it.
+    pub fn run_with_child<S, F>(self, timeout: Duration, mut on_spawn: S, body: F) -> Result<()>
+    where
+        S: FnMut(&Child),
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run_and_get_response(timeout, &mut on_spawn, callback(body))
+            .map(|_| ())
+    }
+
+    /// Same as [`Self::run`], but the closure also takes a [`RunContext`], e.g. to compute the
+    /// paths relative to the output directory.
+    pub fn run_with_context<F>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(&RunContext<'_>, &[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        let out_dir = self.out_dir.clone();
+        let protoc_path = self.resolved_protoc_path()?;
+        let context = RunContext {
+            out_dir: out_dir.as_deref(),
+            protoc_path: &protoc_path,
+        };
+        self.run(timeout, |req| body(&context, req))
+    }
+
+    /// Same as [`Self::run`], but without the timeout, i.e. waits for `protoc` to exit however
+    /// long it takes.
+    ///
+    /// Beware that this hangs forever if `protoc` or the closure never finishes, e.g. on
+    /// a deadlock, so prefer [`Self::run`] with a generous timeout where possible.
+    pub fn run_untimed<F>(self, body: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run(NO_TIMEOUT, body)
+    }
+
+    /// Same as [`Self::run`], but also returns the encoded `CodeGeneratorResponse` bytes exactly
+    /// as they were sent to `protoc`, e.g. for archiving them.
+    ///
+    /// The bytes are the closure's response after the library's own changes to it, i.e. with the
+    /// insertion point entries moved after the files they insert into.
+    pub fn run_raw<F>(self, timeout: Duration, body: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run_and_get_response(timeout, &mut |_| (), callback(body))
+            .map(|run| run.response)
+    }
+
+    /// Same as [`Self::run`], but also returns the summary of the files which `protoc` wrote, in
+    /// the order of the closure's `CodeGeneratorResponse` entries. The insertion point entries are
+    /// reported as [`FileKind::Insertion`], after the files they insert into.
+    pub fn run_with_summary<F>(self, timeout: Duration, body: F) -> Result<Vec<FileSummary>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        let out_dir = self.out_dir.clone().unwrap_or_default();
+        let run = self.run_and_get_response(timeout, &mut |_| (), callback(body))?;
+        summarize_files(&out_dir, &run.response)
+    }
+
+    /// Same as [`Self::run`], but also returns the summary of the files which `protoc` wrote, and
+    /// its warnings and stderr, e.g. for forwarding the warnings to `cargo:warning=` lines.
+    pub fn run_with_output<F>(self, timeout: Duration, body: F) -> Result<RunOutput>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        let out_dir = self.out_dir.clone().unwrap_or_default();
+        let run = self.run_and_get_response(timeout, &mut |_| (), callback(body))?;
+        run_output(&out_dir, run)
+    }
+
+    /// Runs `protoc` once for each of the given [`RunSpec`]s in order, sharing this configuration
+    /// (e.g. the proto paths) and the closure.
+    ///
+    /// The closure takes the index of the `RunSpec` along with the `CodeGeneratorRequest` bytes.
+    /// The `timeout` applies to each run. A failed run is reported in its own result, and the
+    /// rest are still run unless [`Self::fail_fast`] is set, in which case the results end at the
+    /// failed one.
+    ///
+    /// The `protoc` path is resolved, and the server which the plugin binaries connect back to
+    /// is started, only once for all the runs. Each run's plugin binary sends back the index of
+    /// its `RunSpec` in the handshake, and a plugin binary of another run fails the run with
+    /// [`ErrorKind::PluginRunMismatch`].
+    ///
+    /// Returns an error without running any if the `protoc` path can't be resolved or the server
+    /// can't be started.
+    pub fn run_many<F>(
+        mut self,
+        timeout: Duration,
+        specs: Vec<RunSpec>,
+        mut body: F,
+    ) -> Result<Vec<Result<RunOutput>>>
+    where
+        F: FnMut(usize, &[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.protoc_path = self.resolved_protoc_path()?;
+        let (_, server) =
+            IpcServer::start_with_transport(self.transport.unwrap_or_else(Transport::detect))?;
+        let server = Arc::new(Mutex::new(server));
+        let mut results = Vec::with_capacity(specs.len());
+        for (index, spec) in specs.into_iter().enumerate() {
+            let mut protoc = self.clone();
+            protoc.proto_files = spec.proto_files;
+            protoc.proto_dirs.clear();
+            protoc.out_dir = spec.out_dir.or(protoc.out_dir);
+            protoc.shared_server = Some(SharedServer {
+                server: Arc::clone(&server),
+                tag: index as u64,
+            });
+            let out_dir = protoc.out_dir.clone().unwrap_or_default();
+            let result = protoc
+                .run_and_get_response(timeout, &mut |_| (), callback(|req| body(index, req)))
+                .and_then(|run| run_output(&out_dir, run));
+            let failed = result.is_err();
+            results.push(result);
+            if failed && self.fail_fast {
+                break;
+            }
+        }
+        Ok(results)
+    }
+
+    /// Same as [`Self::run`], but also returns the warnings which `protoc` printed,
+    /// e.g. `foo.proto:2:1: warning: Import bar.proto is unused.`
+    /// See [`parse_protoc_diagnostics`].
+    pub fn run_with_warnings<F>(self, timeout: Duration, body: F) -> Result<Vec<Diagnostic>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run_and_get_response(timeout, &mut |_| (), callback(body))
+            .map(|run| parse_warnings(&run.stderr))
+    }
+
+    /// Same as [`Self::run`], but can be aborted by cancelling the given token from another thread.
+    ///
+    /// On cancellation, the `protoc` process is killed, the temporary files are removed, and
+    /// [`ErrorKind::Cancelled`] is returned without waiting for the timeout. A running closure is
+    /// not interrupted, so the cancellation takes effect as soon as the closure returns.
+    ///
+    /// On Unix, `protoc` is spawned in its own process group, and the whole group is killed, so
+    /// the plugins `protoc` has spawned are killed too.
+    pub fn run_cancellable<F>(
+        mut self,
+        timeout: Duration,
+        token: CancellationToken,
+        body: F,
+    ) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.cancellation = Some(token);
+        self.run(timeout, body)
+    }
+
+    /// Same as [`Self::run`], but returns the generated files instead of writing them into
+    /// [`Self::out_dir`].
+    ///
+    /// `protoc` still requires an output directory, so it is pointed to a temporary directory
+    /// which is removed afterwards. The files are returned in the order of the closure's
+    /// `CodeGeneratorResponse`, with the insertion point entries applied to them in memory
+    /// in the same way as `protoc` does on disk.
+    pub fn run_to_memory<F>(self, timeout: Duration, body: F) -> Result<Vec<GeneratedFile>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        let out_dir = self.new_temp_dir()?;
+        let run = self.out_dir(out_dir.path()).run_and_get_response(
+            timeout,
+            &mut |_| (),
+            callback(body),
+        )?;
+        let files = apply_insertions(CodeGeneratorResponse::from_bytes(&run.response)?.files)?;
+        Ok(files.into_iter().map(GeneratedFile::from).collect())
+    }
+
+    /// Runs `protoc` only to inspect the `CodeGeneratorRequest`, e.g. for linting or diffing
+    /// the schemas, without generating any files.
+    ///
+    /// The `inspector` takes the encoded `CodeGeneratorRequest` bytes, and an empty
+    /// `CodeGeneratorResponse` is sent back to `protoc` if it returns `Ok`. [`Self::out_dir`],
+    /// `Self::out_zip` and `Self::manifest_out` are ignored, and nothing is written.
+    pub fn analyze<F>(mut self, timeout: Duration, inspector: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<(), String>,
+    {
+        let out_dir = self.new_temp_dir()?;
+        self.out_dir = Some(out_dir.path().to_path_buf());
+        #[cfg(feature = "zip")]
+        {
+            self.out_zip = None;
+        }
+        #[cfg(feature = "serde")]
+        {
+            self.manifest_out = None;
+        }
+        self.verify_output = None;
+        self.run(timeout, |req| {
+            inspector(req)?;
+            // An empty message is the encoding of the empty `CodeGeneratorResponse`.
+            Ok(Vec::new())
+        })
+    }
+
+    /// Same as [`Self::run`], but the closure returns the generated files instead of
+    /// the `CodeGeneratorResponse` bytes.
+    ///
+    /// After `protoc` wrote the files into [`Self::out_dir`] (or the current directory if it's not
+    /// set), the [`GeneratedFile::mode`]s are applied to them on Unix, e.g. for setting
+    /// the executable bit of the generated scripts. They are ignored on the other platforms,
+    /// and for the files written by `Self::out_zip`.
+    pub fn run_files<F>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<GeneratedFile>, String>,
+    {
+        // `protoc` writes into the current directory if `out_dir` is not set. See `plugin_args`.
+        let out_dir = self.out_dir.clone().unwrap_or_else(|| PathBuf::from("."));
+        #[cfg(feature = "zip")]
+        let out_dir = self.out_zip.is_none().then_some(out_dir);
+        #[cfg(not(feature = "zip"))]
+        let out_dir = Some(out_dir);
+        let mut modes = Vec::new();
+        self.run(timeout, |req| {
+            let files = body(req)?;
+            modes = files
+                .iter()
+                .filter(|generated| generated.file.insertion_point.is_none())
+                .filter_map(|generated| Some((generated.file.name.clone(), generated.mode?)))
+                .collect();
+            let response = CodeGeneratorResponse {
+                files: files.into_iter().map(plugin_proto::File::from).collect(),
+                ..Default::default()
+            };
+            response.to_bytes().map_err(|e| e.to_string())
+        })?;
+
+        #[cfg(unix)]
+        if let Some(out_dir) = out_dir {
+            use ::std::os::unix::fs::PermissionsExt;
+            for (name, mode) in modes {
+                let permissions = ::std::fs::Permissions::from_mode(mode);
+                ::std::fs::set_permissions(out_dir.join(name), permissions)?;
+            }
+        }
+        #[cfg(not(unix))]
+        let _ = (out_dir, modes);
+        Ok(())
+    }
+
+    /// Runs the `protoc` command, and returns the `CodeGeneratorResponse` bytes which the closure
+    /// returned and was sent to `protoc`, and the `protoc`'s stderr.
+    ///
+    /// `on_spawn` is called with each spawned `protoc` process. See [`Self::run_with_child`].
+    fn run_and_get_response<F>(
+        self,
+        timeout: Duration,
+        on_spawn: &mut dyn FnMut(&Child),
+        body: F,
+    ) -> Result<ProtocRun>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        self.check_input()?;
+        self.check_mapped_plugin()?;
+        // With `out_zip`, `protoc` writes into a temp dir which is archived afterwards.
+        #[cfg(feature = "zip")]
+        let (this, out_zip) = {
+            let mut this = self;
+            let out_zip = match this.out_zip.take() {
+                Some(_) if this.out_dir.is_some() => return Err(ErrorKind::OutZipConflictError),
+                Some(out_zip) => {
+                    let temp_dir = this.new_temp_dir()?;
+                    this.out_dir = Some(temp_dir.path().to_path_buf());
+                    Some((out_zip, temp_dir))
+                }
+                None => None,
+            };
+            (this, out_zip)
+        };
+        #[cfg(not(feature = "zip"))]
+        let this = self;
+        this.prepare_out_dir()?;
+
+        let mut protoc_version = None;
+        let body = |req: &[u8]| {
+            protoc_version = request::protoc_version(req);
+            body(req)
+        };
+        let (res, stderr) = this.run_with_retries(timeout, on_spawn, body)?;
+
+        if let (Some(verifier), Some(out_dir)) = (&this.verify_output, &this.out_dir) {
+            verify_files(out_dir, &res, verifier)?;
+        }
+
+        #[cfg(feature = "zip")]
+        if let Some((out_zip, temp_dir)) = out_zip {
+            write_zip(&out_zip, temp_dir.path(), &res)?;
+        }
+        #[cfg(feature = "serde")]
+        if let Some(manifest_out) = &this.manifest_out {
+            Manifest::new(&res, protoc_version.clone())?.write(manifest_out)?;
+        }
+        Ok(ProtocRun {
+            response: res,
+            stderr,
+            protoc_version,
+        })
+    }
+
+    /// Checks the plugin binary exists and is executable at the path mapped by
+    /// [`Self::path_mapper`], if any.
+    fn check_mapped_plugin(&self) -> Result<()> {
+        let Some(mapper) = &self.path_mapper else {
+            return Ok(());
+        };
+        let plugin = mapper(&self.plugin_binary_path()?);
+        check_plugin_binary(&plugin).map_err(|_| ErrorKind::PluginNotFoundInRunner(plugin))
+    }
+
+    /// Runs `protoc`, retrying on the transient failures per `self.retry_policy`.
+    fn run_with_retries<F>(
+        &self,
+        timeout: Duration,
+        on_spawn: &mut dyn FnMut(&Child),
+        body: F,
+    ) -> Result<(Vec<u8>, String)>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let signals = self.handle_signals.then(SignalGuard::install).transpose()?;
+        let interrupts = Interrupts {
+            signals: signals.as_ref(),
+            cancellation: self.cancellation.as_ref(),
+        };
+
+        let mut body = Some(body);
+        let mut attempts = 1;
+        let mut backoff = self.retry_policy.backoff;
+        loop {
+            match self.run_once(timeout, interrupts, on_spawn, &mut body) {
+                Err(failure)
+                    if failure.transient
+                        && body.is_some()
+                        && attempts < self.retry_policy.max_attempts =>
+                {
+                    thread::sleep(backoff);
+                    backoff *= 2;
+                    attempts += 1;
+                }
+                Err(failure) if attempts > 1 => {
+                    return Err(ErrorKind::RetryError {
+                        attempts,
+                        error: Box::new(failure.error),
+                    });
+                }
+                result => return result.map_err(|failure| failure.error),
+            }
+        }
+    }
+
+    /// A single attempt of [`Self::run_with_retries`]. The closure is taken out of `body`
+    /// only when it's called.
+    fn run_once<F>(
+        &self,
+        timeout: Duration,
+        interrupts: Interrupts<'_>,
+        on_spawn: &mut dyn FnMut(&Child),
+        body: &mut Option<F>,
+    ) -> ::std::result::Result<(Vec<u8>, String), RunFailure>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let mut shared_server = self
+            .shared_server
+            .as_ref()
+            .map(|shared| (lock(&shared.server), shared.tag));
+        let mut own_server = None;
+        let (init_key, ipc_server) = match shared_server.as_mut() {
+            Some((server, tag)) => {
+                server.tag = Some(*tag);
+                (server.init_key()?, &mut **server)
+            }
+            None => {
+                let (init_key, server) = IpcServer::start_with_transport(
+                    self.transport.unwrap_or_else(Transport::detect),
+                )?;
+                (init_key, own_server.insert(server))
+            }
+        };
+
+        let command = self.prepared_command(self.plugin_args(&init_key)?);
+        let command_line = command.redacted(&init_key).to_shell_string();
+        let guard = self.spawn_protoc(&command, command_line.clone())?;
+        self.run_spawned(guard, ipc_server, timeout, interrupts, on_spawn, body)
+            .map_err(|failure| failure.with_command(&command_line))
+    }
+
+    /// The rest of [`Self::run_once`] after `protoc` is spawned.
+    fn run_spawned<F>(
+        &self,
+        mut guard: RunGuard,
+        ipc_server: &mut IpcServer,
+        timeout: Duration,
+        interrupts: Interrupts<'_>,
+        on_spawn: &mut dyn FnMut(&Child),
+        body: &mut Option<F>,
+    ) -> ::std::result::Result<(Vec<u8>, String), RunFailure>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let spawned = Instant::now();
+        let mut clock = RunClock::start();
+        self.emit(RunEvent::Spawned);
+        on_spawn(&guard.process);
+
+        let on_event = |event| self.emit(event);
+        let res = ipc_server.exchange(
+            Some(&mut guard.process),
+            self.protoc_parse_timeout,
+            timeout,
+            interrupts,
+            &on_event,
+            |req| {
+                let body = body.take().expect("the closure is called only once");
+                if self.protoc_parse_timeout.is_some() {
+                    // The `timeout` applies only to the rest of the run then.
+                    clock = RunClock::start();
+                }
+                self.call_body(req, body).and_then(|res| {
+                    if clock.remaining(timeout).is_zero() {
+                        return Err(ErrorKind::timeout(Phase::RunningClosure, spawned));
+                    }
+                    Ok(res)
+                })
+            },
+        );
+        let res = match res {
+            Err(ErrorKind::ProtocProcessError(info)) => {
+                let info = guard.exited(info.status);
+                if let Some(exit_code) = info.status {
+                    self.emit(RunEvent::ProtocExited(exit_code));
+                }
+                if let Some(error) = plugin_connect_error(&info.stderr) {
+                    return Err(error.into());
+                }
+                return Err(RunFailure::protoc_failed(info));
+            }
+            Err(ErrorKind::Timeout { phase, .. }) => {
+                let error = guard.timed_out(phase, spawned.elapsed());
+                let ErrorKind::Timeout {
+                    stderr, command, ..
+                } = &error
+                else {
+                    return Err(error.into());
+                };
+                // The plugin binary may have reported why it never connected.
+                if let Some(error) = plugin_connect_error(stderr) {
+                    return Err(error.into());
+                }
+                if self.protoc_parse_timeout.is_some()
+                    && matches!(
+                        phase,
+                        Phase::AwaitingPluginHandshake | Phase::AwaitingRequest
+                    )
+                {
+                    return Err(ErrorKind::ProtocParseTimeout {
+                        elapsed: spawned.elapsed(),
+                        stderr: stderr.clone(),
+                        command: command.clone(),
+                    }
+                    .into());
+                }
+                return Err(error.into());
+            }
+            res => res?,
+        };
+
+        let Some(exit_code) =
+            wait_protoc(&mut guard.process, clock.remaining(timeout), interrupts)?
+        else {
+            return Err(guard
+                .timed_out(Phase::AwaitingProtocExit, spawned.elapsed())
+                .into());
+        };
+        self.emit(RunEvent::ProtocExited(exit_code));
+        let info = guard.exited(Some(exit_code));
+        if !exit_code.success() {
+            // protoc fails with the closure's error, which is buried in its stderr otherwise.
+            if let Some(message) = CodeGeneratorResponse::from_bytes(&res)
+                .ok()
+                .and_then(|response| response.error)
+                .filter(|message| !message.is_empty())
+            {
+                return Err(ErrorKind::GeneratorReportedError(message).into());
+            }
+            return Err(RunFailure::protoc_failed(info));
+        }
+
+        Ok((res, info.stderr))
+    }
+
+    /// Returns the `protoc` arguments for running the closure's plugin binary with the given
+    /// init key, and the [`Self::with_plugin`] plugins.
+    fn plugin_args(&self, init_key: &str) -> Result<Vec<OsString>> {
+        let plugin = self.plugin_binary_path()?;
+        let plugin_name = &self.plugin_name;
+        let mut args: Vec<OsString> = vec![
+            format!(
+                "--plugin=protoc-gen-{}={}",
+                plugin_name,
+                self.path_arg(&plugin)?
+            )
+            .into(),
+            format!(
+                "--{}_out={}",
+                plugin_name,
+                self.out_dir
+                    .as_deref()
+                    .map(|out_dir| self.path_arg(out_dir))
+                    .transpose()?
+                    .unwrap_or_else(|| ".".to_string())
+            )
+            .into(),
+            format!("--{}_opt={}", plugin_name, init_key).into(),
+        ];
+        for (i, plugin) in self.extra_plugins.iter().enumerate() {
+            if plugin.name == *plugin_name
+                || self.extra_plugins[..i]
+                    .iter()
+                    .any(|p| p.name == plugin.name)
+            {
+                return Err(ErrorKind::PluginNameConflict(plugin.name.clone()));
+            }
+            args.push(
+                format!(
+                    "--plugin=protoc-gen-{}={}",
+                    plugin.name,
+                    self.path_arg(&plugin.path)?
+                )
+                .into(),
+            );
+            args.push(format!("--{}_out={}", plugin.name, self.path_arg(&plugin.out_dir)?).into());
+        }
+        if let Some(dependency_out) = &self.dependency_out {
+            args.push(format!("--dependency_out={}", self.path_arg(dependency_out)?).into());
+        }
+        args.extend(self.fatal_warnings.then(|| "--fatal_warnings".into()));
+        args.extend(
+            self.error_format
+                .map(|format| format!("--error_format={}", format.as_arg()).into()),
+        );
+        args.extend(
+            self.direct_dependencies_args()
+                .into_iter()
+                .map(OsString::from),
+        );
+        args.extend(self.split_extra_args().1);
+        args.extend(self.proto_path_args()?.into_iter().map(OsString::from));
+        args.extend(self.proto_file_args()?.into_iter().map(OsString::from));
+        Ok(args)
+    }
+
+    /// Same as [`Self::run`], but also returns the paths of all the proto files which `protoc`
+    /// read, including the transitively imported ones.
+    ///
+    /// This is useful for emitting `cargo:rerun-if-changed` lines in build scripts.
+    /// If [`Self::dependency_out`] is not set, a temporary file is used for it.
+    ///
+    /// Note that `protoc` writes nothing into the dependency file if the closure generated no
+    /// files, so an empty list is returned in that case.
+    pub fn run_with_deps<F>(self, timeout: Duration, body: F) -> Result<Vec<PathBuf>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run_with_output_and_deps(timeout, body)
+            .map(|(_, deps)| deps)
+    }
+
+    /// Same as [`Self::run_with_output`], but also returns the result of
+    /// [`Self::run_with_deps`].
+    pub(crate) fn run_with_output_and_deps<F>(
+        self,
+        timeout: Duration,
+        body: F,
+    ) -> Result<(RunOutput, Vec<PathBuf>)>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        let temp_dir = self.new_temp_dir()?;
+        let dependency_out = self
+            .dependency_out
+            .clone()
+            .unwrap_or_else(|| temp_dir.path().join("dependencies.d"));
+
+        let output = self
+            .dependency_out(&dependency_out)
+            .run_with_output(timeout, body)?;
+
+        let deps = parse_dependency_file(&::std::fs::read_to_string(&dependency_out)?);
+        Ok((output, deps))
+    }
+
+    /// Compiles the input proto files into the encoded `FileDescriptorSet` bytes.
+    ///
+    /// This runs `protoc` with `--descriptor_set_out` option instead of the plugin, so no closure
+    /// is needed. Use [`Self::include_imports`] and [`Self::include_source_info`] to control the
+    /// contents of the output.
+    ///
+    /// Set the `timeout` to the maximum duration of the `protoc` command execution.
+    pub fn compile_descriptor_set(self, timeout: Duration) -> Result<Vec<u8>> {
+        let (descriptor_set, _) = self.run_descriptor_set(timeout)?;
+        Ok(descriptor_set)
+    }
+
+    /// Checks that the input proto files compile, without generating any code.
+    ///
+    /// This runs `protoc` with only the include and the input arguments and a descriptor set
+    /// output into a temp dir which is thrown away, so neither a closure nor the plugin binary
+    /// is needed. Returns the warnings `protoc` printed, or [`ErrorKind::CheckError`] with the
+    /// errors (and the warnings) if the files don't compile.
+    ///
+    /// Set the `timeout` to the maximum duration of the `protoc` command execution.
+    pub fn check(self, timeout: Duration) -> Result<Vec<Diagnostic>> {
+        let result = self
+            .include_imports(false)
+            .include_source_info(false)
+            .run_descriptor_set(timeout);
+        match result {
+            Ok((_, info)) => Ok(parse_protoc_diagnostics(&info.stderr)),
+            Err(ErrorKind::ProtocProcessError(info)) => {
+                let diagnostics = parse_protoc_diagnostics(&info.stderr);
+                if diagnostics.is_empty() {
+                    // e.g. killed by a signal, which is not about the proto files.
+                    Err(ErrorKind::ProtocProcessError(info))
+                } else {
+                    Err(ErrorKind::CheckError(diagnostics))
+                }
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Runs `protoc` with `--descriptor_set_out` option, and returns the encoded
+    /// `FileDescriptorSet` bytes and the exit status with the captured outputs.
+    fn run_descriptor_set(self, timeout: Duration) -> Result<(Vec<u8>, ExitStatusInfo)> {
+        self.check_input()?;
+        let signals = self.handle_signals.then(SignalGuard::install).transpose()?;
+        let interrupts = Interrupts {
+            signals: signals.as_ref(),
+            cancellation: self.cancellation.as_ref(),
+        };
+        let temp_dir = self.new_temp_dir()?;
+        let descriptor_set_path = temp_dir.path().join("descriptor_set.pb");
+
+        let mut args: Vec<OsString> = vec![
+            format!(
+                "--descriptor_set_out={}",
+                self.path_arg(&descriptor_set_path)?
+            )
+            .into(),
+        ];
+        args.extend(self.include_imports.then(|| "--include_imports".into()));
+        args.extend(
+            self.include_source_info
+                .then(|| "--include_source_info".into()),
+        );
+        args.extend(self.fatal_warnings.then(|| "--fatal_warnings".into()));
+        args.extend(
+            self.error_format
+                .map(|format| format!("--error_format={}", format.as_arg()).into()),
+        );
+        args.extend(
+            self.direct_dependencies_args()
+                .into_iter()
+                .map(OsString::from),
+        );
+        args.extend(self.split_extra_args().1);
+        args.extend(self.proto_path_args()?.into_iter().map(OsString::from));
+        args.extend(self.proto_file_args()?.into_iter().map(OsString::from));
+        let command = self.prepared_command(args);
+        let command_line = command.to_shell_string();
+        let mut guard = self.spawn_protoc(&command, command_line.clone())?;
+        self.emit(RunEvent::Spawned);
+
+        let waiting = Instant::now();
+        let Some(exit_code) = wait_protoc(&mut guard.process, timeout, interrupts)
+            .map_err(|e| e.with_command(&command_line))?
+        else {
+            return Err(guard.timed_out(Phase::AwaitingProtocExit, waiting.elapsed()));
+        };
+        self.emit(RunEvent::ProtocExited(exit_code));
+        let info = guard.exited(Some(exit_code));
+        if !exit_code.success() {
+            return Err(ErrorKind::ProtocProcessError(info));
+        }
+
+        let descriptor_set = ::std::fs::read(&descriptor_set_path)
+            .map_err(|e| ErrorKind::from(e).with_command(&command_line))?;
+        Ok((descriptor_set, info))
+    }
+
+    /// Spawns the `protoc` process with the given arguments.
+    ///
+    /// If the arguments are too long for the OS command line, they are written into a temporary
+    /// response file and passed to `protoc` as `@argfile` instead. The returned guard owns the
+    /// process and that file, and cleans both up when dropped.
+    fn spawn_protoc(&self, prepared: &PreparedCommand, command_line: String) -> Result<RunGuard> {
+        let args = &prepared.args;
+        let args_len = args.iter().map(|arg| arg.len() + 1).sum::<usize>();
+        if args_len <= MAX_COMMAND_LINE_ARGS_LEN {
+            let process = self.command(prepared).spawn()?;
+            return Ok(RunGuard::new(
+                process,
+                None,
+                command_line,
+                self.on_event.clone(),
+            ));
+        }
+
+        // protoc reads one argument per line from the response file.
+        let mut argfile_content = String::with_capacity(args_len);
+        for arg in args {
+            let arg = arg.to_str().ok_or(ErrorKind::FileNameError)?;
+            if arg.contains(['\n', '\r']) {
+                return Err(ErrorKind::FileNameError);
+            }
+            argfile_content.push_str(arg);
+            argfile_content.push('\n');
+        }
+        let argfile_dir = self.new_temp_dir()?;
+        let argfile_path = argfile_dir.path().join("protoc.args");
+        ::std::fs::write(&argfile_path, argfile_content)?;
+
+        let argfile_arg = format!("@{}", self.path_arg(&argfile_path)?);
+        let process = self
+            .command(&PreparedCommand {
+                args: vec![argfile_arg.into()],
+                ..prepared.clone()
+            })
+            .spawn()?;
+        Ok(RunGuard::new(
+            process,
+            Some(argfile_dir),
+            command_line,
+            self.on_event.clone(),
+        ))
+    }
+
+    /// Returns the command to spawn for the given `protoc` command, built by [`Self::runner`]
+    /// if it's set.
+    fn command(&self, prepared: &PreparedCommand) -> Command {
+        let mut command = match &self.runner {
+            Some(runner) => runner(prepared),
+            None => {
+                let mut command = Command::new(&prepared.program);
+                command.args(&prepared.args);
+                command
+            }
+        };
+        command.envs(prepared.env.iter().map(|(key, value)| (key, value)));
+        // The outputs are attached to the errors, and the warnings are parsed from the stderr.
+        command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        // A run which can be aborted kills the whole process group of `protoc`, so that the
+        // plugins `protoc` has already spawned are killed too. See [`kill`].
+        #[cfg(unix)]
+        if self.handle_signals || self.cancellation.is_some() {
+            use ::std::os::unix::process::CommandExt;
+            command.process_group(0);
+        }
+        command
+    }
+
+    /// Creates a new temp dir under [`Self::temp_dir`], or the default temp directory.
+    fn new_temp_dir(&self) -> Result<TempDir> {
+        let Some(root) = &self.temp_dir else {
+            return Ok(TempDir::new()?);
+        };
+        ::std::fs::create_dir_all(root)
+            .and_then(|()| TempDir::new_in(root))
+            .map_err(|source| ErrorKind::TempDirError {
+                path: root.clone(),
+                source,
+            })
+    }
+
+    /// Returns the `protoc` command with the given arguments, and the env vars for `protoc` and
+    /// the plugin binary. The `cwd` is left empty if the current directory is not available.
+    fn prepared_command(&self, args: Vec<OsString>) -> PreparedCommand {
+        let mut envs: Vec<(OsString, OsString)> = vec![(
+            MAX_REQUEST_SIZE_ENV_VAR.into(),
+            self.max_request_size.to_string().into(),
+        )];
+        if self.request_size_hint != 0 {
+            envs.push((
+                REQUEST_SIZE_HINT_ENV_VAR.into(),
+                self.request_size_hint.to_string().into(),
+            ));
+        }
+        if self.reproducible && env::var_os(SOURCE_DATE_EPOCH_ENV_VAR).is_none() {
+            envs.push((SOURCE_DATE_EPOCH_ENV_VAR.into(), "0".into()));
+        }
+        if let Some(shared) = &self.shared_server {
+            envs.push((RUN_TAG_ENV_VAR.into(), shared.tag.to_string().into()));
+        }
+        PreparedCommand {
+            program: self.protoc_path.clone(),
+            args,
+            env: envs,
+            cwd: env::current_dir().unwrap_or_default(),
+        }
+    }
+
+    /// Returns the `protoc` command which [`Self::run`] would spawn, without spawning it,
+    /// e.g. for reproducing a failed run by hand.
+    ///
+    /// The `protoc` path is resolved as [`Self::resolved_protoc_path`] does. The init key
+    /// passed to the plugin binary is replaced by a placeholder, since it's only valid while
+    /// this library is waiting for the plugin binary, so the plugin binary fails if the
+    /// command is run standalone. `protoc`'s own errors (e.g. in the proto files) are still
+    /// reproduced. If `Self::out_zip` is set, the output directory is shown as `.` instead
+    /// of a temporary directory.
+    pub fn dry_run(&self) -> Result<PreparedCommand> {
+        self.check_input()?;
+        Ok(PreparedCommand {
+            program: self.resolved_protoc_path()?,
+            cwd: env::current_dir()?,
+            ..self.prepared_command(self.plugin_args(INIT_KEY_PLACEHOLDER)?)
+        })
+    }
+
+    /// Calls the user's plugin closure with the `CodeGeneratorRequest` bytes, checking the sizes
+    /// of the request and the returned `CodeGeneratorResponse` bytes, and the duplicate output
+    /// file names and the insertion points in the response.
+    ///
+    /// The insertion point entries are moved after the other files in the response, since
+    /// `protoc` can only insert into the files which it has already written.
+    fn call_body<F>(&self, request_bytes: &[u8], body: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let limit = self.max_request_size;
+        if limit != 0 && request_bytes.len() > limit {
+            return Err(ErrorKind::RequestTooLarge {
+                limit,
+                actual: request_bytes.len(),
+            });
+        }
+        if let Some(guard) = &self.request_guard {
+            let info = RequestInfo {
+                proto_file_count: request::proto_file_names(request_bytes)?.len(),
+                byte_len: request_bytes.len(),
+            };
+            guard(&info).map_err(ErrorKind::Rejected)?;
+        }
+        self.emit(RunEvent::ClosureStarted);
+        let started = Instant::now();
+        let res = (body)(request_bytes);
+        self.emit(RunEvent::ClosureFinished {
+            duration: started.elapsed(),
+        });
+        let res = res?;
+        let limit = self.max_response_size;
+        if limit != 0 && res.len() > limit {
+            return Err(ErrorKind::ResponseTooLarge {
+                limit,
+                actual: res.len(),
+            });
+        }
+        if let Some(name) = find_duplicate_file_name(&res) {
+            return Err(ErrorKind::DuplicateOutputFileError(name));
+        }
+        let res = plugin_proto::move_insertions_last(&res)?.unwrap_or(res);
+        // Let `protoc` report the malformed responses and the ones with an error, and the
+        // insertions into the files generated by the other plugins in the same run.
+        if let Ok(response) = CodeGeneratorResponse::from_bytes(&res)
+            && response.error.is_none()
+        {
+            apply_own_insertions(response.files)?;
+        }
+        Ok(res)
+    }
+
+    /// Reports the event to the handler set by [`Self::on_event`], if any.
+    fn emit(&self, event: RunEvent) {
+        if let Some(on_event) = &self.on_event {
+            on_event(event);
+        }
+    }
+
+    /// Checks that any input proto file is given, since otherwise `protoc` fails with its usage
+    /// message which doesn't tell what's wrong.
+    fn check_input(&self) -> Result<()> {
+        if self.input_proto_files()?.is_empty() {
+            return Err(ErrorKind::NoInput);
+        }
+        Ok(())
+    }
+
+    /// Creates the output directory if [`Self::create_out_dir`] is set, or checks that it exists
+    /// otherwise, since `protoc`'s error about it is not clear.
+    fn prepare_out_dir(&self) -> Result<()> {
+        let Some(out_dir) = &self.out_dir else {
+            return Ok(());
+        };
+        if self.create_out_dir {
+            ::std::fs::create_dir_all(out_dir).map_err(|source| ErrorKind::OutDirCreateError {
+                path: out_dir.clone(),
+                source,
+            })
+        } else if out_dir.is_dir() {
+            Ok(())
+        } else {
+            Err(ErrorKind::OutDirNotFound(out_dir.clone()))
+        }
+    }
+
+    /// Returns the input proto files, followed by the ones found in [`Self::proto_dir_recursive`]
+    /// directories.
+    fn input_proto_files(&self) -> Result<Vec<PathBuf>> {
+        let mut files = self.proto_files.clone();
+        for dir in &self.proto_dirs {
+            let ignored = match ::std::fs::read_to_string(dir.join(PROTOC_IGNORE_FILE_NAME)) {
+                Ok(content) => content
+                    .lines()
+                    .map(|line| line.trim().trim_end_matches('/'))
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(PathBuf::from)
+                    .collect(),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+                Err(e) => return Err(e.into()),
+            };
+            let start = files.len();
+            collect_proto_files(dir, dir, &ignored, &mut files)?;
+            files[start..].sort();
+        }
+        Ok(files)
+    }
+
+    /// Returns the `--direct_dependencies` and `--direct_dependencies_violation_msg` arguments for
+    /// `protoc`.
+    fn direct_dependencies_args(&self) -> Vec<String> {
+        let mut args = Vec::new();
+        if let Some(names) = &self.direct_dependencies {
+            args.push(format!("--direct_dependencies={}", names.join(":")));
+        }
+        if let Some(message) = &self.direct_dependencies_violation_msg {
+            args.push(format!("--direct_dependencies_violation_msg={}", message));
+        }
+        args
+    }
+
+    /// Splits the [`Self::arg`] arguments into the proto paths and the other arguments.
+    fn split_extra_args(&self) -> (Vec<PathBuf>, Vec<OsString>) {
+        let mut proto_paths = Vec::new();
+        let mut others = Vec::new();
+        let mut args = self.extra_args.iter();
+        while let Some(arg) = args.next() {
+            if arg == "-I" || arg == "--proto_path" {
+                match args.next() {
+                    Some(path) => proto_paths.push(PathBuf::from(path)),
+                    // Leave it to protoc to report the missing value.
+                    None => others.push(arg.clone()),
+                }
+                continue;
+            }
+            let joined = arg.to_str().and_then(|arg| {
+                arg.strip_prefix("--proto_path=")
+                    .or_else(|| arg.strip_prefix("-I"))
+            });
+            match joined {
+                Some(path) => proto_paths.push(PathBuf::from(path)),
+                None => others.push(arg.clone()),
+            }
+        }
+        (proto_paths, others)
+    }
+
+    /// Returns the [`Self::proto_path`] proto paths followed by the [`Self::arg`] ones, without
+    /// the duplicates.
+    fn all_proto_paths(&self) -> Vec<PathBuf> {
+        let mut paths: Vec<PathBuf> = Vec::new();
+        for path in self
+            .proto_paths
+            .iter()
+            .cloned()
+            .chain(self.split_extra_args().0)
+        {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        paths
+    }
+
+    /// Returns the given path as a `protoc` argument, mapped by [`Self::path_mapper`].
+    fn path_arg(&self, path: &Path) -> Result<String> {
+        match &self.path_mapper {
+            Some(mapper) => protoc_path_arg(&mapper(path)),
+            None => protoc_path_arg(path),
+        }
+    }
+
+    /// Returns the plugin binary to run, i.e. the [`Self::plugin_path`] one if it's set.
+    fn plugin_binary_path(&self) -> Result<PathBuf> {
+        match &self.plugin_path {
+            Some(path) => {
+                check_plugin_binary(path)?;
+                Ok(path.clone())
+            }
+            None => plugin_binary(),
+        }
+    }
+
+    /// Returns the `--proto_path` arguments for `protoc`.
+    fn proto_path_args(&self) -> Result<Vec<String>> {
+        let mut paths = self.all_proto_paths();
+        if self.include_std_types {
+            paths.push(self.std_types_dir()?);
+        }
+        paths
+            .iter()
+            .map(|x| Ok(format!("--proto_path={}", self.path_arg(x)?)))
+            .collect()
+    }
+
+    /// Returns `protoc`'s include directory for [`Self::include_std_types`].
+    fn std_types_dir(&self) -> Result<PathBuf> {
+        let mut candidates: Vec<PathBuf> = env::var_os(PROTOC_INCLUDE_ENV_VAR)
+            .filter(|dir| !dir.is_empty())
+            .map(PathBuf::from)
+            .into_iter()
+            .collect();
+        let protoc = self.resolved_protoc_path()?;
+        let canonical = ::std::fs::canonicalize(&protoc).ok();
+        for protoc in ::std::iter::once(protoc).chain(canonical) {
+            if let Some(prefix) = protoc.parent().and_then(Path::parent) {
+                candidates.push(prefix.join("include"));
+            }
+        }
+        if cfg!(unix) {
+            candidates.extend(["/usr/local/include", "/usr/include"].map(PathBuf::from));
+        }
+        candidates.dedup();
+        candidates
+            .iter()
+            .find(|dir| dir.join("google/protobuf/descriptor.proto").is_file())
+            .cloned()
+            .ok_or(ErrorKind::StdTypesNotFound(candidates))
+    }
+
+    /// Returns the input proto file arguments for `protoc`.
+    ///
+    /// On Windows, the files under a [`Self::proto_path`] are passed as the relative paths to it,
+    /// because `protoc` matches them against the proto paths case-sensitively while the
+    /// filesystem is case-insensitive.
+    fn proto_file_args(&self) -> Result<Vec<String>> {
+        let proto_paths = self
+            .all_proto_paths()
+            .iter()
+            .map(|path| self.path_arg(path))
+            .collect::<Result<Vec<_>>>()?;
+        self.input_proto_files()?
+            .iter()
+            .map(|file| {
+                let file = self.path_arg(file)?;
+                if cfg!(windows)
+                    && let Some(relative) = proto_paths
+                        .iter()
+                        .find_map(|dir| strip_dir_prefix_ignore_ascii_case(&file, dir))
+                {
+                    return Ok(relative.to_string());
+                }
+                Ok(file)
+            })
+            .collect()
+    }
+
+    /// Runs the given closure against the given `CodeGeneratorRequest` bytes without `protoc`.
+    ///
+    /// The closure is called in the same way as [`Self::run`] does, but no process is spawned
+    /// and nothing is written to the filesystem. Instead, the files in the returned
+    /// `CodeGeneratorResponse` are returned as the name-content pairs, in the response's order.
+    /// Insertion point entries are returned as they are, without being applied.
+    ///
+    /// This is useful to replay a previously captured request in your generator's tests.
+    pub fn run_offline<F>(self, request_bytes: &[u8], body: F) -> Result<Vec<(String, Vec<u8>)>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        let res = self.call_body(request_bytes, callback(body))?;
+        let response = CodeGeneratorResponse::from_bytes(&res)?;
+        if let Some(error) = response.error {
+            return Err(ErrorKind::CallbackError(error));
+        }
+        Ok(response
+            .files
+            .into_iter()
+            .map(|file| (file.name, file.content))
+            .collect())
+    }
+}
+
+/// Returns the [`FileSummary`]s of the files in the `CodeGeneratorResponse` bytes, written
+/// under `out_dir`.
+fn summarize_files(out_dir: &Path, res: &[u8]) -> Result<Vec<FileSummary>> {
+    Ok(CodeGeneratorResponse::from_bytes(res)?
+        .files
+        .into_iter()
+        .map(|file| FileSummary::new(out_dir, file))
+        .collect())
+}
+
+/// Converts the path into the form which `protoc` accepts in its arguments.
+///
+/// On Windows, the `\\?\` prefix of the verbatim disk paths (e.g. returned by
+/// [`::std::fs::canonicalize`]) is stripped, the UNC paths are rejected with
+/// [`ErrorKind::UnsupportedPathError`], and the backslashes are replaced with the forward slashes.
+/// The spaces need no care since [`Command`] quotes each argument.
+fn protoc_path_arg(path: &Path) -> Result<String> {
+    let path = path.to_str().ok_or(ErrorKind::FileNameError)?;
+    if !cfg!(windows) {
+        return Ok(path.to_string());
+    }
+    let stripped = match path.strip_prefix(r"\\?\") {
+        Some(rest) if rest.starts_with(r"UNC\") => None,
+        Some(rest) => Some(rest),
+        None if path.starts_with(r"\\") || path.starts_with("//") => None,
+        None => Some(path),
+    };
+    let path = stripped.ok_or_else(|| ErrorKind::UnsupportedPathError(path.to_string()))?;
+    Ok(path.replace('\\', "/"))
+}
+
+/// Returns the given `/`-separated file path relative to the given directory if it's under the
+/// directory, comparing them ASCII case-insensitively.
+fn strip_dir_prefix_ignore_ascii_case<'a>(file: &'a str, dir: &str) -> Option<&'a str> {
+    let dir = dir.trim_end_matches('/');
+    let prefix = file.get(..dir.len())?;
+    let relative = file[dir.len()..].strip_prefix('/')?;
+    (!dir.is_empty() && prefix.eq_ignore_ascii_case(dir)).then_some(relative)
+}
+
+/// Parses the make-style dependency file written by `protoc`'s `--dependency_out` option,
+/// and returns the prerequisite (i.e. input proto file) paths.
+///
+/// `protoc` writes the targets and the prerequisites one per line, separated by the line
+/// continuations:
+/// ```text
+/// out/a.rs \
+/// out/b.rs: path/to/b.proto\
+///  path/to/a.proto
+/// ```
+/// `protoc` does not escape the spaces in the paths, but the make-style `\ ` escapes are also
+/// accepted.
+fn parse_dependency_file(content: &str) -> Vec<PathBuf> {
+    let Some((_, prerequisites)) = content.split_once(": ") else {
+        return Vec::new();
+    };
+    prerequisites
+        .split("\\\n")
+        .flat_map(|line| line.split("\\\r\n"))
+        .map(|path| path.trim().replace("\\ ", " "))
+        .filter(|path| !path.is_empty())
+        .map(PathBuf::from)
+        .collect()
+}
+
+/// Appends the `.proto` files under `dir` to `files`, recursively, skipping the paths in
+/// `ignored` which are relative to `root`.
+fn collect_proto_files(
+    root: &Path,
+    dir: &Path,
+    ignored: &[PathBuf],
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    for entry in ::std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+        if ignored.iter().any(|ignored| ignored == relative) {
+            continue;
+        }
+        if path.is_dir() {
+            collect_proto_files(root, &path, ignored, files)?;
+        } else if path.extension().is_some_and(|ext| ext == "proto") {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Applies the insertion point entries to the files before them in the same way as `protoc`,
+/// and returns the other files.
+///
+/// The inserted content is placed just before the line with the
+/// `@@protoc_insertion_point(NAME)` marker, with each line indented by the marker line's
+/// indentation. If the marker is in a `/* ... */` comment, the content is placed just before the
+/// comment without indentation.
+fn apply_insertions(files: Vec<plugin_proto::File>) -> Result<Vec<plugin_proto::File>> {
+    let mut generated: Vec<plugin_proto::File> = Vec::new();
+    for file in files {
+        let Some(insertion_point) = file.insertion_point else {
+            generated.push(file);
+            continue;
+        };
+        let error = |reason: String| ErrorKind::InsertionPointError {
+            file: file.name.clone(),
+            insertion_point: insertion_point.clone(),
+            reason,
+        };
+        let target = generated
+            .iter_mut()
+            .find(|target| target.name == file.name)
+            .ok_or_else(|| error("the file is not generated".to_string()))?;
+        let marker = format!("@@protoc_insertion_point({})", insertion_point);
+        let marker_pos = target
+            .content
+            .windows(marker.len())
+            .position(|window| window == marker.as_bytes())
+            .ok_or_else(|| error(format!("{} is not found", marker)))?;
+
+        let (pos, indent) =
+            if marker_pos >= 3 && target.content[marker_pos - 3..].starts_with(b"/*") {
+                (marker_pos - 3, Vec::new())
+            } else {
+                let line_start = target.content[..marker_pos]
+                    .iter()
+                    .rposition(|&b| b == b'\n')
+                    .map_or(0, |newline| newline + 1);
+                let indent_len = target.content[line_start..]
+                    .iter()
+                    .take_while(|&&b| b == b' ' || b == b'\t')
+                    .count();
+                let indent = target.content[line_start..line_start + indent_len].to_vec();
+                (line_start, indent)
+            };
+
+        let mut data = file.content;
+        if data.last().is_some_and(|&b| b != b'\n') {
+            data.push(b'\n');
+        }
+        let mut inserted = Vec::with_capacity(data.len());
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            inserted.extend_from_slice(&indent);
+            inserted.extend_from_slice(line);
+        }
+        target.content.splice(pos..pos, inserted);
+    }
+    Ok(generated)
+}
+
+/// Same as [`apply_insertions`], but skips the insertion point entries into the files not in the
+/// same response, which may be generated by the other plugins in the same `protoc` run.
+pub(crate) fn apply_own_insertions(
+    mut files: Vec<plugin_proto::File>,
+) -> Result<Vec<plugin_proto::File>> {
+    let generated: ::std::collections::HashSet<_> = files
+        .iter()
+        .filter(|file| file.insertion_point.is_none())
+        .map(|file| file.name.clone())
+        .collect();
+    files.retain(|file| file.insertion_point.is_none() || generated.contains(&file.name));
+    apply_insertions(files)
+}
+
+/// Returns the [`RunOutput`] of a successful run.
+fn run_output(out_dir: &Path, run: ProtocRun) -> Result<RunOutput> {
+    Ok(RunOutput {
+        files: summarize_files(out_dir, &run.response)?,
+        warnings: parse_warnings(&run.stderr),
+        stderr: run.stderr,
+        response: run.response,
+        protoc_version: run.protoc_version,
+    })
+}
+
+/// Parses the warnings in the `protoc`'s stderr, grouping the following indented lines and
+/// `note:`s into them.
+fn parse_warnings(stderr: &str) -> Vec<Diagnostic> {
+    parse_protoc_diagnostics(stderr)
+        .into_iter()
+        .filter(|diagnostic| diagnostic.severity == Severity::Warning)
+        .collect()
+}
+
+/// Parses the warnings and the errors in `protoc`'s stderr in either [`ErrorFormat`], e.g. for
+/// jumping to their locations in an editor.
+///
+/// The located lines are errors unless they're warnings. Of the lines without a location,
+/// `foo.proto: File not found.` is an error of the named file, and `foo: warning: ...` is
+/// a warning. The following indented lines and `note:`s are grouped into the diagnostic above
+/// them. The other lines, e.g. a plugin's failure, are kept as [`Severity::Raw`].
+///
+/// [`ErrorKind::diagnostics`] returns the same for the stderr attached to an error.
+pub fn parse_protoc_diagnostics(stderr: &str) -> Vec<Diagnostic> {
+    parse_diagnostic_lines(stderr).0
+}
+
+/// Same as [`parse_protoc_diagnostics`], but also returns the index of the diagnostic which each
+/// line of the `stderr` belongs to, or `None` for the blank lines.
+fn parse_diagnostic_lines(stderr: &str) -> (Vec<Diagnostic>, Vec<Option<usize>>) {
+    let mut diagnostics: Vec<Diagnostic> = Vec::new();
+    let mut owners = Vec::new();
+    // Whether the following lines belong to the last diagnostic.
+    let mut in_diagnostic = false;
+    for line in stderr.lines() {
+        if line.trim().is_empty() {
+            in_diagnostic = false;
+            owners.push(None);
+            continue;
+        }
+        let (file, line_number, column, rest) = split_location(line);
+        let continuation = line.starts_with(char::is_whitespace) || rest.starts_with("note:");
+        if continuation
+            && in_diagnostic
+            && let Some(diagnostic) = diagnostics.last_mut()
+        {
+            diagnostic.notes.push(line.to_string());
+            owners.push(Some(diagnostics.len() - 1));
+            continue;
+        }
+        let (severity, file, message) = match (line_number, rest.split_once(": ")) {
+            _ if continuation => (Severity::Raw, "", line),
+            (Some(_), _) => match rest.strip_prefix("warning:") {
+                Some(message) => (Severity::Warning, file, message.trim_start()),
+                None => (Severity::Error, file, rest),
+            },
+            (None, _) if rest.starts_with("warning:") => {
+                (Severity::Warning, "", rest["warning:".len()..].trim_start())
+            }
+            (None, Some((file, rest))) if rest.starts_with("warning:") => (
+                Severity::Warning,
+                file,
+                rest["warning:".len()..].trim_start(),
+            ),
+            (None, Some((file, message))) if file.ends_with(".proto") => {
+                (Severity::Error, file, message)
+            }
+            (None, _) => (Severity::Raw, "", line),
+        };
+        owners.push(Some(diagnostics.len()));
+        diagnostics.push(Diagnostic {
+            severity,
+            file: file.to_string(),
+            line: line_number,
+            column,
+            message: message.to_string(),
+            notes: Vec::new(),
+        });
+        in_diagnostic = severity != Severity::Raw;
+    }
+    (diagnostics, owners)
+}
+
+/// Returns the lines of `protoc`'s stderr which are not a part of a warning, as they are.
+#[cfg(feature = "on-memory")]
+fn stderr_without_warnings(stderr: &str) -> String {
+    let (diagnostics, owners) = parse_diagnostic_lines(stderr);
+    stderr
+        .lines()
+        .zip(owners)
+        .filter(|(_, owner)| {
+            owner.is_none_or(|index| diagnostics[index].severity != Severity::Warning)
+        })
+        .map(|(line, _)| format!("{}\n", line))
+        .collect()
+}
+
+/// Formats the diagnostics of [`ErrorKind::CheckError`], one per line.
+fn check_error_details(diagnostics: &[Diagnostic]) -> String {
+    diagnostics
+        .iter()
+        .map(|diagnostic| format!("\n{}", diagnostic))
+        .collect()
+}
+
+/// Splits the location of a `protoc` diagnostic line in either [`ErrorFormat`] into the file,
+/// the line and the column, and returns them with the rest of the line, e.g. `warning: ...`.
+/// Returns the whole line as the rest if it has no location.
+fn split_location(line: &str) -> (&str, Option<u32>, Option<u32>, &str) {
+    // msvs: `foo.proto(2) : warning in column=1: warning: ...`
+    if let Some((head, rest)) = line.split_once(" in column=")
+        && let Some((file_line, _severity)) = head.rsplit_once(" : ")
+        && let Some((file, line_number)) =
+            file_line.strip_suffix(')').and_then(|s| s.rsplit_once('('))
+        && let Ok(line_number) = line_number.parse()
+        && let Some((column, rest)) = rest.split_once(": ")
+        && let Ok(column) = column.parse()
+    {
+        return (file, Some(line_number), Some(column), rest);
+    }
+    // gcc: `foo.proto:2:1: warning: ...`, where the file name may contain `:`.
+    for (i, _) in line.match_indices(':') {
+        let mut parts = line[i + 1..].splitn(3, ':');
+        if let (Some(line_number), Some(column), Some(rest)) =
+            (parts.next(), parts.next(), parts.next())
+            && let (Ok(line_number), Ok(column)) = (line_number.parse(), column.parse())
+            && let Some(rest) = rest.strip_prefix(' ')
+        {
+            return (&line[..i], Some(line_number), Some(column), rest);
+        }
+    }
+    ("", None, None, line)
+}
+
+/// Calls the verifier with each file of the given encoded `CodeGeneratorResponse` which
+/// `protoc` wrote into `out_dir`. See [`Protoc::verify_output`].
+///
+/// On failure, all the files of the response are removed from `out_dir`, so that the rejected
+/// outputs don't look up to date for the next incremental build.
+fn verify_files(out_dir: &Path, response_bytes: &[u8], verifier: &OutputVerifier) -> Result<()> {
+    let response = CodeGeneratorResponse::from_bytes(response_bytes)?;
+    let mut names = Vec::new();
+    for file in response.files {
+        if !names.contains(&file.name) {
+            names.push(file.name);
+        }
+    }
+    for name in &names {
+        let path = out_dir.join(name);
+        let content = ::std::fs::read(&path)?;
+        if let Err(message) = verifier(&path, &content) {
+            for name in &names {
+                match ::std::fs::remove_file(out_dir.join(name)) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => (),
+                }
+            }
+            return Err(ErrorKind::VerificationFailed {
+                file: path,
+                message,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Returns the name of the first non-insertion-point file which appears more than once in the
+/// given encoded `CodeGeneratorResponse`, if any.
+fn find_duplicate_file_name(response_bytes: &[u8]) -> Option<String> {
+    let response = CodeGeneratorResponse::from_bytes(response_bytes).ok()?;
+    let mut names = ::std::collections::HashSet::new();
+    response
+        .files
+        .into_iter()
+        .filter(|file| file.insertion_point.is_none())
+        .find(|file| !names.insert(file.name.clone()))
+        .map(|file| file.name)
+}
+
+/// Writes the files in the `CodeGeneratorResponse` bytes, which `protoc` generated into `out_dir`,
+/// into a zip archive in the order of the response. The archive is written atomically.
+#[cfg(feature = "zip")]
+fn write_zip(zip_path: &Path, out_dir: &Path, response_bytes: &[u8]) -> Result<()> {
+    use ::std::io::Write;
+    use ::zip::write::SimpleFileOptions;
+
+    let response = CodeGeneratorResponse::from_bytes(response_bytes)?;
+    write_atomically(zip_path, |zip_file| {
+        let mut zip = ::zip::ZipWriter::new(zip_file);
+        let options =
+            SimpleFileOptions::default().compression_method(::zip::CompressionMethod::Deflated);
+        let mut written = Vec::new();
+        for file in response.files {
+            // The insertion point entries are already applied by protoc.
+            if file.insertion_point.is_some() || written.contains(&file.name) {
+                continue;
+            }
+            zip.start_file(file.name.as_str(), options)?;
+            zip.write_all(&::std::fs::read(out_dir.join(&file.name))?)?;
+            written.push(file.name);
+        }
+        zip.finish()?;
+        Ok(())
+    })
+}
+
+/// Returns [`ErrorKind::UnsafeOutputFileNameError`] for the first name which is absolute or
+/// contains `..`, i.e. which may point outside of the output directory.
+#[cfg(feature = "on-memory")]
+fn check_file_names<'a>(names: impl IntoIterator<Item = &'a str>) -> Result<()> {
+    for name in names {
+        let is_safe = Path::new(name)
+            .components()
+            .all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !is_safe {
+            return Err(ErrorKind::UnsafeOutputFileNameError(name.to_string()));
+        }
+    }
+    Ok(())
+}
+
+/// Writes the given name-content pairs of the files under the given directory.
+///
+/// Each file is written into a temp file in the same directory and renamed into place, so it's
+/// either complete or left as it was, even if this fails or the process dies in the middle.
+#[cfg(feature = "on-memory")]
+fn write_files<C: AsRef<[u8]>>(dir: &Path, files: &[(String, C)]) -> Result<()> {
+    use ::std::io::Write;
+
+    check_file_names(files.iter().map(|(name, _)| name.as_str()))?;
+    for (name, content) in files {
+        let path = dir.join(name);
+        ::std::fs::create_dir_all(path.parent().unwrap_or(dir))?;
+        write_atomically(&path, |file| Ok(file.write_all(content.as_ref())?))?;
+    }
+    Ok(())
+}
+
+/// Writes the file at the given path by `write` into a temp file in the same directory, and
+/// renames it into place. The file is either complete or left as it was, even if this fails or
+/// the process dies in the middle.
+#[cfg(any(feature = "on-memory", feature = "serde", feature = "zip"))]
+pub(crate) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut ::std::fs::File) -> Result<()>,
+) -> Result<()> {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let mut builder = ::tempfile::Builder::new();
+    // The temp files are private by default, unlike the files written normally.
+    #[cfg(unix)]
+    builder.permissions(::std::os::unix::fs::PermissionsExt::from_mode(0o666));
+    let mut temp_file = builder.tempfile_in(parent)?;
+    write(temp_file.as_file_mut())?;
+    temp_file.persist(path).map_err(|e| e.error)?;
+    Ok(())
+}
+
+impl Default for Protoc {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Returns [`ErrorKind::PluginConnectError`] if `protoc`'s stderr has the plugin binary's error
+/// of connecting back to this process.
+fn plugin_connect_error(stderr: &str) -> Option<ErrorKind> {
+    let start = stderr.find(CONNECT_FAILURE_MESSAGE)?;
+    let message = stderr[start..].lines().next().unwrap_or_default();
+    Some(ErrorKind::PluginConnectError(message.to_string()))
+}
+
+/// Waits for the plugin binary to connect back to the `ipc_init_server`.
+///
+/// `IpcOneShotServer::accept` has no timeout, so it runs on a separate thread while this thread
+/// watches the deadline and the `protoc` process, which may exit (e.g. by a proto syntax error)
+/// without ever invoking the plugin.
+fn accept_plugin(
+    ipc_init_server: IpcOneShotServer<PluginChannels>,
+    ipc_init_name: String,
+    mut process: Option<&mut Child>,
+    timeout: Duration,
+    interrupts: Interrupts<'_>,
+) -> Result<PluginChannels> {
+    let started = Instant::now();
+    let deadline = deadline_after(timeout);
+    let (accepted_send, accepted_recv) = mpsc::channel();
+    let accept_thread = thread::spawn(move || {
+        let _ = accepted_send.send(ipc_init_server.accept());
+    });
+
+    let error = loop {
+        match accepted_recv.recv_timeout(POLL_INTERVAL) {
+            Ok(accepted) => {
+                let _ = accept_thread.join();
+                return Ok(accepted.inspect_err(|_| kill_if_any(process))?.1);
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => (),
+            Err(mpsc::RecvTimeoutError::Disconnected) => break ErrorKind::PluginNotConnectedError,
+        }
+        if let Err(e) = interrupts.check() {
+            break e;
+        }
+        if let Some(exit_code) = try_wait_if_any(process.as_deref_mut())? {
+            break if exit_code.success() {
+                ErrorKind::PluginNotInvoked
+            } else {
+                ErrorKind::ProtocProcessError(ExitStatusInfo::new(Some(exit_code)))
+            };
+        }
+        if Instant::now() >= deadline {
+            break ErrorKind::timeout(Phase::AwaitingPluginHandshake, started);
+        }
+    };
+
+    // Unblock the accepting thread by connecting to the server by ourselves, and join it. The
+    // connection fails only if the plugin binary has connected in the meantime, which unblocks
+    // the thread too. Dropping the connection without sending the channels also unblocks it.
+    kill_if_any(process);
+    if let Ok(dummy_send) = IpcSender::<PluginChannels>::connect(ipc_init_name)
+        && let Ok((_, dummy_req_recv)) = bytes_channel()
+        && let Ok((dummy_res_send, _)) = bytes_channel()
+    {
+        let _ = dummy_send.send((dummy_req_recv, dummy_res_send));
+    }
+    let _ = accept_thread.join();
+    Err(error)
+}
+
+/// Waits for the plugin binary to send a message, i.e. its protocol version or the
+/// `CodeGeneratorRequest` bytes. The `phase` is reported on timeout.
+fn recv_message(
+    req_recv: &IpcBytesReceiver,
+    process: Option<&mut Child>,
+    phase: Phase,
+    timeout: Duration,
+    interrupts: Interrupts<'_>,
+) -> Result<Vec<u8>> {
+    let started = Instant::now();
+    let deadline = deadline_after(timeout);
+    loop {
+        if let Err(e) = interrupts.check() {
+            kill_if_any(process);
+            return Err(e);
+        }
+        match req_recv.try_recv() {
+            Ok(req) => return Ok(req),
+            Err(TryRecvError::Empty) if Instant::now() < deadline => thread::sleep(POLL_INTERVAL),
+            Err(TryRecvError::Empty) => {
+                kill_if_any(process);
+                return Err(ErrorKind::timeout(phase, started));
+            }
+            Err(TryRecvError::IpcError(IpcError::Disconnected)) => {
+                kill_if_any(process);
+                return Err(ErrorKind::PluginNoRequestError);
+            }
+            Err(TryRecvError::IpcError(e)) => {
+                kill_if_any(process);
+                return Err(e.into());
+            }
+        }
+    }
+}
+
+/// Waits for the `protoc` process to exit, up to the timeout. Returns `None` on timeout.
+///
+/// If the signals or the cancellation are being watched, the process is polled instead so that
+/// it is killed and [`ErrorKind::Interrupted`] or [`ErrorKind::Cancelled`] is returned.
+fn wait_protoc(
+    process: &mut Child,
+    timeout: Duration,
+    interrupts: Interrupts<'_>,
+) -> Result<Option<ExitStatus>> {
+    if !interrupts.is_watched() && timeout == NO_TIMEOUT {
+        return Ok(Some(process.wait()?));
+    }
+    let deadline = deadline_after(timeout);
+    if !interrupts.is_watched() {
+        // `wait_timeout` may return slightly before the deadline.
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match process.wait_timeout(remaining)? {
+                Some(exit_code) => return Ok(Some(exit_code)),
+                None if remaining.is_zero() => return Ok(None),
+                None => {}
+            }
+        }
+    }
+    loop {
+        interrupts.check().inspect_err(|_| kill(process))?;
+        if let Some(exit_code) = process.try_wait()? {
+            return Ok(Some(exit_code));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Drains a pipe of the `protoc` process on a separate thread so that `protoc` never blocks on
+/// a full pipe, reporting each line to the event handler as well.
+struct OutputCapture {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    thread: Option<thread::JoinHandle<()>>,
+}
+
+impl OutputCapture {
+    /// Starts reading the pipe. Each line is also reported to `on_event` as the event built by
+    /// `to_event`, if the handler is set.
+    fn start(
+        pipe: Option<impl Read + Send + 'static>,
+        on_event: Option<EventHandler>,
+        to_event: fn(String) -> RunEvent,
+    ) -> Self {
+        let buffer = Arc::new(Mutex::new(Vec::new()));
+        let thread = pipe.map(|pipe| {
+            let buffer = Arc::clone(&buffer);
+            thread::spawn(move || {
+                let mut pipe = BufReader::new(pipe);
+                let mut line = Vec::new();
+                while let Ok(1..) = pipe.read_until(b'\n', &mut line) {
+                    if let Some(on_event) = &on_event {
+                        on_event(to_event(String::from_utf8_lossy(&line).into_owned()));
+                    }
+                    lock(&buffer).append(&mut line);
+                }
+            })
+        });
+        Self { buffer, thread }
+    }
+
+    /// Returns the output captured so far. If `wait` is set, waits for the EOF first.
+    fn output(&mut self, wait: bool) -> String {
+        if wait && let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+        String::from_utf8_lossy(&lock(&self.buffer)).into_owned()
+    }
+}
+
+/// Measures a run against its single timeout.
+struct RunClock {
+    started: Instant,
+}
+
+impl RunClock {
+    fn start() -> Self {
+        Self {
+            started: Instant::now(),
+        }
+    }
+
+    /// Returns the time left of the given timeout, keeping [`NO_TIMEOUT`] as it is.
+    fn remaining(&self, timeout: Duration) -> Duration {
+        if timeout == NO_TIMEOUT {
+            return NO_TIMEOUT;
+        }
+        timeout.saturating_sub(self.started.elapsed())
+    }
 }
 
-impl Protoc {
-    /// Creates a new `Protoc` instance.
-    pub fn new() -> Self {
-        Self {
-            protoc_path: "protoc".into(),
-            out_dir: None,
-            proto_files: Vec::new(),
-            proto_paths: Vec::new(),
-        }
+/// Returns the deadline after the given timeout, clamping [`NO_TIMEOUT`] to a far future.
+fn deadline_after(timeout: Duration) -> Instant {
+    let now = Instant::now();
+    now.checked_add(timeout)
+        .or_else(|| now.checked_add(FAR_FUTURE))
+        .unwrap_or(now)
+}
+
+/// Locks the mutex, ignoring the poisoning since the data is still consistent.
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// Checks the handshake message sent by the plugin binary.
+///
+/// The message is the protocol version as a little-endian `u32`, followed by the run tag as a
+/// little-endian `u64` if [`RUN_TAG_ENV_VAR`] is set for the plugin binary. The plugin binaries
+/// predating the version handshake send the request instead, which is reported as the version
+/// `0`. If the `tag` is given, the message must have it.
+fn check_handshake(message: &[u8], tag: Option<u64>) -> Result<()> {
+    let (version, actual) = match message.split_at_checked(4) {
+        Some((version, tag)) if tag.len() == 8 => (version, tag.try_into().ok()),
+        _ => (message, None),
+    };
+    let actual = actual.map(u64::from_le_bytes);
+    let bin = <[u8; 4]>::try_from(version)
+        .map(u32::from_le_bytes)
+        .unwrap_or(0);
+    if bin != PROTOCOL_VERSION {
+        return Err(ErrorKind::PluginVersionMismatch {
+            lib: PROTOCOL_VERSION,
+            bin,
+        });
     }
-    /// Sets the path to the `protoc` command. Default is `"protoc"`.
-    pub fn protoc_path(mut self, path: impl Into<PathBuf>) -> Self {
-        self.protoc_path = path.into();
-        self
+    if let Some(expected) = tag
+        && actual != Some(expected)
+    {
+        return Err(ErrorKind::PluginRunMismatch { expected, actual });
     }
-    /// Sets the output directory for the generated files. Corresponds to `--rust_out` option of `protoc`.
-    pub fn out_dir(mut self, path: impl Into<PathBuf>) -> Self {
-        self.out_dir = Some(path.into());
-        self
+    Ok(())
+}
+
+/// The [`IpcServer`] shared by the runs of [`Protoc::run_many`], and the tag of the current run.
+#[derive(Clone)]
+struct SharedServer {
+    server: Arc<Mutex<IpcServer>>,
+    tag: u64,
+}
+
+/// The result of a successful `protoc` run, returned by [`Protoc::run_and_get_response`].
+struct ProtocRun {
+    /// The `CodeGeneratorResponse` bytes sent to `protoc`.
+    response: Vec<u8>,
+    /// The whole stderr of `protoc`.
+    stderr: String,
+    /// The `protoc` version in the `CodeGeneratorRequest`.
+    protoc_version: Option<String>,
+}
+
+/// The sources which can abort a run while waiting: the caught signals and the cancellation.
+#[derive(Clone, Copy, Default)]
+struct Interrupts<'a> {
+    signals: Option<&'a SignalGuard>,
+    cancellation: Option<&'a CancellationToken>,
+}
+
+impl Interrupts<'_> {
+    /// Returns whether any source is being watched, so that the waits need polling.
+    fn is_watched(&self) -> bool {
+        self.signals.is_some() || self.cancellation.is_some()
     }
-    /// Sets the path to the input proto file. Corresponds to the unnamed argument of `protoc`.
-    pub fn proto_file(mut self, path: impl Into<PathBuf>) -> Self {
-        self.proto_files.push(path.into());
-        self
+
+    /// Returns [`ErrorKind::Cancelled`] or [`ErrorKind::Interrupted`] if the run is aborted.
+    fn check(&self) -> Result<()> {
+        if self
+            .cancellation
+            .is_some_and(CancellationToken::is_cancelled)
+        {
+            return Err(ErrorKind::Cancelled);
+        }
+        if self.signals.is_some_and(SignalGuard::is_interrupted) {
+            return Err(ErrorKind::Interrupted);
+        }
+        Ok(())
     }
-    /// Sets the paths to the input proto files. Corresponds to the unnamed arguments of `protoc`.
-    pub fn proto_files<I>(mut self, paths: I) -> Self
-    where
-        I: IntoIterator,
-        I::Item: Into<PathBuf>,
-    {
-        self.proto_files.extend(paths.into_iter().map(|p| p.into()));
-        self
+}
+
+/// The error of a single attempt of a `protoc` run, classified whether it's worth retrying.
+struct RunFailure {
+    error: ErrorKind,
+    transient: bool,
+}
+
+impl RunFailure {
+    /// `protoc` exited unsuccessfully. It's transient only if `protoc` printed no diagnostics,
+    /// since otherwise it's likely an error in the input proto files.
+    fn protoc_failed(info: ExitStatusInfo) -> Self {
+        Self {
+            transient: info.stderr.is_empty(),
+            error: ErrorKind::ProtocProcessError(info),
+        }
     }
-    /// Sets the path to the input proto file directory. Corresponds to `--proto_path` option of `protoc`.
-    pub fn proto_path(mut self, path: impl Into<PathBuf>) -> Self {
-        self.proto_paths.push(path.into());
-        self
+
+    /// See [`ErrorKind::with_command`].
+    fn with_command(self, command: &str) -> Self {
+        Self {
+            error: self.error.with_command(command),
+            ..self
+        }
     }
+}
 
-    /// Runs the `protoc` command with the given closure as a plugin code.
-    ///
-    /// The `body` param can be any `FnOnce` closure which takes the encoded `CodeGeneratorRequest` bytes
-    /// and returns the `Result` of encoded `CodeGeneratorResponse` bytes.
-    ///
-    /// Set the `timeout` to the maximum duration of the `protoc` command execution.
-    pub fn run<F>(self, timeout: Duration, body: F) -> Result<()>
-    where
-        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
-    {
-        let (ipc_init_server, ipc_init_name) = IpcOneShotServer::new()?;
+impl From<ErrorKind> for RunFailure {
+    fn from(error: ErrorKind) -> Self {
+        let transient = matches!(
+            error,
+            ErrorKind::IpcIpcError(_)
+                | ErrorKind::IpcError(_)
+                | ErrorKind::PluginNotConnectedError
+                | ErrorKind::PluginConnectError(_)
+                | ErrorKind::PluginNoRequestError
+                | ErrorKind::Timeout {
+                    phase: Phase::AwaitingPluginHandshake | Phase::AwaitingRequest,
+                    ..
+                }
+        );
+        Self { error, transient }
+    }
+}
 
-        let mut process = Command::new(&self.protoc_path)
-            .args(&[
-                // We name our plugin binary name as "rust-ppbc" here.
-                format!("--plugin=protoc-gen-rust-ppbc={}", PLUGIN_PATH),
-                format!(
-                    "--rust-ppbc_out={}",
-                    self.out_dir
-                        .as_ref()
-                        .map(|p| p.to_str().ok_or(ErrorKind::FileNameError))
-                        .transpose()?
-                        .unwrap_or(".")
-                ),
-                format!("--rust-ppbc_opt={}", ipc_init_name),
-            ])
-            .args(
-                self.proto_paths
-                    .iter()
-                    .map(|x| {
-                        Ok(format!(
-                            "--proto_path={}",
-                            x.to_str().ok_or(ErrorKind::FileNameError)?
-                        ))
-                    })
-                    .collect::<Result<Vec<_>>>()?,
-            )
-            .args(&self.proto_files)
-            .spawn()?;
+/// Owns the resources of a single `protoc` run: the process and its temporary files.
+///
+/// When dropped, including by an early `?` return or a panic in the closure, the process is
+/// killed and reaped (which is a no-op if it has already exited), and then the temporary files
+/// are removed.
+struct RunGuard {
+    process: Child,
+    stdout: OutputCapture,
+    stderr: OutputCapture,
+    command_line: String,
+    // Declared after `process` so that it's removed after the process is gone.
+    _argfile_dir: Option<TempDir>,
+}
 
-        {
-            // receive the ipc channels from the plugin exe.
-            let (req_recv, res_send): (IpcBytesReceiver, IpcBytesSender) =
-                ipc_init_server.accept()?.1;
+impl RunGuard {
+    fn new(
+        mut process: Child,
+        argfile_dir: Option<TempDir>,
+        command_line: String,
+        on_event: Option<EventHandler>,
+    ) -> Self {
+        let stdout = OutputCapture::start(
+            process.stdout.take(),
+            on_event.clone(),
+            RunEvent::ProtocStdout,
+        );
+        let stderr = OutputCapture::start(process.stderr.take(), on_event, RunEvent::ProtocStderr);
+        Self {
+            process,
+            stdout,
+            stderr,
+            command_line,
+            _argfile_dir: argfile_dir,
+        }
+    }
 
-            let req = req_recv.recv()?;
-            let res = (body)(&req).map_err(|x| ErrorKind::CallbackError(x))?;
+    /// Returns the info of the exited process, with its whole outputs.
+    fn exited(&mut self, status: Option<ExitStatus>) -> ExitStatusInfo {
+        ExitStatusInfo {
+            stdout: self.stdout.output(true),
+            stderr: self.stderr.output(true),
+            command: self.command_line.clone(),
+            ..ExitStatusInfo::new(status)
+        }
+    }
 
-            res_send.send(&res)?;
+    /// Kills the process, and returns [`ErrorKind::Timeout`] with the stderr captured so far
+    /// and the command line.
+    /// This doesn't wait for the EOF, since the processes spawned by `protoc` may still hold
+    /// the pipes.
+    fn timed_out(&mut self, phase: Phase, elapsed: Duration) -> ErrorKind {
+        kill(&mut self.process);
+        ErrorKind::Timeout {
+            phase,
+            elapsed,
+            stderr: self.stderr.output(false),
+            command: self.command_line.clone(),
         }
+    }
+}
 
-        let Some(exit_code) = process.wait_timeout(timeout)? else {
-            return Err(ErrorKind::ProtocTimeoutError);
-        };
-        if !exit_code.success() {
-            return Err(ErrorKind::ProtocProcessError(exit_code));
+impl Drop for RunGuard {
+    fn drop(&mut self) {
+        kill(&mut self.process);
+    }
+}
+
+/// Kills the `protoc` process and reaps it, ignoring the errors since this is only used
+/// for the cleanup on the error paths.
+///
+/// On Windows, this doesn't kill the plugin processes spawned by `protoc`.
+fn kill(process: &mut Child) {
+    #[cfg(unix)]
+    kill_group(process);
+    let _ = process.kill();
+    let _ = process.wait();
+}
+
+/// Kills the process group led by the still running `protoc` process, if it's spawned as its
+/// own group leader, which also kills the plugin processes `protoc` has spawned.
+#[cfg(unix)]
+fn kill_group(process: &mut Child) {
+    // The process is not reaped yet, so its pid (and the group id) can't be reused.
+    if !matches!(process.try_wait(), Ok(None)) {
+        return;
+    }
+    let pid = process.id() as ::libc::pid_t;
+    unsafe {
+        if ::libc::getpgid(pid) == pid {
+            ::libc::killpg(pid, ::libc::SIGKILL);
         }
+    }
+}
 
-        Ok(())
+/// Same as [`kill`], but does nothing if the `protoc` process is not ours.
+fn kill_if_any(process: Option<&mut Child>) {
+    if let Some(process) = process {
+        kill(process);
+    }
+}
+
+/// Checks whether the `protoc` process, if it's ours, has exited.
+fn try_wait_if_any(process: Option<&mut Child>) -> Result<Option<ExitStatus>> {
+    match process {
+        Some(process) => Ok(process.try_wait()?),
+        None => Ok(None),
     }
 }
 
@@ -194,6 +3926,9 @@ impl Protoc {
 ///
 /// See the [crate level documentation](crate) or [`Protoc`] for the basic explanations.
 ///
+/// Like [`Protoc`], this is [`Clone`]. The added (virtual) proto files are copied as well, and
+/// each run stages them into its own temp dir.
+///
 /// # Example
 /// ```no_run
 /// # fn run_protoc() {
@@ -225,17 +3960,33 @@ impl Protoc {
 /// # }
 /// ```
 #[cfg(feature = "on-memory")]
+#[derive(Clone)]
 pub struct ProtocOnMemory {
     protoc: Protoc,
-    in_files: Vec<(String, String)>,
+    in_files: Vec<(String, Cow<'static, [u8]>)>,
+    files_to_generate: Option<Vec<String>>,
+    out_dir: Option<PathBuf>,
+    keep_temp: bool,
+    strip_parameter: bool,
+    normalize_newlines: bool,
+    #[cfg(feature = "well-known-types")]
+    well_known_types: bool,
 }
 
+#[cfg(feature = "on-memory")]
 impl ProtocOnMemory {
     /// Creates a new `ProtocOnMemory` instance.
     pub fn new() -> Self {
         Self {
             protoc: Protoc::new(),
             in_files: Vec::new(),
+            files_to_generate: None,
+            out_dir: None,
+            keep_temp: false,
+            strip_parameter: false,
+            normalize_newlines: false,
+            #[cfg(feature = "well-known-types")]
+            well_known_types: false,
         }
     }
     /// Sets the path to the `protoc` command. Default is `"protoc"`.
@@ -243,17 +3994,289 @@ impl ProtocOnMemory {
         self.protoc = self.protoc.protoc_path(path);
         self
     }
+    /// Sets the path to the plugin binary to run. See [`Protoc::plugin_path`].
+    pub fn plugin_path(mut self, path: impl Into<PathBuf>) -> Self {
+        self.protoc = self.protoc.plugin_path(path);
+        self
+    }
+    /// Sets the runner to build the command which runs `protoc`. See [`Protoc::runner`].
+    pub fn runner(
+        mut self,
+        runner: impl Fn(&PreparedCommand) -> Command + Send + Sync + 'static,
+    ) -> Self {
+        self.protoc = self.protoc.runner(runner);
+        self
+    }
+    /// Sets the mapping applied to every path in the `protoc` arguments.
+    /// See [`Protoc::path_mapper`].
+    pub fn path_mapper(
+        mut self,
+        mapper: impl Fn(&Path) -> PathBuf + Send + Sync + 'static,
+    ) -> Self {
+        self.protoc = self.protoc.path_mapper(mapper);
+        self
+    }
+    /// Adds a raw argument for `protoc`. See [`Protoc::arg`].
+    pub fn arg(mut self, arg: impl Into<OsString>) -> Self {
+        self.protoc = self.protoc.arg(arg);
+        self
+    }
+    /// Adds raw arguments for `protoc`. See [`Protoc::args`].
+    pub fn args<I, S>(mut self, args: I) -> Self
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<OsString>,
+    {
+        self.protoc = self.protoc.args(args);
+        self
+    }
+    /// Sets the name of the plugin. See [`Protoc::plugin_name`].
+    pub fn plugin_name(mut self, name: &str) -> Self {
+        self.protoc = self.protoc.plugin_name(name);
+        self
+    }
+    /// Catches the interrupt signals while running. See [`Protoc::handle_signals`].
+    pub fn handle_signals(mut self, handle_signals: bool) -> Self {
+        self.protoc = self.protoc.handle_signals(handle_signals);
+        self
+    }
+    /// Sets the policy to re-run `protoc` on the transient failures. See [`Protoc::retry_policy`].
+    pub fn retry_policy(mut self, retry_policy: RetryPolicy) -> Self {
+        self.protoc = self.protoc.retry_policy(retry_policy);
+        self
+    }
+    /// Runs `protoc` for the reproducible builds. See [`Protoc::reproducible`].
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.protoc = self.protoc.reproducible(reproducible);
+        self
+    }
+    /// Sets the handler to be called with the progress of the run. See [`Protoc::on_event`].
+    pub fn on_event(mut self, handler: impl Fn(RunEvent) + Send + Sync + 'static) -> Self {
+        self.protoc = self.protoc.on_event(handler);
+        self
+    }
+    /// Sets the guard to check the request before the closure is called.
+    /// See [`Protoc::request_guard`].
+    pub fn request_guard(
+        mut self,
+        guard: impl Fn(&RequestInfo) -> ::std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.protoc = self.protoc.request_guard(guard);
+        self
+    }
+    /// Sets the verifier to be called with each generated file after `protoc` wrote them.
+    /// See [`Protoc::verify_output`].
+    pub fn verify_output(
+        mut self,
+        verifier: impl Fn(&Path, &[u8]) -> ::std::result::Result<(), String> + Send + Sync + 'static,
+    ) -> Self {
+        self.protoc = self.protoc.verify_output(verifier);
+        self
+    }
+    /// Makes `protoc` fail if any warnings are generated. See [`Protoc::fatal_warnings`].
+    pub fn fatal_warnings(mut self, fatal_warnings: bool) -> Self {
+        self.protoc = self.protoc.fatal_warnings(fatal_warnings);
+        self
+    }
+    /// Sets the timeout of `protoc`'s parsing of the proto files.
+    /// See [`Protoc::protoc_parse_timeout`].
+    pub fn protoc_parse_timeout(mut self, timeout: Duration) -> Self {
+        self.protoc = self.protoc.protoc_parse_timeout(timeout);
+        self
+    }
+    /// Sets the format of `protoc`'s error and warning messages. See [`Protoc::error_format`].
+    pub fn error_format(mut self, error_format: ErrorFormat) -> Self {
+        self.protoc = self.protoc.error_format(error_format);
+        self
+    }
+    /// Restricts the imports of the input proto files. See [`Protoc::direct_dependencies`].
+    pub fn direct_dependencies<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator,
+        I::Item: Into<String>,
+    {
+        self.protoc = self.protoc.direct_dependencies(names);
+        self
+    }
+    /// Sets the error message for the imports violating [`Self::direct_dependencies`].
+    /// See [`Protoc::direct_dependencies_violation_msg`].
+    pub fn direct_dependencies_violation_msg(mut self, message: &str) -> Self {
+        self.protoc = self.protoc.direct_dependencies_violation_msg(message);
+        self
+    }
+    /// Sets the transport to the plugin binary. See [`Protoc::transport`].
+    pub fn transport(mut self, transport: Transport) -> Self {
+        self.protoc = self.protoc.transport(transport);
+        self
+    }
+    /// Sets the maximum size in bytes of the `CodeGeneratorRequest`.
+    /// See [`Protoc::max_request_size`].
+    pub fn max_request_size(mut self, limit: usize) -> Self {
+        self.protoc = self.protoc.max_request_size(limit);
+        self
+    }
+    /// Sets the expected size in bytes of the `CodeGeneratorRequest`.
+    /// See [`Protoc::request_size_hint`].
+    pub fn request_size_hint(mut self, bytes: usize) -> Self {
+        self.protoc = self.protoc.request_size_hint(bytes);
+        self
+    }
+    /// Sets the maximum size in bytes of the `CodeGeneratorResponse`.
+    /// See [`Protoc::max_response_size`].
+    pub fn max_response_size(mut self, limit: usize) -> Self {
+        self.protoc = self.protoc.max_response_size(limit);
+        self
+    }
+    /// Adds the `supported_features` bits of the typed responses.
+    /// See [`Protoc::supported_features`].
+    #[cfg(any(feature = "prost", feature = "protobuf"))]
+    pub fn supported_features(mut self, features: u64) -> Self {
+        self.protoc = self.protoc.supported_features(features);
+        self
+    }
+    /// Includes all the transitive dependencies in the output `FileDescriptorSet`.
+    /// See [`Protoc::include_imports`].
+    pub fn include_imports(mut self, include_imports: bool) -> Self {
+        self.protoc = self.protoc.include_imports(include_imports);
+        self
+    }
+    /// Includes the source code info in the output `FileDescriptorSet`.
+    /// See [`Protoc::include_source_info`].
+    pub fn include_source_info(mut self, include_source_info: bool) -> Self {
+        self.protoc = self.protoc.include_source_info(include_source_info);
+        self
+    }
+    /// Adds the include directory installed with `protoc` as the last proto path.
+    /// See [`Protoc::include_std_types`].
+    pub fn include_std_types(mut self, include_std_types: bool) -> Self {
+        self.protoc = self.protoc.include_std_types(include_std_types);
+        self
+    }
     /// Adds a (virtual) input proto file. Corresponds to the `protoc` command's unnamed argument.
-    pub fn add_file(mut self, name: &str, content: &str) -> Self {
-        self.in_files.push((name.to_string(), content.to_string()));
+    ///
+    /// The content is either a `&'static str` or an owned `String`, which is written into the
+    /// temp dir as it is, without being copied.
+    pub fn add_file(
+        mut self,
+        name: impl Into<String>,
+        content: impl Into<Cow<'static, str>>,
+    ) -> Self {
+        let content = match content.into() {
+            Cow::Borrowed(content) => Cow::Borrowed(content.as_bytes()),
+            Cow::Owned(content) => Cow::Owned(content.into_bytes()),
+        };
+        self.in_files.push((name.into(), content));
+        self
+    }
+    /// Adds a (virtual) input file with the raw bytes, e.g. for a file which is not valid UTF-8.
+    /// Same as [`Self::add_file`] otherwise.
+    pub fn add_file_bytes(
+        mut self,
+        name: impl Into<String>,
+        content: impl Into<Cow<'static, [u8]>>,
+    ) -> Self {
+        self.in_files.push((name.into(), content.into()));
+        self
+    }
+    /// Makes the well-known type protos importable from the input files, e.g.
+    /// `google/protobuf/timestamp.proto`, by writing the sources bundled in this crate into the
+    /// temp dir: `any`, `descriptor`, `duration`, `empty`, `field_mask`, `struct`, `timestamp`
+    /// and `wrappers`. Requires the `well-known-types` feature.
+    ///
+    /// They are not passed to `protoc` as the input files, so they're only in the request's
+    /// `proto_file` when imported. The input files with the same names take precedence.
+    #[cfg(feature = "well-known-types")]
+    pub fn with_well_known_types(mut self) -> Self {
+        self.well_known_types = true;
+        self
+    }
+    /// Adds (virtual) input proto files in the given order. Corresponds to the `protoc`
+    /// command's unnamed arguments.
+    ///
+    /// The contents are the raw bytes like [`Self::add_file_bytes`], so an owned `String` or
+    /// `Vec<u8>` is not copied. Same as the other `add_file` methods, a name added more than once
+    /// makes the run fail with [`ErrorKind::DuplicateInputFileError`].
+    pub fn add_files<I, N, C>(mut self, files: I) -> Self
+    where
+        I: IntoIterator<Item = (N, C)>,
+        N: Into<String>,
+        C: Into<Vec<u8>>,
+    {
+        self.in_files.extend(
+            files
+                .into_iter()
+                .map(|(name, content)| (name.into(), Cow::Owned(content.into()))),
+        );
+        self
+    }
+    /// Sets the directory to also write the generated files into.
+    ///
+    /// If this is set, [`Self::run`] writes each generated file under this directory
+    /// (overwriting the existing files) after `protoc` succeeded, in addition to returning them.
+    /// The file names containing `..` or absolute paths are rejected with
+    /// [`ErrorKind::UnsafeOutputFileNameError`].
+    pub fn out_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.out_dir = Some(path.into());
+        self
+    }
+    /// Writes the manifest of the generated files as JSON to the given path after a successful
+    /// run. See [`Protoc::manifest_out`].
+    #[cfg(feature = "serde")]
+    pub fn manifest_out(mut self, path: impl Into<PathBuf>) -> Self {
+        self.protoc = self.protoc.manifest_out(path);
+        self
+    }
+    /// Keeps the temp dir holding the input proto files (under `proto/`) and the `protoc` outputs
+    /// (under `out/`) after running, for debugging. Default is `false`.
+    ///
+    /// The path of the kept temp dir is reported to [`Self::on_event`] as
+    /// [`RunEvent::TempDirKept`].
+    pub fn keep_temp(mut self, keep_temp: bool) -> Self {
+        self.keep_temp = keep_temp;
+        self
+    }
+    /// Clears the `parameter` field of the request passed to the closure, which carries the
+    /// random key to connect to the plugin binary, so that the same input files give the same
+    /// request bytes. Default is `false`.
+    ///
+    /// The input files are passed to `protoc` by their absolute paths in the temp dir, but
+    /// `protoc` rewrites them relative to the temp dir given as the `--proto_path`. Hence
+    /// the other names in the request, including the ones in the source info, don't depend on
+    /// the temp dir. The paths which `protoc` prints outside the request, e.g. in
+    /// [`Protoc::dependency_out`] files, still contain it.
+    pub fn strip_parameter(mut self, strip_parameter: bool) -> Self {
+        self.strip_parameter = strip_parameter;
+        self
+    }
+    /// Sets the directory to create the temp dirs in, including the one holding the input proto
+    /// files and the `protoc` outputs. See [`Protoc::temp_dir`].
+    ///
+    /// The temp dirs are still removed after running, unless [`Self::keep_temp`] is set.
+    pub fn temp_dir(mut self, path: impl Into<PathBuf>) -> Self {
+        self.protoc = self.protoc.temp_dir(path);
+        self
+    }
+    /// Converts CRLF line endings into LF in the generated files returned by [`Self::run`].
+    /// Default is `false`, returning the contents as they are.
+    ///
+    /// Only applies to the UTF-8 text output of [`Self::run`], not to the bytes returned by
+    /// [`Self::run_map`]. The files written into [`Self::out_dir`] are converted as well.
+    pub fn normalize_newlines(mut self, normalize_newlines: bool) -> Self {
+        self.normalize_newlines = normalize_newlines;
         self
     }
-    /// Adds (virtual) input proto files. Corresponds to the `protoc` command's unnamed arguments.
-    pub fn add_files<I>(mut self, files: I) -> Self
+    /// Restricts the code generation to the given (virtual) input proto files.
+    ///
+    /// The named files are passed to `protoc` as the files to generate, and the other added files
+    /// are only available as imports. Each name must match a name given to [`Self::add_file`],
+    /// otherwise the run fails with [`ErrorKind::UnknownGenerateOnlyFileError`] before running
+    /// `protoc`. If this is not called, the code is generated for all the added files.
+    pub fn generate_only<I>(mut self, names: I) -> Self
     where
-        I: IntoIterator<Item = (String, String)>,
+        I: IntoIterator,
+        I::Item: Into<String>,
     {
-        self.in_files.extend(files);
+        self.files_to_generate = Some(names.into_iter().map(|n| n.into()).collect());
         self
     }
 
@@ -263,31 +4286,223 @@ impl ProtocOnMemory {
     /// and returns the `Result` of encoded `CodeGeneratorResponse` bytes.
     ///
     /// Set the `timeout` to the maximum duration of the `protoc` command execution.
+    ///
+    /// The generated files are returned as the name-content pairs in the order of the closure's
+    /// `CodeGeneratorResponse`, regardless of the filesystem's order. The insertion point entries
+    /// are applied to their target files rather than being returned separately.
+    ///
+    /// An empty `CodeGeneratorResponse`, i.e. with no files and no error, is a success which
+    /// returns an empty list.
     pub fn run<F>(self, timeout: Duration, func: F) -> Result<Vec<(String, String)>>
     where
         F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
     {
-        let proto_dir = TempDir::new()?;
-        let out_dir = TempDir::new()?;
-        let out_dir_path = out_dir
-            .path()
-            .to_str()
-            .ok_or(ErrorKind::FileNameError)?
-            .to_string();
+        self.run_with_warnings(timeout, func)
+            .map(|output| output.files)
+    }
+
+    /// Same as [`Self::run`], but without the timeout. See [`Protoc::run_untimed`] for the risk
+    /// of hangs.
+    pub fn run_untimed<F>(self, func: F) -> Result<Vec<(String, String)>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run(NO_TIMEOUT, func)
+    }
+
+    /// Same as [`Self::run`], but also returns the warnings which `protoc` printed and
+    /// the rest of its stderr. See [`Protoc::run_with_warnings`].
+    pub fn run_with_warnings<F>(self, timeout: Duration, func: F) -> Result<OnMemoryOutput>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run_and_read_strings(timeout, callback(func))
+    }
+
+    /// [`Self::run_with_warnings`] with the closure returning [`ErrorKind`] rather than
+    /// the message of [`ErrorKind::CallbackError`].
+    pub(crate) fn run_and_read_strings<F>(
+        self,
+        timeout: Duration,
+        func: F,
+    ) -> Result<OnMemoryOutput>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+    {
+        let normalize_newlines = self.normalize_newlines;
+        let output = self.run_and_read(timeout, func, |path| {
+            let content = ::std::fs::read_to_string(path)?;
+            Ok(if normalize_newlines {
+                content.replace("\r\n", "\n")
+            } else {
+                content
+            })
+        })?;
+        Ok(OnMemoryOutput {
+            files: output.files,
+            warnings: parse_warnings(&output.stderr),
+            stderr: stderr_without_warnings(&output.stderr),
+        })
+    }
+
+    /// Same as [`Self::run`], but returns the encoded `CodeGeneratorResponse` bytes sent to
+    /// `protoc` instead of the generated files. See [`Protoc::run_raw`].
+    ///
+    /// The generated files are still written into [`Self::out_dir`] if set.
+    pub fn run_raw<F>(self, timeout: Duration, func: F) -> Result<Vec<u8>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        self.run_and_read(timeout, callback(func), ::std::fs::read)
+            .map(|output| output.response)
+    }
+
+    /// Same as [`Self::run`], but returns the generated files as a map from the file names
+    /// to the contents.
+    ///
+    /// Like [`Self::run`], returns [`ErrorKind::DuplicateOutputFileError`] if the closure's
+    /// `CodeGeneratorResponse` contains more than one file with the same name, instead of keeping
+    /// either of them.
+    pub fn run_map<F>(self, timeout: Duration, func: F) -> Result<BTreeMap<String, Vec<u8>>>
+    where
+        F: FnOnce(&[u8]) -> ::std::result::Result<Vec<u8>, String>,
+    {
+        Ok(self
+            .run_and_read(timeout, callback(func), ::std::fs::read)?
+            .files
+            .into_iter()
+            .collect())
+    }
+
+    /// Runs `protoc`, and reads the generated files by the given `read` function in the order
+    /// of the closure's `CodeGeneratorResponse`. Also writes them into [`Self::out_dir`] if set.
+    fn run_and_read<F, C, R>(self, timeout: Duration, func: F, read: R) -> Result<ReadOutput<C>>
+    where
+        F: FnOnce(&[u8]) -> Result<Vec<u8>>,
+        C: AsRef<[u8]>,
+        R: Fn(PathBuf) -> ::std::io::Result<C>,
+    {
+        let write_through_dir = self.out_dir.clone();
+        let strip_parameter = self.strip_parameter;
+        let (temp_dir, protoc) = self.stage()?;
+        let func = |req: &[u8]| {
+            let res = match strip_parameter {
+                true => func(&plugin_proto::CodeGeneratorRequest::strip_parameter(req)?)?,
+                false => func(req)?,
+            };
+            // Checked before `protoc` sees the names, which rejects some of them by itself.
+            if write_through_dir.is_some() {
+                let response = CodeGeneratorResponse::from_bytes(&res)?;
+                check_file_names(response.files.iter().map(|file| file.name.as_str()))?;
+            }
+            Ok(res)
+        };
+        let run = protoc.run_and_get_response(timeout, &mut |_| (), func)?;
+        let response = CodeGeneratorResponse::from_bytes(&run.response)?;
+
+        // read the generated files. The insertion point entries are already applied by protoc.
+        let out_dir = temp_dir.path().join(TEMP_OUT_DIR_NAME);
+        let mut output_files: Vec<(String, C)> = Vec::new();
+        for file in response.files {
+            if file.insertion_point.is_some() || output_files.iter().any(|(n, _)| *n == file.name) {
+                continue;
+            }
+            let content = read(out_dir.join(&file.name))?;
+            output_files.push((file.name, content));
+        }
+
+        if let Some(write_through_dir) = write_through_dir {
+            write_files(&write_through_dir, &output_files)?;
+        }
+
+        Ok(ReadOutput {
+            files: output_files,
+            response: run.response,
+            stderr: run.stderr,
+        })
+    }
+
+    /// Compiles the (virtual) input proto files into the encoded `FileDescriptorSet` bytes.
+    ///
+    /// See [`Protoc::compile_descriptor_set`] for the details.
+    pub fn compile_descriptor_set(self, timeout: Duration) -> Result<Vec<u8>> {
+        let (_temp_dir, protoc) = self.stage()?;
+        protoc.compile_descriptor_set(timeout)
+    }
+
+    /// Checks that the (virtual) input proto files compile, without generating any code.
+    ///
+    /// See [`Protoc::check`] for the details.
+    pub fn check(self, timeout: Duration) -> Result<Vec<Diagnostic>> {
+        let (_temp_dir, protoc) = self.stage()?;
+        protoc.check(timeout)
+    }
+
+    /// Writes the input proto files into a temp dir, and returns the temp dir and the [`Protoc`]
+    /// instance configured to read them and to write the outputs into the same temp dir.
+    /// The temp dir must be kept alive while running `protoc`, and is removed when it's dropped
+    /// unless [`Self::keep_temp`] is set.
+    fn stage(self) -> Result<(TempDir, Protoc)> {
+        if self.in_files.is_empty() {
+            return Err(ErrorKind::NoInput);
+        }
+        let mut names = ::std::collections::HashSet::new();
+        for (name, _) in &self.in_files {
+            if !names.insert(name.as_str()) {
+                return Err(ErrorKind::DuplicateInputFileError(name.clone()));
+            }
+        }
+        if let Some(name) = self
+            .files_to_generate
+            .iter()
+            .flatten()
+            .find(|name| !names.contains(name.as_str()))
+        {
+            return Err(ErrorKind::UnknownGenerateOnlyFileError(name.clone()));
+        }
+        let mut temp_dir = self.protoc.new_temp_dir()?;
+        if self.keep_temp {
+            temp_dir.disable_cleanup(true);
+            self.protoc
+                .emit(RunEvent::TempDirKept(temp_dir.path().to_path_buf()));
+        }
+        let proto_dir = temp_dir.path().join(TEMP_PROTO_DIR_NAME);
+        let out_dir = temp_dir.path().join(TEMP_OUT_DIR_NAME);
+        ::std::fs::create_dir(&proto_dir)?;
+        ::std::fs::create_dir(&out_dir)?;
 
         // write the proto files to the temp dir.
         for (name, content) in &self.in_files {
-            let path = proto_dir.path().join(&name);
-            ::std::fs::write(&path, &content)?;
+            let path = proto_dir.join(name);
+            if let Some(parent) = path.parent() {
+                ::std::fs::create_dir_all(parent)?;
+            }
+            ::std::fs::write(&path, content)?;
+        }
+        #[cfg(feature = "well-known-types")]
+        if self.well_known_types {
+            for (name, content) in well_known_types::WELL_KNOWN_TYPES {
+                if names.contains(name) {
+                    continue;
+                }
+                let path = proto_dir.join(name);
+                ::std::fs::create_dir_all(path.parent().expect("the name has a directory"))?;
+                ::std::fs::write(&path, content)?;
+            }
         }
 
-        // run the protoc
-        let proto_file_paths = self
-            .in_files
-            .iter()
-            .map(|(name, _)| {
+        let proto_file_names = match &self.files_to_generate {
+            Some(names) => names.iter().map(String::as_str).collect::<Vec<_>>(),
+            None => self
+                .in_files
+                .iter()
+                .map(|(name, _)| name.as_str())
+                .collect(),
+        };
+        let proto_file_paths = proto_file_names
+            .into_iter()
+            .map(|name| {
                 proto_dir
-                    .path()
                     .join(name)
                     .to_str()
                     .ok_or(ErrorKind::FileNameError)
@@ -295,27 +4510,18 @@ impl ProtocOnMemory {
             })
             .collect::<Result<Vec<_>>>()?;
 
-        self.protoc
-            .out_dir(&out_dir_path)
-            .proto_path(proto_dir.path().to_str().ok_or(ErrorKind::FileNameError)?)
-            .proto_files(proto_file_paths)
-            .run(timeout, func)?;
-
-        // read the generated files
-        let output_files = ::std::fs::read_dir(out_dir.path())?
-            .map(|entry| -> Result<_> {
-                let path = entry?.path();
-                let name = path
-                    .strip_prefix(out_dir.path())
-                    .map_err(|_| ErrorKind::FileNameError)?
-                    .to_str()
-                    .ok_or(ErrorKind::FileNameError)?
-                    .to_string();
-                let content = ::std::fs::read_to_string(&path)?;
-                Ok((name, content))
-            })
-            .collect::<Result<Vec<_>>>()?;
+        let protoc = self
+            .protoc
+            .out_dir(out_dir.to_str().ok_or(ErrorKind::FileNameError)?)
+            .proto_path(proto_dir.to_str().ok_or(ErrorKind::FileNameError)?)
+            .proto_files(proto_file_paths);
+        Ok((temp_dir, protoc))
+    }
+}
 
-        Ok(output_files)
+#[cfg(feature = "on-memory")]
+impl Default for ProtocOnMemory {
+    fn default() -> Self {
+        Self::new()
     }
 }
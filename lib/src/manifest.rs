@@ -0,0 +1,103 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The manifest of the files generated by a run, e.g. for verifying or vendoring them. Enabled
+//! by the `serde` feature.
+
+use crate::plugin_proto::CodeGeneratorResponse;
+use crate::{FileKind, Result, apply_own_insertions, write_atomically};
+use ::serde::{Deserialize, Serialize};
+use ::sha2::{Digest, Sha256};
+use ::std::io::Write;
+use ::std::path::Path;
+
+/// The record of the file entries which a `protoc` run generated, written as JSON by
+/// [`Protoc::manifest_out`](crate::Protoc::manifest_out) and returned by
+/// [`RunOutput::manifest`](crate::RunOutput::manifest).
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    /// The version of this crate.
+    pub crate_version: String,
+    /// The `protoc` version in the `CodeGeneratorRequest`, e.g. `6.32.0` or `6.33.0-rc1`, if
+    /// `protoc` sent it.
+    pub protoc_version: Option<String>,
+    /// The file entries of the closure's `CodeGeneratorResponse`, in the order which `protoc`
+    /// applied them, i.e. with the insertion point entries after the files they insert into.
+    pub files: Vec<ManifestFile>,
+}
+
+/// A file entry of the [`Manifest`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestFile {
+    /// The file name relative to the output directory, as in the `CodeGeneratorResponse`.
+    pub name: String,
+    /// Whether the entry created a new file or inserted into one.
+    pub kind: FileKind,
+    /// The size in bytes of the file as written, i.e. after the insertions into it, or of the
+    /// inserted text for [`FileKind::Insertion`].
+    pub size: u64,
+    /// The SHA-256 hash of the same content as [`Self::size`], in lowercase hex.
+    pub sha256: String,
+}
+
+impl Manifest {
+    /// Creates the manifest of the given `CodeGeneratorResponse` bytes. The hashes are computed
+    /// from the response rather than the written files, so they don't race with the other
+    /// writers of the output directory.
+    pub(crate) fn new(response_bytes: &[u8], protoc_version: Option<String>) -> Result<Self> {
+        let files = CodeGeneratorResponse::from_bytes(response_bytes)?.files;
+        // The insertions into the other plugins' files are left to protoc, and not reflected.
+        let written = apply_own_insertions(files.clone())?;
+        let files = files
+            .into_iter()
+            .map(|file| {
+                let content = match &file.insertion_point {
+                    Some(_) => &file.content,
+                    None => written
+                        .iter()
+                        .find(|written| written.name == file.name)
+                        .map_or(&file.content, |written| &written.content),
+                };
+                let sha256 = Sha256::digest(content)
+                    .iter()
+                    .map(|byte| format!("{byte:02x}"))
+                    .collect();
+                ManifestFile {
+                    size: content.len() as u64,
+                    sha256,
+                    kind: match file.insertion_point {
+                        Some(point) => FileKind::Insertion {
+                            point,
+                            target: file.name.clone(),
+                        },
+                        None => FileKind::New,
+                    },
+                    name: file.name,
+                }
+            })
+            .collect();
+        Ok(Self {
+            crate_version: env!("CARGO_PKG_VERSION").to_string(),
+            protoc_version,
+            files,
+        })
+    }
+
+    /// Writes the manifest as pretty-printed JSON, atomically.
+    pub(crate) fn write(&self, path: &Path) -> Result<()> {
+        let mut json = ::serde_json::to_vec_pretty(self)?;
+        json.push(b'\n');
+        write_atomically(path, |file| Ok(file.write_all(&json)?))
+    }
+}
@@ -0,0 +1,931 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal implementation of the google.protobuf.compiler plugin messages, for when you don't
+//! want to depend on a full protobuf library.
+//!
+//! See: google/protobuf/compiler/plugin.proto in the Google Protobuf repository.
+//!
+//! The messages are parsed and serialized with protobuf-core. Only the fields listed in each
+//! struct's documentation are implemented, and the other fields (including the deprecated
+//! groups) are skipped on parsing.
+//!
+//! # Example
+//! ```no_run
+//! # use protoc_plugin_by_closure::Protoc;
+//! # use protoc_plugin_by_closure::plugin_proto::{
+//! #     CodeGeneratorRequest, CodeGeneratorResponse, File,
+//! # };
+//! # use std::time::Duration;
+//! Protoc::new()
+//!     .proto_file("my_protobuf_file.proto")
+//!     .run(Duration::from_secs(3), |request_bytes| {
+//!         let request = CodeGeneratorRequest::from_bytes(request_bytes).map_err(|e| e.to_string())?;
+//!         let response = CodeGeneratorResponse {
+//!             files: vec![File {
+//!                 name: "files.txt".to_string(),
+//!                 content: request.file_to_generate.join("\n").into_bytes(),
+//!                 ..Default::default()
+//!             }],
+//!             ..Default::default()
+//!         };
+//!         response.to_bytes().map_err(|e| e.to_string())
+//!     })
+//!     .unwrap();
+//! ```
+
+use crate::{ErrorKind, Result};
+use ::protobuf_core::{
+    Field, FieldNumber, FieldValue, ProtobufError, ReadExtProtobuf, ReadExtTag, ReadExtVarint,
+    Varint, WireType, WriteExtProtobuf, WriteExtVarint,
+};
+
+/// The `CodeGeneratorResponse.Feature.FEATURE_PROTO3_OPTIONAL` bit of
+/// [`CodeGeneratorResponse::supported_features`].
+pub const FEATURE_PROTO3_OPTIONAL: u64 = 1;
+/// The `CodeGeneratorResponse.Feature.FEATURE_SUPPORTS_EDITIONS` bit of
+/// [`CodeGeneratorResponse::supported_features`].
+pub const FEATURE_SUPPORTS_EDITIONS: u64 = 2;
+
+// Field numbers from google/protobuf/compiler/plugin.proto
+const CODE_GENERATOR_REQUEST_FILE_TO_GENERATE_FIELD_NUMBER: u32 = 1;
+const CODE_GENERATOR_REQUEST_PARAMETER_FIELD_NUMBER: u32 = 2;
+const CODE_GENERATOR_REQUEST_COMPILER_VERSION_FIELD_NUMBER: u32 = 3;
+const CODE_GENERATOR_REQUEST_PROTO_FILE_FIELD_NUMBER: u32 = 15;
+const VERSION_MAJOR_FIELD_NUMBER: u32 = 1;
+const VERSION_MINOR_FIELD_NUMBER: u32 = 2;
+const VERSION_PATCH_FIELD_NUMBER: u32 = 3;
+const VERSION_SUFFIX_FIELD_NUMBER: u32 = 4;
+const CODE_GENERATOR_RESPONSE_ERROR_FIELD_NUMBER: u32 = 1;
+const CODE_GENERATOR_RESPONSE_SUPPORTED_FEATURES_FIELD_NUMBER: u32 = 2;
+const CODE_GENERATOR_RESPONSE_MINIMUM_EDITION_FIELD_NUMBER: u32 = 3;
+const CODE_GENERATOR_RESPONSE_MAXIMUM_EDITION_FIELD_NUMBER: u32 = 4;
+const CODE_GENERATOR_RESPONSE_FILE_FIELD_NUMBER: u32 = 15;
+const FILE_NAME_FIELD_NUMBER: u32 = 1;
+const FILE_INSERTION_POINT_FIELD_NUMBER: u32 = 2;
+const FILE_CONTENT_FIELD_NUMBER: u32 = 15;
+const FILE_GENERATED_CODE_INFO_FIELD_NUMBER: u32 = 16;
+// Field numbers from google/protobuf/descriptor.proto
+const FILE_DESCRIPTOR_PROTO_NAME_FIELD_NUMBER: u32 = 1;
+const FILE_DESCRIPTOR_PROTO_PACKAGE_FIELD_NUMBER: u32 = 2;
+const FILE_DESCRIPTOR_PROTO_DEPENDENCY_FIELD_NUMBER: u32 = 3;
+const FILE_DESCRIPTOR_PROTO_MESSAGE_TYPE_FIELD_NUMBER: u32 = 4;
+const FILE_DESCRIPTOR_PROTO_ENUM_TYPE_FIELD_NUMBER: u32 = 5;
+const FILE_DESCRIPTOR_PROTO_SYNTAX_FIELD_NUMBER: u32 = 12;
+const FILE_DESCRIPTOR_PROTO_EDITION_FIELD_NUMBER: u32 = 14;
+const DESCRIPTOR_PROTO_NAME_FIELD_NUMBER: u32 = 1;
+const ENUM_DESCRIPTOR_PROTO_NAME_FIELD_NUMBER: u32 = 1;
+const GENERATED_CODE_INFO_ANNOTATION_FIELD_NUMBER: u32 = 1;
+const ANNOTATION_PATH_FIELD_NUMBER: u32 = 1;
+const ANNOTATION_SOURCE_FILE_FIELD_NUMBER: u32 = 2;
+const ANNOTATION_BEGIN_FIELD_NUMBER: u32 = 3;
+const ANNOTATION_END_FIELD_NUMBER: u32 = 4;
+const ANNOTATION_SEMANTIC_FIELD_NUMBER: u32 = 5;
+
+/// Minimal implementation of google.protobuf.compiler.CodeGeneratorRequest
+///
+/// This only implements:
+/// - file_to_generate (field 1): The names of the files to generate
+/// - parameter (field 2): The plugin parameter
+/// - compiler_version (field 3): The version of `protoc`
+/// - proto_file (field 15): The FileDescriptorProto messages, left encoded
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CodeGeneratorRequest {
+    /// repeated string file_to_generate = 1;
+    pub file_to_generate: Vec<String>,
+    /// optional string parameter = 2;
+    pub parameter: Option<String>,
+    /// optional Version compiler_version = 3;
+    pub compiler_version: Option<Version>,
+    /// repeated FileDescriptorProto proto_file = 15;
+    ///
+    /// Each entry is an encoded FileDescriptorProto, to be parsed by the descriptor crate of
+    /// your choice. See [`FileDescriptorLite`] for the basic information of the files.
+    pub proto_file: Vec<Vec<u8>>,
+}
+
+/// Minimal implementation of google.protobuf.compiler.Version
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Version {
+    /// optional int32 major = 1;
+    pub major: Option<i32>,
+    /// optional int32 minor = 2;
+    pub minor: Option<i32>,
+    /// optional int32 patch = 3;
+    pub patch: Option<i32>,
+    /// optional string suffix = 4;
+    pub suffix: Option<String>,
+}
+
+/// Minimal implementation of google.protobuf.compiler.CodeGeneratorResponse
+///
+/// This only implements:
+/// - error (field 1): The error message
+/// - supported_features (field 2): The bitset of the `Feature` enum values
+/// - minimum_edition / maximum_edition (fields 3 and 4): The range of the supported editions,
+///   as the raw `Edition` enum values
+/// - file (field 15): The generated files
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CodeGeneratorResponse {
+    /// optional string error = 1;
+    pub error: Option<String>,
+    /// optional uint64 supported_features = 2;
+    pub supported_features: Option<u64>,
+    /// optional int32 minimum_edition = 3;
+    pub minimum_edition: Option<i32>,
+    /// optional int32 maximum_edition = 4;
+    pub maximum_edition: Option<i32>,
+    /// repeated File file = 15;
+    pub files: Vec<File>,
+}
+
+/// Minimal implementation of google.protobuf.compiler.CodeGeneratorResponse.File
+///
+/// This only implements:
+/// - name (field 1): The output file name
+/// - insertion_point (field 2): The insertion point name
+/// - content (field 15): The file content
+/// - generated_code_info (field 16): The annotations of the content
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct File {
+    /// optional string name = 1;
+    pub name: String,
+    /// optional string insertion_point = 2;
+    ///
+    /// An empty insertion point is the same as no insertion point, and is parsed as `None`.
+    pub insertion_point: Option<String>,
+    /// optional string content = 15;
+    pub content: Vec<u8>,
+    /// optional GeneratedCodeInfo generated_code_info = 16;
+    pub generated_code_info: Option<GeneratedCodeInfo>,
+}
+
+/// Minimal implementation of google.protobuf.GeneratedCodeInfo
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GeneratedCodeInfo {
+    /// repeated Annotation annotation = 1;
+    pub annotation: Vec<Annotation>,
+}
+
+/// Minimal implementation of google.protobuf.GeneratedCodeInfo.Annotation
+///
+/// Maps a span of the generated content to the element of the source proto file.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Annotation {
+    /// repeated int32 path = 1 [packed = true];
+    ///
+    /// Both the packed and the non-packed encodings are accepted on parsing.
+    pub path: Vec<i32>,
+    /// optional string source_file = 2;
+    pub source_file: Option<String>,
+    /// optional int32 begin = 3;
+    pub begin: Option<i32>,
+    /// optional int32 end = 4;
+    pub end: Option<i32>,
+    /// optional Semantic semantic = 5;
+    ///
+    /// The raw `Semantic` enum value: `0` for `NONE`, `1` for `SET` and `2` for `ALIAS`.
+    pub semantic: Option<i32>,
+}
+
+/// Shallow, read-only view of google.protobuf.FileDescriptorProto
+///
+/// This only implements:
+/// - name (field 1): The file name, relative to the proto path
+/// - package (field 2): The package name
+/// - dependency (field 3): The names of the imported files
+/// - message_type (field 4): Only the names of the top-level messages
+/// - enum_type (field 5): Only the names of the top-level enums
+/// - syntax (field 12): The syntax, e.g. `"proto3"`
+/// - edition (field 14): The edition, as the raw `Edition` enum value
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct FileDescriptorLite {
+    /// optional string name = 1; or an empty string if it's not set.
+    pub name: String,
+    /// optional string package = 2; or an empty string if it's not set.
+    pub package: String,
+    /// repeated string dependency = 3;
+    pub dependency: Vec<String>,
+    /// The `name` of each `repeated DescriptorProto message_type = 4;`
+    pub message_type_names: Vec<String>,
+    /// The `name` of each `repeated EnumDescriptorProto enum_type = 5;`
+    pub enum_type_names: Vec<String>,
+    /// optional string syntax = 12;
+    ///
+    /// `protoc` leaves this unset for proto2, and sets it to `"editions"` for the editions.
+    pub syntax: Option<String>,
+    /// optional Edition edition = 14;
+    pub edition: Option<i32>,
+}
+
+impl CodeGeneratorRequest {
+    /// Parse a CodeGeneratorRequest from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut request = Self::default();
+
+        for field_result in read_fields_with_offsets(bytes) {
+            let (offset, field) = field_result?;
+            let FieldValue::Len(value) = field.value else {
+                continue;
+            };
+            match field.field_number.as_u32() {
+                CODE_GENERATOR_REQUEST_FILE_TO_GENERATE_FIELD_NUMBER => {
+                    request.file_to_generate.push(string_value(value));
+                }
+                CODE_GENERATOR_REQUEST_PARAMETER_FIELD_NUMBER => {
+                    request.parameter = Some(string_value(value));
+                }
+                CODE_GENERATOR_REQUEST_COMPILER_VERSION_FIELD_NUMBER => {
+                    request.compiler_version = Some(nested(Version::from_bytes(&value), offset)?);
+                }
+                CODE_GENERATOR_REQUEST_PROTO_FILE_FIELD_NUMBER => {
+                    request.proto_file.push(value);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(request)
+    }
+
+    /// Serialize the request to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for name in &self.file_to_generate {
+            bytes.write_protobuf_field(&len_field(
+                CODE_GENERATOR_REQUEST_FILE_TO_GENERATE_FIELD_NUMBER,
+                name.as_bytes().to_vec(),
+            ))?;
+        }
+        if let Some(parameter) = &self.parameter {
+            bytes.write_protobuf_field(&len_field(
+                CODE_GENERATOR_REQUEST_PARAMETER_FIELD_NUMBER,
+                parameter.as_bytes().to_vec(),
+            ))?;
+        }
+        if let Some(compiler_version) = &self.compiler_version {
+            bytes.write_protobuf_field(&len_field(
+                CODE_GENERATOR_REQUEST_COMPILER_VERSION_FIELD_NUMBER,
+                compiler_version.to_bytes()?,
+            ))?;
+        }
+        for proto_file in &self.proto_file {
+            bytes.write_protobuf_field(&len_field(
+                CODE_GENERATOR_REQUEST_PROTO_FILE_FIELD_NUMBER,
+                proto_file.clone(),
+            ))?;
+        }
+        Ok(bytes)
+    }
+
+    /// Returns the given encoded request without the `parameter` field, keeping the bytes of
+    /// the other fields as they are, including the ones which [`Self::from_bytes`] drops.
+    #[cfg(feature = "on-memory")]
+    pub(crate) fn strip_parameter(bytes: &[u8]) -> Result<Vec<u8>> {
+        let mut stripped = Vec::with_capacity(bytes.len());
+        let mut rest = bytes;
+        loop {
+            let start = bytes.len() - rest.len();
+            let Some(field) = read_field(&mut rest)? else {
+                return Ok(stripped);
+            };
+            if field.field_number.as_u32() != CODE_GENERATOR_REQUEST_PARAMETER_FIELD_NUMBER {
+                stripped.extend_from_slice(&bytes[start..bytes.len() - rest.len()]);
+            }
+        }
+    }
+
+    /// Returns the `name` of each `proto_file` entry, or an empty string if it's not set.
+    pub fn proto_file_names(&self) -> Result<Vec<String>> {
+        Ok(self
+            .proto_file_descriptors()?
+            .into_iter()
+            .map(|file| file.name)
+            .collect())
+    }
+
+    /// Parses each `proto_file` entry shallowly. See [`FileDescriptorLite`].
+    pub fn proto_file_descriptors(&self) -> Result<Vec<FileDescriptorLite>> {
+        self.proto_file
+            .iter()
+            .map(|proto_file| FileDescriptorLite::from_bytes(proto_file))
+            .collect()
+    }
+}
+
+impl Version {
+    /// Parse a Version from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut version = Self::default();
+
+        for field_result in read_fields(bytes) {
+            let field = field_result?;
+            match (field.field_number.as_u32(), field.value) {
+                (VERSION_MAJOR_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    version.major = Some(value.try_to_int32()?);
+                }
+                (VERSION_MINOR_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    version.minor = Some(value.try_to_int32()?);
+                }
+                (VERSION_PATCH_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    version.patch = Some(value.try_to_int32()?);
+                }
+                (VERSION_SUFFIX_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    version.suffix = Some(string_value(value));
+                }
+                _ => (),
+            }
+        }
+
+        Ok(version)
+    }
+
+    /// Serialize the version to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for (field_number, value) in [
+            (VERSION_MAJOR_FIELD_NUMBER, self.major),
+            (VERSION_MINOR_FIELD_NUMBER, self.minor),
+            (VERSION_PATCH_FIELD_NUMBER, self.patch),
+        ] {
+            if let Some(value) = value {
+                bytes.write_protobuf_field(&Field::new(
+                    field_number_of(field_number),
+                    FieldValue::from_int32(value),
+                ))?;
+            }
+        }
+        if let Some(suffix) = &self.suffix {
+            bytes.write_protobuf_field(&len_field(
+                VERSION_SUFFIX_FIELD_NUMBER,
+                suffix.as_bytes().to_vec(),
+            ))?;
+        }
+        Ok(bytes)
+    }
+}
+
+impl FileDescriptorLite {
+    /// Parse a FileDescriptorProto from bytes, skipping the unimplemented fields
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut file = Self::default();
+
+        for field_result in read_fields_with_offsets(bytes) {
+            let (offset, field) = field_result?;
+            match (field.field_number.as_u32(), field.value) {
+                (FILE_DESCRIPTOR_PROTO_NAME_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    file.name = string_value(value);
+                }
+                (FILE_DESCRIPTOR_PROTO_PACKAGE_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    file.package = string_value(value);
+                }
+                (FILE_DESCRIPTOR_PROTO_DEPENDENCY_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    file.dependency.push(string_value(value));
+                }
+                (FILE_DESCRIPTOR_PROTO_MESSAGE_TYPE_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    file.message_type_names.push(nested(
+                        name_field(&value, DESCRIPTOR_PROTO_NAME_FIELD_NUMBER),
+                        offset,
+                    )?);
+                }
+                (FILE_DESCRIPTOR_PROTO_ENUM_TYPE_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    file.enum_type_names.push(nested(
+                        name_field(&value, ENUM_DESCRIPTOR_PROTO_NAME_FIELD_NUMBER),
+                        offset,
+                    )?);
+                }
+                (FILE_DESCRIPTOR_PROTO_SYNTAX_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    file.syntax = Some(string_value(value));
+                }
+                (FILE_DESCRIPTOR_PROTO_EDITION_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    file.edition = Some(value.try_to_int32()?);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(file)
+    }
+}
+
+/// Returns the string field of the given field number in the given encoded message,
+/// or an empty string if it's not set.
+fn name_field(bytes: &[u8], field_number: u32) -> Result<String> {
+    let mut name = String::new();
+    for field_result in read_fields(bytes) {
+        let field = field_result?;
+        if field.field_number.as_u32() == field_number
+            && let FieldValue::Len(value) = field.value
+        {
+            name = string_value(value);
+        }
+    }
+    Ok(name)
+}
+
+/// A builder of [`CodeGeneratorResponse`].
+///
+/// # Example
+/// ```
+/// # use protoc_plugin_by_closure::plugin_proto::ResponseBuilder;
+/// let response_bytes = ResponseBuilder::new()
+///     .file("foo.rs", "// @@protoc_insertion_point(imports)\n")
+///     .file_with_insertion("foo.rs", "imports", "use std::fmt;\n")
+///     .support_proto3_optional()
+///     .build_bytes();
+/// # assert!(!response_bytes.is_empty());
+/// ```
+#[derive(Debug, Default, Clone)]
+pub struct ResponseBuilder {
+    response: CodeGeneratorResponse,
+}
+
+impl ResponseBuilder {
+    /// Creates a builder of an empty response.
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Adds a generated file.
+    pub fn file(mut self, name: impl Into<String>, content: impl Into<Vec<u8>>) -> Self {
+        self.response.files.push(File {
+            name: name.into(),
+            content: content.into(),
+            ..Default::default()
+        });
+        self
+    }
+    /// Adds an insertion into the file at the `@@protoc_insertion_point(insertion_point)` marker.
+    pub fn file_with_insertion(
+        mut self,
+        name: impl Into<String>,
+        insertion_point: impl Into<String>,
+        content: impl Into<Vec<u8>>,
+    ) -> Self {
+        self.response.files.push(File {
+            name: name.into(),
+            insertion_point: Some(insertion_point.into()),
+            content: content.into(),
+            ..Default::default()
+        });
+        self
+    }
+    /// Sets the error message, which makes `protoc` fail with it.
+    ///
+    /// `protoc` ignores the files if the error is set, so setting both is allowed but the files
+    /// are dropped by [`Self::build`].
+    pub fn error(mut self, error: impl Into<String>) -> Self {
+        self.response.error = Some(error.into());
+        self
+    }
+    /// Declares the support of the `optional` fields in proto3, without which `protoc` rejects
+    /// such proto files. Sets [`FEATURE_PROTO3_OPTIONAL`] in the `supported_features`.
+    pub fn support_proto3_optional(mut self) -> Self {
+        let features = self.response.supported_features.get_or_insert(0);
+        *features |= FEATURE_PROTO3_OPTIONAL;
+        self
+    }
+    /// Adds the given bits, e.g. [`FEATURE_PROTO3_OPTIONAL`], to the `supported_features`.
+    ///
+    /// The closure can decide them per request, e.g. after inspecting the proto files.
+    pub fn supported_features(mut self, features: u64) -> Self {
+        let supported = self.response.supported_features.get_or_insert(0);
+        *supported |= features;
+        self
+    }
+    /// Declares the support of the editions from `minimum_edition` to `maximum_edition`, which
+    /// are the raw `google.protobuf.Edition` enum values, e.g. `1000` for `EDITION_2023`.
+    /// Sets [`FEATURE_SUPPORTS_EDITIONS`] in the `supported_features`.
+    ///
+    /// `protoc` rejects the editions files without this, or outside of the range.
+    pub fn support_editions(mut self, minimum_edition: i32, maximum_edition: i32) -> Self {
+        self.response.minimum_edition = Some(minimum_edition);
+        self.response.maximum_edition = Some(maximum_edition);
+        self.supported_features(FEATURE_SUPPORTS_EDITIONS)
+    }
+    /// Returns the built response.
+    pub fn build(mut self) -> CodeGeneratorResponse {
+        if self.response.error.is_some() {
+            self.response.files.clear();
+        }
+        self.response
+    }
+    /// Returns the encoded bytes of the built response, ready to be returned from the closure.
+    pub fn build_bytes(self) -> Vec<u8> {
+        self.build()
+            .to_bytes()
+            .expect("serializing into a Vec never fails")
+    }
+}
+
+impl CodeGeneratorResponse {
+    /// Parse a CodeGeneratorResponse from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut response = Self::default();
+
+        for field_result in read_fields_with_offsets(bytes) {
+            let (offset, field) = field_result?;
+            match (field.field_number.as_u32(), field.value) {
+                (CODE_GENERATOR_RESPONSE_ERROR_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    response.error = Some(string_value(value));
+                }
+                (
+                    CODE_GENERATOR_RESPONSE_SUPPORTED_FEATURES_FIELD_NUMBER,
+                    FieldValue::Varint(value),
+                ) => {
+                    response.supported_features = Some(value.to_uint64());
+                }
+                (
+                    CODE_GENERATOR_RESPONSE_MINIMUM_EDITION_FIELD_NUMBER,
+                    FieldValue::Varint(value),
+                ) => {
+                    response.minimum_edition = Some(value.try_to_int32()?);
+                }
+                (
+                    CODE_GENERATOR_RESPONSE_MAXIMUM_EDITION_FIELD_NUMBER,
+                    FieldValue::Varint(value),
+                ) => {
+                    response.maximum_edition = Some(value.try_to_int32()?);
+                }
+                (CODE_GENERATOR_RESPONSE_FILE_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    let index = response.files.len();
+                    response
+                        .files
+                        .push(nested(File::from_bytes_at(&value, index), offset)?);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(response)
+    }
+
+    /// Serialize the response to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        if let Some(error) = &self.error {
+            bytes.write_protobuf_field(&len_field(
+                CODE_GENERATOR_RESPONSE_ERROR_FIELD_NUMBER,
+                error.as_bytes().to_vec(),
+            ))?;
+        }
+        if let Some(supported_features) = self.supported_features {
+            bytes.write_protobuf_field(&Field::new(
+                field_number_of(CODE_GENERATOR_RESPONSE_SUPPORTED_FEATURES_FIELD_NUMBER),
+                FieldValue::from_uint64(supported_features),
+            ))?;
+        }
+        for (field_number, value) in [
+            (
+                CODE_GENERATOR_RESPONSE_MINIMUM_EDITION_FIELD_NUMBER,
+                self.minimum_edition,
+            ),
+            (
+                CODE_GENERATOR_RESPONSE_MAXIMUM_EDITION_FIELD_NUMBER,
+                self.maximum_edition,
+            ),
+        ] {
+            if let Some(value) = value {
+                bytes.write_protobuf_field(&Field::new(
+                    field_number_of(field_number),
+                    FieldValue::from_int32(value),
+                ))?;
+            }
+        }
+        for file in &self.files {
+            bytes.write_protobuf_field(&len_field(
+                CODE_GENERATOR_RESPONSE_FILE_FIELD_NUMBER,
+                file.to_bytes()?,
+            ))?;
+        }
+        Ok(bytes)
+    }
+}
+
+impl File {
+    /// Parse a CodeGeneratorResponse.File from bytes
+    ///
+    /// Returns [`ErrorKind::InvalidFileName`] with the index `0` if the name is not valid UTF-8.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        Self::from_bytes_at(bytes, 0)
+    }
+
+    /// Same as [`Self::from_bytes`], but reports the given index of the entry in the
+    /// `CodeGeneratorResponse` on the invalid name.
+    fn from_bytes_at(bytes: &[u8], index: usize) -> Result<Self> {
+        let mut file = Self::default();
+
+        for field_result in read_fields_with_offsets(bytes) {
+            let (offset, field) = field_result?;
+            let FieldValue::Len(value) = field.value else {
+                continue;
+            };
+            match field.field_number.as_u32() {
+                FILE_NAME_FIELD_NUMBER => {
+                    file.name =
+                        String::from_utf8(value).map_err(|e| ErrorKind::InvalidFileName {
+                            index,
+                            bytes: e.into_bytes(),
+                        })?;
+                }
+                FILE_INSERTION_POINT_FIELD_NUMBER if !value.is_empty() => {
+                    file.insertion_point = Some(string_value(value));
+                }
+                FILE_CONTENT_FIELD_NUMBER => {
+                    file.content = value;
+                }
+                FILE_GENERATED_CODE_INFO_FIELD_NUMBER => {
+                    file.generated_code_info =
+                        Some(nested(GeneratedCodeInfo::from_bytes(&value), offset)?);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(file)
+    }
+
+    /// Serialize the CodeGeneratorResponse.File to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        bytes.write_protobuf_field(&len_field(
+            FILE_NAME_FIELD_NUMBER,
+            self.name.as_bytes().to_vec(),
+        ))?;
+        if let Some(insertion_point) = self.insertion_point.as_ref().filter(|p| !p.is_empty()) {
+            bytes.write_protobuf_field(&len_field(
+                FILE_INSERTION_POINT_FIELD_NUMBER,
+                insertion_point.as_bytes().to_vec(),
+            ))?;
+        }
+        bytes.write_protobuf_field(&len_field(FILE_CONTENT_FIELD_NUMBER, self.content.clone()))?;
+        if let Some(generated_code_info) = &self.generated_code_info {
+            bytes.write_protobuf_field(&len_field(
+                FILE_GENERATED_CODE_INFO_FIELD_NUMBER,
+                generated_code_info.to_bytes()?,
+            ))?;
+        }
+        Ok(bytes)
+    }
+}
+
+impl GeneratedCodeInfo {
+    /// Parse a GeneratedCodeInfo from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut info = Self::default();
+
+        for field_result in read_fields_with_offsets(bytes) {
+            let (offset, field) = field_result?;
+            if field.field_number.as_u32() == GENERATED_CODE_INFO_ANNOTATION_FIELD_NUMBER
+                && let FieldValue::Len(value) = field.value
+            {
+                info.annotation
+                    .push(nested(Annotation::from_bytes(&value), offset)?);
+            }
+        }
+
+        Ok(info)
+    }
+
+    /// Serialize the GeneratedCodeInfo to bytes
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for annotation in &self.annotation {
+            bytes.write_protobuf_field(&len_field(
+                GENERATED_CODE_INFO_ANNOTATION_FIELD_NUMBER,
+                annotation.to_bytes()?,
+            ))?;
+        }
+        Ok(bytes)
+    }
+}
+
+impl Annotation {
+    /// Parse a GeneratedCodeInfo.Annotation from bytes
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        let mut annotation = Self::default();
+
+        for field_result in read_fields(bytes) {
+            let field = field_result?;
+            match (field.field_number.as_u32(), field.value) {
+                (ANNOTATION_PATH_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    annotation.path.push(value.try_to_int32()?);
+                }
+                (ANNOTATION_PATH_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    let mut packed = value.as_slice();
+                    while let Some(value) = packed.read_varint()? {
+                        annotation.path.push(value.try_to_int32()?);
+                    }
+                }
+                (ANNOTATION_SOURCE_FILE_FIELD_NUMBER, FieldValue::Len(value)) => {
+                    annotation.source_file = Some(string_value(value));
+                }
+                (ANNOTATION_BEGIN_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    annotation.begin = Some(value.try_to_int32()?);
+                }
+                (ANNOTATION_END_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    annotation.end = Some(value.try_to_int32()?);
+                }
+                (ANNOTATION_SEMANTIC_FIELD_NUMBER, FieldValue::Varint(value)) => {
+                    annotation.semantic = Some(value.try_to_int32()?);
+                }
+                _ => (),
+            }
+        }
+
+        Ok(annotation)
+    }
+
+    /// Serialize the GeneratedCodeInfo.Annotation to bytes, with the packed `path`
+    pub fn to_bytes(&self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        if !self.path.is_empty() {
+            let mut packed = Vec::new();
+            for &value in &self.path {
+                packed.write_varint(&Varint::from_int32(value))?;
+            }
+            bytes.write_protobuf_field(&len_field(ANNOTATION_PATH_FIELD_NUMBER, packed))?;
+        }
+        if let Some(source_file) = &self.source_file {
+            bytes.write_protobuf_field(&len_field(
+                ANNOTATION_SOURCE_FILE_FIELD_NUMBER,
+                source_file.as_bytes().to_vec(),
+            ))?;
+        }
+        for (field_number, value) in [
+            (ANNOTATION_BEGIN_FIELD_NUMBER, self.begin),
+            (ANNOTATION_END_FIELD_NUMBER, self.end),
+            (ANNOTATION_SEMANTIC_FIELD_NUMBER, self.semantic),
+        ] {
+            if let Some(value) = value {
+                bytes.write_protobuf_field(&Field::new(
+                    field_number_of(field_number),
+                    FieldValue::from_int32(value),
+                ))?;
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+/// Moves the insertion point `file` entries in the given encoded CodeGeneratorResponse after
+/// the other `file` entries, keeping the other fields and the relative orders as they are.
+/// Returns `None` if they are already in that order.
+pub(crate) fn move_insertions_last(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+    let mut fields = Vec::new();
+    let mut insertions = Vec::new();
+    let mut reordered = false;
+    for field_result in read_fields(bytes) {
+        let field = field_result?;
+        if field.field_number.as_u32() == CODE_GENERATOR_RESPONSE_FILE_FIELD_NUMBER
+            && let FieldValue::Len(value) = &field.value
+        {
+            if File::from_bytes(value)?.insertion_point.is_some() {
+                insertions.push(field);
+                continue;
+            }
+            reordered |= !insertions.is_empty();
+        }
+        fields.push(field);
+    }
+    if !reordered {
+        return Ok(None);
+    }
+    let mut reordered_bytes = Vec::with_capacity(bytes.len());
+    for field in fields.iter().chain(&insertions) {
+        reordered_bytes.write_protobuf_field(field)?;
+    }
+    Ok(Some(reordered_bytes))
+}
+
+/// Reads the fields of the given encoded message like `read_protobuf_fields`, but skips
+/// the group fields, which protobuf-core can't read.
+///
+/// The corrupt bytes are reported as [`ErrorKind::MalformedMessage`] with the offset.
+fn read_fields(bytes: &[u8]) -> impl Iterator<Item = Result<Field>> {
+    read_fields_with_offsets(bytes).map(|result| result.map(|(_, field)| field))
+}
+
+/// Same as [`read_fields`], but also returns the offset of each field's value in `bytes`, for
+/// passing to [`nested`].
+fn read_fields_with_offsets(bytes: &[u8]) -> impl Iterator<Item = Result<(usize, Field)>> {
+    let mut rest = bytes;
+    let mut last_field_number = None;
+    ::std::iter::from_fn(move || {
+        let offset = bytes.len() - rest.len();
+        match read_field(&mut rest) {
+            Ok(Some(field)) => {
+                last_field_number = Some(field.field_number.as_u32());
+                let end = bytes.len() - rest.len();
+                let value_offset = match &field.value {
+                    FieldValue::Len(value) => end - value.len(),
+                    _ => end,
+                };
+                Some(Ok((value_offset, field)))
+            }
+            Ok(None) => None,
+            Err(e) => {
+                rest = &[];
+                Some(Err(match e {
+                    ErrorKind::ProtobufError(source) => ErrorKind::MalformedMessage {
+                        offset,
+                        last_field_number,
+                        source,
+                    },
+                    e => e,
+                }))
+            }
+        }
+    })
+}
+
+/// Shifts the offset of the [`ErrorKind::MalformedMessage`] of a nested message by the offset
+/// of its bytes in the outer message.
+fn nested<T>(result: Result<T>, value_offset: usize) -> Result<T> {
+    result.map_err(|e| match e {
+        ErrorKind::MalformedMessage {
+            offset,
+            last_field_number,
+            source,
+        } => ErrorKind::MalformedMessage {
+            offset: value_offset + offset,
+            last_field_number,
+            source,
+        },
+        e => e,
+    })
+}
+
+/// Reads the next non-group field, or returns `None` at the end.
+fn read_field(bytes: &mut &[u8]) -> Result<Option<Field>> {
+    loop {
+        let mut rest = *bytes;
+        let Some(tag) = rest.read_tag()? else {
+            return Ok(None);
+        };
+        match tag.wire_type {
+            WireType::SGroup => {
+                *bytes = rest;
+                skip_group(bytes, tag.field_number)?;
+            }
+            WireType::EGroup => return Err(unexpected_end_group(tag.field_number).into()),
+            _ => return Ok(bytes.read_protobuf_field()?),
+        }
+    }
+}
+
+/// Skips the fields up to and including the end of the group with the given field number,
+/// whose start tag is already read.
+fn skip_group(bytes: &mut &[u8], field_number: FieldNumber) -> Result<()> {
+    loop {
+        let mut rest = *bytes;
+        let Some(tag) = rest.read_tag()? else {
+            return Err(ProtobufError::UnexpectedEof.into());
+        };
+        match tag.wire_type {
+            WireType::SGroup => {
+                *bytes = rest;
+                skip_group(bytes, tag.field_number)?;
+            }
+            WireType::EGroup if tag.field_number == field_number => {
+                *bytes = rest;
+                return Ok(());
+            }
+            WireType::EGroup => return Err(unexpected_end_group(tag.field_number).into()),
+            _ => {
+                bytes.read_protobuf_field()?;
+            }
+        }
+    }
+}
+
+fn unexpected_end_group(field_number: FieldNumber) -> ProtobufError {
+    ProtobufError::MalformedTag {
+        field_number: field_number.as_u32(),
+        wire_type: WireType::EGroup as u8,
+    }
+}
+
+/// Converts the bytes of a `string` field, replacing the invalid UTF-8 sequences.
+fn string_value(bytes: Vec<u8>) -> String {
+    String::from_utf8_lossy(&bytes).into_owned()
+}
+
+/// Returns a length-delimited field with the given field number and bytes.
+fn len_field(field_number: u32, bytes: Vec<u8>) -> Field {
+    Field::new(field_number_of(field_number), FieldValue::from_bytes(bytes))
+}
+
+fn field_number_of(field_number: u32) -> FieldNumber {
+    FieldNumber::try_from(field_number).expect("the field numbers are valid")
+}
@@ -0,0 +1,74 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `prost` integration, letting the closure work on the `prost_types` messages instead of
+//! the encoded bytes. Enabled by the `prost` feature.
+
+use crate::typed_plugin::{TypedRequest, TypedResponse};
+use crate::{ErrorKind, IntoResponseBytes, Protoc, Result};
+use ::prost::Message;
+use ::prost_types::compiler::{CodeGeneratorRequest, CodeGeneratorResponse};
+use ::std::time::Duration;
+
+impl Protoc {
+    /// Same as [`Protoc::run`], but the closure takes the decoded `CodeGeneratorRequest` and
+    /// returns the `CodeGeneratorResponse` of `prost_types`.
+    ///
+    /// The `parameter` field of the request, which carries the key to connect to the plugin
+    /// binary, is cleared before calling the closure, since user parameters are not passed
+    /// to the plugin. [`Protoc::supported_features`] are added to the response. Returns
+    /// [`ErrorKind::ProstDecodeError`] if the request can't be decoded.
+    pub fn run_prost<F>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(CodeGeneratorRequest) -> ::std::result::Result<CodeGeneratorResponse, String>,
+    {
+        self.run_decoded(timeout, body)
+    }
+}
+
+#[cfg(feature = "on-memory")]
+impl crate::ProtocOnMemory {
+    /// Same as [`crate::ProtocOnMemory::run`], but with the `prost_types` messages.
+    /// See [`Protoc::run_prost`].
+    pub fn run_prost<F>(self, timeout: Duration, body: F) -> Result<Vec<(String, String)>>
+    where
+        F: FnOnce(CodeGeneratorRequest) -> ::std::result::Result<CodeGeneratorResponse, String>,
+    {
+        self.run_decoded(timeout, body)
+    }
+}
+
+impl TypedRequest for CodeGeneratorRequest {
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        <Self as Message>::decode(bytes).map_err(|source| ErrorKind::ProstDecodeError {
+            message_name: "google.protobuf.compiler.CodeGeneratorRequest",
+            source,
+        })
+    }
+    fn clear_parameter(&mut self) {
+        self.parameter = None;
+    }
+}
+
+impl TypedResponse for CodeGeneratorResponse {
+    fn supported_features_mut(&mut self) -> &mut Option<u64> {
+        &mut self.supported_features
+    }
+}
+
+impl IntoResponseBytes for CodeGeneratorResponse {
+    fn into_response_bytes(self) -> Result<Vec<u8>> {
+        Ok(self.encode_to_vec())
+    }
+}
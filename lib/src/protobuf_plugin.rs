@@ -0,0 +1,77 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The `protobuf` crate integration, letting the closure work on the `protobuf::plugin`
+//! messages instead of the encoded bytes. Enabled by the `protobuf` feature.
+
+use crate::typed_plugin::{TypedRequest, TypedResponse};
+use crate::{ErrorKind, IntoResponseBytes, Protoc, Result};
+use ::protobuf::Message;
+use ::protobuf::plugin::{CodeGeneratorRequest, CodeGeneratorResponse};
+use ::std::time::Duration;
+
+impl Protoc {
+    /// Same as [`Protoc::run`], but the closure takes the parsed `CodeGeneratorRequest` and
+    /// returns the `CodeGeneratorResponse` of the `protobuf` crate.
+    ///
+    /// The request and the response are handled in the same way as [`Protoc::run_prost`] does.
+    /// Returns [`ErrorKind::ProtobufCodecError`] if the request can't be parsed or the response
+    /// can't be serialized.
+    pub fn run_protobuf<F>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(CodeGeneratorRequest) -> ::std::result::Result<CodeGeneratorResponse, String>,
+    {
+        self.run_decoded(timeout, body)
+    }
+}
+
+#[cfg(feature = "on-memory")]
+impl crate::ProtocOnMemory {
+    /// Same as [`crate::ProtocOnMemory::run`], but with the `protobuf` crate messages.
+    /// See [`Protoc::run_protobuf`].
+    pub fn run_protobuf<F>(self, timeout: Duration, body: F) -> Result<Vec<(String, String)>>
+    where
+        F: FnOnce(CodeGeneratorRequest) -> ::std::result::Result<CodeGeneratorResponse, String>,
+    {
+        self.run_decoded(timeout, body)
+    }
+}
+
+impl TypedRequest for CodeGeneratorRequest {
+    fn decode(bytes: &[u8]) -> Result<Self> {
+        Self::parse_from_bytes(bytes).map_err(|source| ErrorKind::ProtobufCodecError {
+            message_name: "google.protobuf.compiler.CodeGeneratorRequest",
+            source,
+        })
+    }
+    fn clear_parameter(&mut self) {
+        self.parameter = None;
+    }
+}
+
+impl TypedResponse for CodeGeneratorResponse {
+    fn supported_features_mut(&mut self) -> &mut Option<u64> {
+        &mut self.supported_features
+    }
+}
+
+impl IntoResponseBytes for CodeGeneratorResponse {
+    fn into_response_bytes(self) -> Result<Vec<u8>> {
+        self.write_to_bytes()
+            .map_err(|source| ErrorKind::ProtobufCodecError {
+                message_name: "google.protobuf.compiler.CodeGeneratorResponse",
+                source,
+            })
+    }
+}
@@ -0,0 +1,93 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lightweight accessors for the encoded `CodeGeneratorRequest` bytes passed to the closure,
+//! for when you don't need a full protobuf descriptor crate.
+
+use crate::Result;
+use crate::plugin_proto::{CodeGeneratorRequest, Version};
+
+/// Returns the names of the `proto_file` entries (i.e. `FileDescriptorProto.name`) in the given
+/// encoded `CodeGeneratorRequest`, in the request's order.
+///
+/// Note that `proto_file` contains all the files which `protoc` parsed, including the imported
+/// ones, in the topological order (i.e. the imported files come first).
+///
+/// # Example
+/// ```no_run
+/// # use protoc_plugin_by_closure::{Protoc, request};
+/// # use std::time::Duration;
+/// Protoc::new()
+///     .proto_file("my_protobuf_file.proto")
+///     .run(Duration::from_secs(3), |request_bytes| {
+///         let names = request::proto_file_names(request_bytes).map_err(|e| e.to_string())?;
+///         eprintln!("generating for {}", names.join(", "));
+/// #       unimplemented!()
+///     })
+///     .unwrap();
+/// ```
+pub fn proto_file_names(request_bytes: &[u8]) -> Result<Vec<String>> {
+    CodeGeneratorRequest::from_bytes(request_bytes)?.proto_file_names()
+}
+
+/// Splits a plugin `parameter` string into its comma separated `key=value` options, in order.
+///
+/// A segment without `=` is a flag and has no value, and only the first `=` separates the key
+/// from the value (so `c=` has an empty value). Empty segments are skipped.
+///
+/// # Example
+/// ```
+/// # use protoc_plugin_by_closure::request::parse_parameters;
+/// assert_eq!(
+///     parse_parameters("a=1,b,,c="),
+///     [
+///         ("a".to_string(), Some("1".to_string())),
+///         ("b".to_string(), None),
+///         ("c".to_string(), Some(String::new())),
+///     ]
+/// );
+/// ```
+pub fn parse_parameters(parameter: &str) -> Vec<(String, Option<String>)> {
+    parameter
+        .split(',')
+        .filter(|segment| !segment.is_empty())
+        .map(|segment| match segment.split_once('=') {
+            Some((key, value)) => (key.to_string(), Some(value.to_string())),
+            None => (segment.to_string(), None),
+        })
+        .collect()
+}
+
+/// Returns the `protoc` version in the given `CodeGeneratorRequest` bytes, e.g. `6.32.0` or
+/// `6.33.0-rc1`, if any.
+pub(crate) fn protoc_version(request_bytes: &[u8]) -> Option<String> {
+    let Version {
+        major,
+        minor,
+        patch,
+        suffix,
+    } = CodeGeneratorRequest::from_bytes(request_bytes)
+        .ok()?
+        .compiler_version?;
+    let version = format!(
+        "{}.{}.{}",
+        major.unwrap_or_default(),
+        minor.unwrap_or_default(),
+        patch.unwrap_or_default()
+    );
+    Some(match suffix {
+        Some(suffix) if !suffix.is_empty() => format!("{version}-{suffix}"),
+        _ => version,
+    })
+}
@@ -0,0 +1,101 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The [`IntoResponseBytes`] trait, letting the closure return the `CodeGeneratorResponse` in
+//! whichever representation is natural for it.
+
+use crate::plugin_proto::{CodeGeneratorResponse, ResponseBuilder};
+use crate::{Protoc, Result};
+use ::std::time::Duration;
+
+/// A value which the closure of [`Protoc::run_typed`] can return as the `CodeGeneratorResponse`.
+///
+/// Implemented for the encoded bytes, the [`plugin_proto`](crate::plugin_proto) messages, the
+/// `(name, content)` pairs of the generated files, and the `CodeGeneratorResponse` of `prost`
+/// and of the `protobuf` crate with their features.
+pub trait IntoResponseBytes {
+    /// Encodes `self` into the `CodeGeneratorResponse` bytes.
+    fn into_response_bytes(self) -> Result<Vec<u8>>;
+}
+
+impl IntoResponseBytes for Vec<u8> {
+    fn into_response_bytes(self) -> Result<Vec<u8>> {
+        Ok(self)
+    }
+}
+
+impl IntoResponseBytes for CodeGeneratorResponse {
+    fn into_response_bytes(self) -> Result<Vec<u8>> {
+        self.to_bytes()
+    }
+}
+
+impl IntoResponseBytes for ResponseBuilder {
+    fn into_response_bytes(self) -> Result<Vec<u8>> {
+        self.build().to_bytes()
+    }
+}
+
+/// The generated files as the `(name, content)` pairs, e.g. as returned by
+/// [`ProtocOnMemory::run`](crate::ProtocOnMemory::run).
+impl IntoResponseBytes for Vec<(String, String)> {
+    fn into_response_bytes(self) -> Result<Vec<u8>> {
+        self.into_iter()
+            .fold(ResponseBuilder::new(), |builder, (name, content)| {
+                builder.file(name, content)
+            })
+            .into_response_bytes()
+    }
+}
+
+impl Protoc {
+    /// Same as [`Self::run`], but the closure can return any [`IntoResponseBytes`] instead of
+    /// the encoded `CodeGeneratorResponse` bytes, e.g. the typed
+    /// [`CodeGeneratorResponse`].
+    ///
+    /// The closure returns an [`ErrorKind`](crate::ErrorKind), e.g.
+    /// [`ErrorKind::CallbackError`](crate::ErrorKind::CallbackError) with a message.
+    /// It's returned as is, as well as an error of encoding the returned value.
+    pub fn run_typed<F, R>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(&[u8]) -> Result<R>,
+        R: IntoResponseBytes,
+    {
+        self.run_and_get_response(timeout, &mut |_| (), typed_body(body))
+            .map(|_| ())
+    }
+}
+
+#[cfg(feature = "on-memory")]
+impl crate::ProtocOnMemory {
+    /// Same as [`crate::ProtocOnMemory::run`], but the closure can return any
+    /// [`IntoResponseBytes`]. See [`Protoc::run_typed`].
+    pub fn run_typed<F, R>(self, timeout: Duration, body: F) -> Result<Vec<(String, String)>>
+    where
+        F: FnOnce(&[u8]) -> Result<R>,
+        R: IntoResponseBytes,
+    {
+        self.run_and_read_strings(timeout, typed_body(body))
+            .map(|output| output.files)
+    }
+}
+
+/// Wraps the closure to encode its response.
+fn typed_body<F, R>(body: F) -> impl FnOnce(&[u8]) -> Result<Vec<u8>>
+where
+    F: FnOnce(&[u8]) -> Result<R>,
+    R: IntoResponseBytes,
+{
+    |request_bytes| body(request_bytes)?.into_response_bytes()
+}
@@ -0,0 +1,61 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The interrupt signal handling for [`crate::Protoc::handle_signals`].
+
+use crate::Result;
+use ::signal_hook::SigId;
+use ::signal_hook::consts::{SIGINT, SIGTERM};
+use ::std::sync::Arc;
+use ::std::sync::atomic::{AtomicBool, Ordering};
+
+/// Catches `SIGINT` and `SIGTERM` while alive, instead of letting them terminate the process.
+///
+/// On Windows, `SIGINT` covers Ctrl-C only. Ctrl-Break and the other console control events
+/// are not caught.
+///
+/// The handlers are unregistered when this is dropped.
+pub(crate) struct SignalGuard {
+    interrupted: Arc<AtomicBool>,
+    ids: Vec<SigId>,
+}
+
+impl SignalGuard {
+    /// Registers the signal handlers.
+    pub(crate) fn install() -> Result<Self> {
+        let interrupted = Arc::new(AtomicBool::new(false));
+        let mut guard = Self {
+            interrupted,
+            ids: Vec::new(),
+        };
+        for signal in [SIGINT, SIGTERM] {
+            let id = ::signal_hook::flag::register(signal, Arc::clone(&guard.interrupted))?;
+            guard.ids.push(id);
+        }
+        Ok(guard)
+    }
+
+    /// Returns whether any of the signals has been caught.
+    pub(crate) fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for SignalGuard {
+    fn drop(&mut self) {
+        for id in self.ids.drain(..) {
+            ::signal_hook::low_level::unregister(id);
+        }
+    }
+}
@@ -0,0 +1,85 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The closures taking the plugin messages of a protobuf crate, shared by the `prost` and
+//! the `protobuf` features.
+
+use crate::{ErrorKind, IntoResponseBytes, Protoc, Result};
+use ::std::time::Duration;
+
+/// The `CodeGeneratorRequest` of a protobuf crate.
+pub(crate) trait TypedRequest: Sized {
+    /// Decodes the request, returning the crate's own error kind on failure.
+    fn decode(bytes: &[u8]) -> Result<Self>;
+    /// Clears the `parameter` field, which carries the key to connect to the plugin binary
+    /// rather than the user parameters.
+    fn clear_parameter(&mut self);
+}
+
+/// The `CodeGeneratorResponse` of a protobuf crate.
+pub(crate) trait TypedResponse: IntoResponseBytes {
+    fn supported_features_mut(&mut self) -> &mut Option<u64>;
+}
+
+impl Protoc {
+    /// Runs the closure taking the decoded request and returning the typed response.
+    pub(crate) fn run_decoded<F, Req, Res>(self, timeout: Duration, body: F) -> Result<()>
+    where
+        F: FnOnce(Req) -> ::std::result::Result<Res, String>,
+        Req: TypedRequest,
+        Res: TypedResponse,
+    {
+        let features = self.supported_features;
+        self.run_and_get_response(timeout, &mut |_| (), decoded_body(features, body))
+            .map(|_| ())
+    }
+}
+
+#[cfg(feature = "on-memory")]
+impl crate::ProtocOnMemory {
+    /// See [`Protoc::run_decoded`].
+    pub(crate) fn run_decoded<F, Req, Res>(
+        self,
+        timeout: Duration,
+        body: F,
+    ) -> Result<Vec<(String, String)>>
+    where
+        F: FnOnce(Req) -> ::std::result::Result<Res, String>,
+        Req: TypedRequest,
+        Res: TypedResponse,
+    {
+        let features = self.protoc.supported_features;
+        self.run_and_read_strings(timeout, decoded_body(features, body))
+            .map(|output| output.files)
+    }
+}
+
+/// Wraps the closure to decode the request and to encode the response, with the `features`
+/// merged into its `supported_features`.
+fn decoded_body<F, Req, Res>(features: u64, body: F) -> impl FnOnce(&[u8]) -> Result<Vec<u8>>
+where
+    F: FnOnce(Req) -> ::std::result::Result<Res, String>,
+    Req: TypedRequest,
+    Res: TypedResponse,
+{
+    move |request_bytes| {
+        let mut request = Req::decode(request_bytes)?;
+        request.clear_parameter();
+        let mut response = body(request).map_err(ErrorKind::CallbackError)?;
+        if features != 0 {
+            *response.supported_features_mut().get_or_insert(0) |= features;
+        }
+        response.into_response_bytes()
+    }
+}
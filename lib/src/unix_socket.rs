@@ -0,0 +1,164 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The Unix domain socket transport between this library and the plugin binary.
+//!
+//! The library binds a socket in a temp dir and passes its path, prefixed by
+//! [`INIT_KEY_PREFIX`], to the plugin binary as the init key. The plugin binary connects to it,
+//! writes its protocol version and the `CodeGeneratorRequest`, and reads the
+//! `CodeGeneratorResponse`, each framed by a little-endian `u64` length prefix. The framing must be in sync with the plugin binary.
+
+use crate::{
+    ErrorKind, ExitStatusInfo, Interrupts, POLL_INTERVAL, Phase, Result, deadline_after,
+    kill_if_any, try_wait_if_any,
+};
+use ::std::io::{self, Read, Write};
+use ::std::os::unix::net::{UnixListener, UnixStream};
+use ::std::process::Child;
+use ::std::thread;
+use ::std::time::{Duration, Instant};
+use ::tempfile::TempDir;
+
+/// The prefix of the init key telling the plugin binary to use this transport.
+const INIT_KEY_PREFIX: &str = "unix:";
+
+/// The maximum buffer size reserved up front by a frame's length prefix. The larger frames grow
+/// the buffer as the bytes arrive, so a corrupt prefix can't make us allocate a huge buffer.
+/// Must be in sync with the plugin binary.
+const MAX_PREALLOCATED_FRAME_LEN: usize = 64 * 1024 * 1024;
+
+/// Binds a new socket in a temp dir. Returns the temp dir, which must be kept alive while
+/// running `protoc`, the listener and the init key to pass to the plugin binary.
+pub(crate) fn bind() -> Result<(TempDir, UnixListener, String)> {
+    let temp_dir = TempDir::new()?;
+    let socket_path = temp_dir.path().join("plugin.sock");
+    let listener = UnixListener::bind(&socket_path)?;
+    listener.set_nonblocking(true)?;
+    let init_key = format!(
+        "{}{}",
+        INIT_KEY_PREFIX,
+        socket_path.to_str().ok_or(ErrorKind::FileNameError)?
+    );
+    Ok((temp_dir, listener, init_key))
+}
+
+/// Waits for the plugin binary to connect to the socket, while watching the deadline and
+/// the `protoc` process like [`crate::accept_plugin`].
+pub(crate) fn accept_plugin(
+    listener: &UnixListener,
+    mut process: Option<&mut Child>,
+    timeout: Duration,
+    interrupts: Interrupts<'_>,
+) -> Result<UnixStream> {
+    let started = Instant::now();
+    let deadline = deadline_after(timeout);
+    let error = loop {
+        match listener.accept() {
+            Ok((stream, _)) => {
+                stream.set_nonblocking(false)?;
+                return Ok(stream);
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => break e.into(),
+        }
+        if let Err(e) = interrupts.check() {
+            break e;
+        }
+        if let Some(exit_code) = try_wait_if_any(process.as_deref_mut())? {
+            break if exit_code.success() {
+                ErrorKind::PluginNotInvoked
+            } else {
+                ErrorKind::ProtocProcessError(ExitStatusInfo::new(Some(exit_code)))
+            };
+        }
+        if Instant::now() >= deadline {
+            break ErrorKind::timeout(Phase::AwaitingPluginHandshake, started);
+        }
+        thread::sleep(POLL_INTERVAL);
+    };
+    kill_if_any(process);
+    Err(error)
+}
+
+/// Waits for the plugin binary to send a message, like [`crate::recv_message`].
+pub(crate) fn recv_message(
+    stream: &mut UnixStream,
+    process: Option<&mut Child>,
+    phase: Phase,
+    timeout: Duration,
+    interrupts: Interrupts<'_>,
+) -> Result<Vec<u8>> {
+    read_frame(stream, phase, timeout, interrupts).inspect_err(|_| kill_if_any(process))
+}
+
+/// Reads a length-prefixed frame. The length prefix is polled so that the interrupts are noticed
+/// while waiting for the message. The `phase` is reported on timeout.
+fn read_frame(
+    stream: &mut UnixStream,
+    phase: Phase,
+    timeout: Duration,
+    interrupts: Interrupts<'_>,
+) -> Result<Vec<u8>> {
+    let started = Instant::now();
+    let deadline = deadline_after(timeout);
+    let mut len = [0u8; 8];
+    let mut filled = 0;
+    stream.set_nonblocking(true)?;
+    while filled < len.len() {
+        match stream.read(&mut len[filled..]) {
+            Ok(0) => return Err(ErrorKind::PluginNoRequestError),
+            Ok(n) => {
+                filled += n;
+                continue;
+            }
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+        interrupts.check()?;
+        if Instant::now() >= deadline {
+            return Err(ErrorKind::timeout(phase, started));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+    stream.set_nonblocking(false)?;
+
+    let len = usize::try_from(u64::from_le_bytes(len))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let remaining = deadline.saturating_duration_since(Instant::now());
+    stream.set_read_timeout(Some(remaining.max(POLL_INTERVAL)))?;
+    // Reading into the buffer sized by the prefix avoids the reallocations of the large
+    // frames. The socket is blocking, so the writer is throttled to our reading speed.
+    let mut buffer = Vec::with_capacity(len.min(MAX_PREALLOCATED_FRAME_LEN));
+    stream
+        .take(len as u64)
+        .read_to_end(&mut buffer)
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => {
+                ErrorKind::timeout(phase, started)
+            }
+            _ => e.into(),
+        })?;
+    if buffer.len() != len {
+        return Err(ErrorKind::PluginNoRequestError);
+    }
+    Ok(buffer)
+}
+
+/// Writes a length-prefixed frame.
+pub(crate) fn write_frame(writer: &mut impl Write, bytes: &[u8]) -> io::Result<()> {
+    writer.write_all(&(bytes.len() as u64).to_le_bytes())?;
+    writer.write_all(bytes)?;
+    writer.flush()
+}
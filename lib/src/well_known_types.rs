@@ -0,0 +1,40 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! The sources of the well-known type protos, e.g. `google/protobuf/timestamp.proto`, for
+//! [`crate::ProtocOnMemory::with_well_known_types`]. Enabled by the `well-known-types` feature.
+//!
+//! They are copied from the protobuf release, and are self-contained (i.e. import nothing).
+
+/// Returns the file name and the content of the well-known type proto with the given name.
+macro_rules! wkt {
+    ($name:literal) => {
+        (
+            concat!("google/protobuf/", $name, ".proto"),
+            include_str!(concat!("../proto/google/protobuf/", $name, ".proto")),
+        )
+    };
+}
+
+/// The file names and the contents of the well-known type protos.
+pub(crate) const WELL_KNOWN_TYPES: &[(&str, &str)] = &[
+    wkt!("any"),
+    wkt!("descriptor"),
+    wkt!("duration"),
+    wkt!("empty"),
+    wkt!("field_mask"),
+    wkt!("struct"),
+    wkt!("timestamp"),
+    wkt!("wrappers"),
+];
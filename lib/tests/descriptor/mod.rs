@@ -0,0 +1,55 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Minimal implementation of google.protobuf.FileDescriptorSet for testing.
+//!
+//! See: google/protobuf/descriptor.proto in the Google Protobuf repository.
+//!
+//! The plugin messages are provided by the library itself, in
+//! `protoc_plugin_by_closure::plugin_proto`.
+
+use ::protobuf_core::{FieldValue, ReadExtProtobuf};
+
+// Field numbers from google/protobuf/descriptor.proto
+const FILE_DESCRIPTOR_SET_FILE_FIELD_NUMBER: u32 = 1;
+
+/// Minimal implementation of google.protobuf.FileDescriptorSet
+///
+/// This only implements:
+/// - file (field 1): The FileDescriptorProto messages
+#[derive(Debug, Default)]
+pub struct FileDescriptorSet {
+    /// repeated FileDescriptorProto file = 1;
+    /// For testing, we just count them, not parse the full FileDescriptorProto
+    pub file_count: usize,
+}
+
+impl FileDescriptorSet {
+    /// Parse a FileDescriptorSet from bytes
+    pub fn from_bytes(bytes: &[u8]) -> protobuf_core::Result<Self> {
+        let mut file_count = 0;
+
+        for field_result in bytes.read_protobuf_fields() {
+            let field = field_result?;
+
+            if field.field_number.as_u32() == FILE_DESCRIPTOR_SET_FILE_FIELD_NUMBER
+                && matches!(field.value, FieldValue::Len(_))
+            {
+                file_count += 1;
+            }
+        }
+
+        Ok(Self { file_count })
+    }
+}
@@ -0,0 +1,40 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+//! Tests that the thread accepting the plugin binary's connection is joined on a timeout. The
+//! threads are counted process-wide, so it's in a separate test binary with a single test.
+#![cfg(target_os = "linux")]
+
+use ::protoc_plugin_by_closure::{ErrorKind, IpcServer, Phase, Transport};
+use ::std::time::Duration;
+
+#[test]
+fn test_accept_thread_joined_on_timeout() {
+    let threads = || ::std::fs::read_dir("/proc/self/task").unwrap().count();
+    let before = threads();
+
+    let (_, server) = IpcServer::start_with_transport(Transport::IpcChannel).unwrap();
+    let result = server.serve(Duration::from_millis(100), |_| unreachable!());
+    assert!(
+        matches!(
+            result,
+            Err(ErrorKind::Timeout {
+                phase: Phase::AwaitingPluginHandshake,
+                ..
+            })
+        ),
+        "{:?}",
+        result
+    );
+    assert_eq!(threads(), before);
+}
@@ -0,0 +1,222 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ::protoc_plugin_by_closure::plugin_proto::{CodeGeneratorResponse, File};
+use ::protoc_plugin_by_closure::{ErrorKind, Protoc, build_helper};
+use ::std::env;
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+// The env var is process global, so all the cases are in a single test.
+#[test]
+fn test_compile_with_writer() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("build.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package build;").unwrap();
+    let new_protoc = || {
+        Protoc::new()
+            .proto_file(&proto_file)
+            .proto_path(proto_dir.path())
+    };
+
+    // Without OUT_DIR, it refuses to run.
+    unsafe { env::remove_var("OUT_DIR") };
+    let mut directives = Vec::new();
+    let result = build_helper::compile_with_writer(
+        new_protoc(),
+        Duration::from_secs(3),
+        |_| panic!("the closure must not be called"),
+        &mut directives,
+    );
+    assert!(matches!(result, Err(ErrorKind::OutDirNotSetError)));
+
+    // With OUT_DIR, the files are generated there.
+    unsafe { env::set_var("OUT_DIR", out_dir.path()) };
+    let mut directives = Vec::new();
+    build_helper::compile_with_writer(
+        new_protoc(),
+        Duration::from_secs(3),
+        |_| {
+            let mut res = CodeGeneratorResponse::default();
+            res.files.push(File {
+                name: "build.rs".to_string(),
+                content: "// build".into(),
+                ..Default::default()
+            });
+            let res_bytes = res.to_bytes().unwrap();
+            Ok(res_bytes)
+        },
+        &mut directives,
+    )
+    .unwrap();
+    unsafe { env::remove_var("OUT_DIR") };
+
+    assert_eq!(
+        String::from_utf8(directives).unwrap(),
+        format!(
+            "cargo:rerun-if-changed={}\ncargo:rerun-if-changed={}\n",
+            proto_file.display(),
+            proto_dir.path().display()
+        )
+    );
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join("build.rs")).unwrap();
+    assert_eq!(actual_out, "// build");
+}
+
+#[test]
+fn test_compile_with_writer_imports() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let a_proto = proto_dir.path().join("a.proto");
+    let b_proto = proto_dir.path().join("b.proto");
+    let c_proto = proto_dir.path().join("c.proto");
+    ::std::fs::write(
+        &a_proto,
+        "syntax = \"proto3\"; import \"b.proto\"; message A { B b = 1; }",
+    )
+    .unwrap();
+    ::std::fs::write(
+        &b_proto,
+        "syntax = \"proto3\"; import \"c.proto\"; message B { C c = 1; }",
+    )
+    .unwrap();
+    ::std::fs::write(&c_proto, "syntax = \"proto3\"; message C {}").unwrap();
+
+    let mut directives = Vec::new();
+    build_helper::compile_with_writer(
+        Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_file(&a_proto)
+            .proto_path(proto_dir.path()),
+        Duration::from_secs(3),
+        |_| {
+            let mut res = CodeGeneratorResponse::default();
+            res.files.push(File {
+                name: "a.rs".to_string(),
+                content: "// a".into(),
+                ..Default::default()
+            });
+            Ok(res.to_bytes().unwrap())
+        },
+        &mut directives,
+    )
+    .unwrap();
+
+    // The imported files follow the configured proto file and proto path, in the dependency
+    // order of protoc.
+    assert_eq!(
+        String::from_utf8(directives).unwrap(),
+        format!(
+            "cargo:rerun-if-changed={}\ncargo:rerun-if-changed={}\n\
+             cargo:rerun-if-changed={}\ncargo:rerun-if-changed={}\n",
+            a_proto.display(),
+            proto_dir.path().display(),
+            c_proto.display(),
+            b_proto.display()
+        )
+    );
+}
+
+#[test]
+fn test_compile_with_writer_warnings() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("warned.proto");
+    ::std::fs::write(
+        proto_dir.path().join("unused.proto"),
+        "syntax = \"proto3\";",
+    )
+    .unwrap();
+    // The import is unused, which protoc warns about.
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\"; import \"unused.proto\"; message Warned {}",
+    )
+    .unwrap();
+
+    let mut directives = Vec::new();
+    build_helper::compile_with_writer(
+        Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_file(&proto_file)
+            .proto_path(proto_dir.path()),
+        Duration::from_secs(3),
+        |_| Ok(Vec::new()),
+        &mut directives,
+    )
+    .unwrap();
+
+    let directives = String::from_utf8(directives).unwrap();
+    let warnings = directives
+        .lines()
+        .filter(|line| line.starts_with("cargo:warning="))
+        .collect::<Vec<_>>();
+    assert_eq!(warnings.len(), 1, "{}", directives);
+    assert!(warnings[0].contains("unused.proto"), "{}", directives);
+}
+
+#[cfg(feature = "build")]
+#[test]
+fn test_run_in_build_script_with_writer() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let base_proto = proto_dir.path().join("base.proto");
+    let user_proto = proto_dir.path().join("user.proto");
+    ::std::fs::write(&base_proto, "syntax = \"proto3\"; message Base {}").unwrap();
+    // The import is unused, which protoc warns about.
+    ::std::fs::write(
+        &user_proto,
+        "syntax = \"proto3\"; import \"base.proto\"; message User {}",
+    )
+    .unwrap();
+
+    let mut directives = Vec::new();
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&user_proto)
+        .proto_path(proto_dir.path())
+        .run_in_build_script_with_writer(
+            Duration::from_secs(3),
+            |_| {
+                let mut res = CodeGeneratorResponse::default();
+                res.files.push(File {
+                    name: "user.rs".to_string(),
+                    content: "// user".into(),
+                    ..Default::default()
+                });
+                Ok(res.to_bytes().unwrap())
+            },
+            &mut directives,
+        )
+        .unwrap();
+
+    let directives = String::from_utf8(directives).unwrap();
+    let lines = directives.lines().collect::<Vec<_>>();
+    assert_eq!(lines.len(), 4, "{}", directives);
+    assert_eq!(
+        lines[0],
+        format!("cargo:rerun-if-changed={}", user_proto.display())
+    );
+    assert_eq!(
+        lines[1],
+        format!("cargo:rerun-if-changed={}", proto_dir.path().display())
+    );
+    assert_eq!(
+        lines[2],
+        format!("cargo:rerun-if-changed={}", base_proto.display())
+    );
+    assert!(lines[3].starts_with("cargo:warning="), "{}", directives);
+    assert!(lines[3].contains("base.proto"), "{}", directives);
+}
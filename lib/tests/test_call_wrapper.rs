@@ -12,14 +12,23 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-mod compiler_plugin;
+mod descriptor;
 
-use ::protoc_plugin_by_closure::Protoc;
+use ::protoc_plugin_by_closure::plugin_proto::{
+    CodeGeneratorRequest, CodeGeneratorResponse, FEATURE_PROTO3_OPTIONAL,
+    FEATURE_SUPPORTS_EDITIONS, File, FileDescriptorLite, ResponseBuilder,
+};
+use ::protoc_plugin_by_closure::{
+    CancellationToken, ErrorFormat, ErrorKind, FileKind, FileSummary, GeneratedFile,
+    IntoResponseBytes, IpcServer, Phase, Protoc, RequestInfo, RetryPolicy, RunEvent, RunSpec,
+    Severity, Transport, chain, plugin_path,
+};
 use ::std::io::Write;
+use ::std::sync::{Arc, Mutex};
 use ::std::time::Duration;
 use ::tempfile::{NamedTempFile, tempdir};
 
-use self::compiler_plugin::{CodeGeneratorRequest, CodeGeneratorResponse, File};
+use self::descriptor::FileDescriptorSet;
 
 #[test]
 fn test_call_wrapper() {
@@ -56,6 +65,2946 @@ package empty;
     assert_eq!(actual_out, out_file_content);
 }
 
+#[test]
+fn test_run_to_memory() {
+    let out_file_name = "empty_test.rs";
+    let out_file_content = "This\nis\na\ntest";
+    let proto_dir = tempdir().unwrap();
+    let proto_file = NamedTempFile::new_in(proto_dir.path()).unwrap();
+    proto_file
+        .as_file()
+        .write_all(b"syntax = \"proto3\"; package empty;")
+        .unwrap();
+
+    let files = Protoc::new()
+        .proto_file(proto_file.path())
+        .proto_path(proto_dir.path())
+        .run_to_memory(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    assert_eq!(
+        files,
+        vec![GeneratedFile {
+            file: File {
+                name: out_file_name.to_string(),
+                content: out_file_content.as_bytes().to_vec(),
+                ..Default::default()
+            },
+            mode: None,
+        }]
+    );
+    // Nothing is written into the current directory.
+    assert!(!::std::path::Path::new(out_file_name).exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_files_mode() {
+    use ::std::os::unix::fs::PermissionsExt;
+
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("scripts.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package scripts;").unwrap();
+
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_files(Duration::from_secs(3), |_| {
+            Ok(vec![
+                GeneratedFile {
+                    file: File {
+                        name: "run.sh".to_string(),
+                        content: b"#!/bin/sh\n".to_vec(),
+                        ..Default::default()
+                    },
+                    mode: Some(0o755),
+                },
+                GeneratedFile {
+                    file: File {
+                        name: "README".to_string(),
+                        content: b"readme\n".to_vec(),
+                        ..Default::default()
+                    },
+                    mode: None,
+                },
+            ])
+        })
+        .unwrap();
+
+    let mode = |name| {
+        ::std::fs::metadata(out_dir.path().join(name))
+            .unwrap()
+            .permissions()
+            .mode()
+    };
+    assert_eq!(mode("run.sh") & 0o777, 0o755);
+    assert_eq!(mode("README") & 0o111, 0);
+    assert_eq!(
+        ::std::fs::read_to_string(out_dir.path().join("run.sh")).unwrap(),
+        "#!/bin/sh\n"
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_files_mode_without_out_dir() {
+    use ::std::os::unix::fs::PermissionsExt;
+
+    // `protoc` writes into the current directory, so generate into a temp dir under it.
+    let out_dir = ::tempfile::tempdir_in(".").unwrap();
+    let out_file_name = ::std::path::Path::new(out_dir.path().file_name().unwrap())
+        .join("run.sh")
+        .to_str()
+        .unwrap()
+        .to_string();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("scripts.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package scripts;").unwrap();
+
+    Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_files(Duration::from_secs(3), |_| {
+            Ok(vec![GeneratedFile {
+                file: File {
+                    name: out_file_name.clone(),
+                    content: b"#!/bin/sh\n".to_vec(),
+                    ..Default::default()
+                },
+                mode: Some(0o755),
+            }])
+        })
+        .unwrap();
+
+    let mode = ::std::fs::metadata(out_dir.path().join("run.sh"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o755);
+}
+
+#[test]
+fn test_analyze() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("analyzed.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\"; package analyzed; import \"dep.proto\";",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("dep.proto"),
+        "syntax = \"proto3\"; package dep;",
+    )
+    .unwrap();
+
+    let mut proto_file_count = 0;
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .analyze(Duration::from_secs(3), |req| {
+            proto_file_count = CodeGeneratorRequest::from_bytes(req)
+                .unwrap()
+                .proto_file
+                .len();
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(proto_file_count, 2);
+    assert_eq!(::std::fs::read_dir(out_dir.path()).unwrap().count(), 0);
+
+    let result = Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .analyze(Duration::from_secs(3), |_| Err("lint failed".to_string()));
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(&result, Err(ErrorKind::CallbackError(msg)) if msg == "lint failed"),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_insertion_points() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("inserted.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package inserted;").unwrap();
+    let protoc = Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+    let file = |content: &str, insertion_point: Option<&str>| File {
+        name: "inserted.rs".to_string(),
+        insertion_point: insertion_point.map(str::to_string),
+        content: content.into(),
+        generated_code_info: None,
+    };
+    let base = "struct A {\n    // @@protoc_insertion_point(fields)\n}\n/* @@protoc_insertion_point(eof) */\n";
+    // The insertions come first in the response, but are applied after the base file.
+    let res = CodeGeneratorResponse {
+        files: vec![
+            file("a: i32,\n\nb: i32", Some("fields")),
+            file("c: i32,\n", Some("fields")),
+            file("// end\n", Some("eof")),
+            file(base, None),
+        ],
+        ..Default::default()
+    };
+    let expected = "struct A {\n    a: i32,\n    \n    b: i32\n    c: i32,\n    \
+        // @@protoc_insertion_point(fields)\n}\n// end\n/* @@protoc_insertion_point(eof) */\n";
+
+    protoc
+        .clone()
+        .out_dir(out_dir.path())
+        .run(Duration::from_secs(3), |_| Ok(res.to_bytes().unwrap()))
+        .unwrap();
+    let on_disk = ::std::fs::read_to_string(out_dir.path().join("inserted.rs")).unwrap();
+    assert_eq!(on_disk, expected);
+
+    let files = protoc
+        .clone()
+        .run_to_memory(Duration::from_secs(3), |_| Ok(res.to_bytes().unwrap()))
+        .unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(
+        String::from_utf8(files[0].file.content.clone()).unwrap(),
+        expected
+    );
+
+    // The missing marker is reported before protoc writes the files.
+    let res = CodeGeneratorResponse {
+        files: vec![file(base, None), file("x\n", Some("missing"))],
+        ..Default::default()
+    };
+    let result = protoc
+        .clone()
+        .run_to_memory(Duration::from_secs(3), |_| Ok(res.to_bytes().unwrap()));
+    let result = result.map_err(ErrorKind::into_inner);
+    let Err(ErrorKind::InsertionPointError {
+        file: target,
+        reason,
+        ..
+    }) = result
+    else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert_eq!(target, "inserted.rs");
+    assert_eq!(reason, "@@protoc_insertion_point(missing) is not found");
+
+    // The target file may be generated by another plugin in the same run, so it's left to protoc.
+    let res = CodeGeneratorResponse {
+        files: vec![file("x\n", Some("fields"))],
+        ..Default::default()
+    };
+    let result = protoc
+        .clone()
+        .run_to_memory(Duration::from_secs(3), |_| Ok(res.to_bytes().unwrap()));
+    let Err(ErrorKind::ProtocProcessError(info)) = result else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert!(info.stderr.contains("inserted.rs"), "{}", info.stderr);
+}
+
+#[test]
+fn test_max_response_size() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("large.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package large;").unwrap();
+
+    let result = Protoc::new()
+        .max_response_size(64)
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "large.rs", &"x".repeat(1024)))
+        });
+
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(matches!(
+        result,
+        Err(ErrorKind::ResponseTooLarge { limit: 64, actual }) if actual > 1024
+    ));
+    assert!(!out_dir.path().join("large.rs").exists());
+}
+
+#[test]
+fn test_max_request_size() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("large.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package large;").unwrap();
+
+    // The plugin binary refuses to read the request, so protoc fails.
+    let mut called = false;
+    let result = Protoc::new()
+        .max_request_size(16)
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            called = true;
+            Ok(Vec::new())
+        });
+    assert!(matches!(result, Err(ErrorKind::ProtocProcessError(_))));
+    assert!(!called);
+
+    let result = Protoc::new()
+        .max_request_size(16)
+        .run_offline(&[0u8; 17], |_| Ok(Vec::new()));
+    assert!(matches!(
+        result,
+        Err(ErrorKind::RequestTooLarge {
+            limit: 16,
+            actual: 17
+        })
+    ));
+
+    // 0 disables the check.
+    let result = Protoc::new()
+        .max_request_size(0)
+        .run_offline(&[0u8; 17], |_| Ok(Vec::new()));
+    assert!(result.unwrap().is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_unix_socket_transport() {
+    let out_dir = tempdir().unwrap();
+    let out_file_name = "unix_socket.rs";
+    let out_file_content = "// over the unix socket";
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("unix_socket.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package unix_socket;").unwrap();
+
+    Protoc::new()
+        .transport(Transport::UnixSocket)
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join(out_file_name)).unwrap();
+    assert_eq!(actual_out, out_file_content);
+}
+
+#[test]
+fn test_no_input() {
+    let result = Protoc::new().run(Duration::from_secs(3), |_| {
+        panic!("the closure must not be called")
+    });
+    assert!(matches!(result, Err(ErrorKind::NoInput)));
+
+    let result = Protoc::new().compile_descriptor_set(Duration::from_secs(3));
+    assert!(matches!(result, Err(ErrorKind::NoInput)));
+}
+
+#[test]
+fn test_line_endings_round_trip() {
+    // Any text mode translation (e.g. on Windows) would corrupt these bytes.
+    let out_file_name = "line_endings.rs";
+    let out_file_content = "crlf\r\nlf\ncr\rcrcrlf\r\r\n";
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("line_endings.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\";\r\npackage line_endings;\r\n",
+    )
+    .unwrap();
+
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read(out_dir.path().join(out_file_name)).unwrap();
+    assert_eq!(actual_out, out_file_content.as_bytes());
+}
+
+#[test]
+fn test_run_with_context() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("context.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package context;").unwrap();
+
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_with_context(Duration::from_secs(3), |ctx, req| {
+            assert!(ctx.protoc_path.is_absolute());
+            let content = format!("// {}", ctx.out_dir.unwrap().display());
+            Ok(test_call_wrapper_inner(req, "context.txt", &content))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join("context.txt")).unwrap();
+    assert_eq!(actual_out, format!("// {}", out_dir.path().display()));
+}
+
+#[test]
+fn test_run_untimed() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("slow.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package slow;").unwrap();
+
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_untimed(|req| {
+            ::std::thread::sleep(Duration::from_millis(500));
+            Ok(test_call_wrapper_inner(req, "slow.txt", "slow"))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join("slow.txt")).unwrap();
+    assert_eq!(actual_out, "slow");
+}
+
+#[test]
+fn test_run_raw() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("raw.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package raw;").unwrap();
+
+    let res_bytes = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_raw(Duration::from_secs(3), |_| {
+            // The insertion comes before its target file, which the library reorders.
+            let mut res = CodeGeneratorResponse::default();
+            res.files.push(File {
+                name: "raw.txt".to_string(),
+                insertion_point: Some("here".to_string()),
+                content: b"inserted\n".to_vec(),
+                ..Default::default()
+            });
+            res.files.push(File {
+                name: "raw.txt".to_string(),
+                content: b"// @@protoc_insertion_point(here)\n".to_vec(),
+                ..Default::default()
+            });
+            Ok(res.to_bytes().unwrap())
+        })
+        .unwrap();
+
+    let res = CodeGeneratorResponse::from_bytes(&res_bytes).unwrap();
+    assert_eq!(res.files.len(), 2);
+    assert_eq!(res.files[0].insertion_point, None);
+    assert_eq!(res.files[1].insertion_point.as_deref(), Some("here"));
+    let actual_out = ::std::fs::read(out_dir.path().join("raw.txt")).unwrap();
+    assert_eq!(
+        actual_out,
+        [res.files[1].content.as_slice(), &res.files[0].content].concat()
+    );
+}
+
+#[test]
+fn test_run_with_summary() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("summary.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package summary;").unwrap();
+
+    let summary = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_with_summary(Duration::from_secs(3), |_| {
+            let mut res = CodeGeneratorResponse::default();
+            res.files.push(File {
+                name: "new.txt".to_string(),
+                content: b"// @@protoc_insertion_point(here)\n".to_vec(),
+                ..Default::default()
+            });
+            res.files.push(File {
+                name: "new.txt".to_string(),
+                insertion_point: Some("here".to_string()),
+                content: b"inserted\n".to_vec(),
+                ..Default::default()
+            });
+            Ok(res.to_bytes().unwrap())
+        })
+        .unwrap();
+
+    assert_eq!(
+        summary,
+        vec![
+            FileSummary {
+                path: out_dir.path().join("new.txt"),
+                kind: FileKind::New,
+            },
+            FileSummary {
+                path: out_dir.path().join("new.txt"),
+                kind: FileKind::Insertion {
+                    point: "here".to_string(),
+                    target: "new.txt".to_string(),
+                },
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_request_has_imports_and_source_info() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("dep.proto"),
+        "syntax = \"proto3\"; package dep; message Dep {}",
+    )
+    .unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\";\npackage main;\nimport \"dep.proto\";\n// The main message.\nmessage Main { dep.Dep dep = 1; }\n",
+    )
+    .unwrap();
+
+    // Neither `include_imports` nor `include_source_info` is needed for the plugins.
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req_bytes| {
+            let req = CodeGeneratorRequest::from_bytes(req_bytes).unwrap();
+            let names = req
+                .proto_file
+                .iter()
+                .map(|bytes| FileDescriptorLite::from_bytes(bytes).unwrap().name)
+                .collect::<Vec<_>>();
+            assert_eq!(names, ["dep.proto", "main.proto"]);
+            let main = req.proto_file.last().unwrap();
+            assert!(main.windows(18).any(|w| w == b" The main message."));
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_empty_response() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("empty.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package empty;").unwrap();
+    let protoc = Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    protoc
+        .clone()
+        .out_dir(out_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+    assert!(
+        ::std::fs::read_dir(out_dir.path())
+            .unwrap()
+            .next()
+            .is_none()
+    );
+
+    let files = protoc
+        .run_to_memory(Duration::from_secs(3), |_| {
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+    assert_eq!(files, vec![]);
+}
+
+#[test]
+fn test_temp_dir() {
+    let root_dir = tempdir().unwrap();
+    let base_dir = root_dir.path().join("target").join("tmp");
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("scratch.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package scratch;").unwrap();
+    let protoc = Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    let files = protoc
+        .clone()
+        .temp_dir(&base_dir)
+        .run_to_memory(Duration::from_secs(3), |req| {
+            // The temporary output directory is under the given directory while running.
+            assert_eq!(::std::fs::read_dir(&base_dir).unwrap().count(), 1);
+            Ok(test_call_wrapper_inner(req, "scratch.rs", "// scratch"))
+        })
+        .unwrap();
+    assert_eq!(files.len(), 1);
+    assert_eq!(::std::fs::read_dir(&base_dir).unwrap().count(), 0);
+
+    // A regular file can't hold the temp dirs.
+    let blocker = root_dir.path().join("file");
+    ::std::fs::write(&blocker, "").unwrap();
+    let result = protoc
+        .temp_dir(&blocker)
+        .run_to_memory(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    match result {
+        Err(ErrorKind::TempDirError { path, .. }) => assert_eq!(path, blocker),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_run_many() {
+    // The runs share one server, which the Unix socket keeps listening and ipc-channel rearms.
+    let mut transports = vec![Transport::IpcChannel];
+    #[cfg(unix)]
+    transports.push(Transport::UnixSocket);
+
+    for transport in transports {
+        let proto_dir = tempdir().unwrap();
+        let out_dirs = [tempdir().unwrap(), tempdir().unwrap(), tempdir().unwrap()];
+        let specs = out_dirs
+            .iter()
+            .enumerate()
+            .map(|(i, out_dir)| {
+                let proto_file = proto_dir.path().join(format!("batch{}.proto", i));
+                let content = format!("syntax = \"proto3\"; package batch{};", i);
+                ::std::fs::write(&proto_file, content).unwrap();
+                RunSpec {
+                    proto_files: vec![proto_file],
+                    out_dir: Some(out_dir.path().to_path_buf()),
+                }
+            })
+            .collect();
+
+        let results = Protoc::new()
+            .transport(transport)
+            .proto_path(proto_dir.path())
+            .run_many(Duration::from_secs(3), specs, |index, req_bytes| {
+                let req = CodeGeneratorRequest::from_bytes(req_bytes).unwrap();
+                assert_eq!(req.file_to_generate, [format!("batch{}.proto", index)]);
+                let name = format!("batch{}.txt", index);
+                Ok(test_call_wrapper_inner(
+                    req_bytes,
+                    &name,
+                    &index.to_string(),
+                ))
+            })
+            .unwrap();
+
+        assert_eq!(results.len(), 3);
+        for (i, (result, out_dir)) in results.into_iter().zip(&out_dirs).enumerate() {
+            let path = out_dir.path().join(format!("batch{}.txt", i));
+            assert_eq!(
+                result.unwrap().files,
+                vec![FileSummary {
+                    path: path.clone(),
+                    kind: FileKind::New,
+                }]
+            );
+            assert_eq!(::std::fs::read_to_string(path).unwrap(), i.to_string());
+        }
+    }
+}
+
+#[test]
+fn test_run_many_protoc_not_found() {
+    let specs = vec![RunSpec::default(), RunSpec::default()];
+    let result = Protoc::new()
+        .protoc_path("protoc-which-does-not-exist")
+        .run_many(Duration::from_secs(3), specs, |_, _| {
+            panic!("the closure must not be called")
+        });
+    assert!(matches!(result, Err(ErrorKind::ProtocNotFound(_))));
+}
+
+#[test]
+fn test_run_many_failure() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("ok.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package ok;").unwrap();
+    let specs = vec![
+        RunSpec {
+            proto_files: vec![proto_file.clone()],
+            ..Default::default()
+        },
+        RunSpec {
+            proto_files: vec![proto_dir.path().join("missing.proto")],
+            ..Default::default()
+        },
+        RunSpec {
+            proto_files: vec![proto_file],
+            ..Default::default()
+        },
+    ];
+
+    for fail_fast in [false, true] {
+        let results = Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_path(proto_dir.path())
+            .fail_fast(fail_fast)
+            .run_many(Duration::from_secs(3), specs.clone(), |_, req_bytes| {
+                Ok(test_call_wrapper_inner(req_bytes, "ok.txt", "ok"))
+            })
+            .unwrap();
+
+        assert_eq!(results.len(), if fail_fast { 2 } else { 3 });
+        assert!(results[0].is_ok());
+        assert!(matches!(results[1], Err(ErrorKind::ProtocProcessError(_))));
+        if !fail_fast {
+            assert!(results[2].is_ok());
+        }
+    }
+}
+
+#[test]
+fn test_generator_reported_error() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("failing.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package failing;").unwrap();
+
+    let result = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            let res = CodeGeneratorResponse {
+                error: Some("unsupported option: foo".to_string()),
+                ..Default::default()
+            };
+            Ok(res.to_bytes().unwrap())
+        });
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(
+            &result,
+            Err(ErrorKind::GeneratorReportedError(message)) if message == "unsupported option: foo"
+        ),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_direct_dependencies() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let user_proto = proto_dir.path().join("user.proto");
+    ::std::fs::write(
+        proto_dir.path().join("base.proto"),
+        "syntax = \"proto3\"; message Base {}",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("other.proto"),
+        "syntax = \"proto3\"; message Other {}",
+    )
+    .unwrap();
+    ::std::fs::write(
+        &user_proto,
+        "syntax = \"proto3\"; import \"base.proto\"; import \"other.proto\"; \
+         message User { Base base = 1; Other other = 2; }",
+    )
+    .unwrap();
+    let new_protoc = || {
+        Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_file(&user_proto)
+            .proto_path(proto_dir.path())
+            .direct_dependencies_violation_msg("%s is not a declared dependency")
+    };
+
+    let result = new_protoc()
+        .direct_dependencies(["base.proto"])
+        .run(Duration::from_secs(3), |_| Ok(Vec::new()));
+    let Err(ErrorKind::ProtocProcessError(info)) = result else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert!(
+        info.stderr
+            .contains("other.proto is not a declared dependency"),
+        "{}",
+        info.stderr
+    );
+    assert!(
+        !info.stderr.contains("base.proto is not"),
+        "{}",
+        info.stderr
+    );
+
+    // An empty list allows no imports.
+    let result = new_protoc()
+        .direct_dependencies(Vec::<String>::new())
+        .run(Duration::from_secs(3), |_| Ok(Vec::new()));
+    let Err(ErrorKind::ProtocProcessError(info)) = result else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert!(
+        info.stderr
+            .contains("base.proto is not a declared dependency"),
+        "{}",
+        info.stderr
+    );
+
+    new_protoc()
+        .direct_dependencies(["base.proto", "other.proto"])
+        .run(Duration::from_secs(3), |_| Ok(Vec::new()))
+        .unwrap();
+}
+
+#[test]
+fn test_run_with_warnings() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let base_proto = proto_dir.path().join("base.proto");
+    let user_proto = proto_dir.path().join("user.proto");
+    ::std::fs::write(&base_proto, "syntax = \"proto3\"; message Base {}").unwrap();
+    // The import is unused, which protoc warns about.
+    ::std::fs::write(
+        &user_proto,
+        "syntax = \"proto3\"; import \"base.proto\"; message User {}",
+    )
+    .unwrap();
+    let new_protoc = || {
+        Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_file(&user_proto)
+            .proto_path(proto_dir.path())
+    };
+
+    let warnings = new_protoc()
+        .run_with_warnings(Duration::from_secs(3), |_| Ok(Vec::new()))
+        .unwrap();
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].message.contains("base.proto"), "{:?}", warnings);
+    assert!(warnings[0].file.ends_with("user.proto"), "{:?}", warnings);
+
+    let result = new_protoc()
+        .fatal_warnings(true)
+        .run(Duration::from_secs(3), |_| Ok(Vec::new()));
+    assert!(matches!(result, Err(ErrorKind::ProtocProcessError(_))));
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_paths_with_spaces() {
+    let temp_dir = tempdir().unwrap();
+    let out_dir = temp_dir.path().join("out dir");
+    let proto_dir = temp_dir.path().join("proto dir");
+    let deps_file = temp_dir.path().join("deps dir").join("deps file.d");
+    ::std::fs::create_dir_all(&out_dir).unwrap();
+    ::std::fs::create_dir_all(&proto_dir).unwrap();
+    ::std::fs::create_dir_all(deps_file.parent().unwrap()).unwrap();
+    ::std::fs::write(
+        proto_dir.join("with space.proto"),
+        "syntax = \"proto3\"; package with_space;",
+    )
+    .unwrap();
+    // The verbatim path prefix, the mixed separators and the different case in the proto file
+    // path, which protoc itself doesn't accept as is.
+    let proto_dir = ::std::fs::canonicalize(&proto_dir).unwrap();
+    let proto_file = format!(
+        "{}/With Space.proto",
+        proto_dir.to_str().unwrap().to_uppercase()
+    );
+
+    let deps = Protoc::new()
+        .out_dir(&out_dir)
+        .proto_path(&proto_dir)
+        .proto_file(&proto_file)
+        .dependency_out(&deps_file)
+        .run_with_deps(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                "with space.rs",
+                "// with space",
+            ))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.join("with space.rs")).unwrap();
+    assert_eq!(actual_out, "// with space");
+    assert_eq!(deps.len(), 1);
+}
+
+#[cfg(windows)]
+#[test]
+fn test_windows_unc_path_is_rejected() {
+    let result = Protoc::new()
+        .out_dir(r"\\server\share\out")
+        .proto_file("a.proto")
+        .run(Duration::from_secs(3), |_| Ok(Vec::new()));
+    assert!(matches!(result, Err(ErrorKind::UnsupportedPathError(_))));
+}
+
+#[test]
+fn test_clone() {
+    let out_dir = tempdir().unwrap();
+    let cloned_out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("clone.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package clone;").unwrap();
+
+    let base = Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+    let cloned = base.clone().out_dir(cloned_out_dir.path());
+
+    base.out_dir(out_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "clone.rs", "// base"))
+        })
+        .unwrap();
+    cloned
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "clone.rs", "// cloned"))
+        })
+        .unwrap();
+
+    let base_out = ::std::fs::read_to_string(out_dir.path().join("clone.rs")).unwrap();
+    let cloned_out = ::std::fs::read_to_string(cloned_out_dir.path().join("clone.rs")).unwrap();
+    assert_eq!(base_out, "// base");
+    assert_eq!(cloned_out, "// cloned");
+}
+
+#[test]
+fn test_ipc_server_with_own_protoc() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("own_protoc.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package own_protoc;").unwrap();
+
+    let (init_key, server) = IpcServer::start().unwrap();
+    let mut protoc = ::std::process::Command::new("protoc")
+        .arg(format!(
+            "--plugin=protoc-gen-own={}",
+            plugin_path().display()
+        ))
+        .arg(format!("--own_out={}", out_dir.path().display()))
+        .arg(format!("--own_opt={}", init_key))
+        .arg(format!("--proto_path={}", proto_dir.path().display()))
+        .arg(&proto_file)
+        .spawn()
+        .unwrap();
+    server
+        .serve(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                "own_protoc.rs",
+                "// own protoc",
+            ))
+        })
+        .unwrap();
+    assert!(protoc.wait().unwrap().success());
+
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join("own_protoc.rs")).unwrap();
+    assert_eq!(actual_out, "// own protoc");
+}
+
+#[test]
+fn test_ipc_server_serve_many() {
+    use ::std::io::Read;
+    use ::std::process::{Command, Stdio};
+
+    let (init_key, server) = IpcServer::start().unwrap();
+    let mut plugin = Command::new(plugin_path())
+        .arg("--batch")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // Two requests, each preceded by the control byte 1 and a u64 length prefix, then the end 0.
+    let mut stdin = plugin.stdin.take().unwrap();
+    for name in ["first.proto", "second.proto"] {
+        let req = CodeGeneratorRequest {
+            file_to_generate: vec![name.to_string()],
+            parameter: Some(init_key.clone()),
+            ..Default::default()
+        };
+        let req_bytes = req.to_bytes().unwrap();
+        stdin.write_all(&[1]).unwrap();
+        stdin
+            .write_all(&(req_bytes.len() as u64).to_le_bytes())
+            .unwrap();
+        stdin.write_all(&req_bytes).unwrap();
+    }
+    stdin.write_all(&[0]).unwrap();
+    drop(stdin);
+
+    let mut served = Vec::new();
+    let count = server
+        .serve_many(Duration::from_secs(3), |req| {
+            let req = CodeGeneratorRequest::from_bytes(req).unwrap();
+            served.extend(req.file_to_generate.iter().cloned());
+            Ok(req.file_to_generate.concat().into_bytes())
+        })
+        .unwrap();
+    assert_eq!(count, 2);
+    assert_eq!(served, ["first.proto", "second.proto"]);
+
+    let mut stdout = Vec::new();
+    plugin
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut stdout)
+        .unwrap();
+    assert!(plugin.wait().unwrap().success());
+    let mut expected = Vec::new();
+    for res in [&b"first.proto"[..], b"second.proto"] {
+        expected.extend((res.len() as u64).to_le_bytes());
+        expected.extend(res);
+    }
+    assert_eq!(stdout, expected);
+}
+
+#[test]
+fn test_custom_plugin_name() {
+    let out_dir = tempdir().unwrap();
+    let out_file_name = "custom_name.rs";
+    let out_file_content = "// custom plugin name";
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("custom_name.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package custom_name;").unwrap();
+
+    Protoc::new()
+        .plugin_name("my-custom-gen")
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join(out_file_name)).unwrap();
+    assert_eq!(actual_out, out_file_content);
+}
+
+#[test]
+fn test_run_offline_replays_captured_request() {
+    let out_dir = tempdir().unwrap();
+    let out_file_name = "replay_test.rs";
+    let out_file_content = "// replayed";
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("replay.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package replay;").unwrap();
+
+    let mut captured_request = Vec::new();
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            captured_request = req.to_vec();
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    let replayed_files = Protoc::new()
+        .run_offline(&captured_request, |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    let on_disk = ::std::fs::read(out_dir.path().join(out_file_name)).unwrap();
+    assert_eq!(
+        replayed_files,
+        vec![(out_file_name.to_string(), on_disk.clone())]
+    );
+    assert_eq!(on_disk, out_file_content.as_bytes());
+}
+
+#[test]
+fn test_compile_descriptor_set() {
+    let proto_dir = tempdir().unwrap();
+    let base_proto = proto_dir.path().join("base.proto");
+    let user_proto = proto_dir.path().join("user.proto");
+    ::std::fs::write(&base_proto, "syntax = \"proto3\"; message Base {}").unwrap();
+    ::std::fs::write(
+        &user_proto,
+        "syntax = \"proto3\"; import \"base.proto\"; message User { Base b = 1; }",
+    )
+    .unwrap();
+
+    let descriptor_set = Protoc::new()
+        .proto_file(&user_proto)
+        .proto_path(proto_dir.path())
+        .compile_descriptor_set(Duration::from_secs(3))
+        .unwrap();
+    let set = FileDescriptorSet::from_bytes(&descriptor_set).unwrap();
+    assert_eq!(set.file_count, 1);
+
+    let descriptor_set = Protoc::new()
+        .proto_file(&user_proto)
+        .proto_path(proto_dir.path())
+        .include_imports(true)
+        .compile_descriptor_set(Duration::from_secs(3))
+        .unwrap();
+    let set = FileDescriptorSet::from_bytes(&descriptor_set).unwrap();
+    assert_eq!(set.file_count, 2);
+}
+
+#[test]
+fn test_check() {
+    let proto_dir = tempdir().unwrap();
+    let good_proto = proto_dir.path().join("good.proto");
+    let bad_proto = proto_dir.path().join("bad.proto");
+    ::std::fs::write(&good_proto, "syntax = \"proto3\"; message Good {}").unwrap();
+    ::std::fs::write(
+        &bad_proto,
+        "syntax = \"proto3\";\nmessage Bad { Missing m = 1; }\n",
+    )
+    .unwrap();
+
+    // The plugin binary is not needed.
+    let protoc = Protoc::new()
+        .proto_path(proto_dir.path())
+        .plugin_path(proto_dir.path().join("no-such-plugin"));
+    let diagnostics = protoc
+        .clone()
+        .proto_file(&good_proto)
+        .check(Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(diagnostics, vec![]);
+
+    for format in [ErrorFormat::Gcc, ErrorFormat::Msvs] {
+        let result = protoc
+            .clone()
+            .proto_file(&bad_proto)
+            .error_format(format)
+            .check(Duration::from_secs(3));
+        let Err(ErrorKind::CheckError(diagnostics)) = &result else {
+            panic!("{:?}", result);
+        };
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        let error = &diagnostics[0];
+        assert_eq!(error.severity, Severity::Error);
+        assert!(error.file.ends_with("bad.proto"), "{:?}", error);
+        assert_eq!(error.line, Some(2));
+        assert!(error.message.contains("Missing"), "{:?}", error);
+    }
+}
+
+#[test]
+fn test_long_command_line_uses_argfile() {
+    let out_dir = tempdir().unwrap();
+    let out_file_name = "argfile_test.rs";
+    let out_file_content = "// long command line";
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("argfile.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package argfile;").unwrap();
+
+    // Enough include dirs to exceed any reasonable command line length limit.
+    let include_dirs = (0..200)
+        .map(|i| {
+            let dir = proto_dir
+                .path()
+                .join(format!("some_long_include_directory_name_{:0>64}", i));
+            ::std::fs::create_dir(&dir).unwrap();
+            dir
+        })
+        .collect::<Vec<_>>();
+
+    let mut protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+    for dir in include_dirs {
+        protoc = protoc.proto_path(dir);
+    }
+    protoc
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join(out_file_name)).unwrap();
+    assert_eq!(actual_out, out_file_content);
+}
+
+#[test]
+fn test_run_with_deps() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let base_proto = proto_dir.path().join("base.proto");
+    let user_proto = proto_dir.path().join("user.proto");
+    ::std::fs::write(&base_proto, "syntax = \"proto3\"; message Base {}").unwrap();
+    ::std::fs::write(
+        &user_proto,
+        "syntax = \"proto3\"; import \"base.proto\"; message User { Base b = 1; }",
+    )
+    .unwrap();
+
+    let deps = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&user_proto)
+        .proto_path(proto_dir.path())
+        .run_with_deps(Duration::from_secs(3), |_| {
+            let mut res = CodeGeneratorResponse::default();
+            res.files.push(File {
+                name: "user.rs".to_string(),
+                content: "// user".into(),
+                ..Default::default()
+            });
+            let res_bytes = res.to_bytes().unwrap();
+            Ok(res_bytes)
+        })
+        .unwrap();
+
+    assert_eq!(deps.len(), 2);
+    assert!(deps.iter().any(|p| p.ends_with("base.proto")));
+    assert!(deps.iter().any(|p| p.ends_with("user.proto")));
+}
+
+#[test]
+fn test_run_with_deps_transitive() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    // a.proto imports b.proto, which imports c.proto.
+    ::std::fs::write(
+        proto_dir.path().join("c.proto"),
+        "syntax = \"proto3\"; message C {}",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("b.proto"),
+        "syntax = \"proto3\"; import \"c.proto\"; message B { C c = 1; }",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("a.proto"),
+        "syntax = \"proto3\"; import \"b.proto\"; message A { B b = 1; }",
+    )
+    .unwrap();
+
+    let deps = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(proto_dir.path().join("a.proto"))
+        .proto_path(proto_dir.path())
+        .run_with_deps(Duration::from_secs(3), |_| {
+            let mut res = CodeGeneratorResponse::default();
+            res.files.push(File {
+                name: "a.rs".to_string(),
+                content: "// a".into(),
+                ..Default::default()
+            });
+            Ok(res.to_bytes().unwrap())
+        })
+        .unwrap();
+
+    let mut names = deps
+        .iter()
+        .map(|p| p.file_name().unwrap().to_str().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a.proto", "b.proto", "c.proto"]);
+}
+
+#[test]
+fn test_protoc_fails_before_plugin_connects() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("broken.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; message {").unwrap();
+
+    let result = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    assert!(matches!(result, Err(ErrorKind::ProtocProcessError(_))));
+}
+
+#[test]
+fn test_proto_dir_recursive() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let write = |name: &str, content: &str| {
+        let path = proto_dir.path().join(name);
+        ::std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        ::std::fs::write(path, content).unwrap();
+    };
+    write("a.proto", "syntax = \"proto3\"; package a;");
+    write(
+        "sub/b.proto",
+        "syntax = \"proto3\"; package b; import \"a.proto\";",
+    );
+    write("sub/deeper/c.proto", "syntax = \"proto3\"; package c;");
+    write("sub/notes.txt", "not a proto file");
+    write("sub/broken.proto", "syntax = \"proto3\"; message {");
+    write("vendor/v.proto", "syntax = \"proto3\"; message {");
+    write(
+        ".protocignore",
+        "# excluded from the compilation\nvendor/\n\nsub/broken.proto\n",
+    );
+
+    let mut files_to_generate = Vec::new();
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_dir_recursive(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            files_to_generate = CodeGeneratorRequest::from_bytes(req)
+                .unwrap()
+                .file_to_generate;
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+    assert_eq!(
+        files_to_generate,
+        ["a.proto", "sub/b.proto", "sub/deeper/c.proto"]
+    );
+
+    // An empty directory is an error, like no input files.
+    let empty_dir = tempdir().unwrap();
+    let result = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_dir_recursive(empty_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    assert!(matches!(result, Err(ErrorKind::NoInput)), "{:?}", result);
+}
+
+#[test]
+fn test_duplicate_output_file() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("dup.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package dup;").unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+    let file = |content: &str, insertion_point: Option<&str>| File {
+        name: "dup.rs".to_string(),
+        insertion_point: insertion_point.map(str::to_string),
+        content: content.into(),
+        generated_code_info: None,
+    };
+
+    let res = CodeGeneratorResponse {
+        files: vec![file("// first", None), file("// second", None)],
+        ..Default::default()
+    };
+    let result = protoc
+        .clone()
+        .run(Duration::from_secs(3), |_| Ok(res.to_bytes().unwrap()));
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(&result, Err(ErrorKind::DuplicateOutputFileError(name)) if name == "dup.rs"),
+        "{:?}",
+        result
+    );
+    assert!(!out_dir.path().join("dup.rs").exists());
+
+    // The insertion point entries are not duplicates.
+    let res = CodeGeneratorResponse {
+        files: vec![
+            file("// @@protoc_insertion_point(here)\n", None),
+            file("// inserted\n", Some("here")),
+        ],
+        ..Default::default()
+    };
+    protoc
+        .run(Duration::from_secs(3), |_| Ok(res.to_bytes().unwrap()))
+        .unwrap();
+    let content = ::std::fs::read_to_string(out_dir.path().join("dup.rs")).unwrap();
+    assert!(content.contains("// inserted"), "{}", content);
+}
+
+#[cfg(feature = "zip")]
+#[test]
+fn test_out_zip() {
+    use ::std::io::Read;
+
+    let zip_dir = tempdir().unwrap();
+    let zip_path = zip_dir.path().join("out.zip");
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("zipped.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package zipped;").unwrap();
+    let mut res = CodeGeneratorResponse::default();
+    for name in ["a.rs", "nested/dir/b.rs"] {
+        res.files.push(File {
+            name: name.to_string(),
+            content: format!("// {}", name).into_bytes(),
+            ..Default::default()
+        });
+    }
+    let res_bytes = res.to_bytes().unwrap();
+
+    let protoc = Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .out_zip(&zip_path);
+    protoc
+        .clone()
+        .run(Duration::from_secs(3), |_| Ok(res_bytes))
+        .unwrap();
+
+    let mut zip = ::zip::ZipArchive::new(::std::fs::File::open(&zip_path).unwrap()).unwrap();
+    let names = zip
+        .file_names()
+        .collect::<::std::collections::BTreeSet<_>>();
+    assert_eq!(names, ["a.rs", "nested/dir/b.rs"].into());
+    let mut content = String::new();
+    zip.by_name("nested/dir/b.rs")
+        .unwrap()
+        .read_to_string(&mut content)
+        .unwrap();
+    assert_eq!(content, "// nested/dir/b.rs");
+
+    let result = protoc
+        .out_dir(zip_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    assert!(matches!(result, Err(ErrorKind::OutZipConflictError)));
+}
+
+#[test]
+fn test_resolved_protoc_path() {
+    let resolved = Protoc::new().resolved_protoc_path().unwrap();
+    assert!(resolved.is_absolute());
+    assert!(resolved.is_file());
+    // Resolving an absolute path gives the same path.
+    let resolved_again = Protoc::new()
+        .protoc_path(&resolved)
+        .resolved_protoc_path()
+        .unwrap();
+    assert_eq!(resolved_again, resolved);
+
+    let result = Protoc::new()
+        .protoc_path("protoc-which-does-not-exist")
+        .resolved_protoc_path();
+    assert!(matches!(result, Err(ErrorKind::ProtocNotFound(_))));
+}
+
+#[test]
+fn test_protoc_error_has_stderr() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("importer.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\"; import \"nonexistent.proto\";",
+    )
+    .unwrap();
+
+    let result = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    let Err(ErrorKind::ProtocProcessError(info)) = result else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert_eq!(info.code, Some(1));
+    assert_eq!(info.signal, None);
+    assert!(info.stderr.contains("nonexistent.proto"), "{}", info.stderr);
+    assert!(
+        info.command.contains("'--rust-ppbc_opt=<init-key>'"),
+        "{}",
+        info.command
+    );
+}
+
+#[test]
+fn test_callback_error_has_command() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("failing.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package failing;").unwrap();
+
+    let result = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |_| Err("failed".to_string()));
+    let Err(ErrorKind::WithCommand { command, error }) = result else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert!(matches!(*error, ErrorKind::CallbackError(_)), "{:?}", error);
+    assert!(
+        command.contains("'--rust-ppbc_opt=<init-key>'"),
+        "{}",
+        command
+    );
+}
+
+#[test]
+fn test_plugin_path_override() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("override.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package override;").unwrap();
+    let plugin_dir = tempdir().unwrap();
+    let plugin = plugin_dir.path().join("my plugin");
+    ::std::fs::copy(plugin_path(), &plugin).unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .plugin_path(&plugin);
+
+    let command = protoc.dry_run().unwrap();
+    assert_eq!(
+        command.args[0].to_str().unwrap(),
+        format!("--plugin=protoc-gen-rust-ppbc={}", plugin.display())
+    );
+    protoc
+        .clone()
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "override.rs", "// override"))
+        })
+        .unwrap();
+    assert_eq!(
+        ::std::fs::read_to_string(out_dir.path().join("override.rs")).unwrap(),
+        "// override"
+    );
+
+    // The missing or non-executable binary is reported before running protoc.
+    let missing = plugin_dir.path().join("missing");
+    let result = protoc
+        .clone()
+        .plugin_path(&missing)
+        .run(Duration::from_secs(3), |_| unreachable!());
+    assert!(
+        matches!(&result, Err(ErrorKind::PluginNotFound(path)) if path == &missing),
+        "{:?}",
+        result
+    );
+    #[cfg(unix)]
+    {
+        use ::std::os::unix::fs::PermissionsExt;
+        ::std::fs::set_permissions(&plugin, ::std::fs::Permissions::from_mode(0o644)).unwrap();
+        let result = protoc.run(Duration::from_secs(3), |_| unreachable!());
+        assert!(
+            matches!(&result, Err(ErrorKind::PluginNotExecutable(path)) if path == &plugin),
+            "{:?}",
+            result
+        );
+    }
+}
+
+#[test]
+fn test_raw_proto_path_args() {
+    // main.proto in the first dir imports dep.proto in the second dir.
+    let out_dir = tempdir().unwrap();
+    let main_dir = tempdir().unwrap();
+    let dep_dir = tempdir().unwrap();
+    let main_proto = main_dir.path().join("main.proto");
+    ::std::fs::write(
+        &main_proto,
+        "syntax = \"proto3\"; import \"dep.proto\"; message Main { Dep dep = 1; }",
+    )
+    .unwrap();
+    ::std::fs::write(
+        dep_dir.path().join("dep.proto"),
+        "syntax = \"proto3\"; message Dep {}",
+    )
+    .unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&main_proto)
+        .proto_path(main_dir.path())
+        .args(["-I", dep_dir.path().to_str().unwrap()])
+        .arg(format!("-I{}", main_dir.path().display()))
+        .arg("--fatal_warnings");
+
+    // The raw proto paths are merged after the builder's ones without the duplicate.
+    let command = protoc.dry_run().unwrap();
+    let args = command
+        .args
+        .iter()
+        .map(|arg| arg.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        args[3..],
+        [
+            "--fatal_warnings".to_string(),
+            format!("--proto_path={}", main_dir.path().display()),
+            format!("--proto_path={}", dep_dir.path().display()),
+            main_proto.display().to_string(),
+        ]
+    );
+
+    protoc
+        .run(Duration::from_secs(3), |req| {
+            let req = CodeGeneratorRequest::from_bytes(req).unwrap();
+            assert_eq!(req.proto_file_names().unwrap(), ["dep.proto", "main.proto"]);
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+}
+
+#[cfg(unix)]
+#[test]
+fn test_runner() {
+    use ::std::os::unix::fs::PermissionsExt;
+
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("wrapped.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package wrapped;").unwrap();
+    // A wrapper which logs its call and re-execs its arguments.
+    let wrapper_dir = tempdir().unwrap();
+    let wrapper = wrapper_dir.path().join("wrapper.sh");
+    let log = wrapper_dir.path().join("wrapper.log");
+    ::std::fs::write(
+        &wrapper,
+        format!(
+            "#!/bin/sh\necho \"$1\" >> '{}'\nexec \"$@\"\n",
+            log.display()
+        ),
+    )
+    .unwrap();
+    ::std::fs::set_permissions(&wrapper, ::std::fs::Permissions::from_mode(0o755)).unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .runner(move |command| {
+            let mut wrapped = ::std::process::Command::new(&wrapper);
+            wrapped.arg(&command.program).args(&command.args);
+            wrapped
+        });
+
+    protoc
+        .clone()
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "wrapped.rs", "// wrapped"))
+        })
+        .unwrap();
+    assert_eq!(
+        ::std::fs::read_to_string(out_dir.path().join("wrapped.rs")).unwrap(),
+        "// wrapped"
+    );
+    assert_eq!(::std::fs::read_to_string(&log).unwrap(), "protoc\n");
+
+    // The plugin binary missing at the mapped path is reported as such, before running protoc.
+    let missing = wrapper_dir.path().join("missing");
+    let plugin = plugin_path().to_path_buf();
+    let result = protoc
+        .path_mapper({
+            let missing = missing.clone();
+            move |path| {
+                if path == plugin {
+                    missing.clone()
+                } else {
+                    path.to_path_buf()
+                }
+            }
+        })
+        .run(Duration::from_secs(3), |_| unreachable!());
+    assert!(
+        matches!(&result, Err(ErrorKind::PluginNotFoundInRunner(path)) if path == &missing),
+        "{:?}",
+        result
+    );
+    assert_eq!(::std::fs::read_to_string(&log).unwrap(), "protoc\n");
+}
+
+#[test]
+fn test_run_with_child() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("child.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package child;").unwrap();
+
+    let mut pid = None;
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_with_child(
+            Duration::from_secs(3),
+            |child| pid = Some(child.id()),
+            |req| Ok(test_call_wrapper_inner(req, "child.rs", "// child")),
+        )
+        .unwrap();
+    assert!(pid.is_some_and(|pid| pid > 0), "{:?}", pid);
+}
+
+#[test]
+fn test_include_std_types() {
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("std.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\"; import \"google/protobuf/empty.proto\"; \
+         message Std { google.protobuf.Empty empty = 1; }",
+    )
+    .unwrap();
+    // A copy of protoc without its include dir next to it, which protoc would find by itself
+    // otherwise.
+    let protoc_dir = tempdir().unwrap();
+    let protoc_path = protoc_dir.path().join("protoc");
+    ::std::fs::copy(Protoc::new().resolved_protoc_path().unwrap(), &protoc_path).unwrap();
+    let protoc = Protoc::new()
+        .protoc_path(&protoc_path)
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    let result = protoc
+        .clone()
+        .compile_descriptor_set(Duration::from_secs(3));
+    assert!(
+        matches!(&result, Err(ErrorKind::ProtocProcessError(info))
+            if info.stderr.contains("google/protobuf/empty.proto")),
+        "{:?}",
+        result
+    );
+
+    let protoc = protoc.include_std_types(true);
+    let command = protoc.dry_run().unwrap();
+    let include_dir = command
+        .args
+        .iter()
+        .filter_map(|arg| arg.to_str()?.strip_prefix("--proto_path="))
+        .next_back()
+        .unwrap();
+    assert!(
+        ::std::path::Path::new(include_dir)
+            .join("google/protobuf/empty.proto")
+            .is_file()
+    );
+    let descriptor_set = protoc
+        .include_imports(true)
+        .compile_descriptor_set(Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(
+        FileDescriptorSet::from_bytes(&descriptor_set)
+            .unwrap()
+            .file_count,
+        2
+    );
+}
+
+#[test]
+fn test_error_format() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("broken.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\";\nmessage A { int32 x = 1 }\n",
+    )
+    .unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+    let stderr = |protoc: Protoc| match protoc.run(Duration::from_secs(3), |_| unreachable!()) {
+        Err(ErrorKind::ProtocProcessError(info)) => info.stderr,
+        result => panic!("unexpected result: {:?}", result),
+    };
+
+    let gcc = stderr(protoc.clone().error_format(ErrorFormat::Gcc));
+    assert!(gcc.contains("broken.proto:2:25: "), "{}", gcc);
+    let msvs = stderr(protoc.error_format(ErrorFormat::Msvs));
+    assert!(
+        msvs.contains("broken.proto(2) : error in column=25: "),
+        "{}",
+        msvs
+    );
+}
+
+#[test]
+fn test_run_with_output() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("user.proto");
+    // The import is unused, which protoc warns about.
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\";\nimport \"base.proto\";\nmessage User {}\n",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("base.proto"),
+        "syntax = \"proto3\"; message Base {}",
+    )
+    .unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    for format in [ErrorFormat::Gcc, ErrorFormat::Msvs] {
+        let output = protoc
+            .clone()
+            .error_format(format)
+            .run_with_output(Duration::from_secs(3), |_| {
+                let res = CodeGeneratorResponse {
+                    files: vec![File {
+                        name: "user.rs".to_string(),
+                        content: b"// user".to_vec(),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                };
+                Ok(res.to_bytes().unwrap())
+            })
+            .unwrap();
+        assert_eq!(
+            output.files,
+            vec![FileSummary {
+                path: out_dir.path().join("user.rs"),
+                kind: FileKind::New,
+            }]
+        );
+        assert_eq!(output.warnings.len(), 1, "{:?}", output);
+        let warning = &output.warnings[0];
+        assert!(warning.file.ends_with("user.proto"), "{:?}", warning);
+        assert_eq!(warning.line, Some(2));
+        assert!(warning.message.contains("base.proto"), "{:?}", warning);
+        assert!(output.stderr.contains("base.proto"), "{:?}", output);
+        assert_eq!(
+            warning.to_string(),
+            format!(
+                "{}:2:{}: warning: {}",
+                warning.file,
+                warning.column.unwrap(),
+                warning.message
+            )
+        );
+    }
+}
+
+#[test]
+fn test_chain() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; message Main {}").unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    protoc
+        .clone()
+        .run(
+            Duration::from_secs(3),
+            chain(
+                |req| Ok(test_call_wrapper_inner(req, "messages.rs", "// messages")),
+                |req| Ok(test_call_wrapper_inner(req, "services.rs", "// services")),
+            ),
+        )
+        .unwrap();
+    assert_eq!(
+        ::std::fs::read_to_string(out_dir.path().join("messages.rs")).unwrap(),
+        "// messages"
+    );
+    assert_eq!(
+        ::std::fs::read_to_string(out_dir.path().join("services.rs")).unwrap(),
+        "// services"
+    );
+
+    let result = protoc.run(
+        Duration::from_secs(3),
+        chain(
+            |req| Ok(test_call_wrapper_inner(req, "main.rs", "// a")),
+            |req| Ok(test_call_wrapper_inner(req, "main.rs", "// b")),
+        ),
+    );
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(&result, Err(ErrorKind::CallbackError(msg)) if msg.contains("main.rs")),
+        "{:?}",
+        result
+    );
+    assert!(!out_dir.path().join("main.rs").exists());
+}
+
+#[test]
+fn test_request_size_hint() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; message Main {}").unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    let mut request_size = 0;
+    let mut proto_files = Vec::new();
+    protoc
+        .clone()
+        .run(Duration::from_secs(3), |req| {
+            request_size = req.len();
+            proto_files = CodeGeneratorRequest::from_bytes(req).unwrap().proto_file;
+            Ok(test_call_wrapper_inner(req, "main.rs", "// main"))
+        })
+        .unwrap();
+
+    // The request is read in full whether the hint is accurate or not.
+    for hint in [
+        1,
+        request_size - 1,
+        request_size,
+        request_size + 1,
+        usize::MAX,
+    ] {
+        let mut received = Vec::new();
+        protoc
+            .clone()
+            .request_size_hint(hint)
+            .run(Duration::from_secs(3), |req| {
+                received = CodeGeneratorRequest::from_bytes(req).unwrap().proto_file;
+                Ok(test_call_wrapper_inner(req, "main.rs", "// main"))
+            })
+            .unwrap();
+        assert_eq!(received, proto_files, "hint: {}", hint);
+    }
+}
+
+#[test]
+fn test_supported_features_from_request() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto3_file = proto_dir.path().join("proto3.proto");
+    let editions_file = proto_dir.path().join("editions.proto");
+    ::std::fs::write(
+        &proto3_file,
+        "syntax = \"proto3\"; message A { optional int32 a = 1; }",
+    )
+    .unwrap();
+    ::std::fs::write(&editions_file, "edition = \"2023\"; message B {}").unwrap();
+
+    // Supports the editions only if the request has an editions file.
+    let generate = |req_bytes: &[u8]| {
+        let req = CodeGeneratorRequest::from_bytes(req_bytes).unwrap();
+        let has_editions = req.proto_file.iter().any(|bytes| {
+            FileDescriptorLite::from_bytes(bytes)
+                .unwrap()
+                .syntax
+                .as_deref()
+                == Some("editions")
+        });
+        let builder = ResponseBuilder::new().supported_features(FEATURE_PROTO3_OPTIONAL);
+        match has_editions {
+            true => builder.support_editions(1000, 1000),
+            false => builder,
+        }
+        .build_bytes()
+    };
+    for (proto_file, features, editions) in [
+        (&proto3_file, FEATURE_PROTO3_OPTIONAL, None),
+        (
+            &editions_file,
+            FEATURE_PROTO3_OPTIONAL | FEATURE_SUPPORTS_EDITIONS,
+            Some(1000),
+        ),
+    ] {
+        let response = Mutex::new(None);
+        Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_file(proto_file)
+            .proto_path(proto_dir.path())
+            .run(Duration::from_secs(3), |req_bytes| {
+                let bytes = generate(req_bytes);
+                *response.lock().unwrap() = Some(bytes.clone());
+                Ok(bytes)
+            })
+            .unwrap();
+        let response = response.into_inner().unwrap().unwrap();
+        let response = CodeGeneratorResponse::from_bytes(&response).unwrap();
+        assert_eq!(response.supported_features, Some(features));
+        assert_eq!(response.minimum_edition, editions);
+        assert_eq!(response.maximum_edition, editions);
+    }
+
+    // protoc rejects the editions file without the support.
+    let result = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&editions_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            Ok(ResponseBuilder::new().build_bytes())
+        });
+    assert!(
+        matches!(&result, Err(ErrorKind::ProtocProcessError(info)) if info.stderr.contains("editions")),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_run_typed() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; message Main {}").unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+    let read = |name: &str| ::std::fs::read_to_string(out_dir.path().join(name)).unwrap();
+
+    protoc
+        .clone()
+        .run_typed(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "bytes.rs", "// bytes"))
+        })
+        .unwrap();
+    assert_eq!(read("bytes.rs"), "// bytes");
+
+    protoc
+        .clone()
+        .run_typed(Duration::from_secs(3), |_| {
+            Ok(CodeGeneratorResponse {
+                files: vec![File {
+                    name: "typed.rs".to_string(),
+                    content: b"// typed".to_vec(),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        })
+        .unwrap();
+    assert_eq!(read("typed.rs"), "// typed");
+
+    protoc
+        .clone()
+        .run_typed(Duration::from_secs(3), |_| {
+            Ok(ResponseBuilder::new().file("builder.rs", "// builder"))
+        })
+        .unwrap();
+    assert_eq!(read("builder.rs"), "// builder");
+
+    protoc
+        .clone()
+        .run_typed(Duration::from_secs(3), |_| {
+            Ok(vec![
+                ("a.rs".to_string(), "// a".to_string()),
+                ("b.rs".to_string(), "// b".to_string()),
+            ])
+        })
+        .unwrap();
+    assert_eq!(read("a.rs"), "// a");
+    assert_eq!(read("b.rs"), "// b");
+
+    // The closure's error and the encoding error are returned as is.
+    let result = protoc
+        .clone()
+        .run_typed::<_, Vec<u8>>(Duration::from_secs(3), |_| Err(ErrorKind::NoInput))
+        .map_err(ErrorKind::into_inner);
+    assert!(matches!(result, Err(ErrorKind::NoInput)), "{:?}", result);
+    struct Unencodable;
+    impl IntoResponseBytes for Unencodable {
+        fn into_response_bytes(self) -> Result<Vec<u8>, ErrorKind> {
+            Err(ErrorKind::FileNameError)
+        }
+    }
+    let result = protoc
+        .run_typed(Duration::from_secs(3), |_| Ok(Unencodable))
+        .map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(result, Err(ErrorKind::FileNameError)),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_plugin_connect_failure() {
+    use ::std::process::{Command, Stdio};
+
+    // The init key starts with the protocol version, which the plugin binary checks first.
+    let (init_key, _server) = IpcServer::start().unwrap();
+    let (version, _) = init_key.split_once(':').unwrap();
+    for bogus_key in ["bogus", "unix:/nonexistent/socket"] {
+        let req = CodeGeneratorRequest {
+            file_to_generate: vec!["main.proto".to_string()],
+            parameter: Some(format!("{}:{}", version, bogus_key)),
+            ..Default::default()
+        };
+        let mut plugin = Command::new(plugin_path())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .unwrap();
+        plugin
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(&req.to_bytes().unwrap())
+            .unwrap();
+        let output = plugin.wait_with_output().unwrap();
+        // The failure is reported to protoc in the response, rather than by the exit status.
+        assert!(output.status.success(), "{:?}", output);
+        let error = CodeGeneratorResponse::from_bytes(&output.stdout)
+            .unwrap()
+            .error
+            .unwrap();
+        assert!(
+            error.starts_with("failed to connect back to the host process: "),
+            "{}",
+            error
+        );
+        assert!(
+            error.ends_with("the controlling library may have exited"),
+            "{}",
+            error
+        );
+    }
+}
+
+#[test]
+fn test_plugin_connect_error() {
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; message Main {}").unwrap();
+    // A runner which breaks the init key passed to the plugin binary.
+    let result = Protoc::new()
+        .out_dir(tempdir().unwrap().path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .runner(|command| {
+            let mut protoc = ::std::process::Command::new(&command.program);
+            for arg in &command.args {
+                match arg.to_str().unwrap().split_once("_opt=") {
+                    Some((flag, init_key)) => {
+                        let (version, _) = init_key.split_once(':').unwrap();
+                        protoc.arg(format!("{}_opt={}:bogus", flag, version))
+                    }
+                    None => protoc.arg(arg),
+                };
+            }
+            protoc.envs(command.env.iter().map(|(k, v)| (k, v)));
+            protoc
+        })
+        .run(Duration::from_secs(3), |_| unreachable!());
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(&result, Err(ErrorKind::PluginConnectError(message))
+            if message.starts_with("failed to connect back to the host process")),
+        "{:?}",
+        result
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_protoc_parse_timeout() {
+    // A fake protoc which is slow to parse the input before running the plugin.
+    let (_script_dir, fake_protoc) = fake_protoc("#!/bin/sh\nsleep 0.5\nprotoc \"$@\"\n");
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("parse.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package parse;").unwrap();
+    let protoc = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .out_dir(tempdir().unwrap().path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    let result = protoc
+        .clone()
+        .protoc_parse_timeout(Duration::from_millis(100))
+        .run(Duration::from_secs(30), |_| unreachable!());
+    let Err(ErrorKind::ProtocParseTimeout { elapsed, .. }) = result else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert!(elapsed >= Duration::from_millis(100), "{:?}", elapsed);
+
+    // A parse timeout long enough. The run's timeout, shorter than the parse, applies only
+    // after the request arrives.
+    protoc
+        .protoc_parse_timeout(Duration::from_secs(30))
+        .run(Duration::from_millis(300), |req| {
+            assert_eq!(
+                CodeGeneratorRequest::from_bytes(req)
+                    .unwrap()
+                    .proto_file
+                    .len(),
+                1
+            );
+            Ok(Vec::new())
+        })
+        .unwrap();
+}
+
+#[test]
+fn test_dry_run() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("dry run.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\";").unwrap();
+
+    let command = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .dry_run()
+        .unwrap();
+
+    assert_eq!(
+        command.program,
+        Protoc::new().resolved_protoc_path().unwrap()
+    );
+    assert_eq!(command.cwd, ::std::env::current_dir().unwrap());
+    let args = command
+        .args
+        .iter()
+        .map(|arg| arg.to_str().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        args,
+        [
+            format!("--plugin=protoc-gen-rust-ppbc={}", plugin_path().display()),
+            format!("--rust-ppbc_out={}", out_dir.path().display()),
+            "--rust-ppbc_opt=<init-key>".to_string(),
+            format!("--proto_path={}", proto_dir.path().display()),
+            proto_file.display().to_string(),
+        ]
+    );
+    let shell = command.to_shell_string();
+    assert!(
+        shell.contains(&format!("--rust-ppbc_out={}", out_dir.path().display())),
+        "{}",
+        shell
+    );
+    assert!(
+        shell.ends_with(&format!("'{}'", proto_file.display())),
+        "{}",
+        shell
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_with_plugin() {
+    use ::std::os::unix::fs::PermissionsExt;
+
+    // An external plugin which ignores the request and generates `extra.txt` with `extra`.
+    let script_dir = tempdir().unwrap();
+    let extra_plugin = script_dir.path().join("protoc-gen-extra");
+    ::std::fs::write(
+        &extra_plugin,
+        "#!/bin/sh\ncat >/dev/null\nprintf '\\172\\022\\012\\011extra.txt\\172\\005extra'\n",
+    )
+    .unwrap();
+    ::std::fs::set_permissions(&extra_plugin, ::std::fs::Permissions::from_mode(0o755)).unwrap();
+    let out_dir = tempdir().unwrap();
+    let extra_out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("chained.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package chained;").unwrap();
+    let new_protoc = || {
+        Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_file(&proto_file)
+            .proto_path(proto_dir.path())
+    };
+
+    new_protoc()
+        .with_plugin("extra", &extra_plugin, extra_out_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "chained.rs", "// chained"))
+        })
+        .unwrap();
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join("chained.rs")).unwrap();
+    assert_eq!(actual_out, "// chained");
+    let extra_out = ::std::fs::read_to_string(extra_out_dir.path().join("extra.txt")).unwrap();
+    assert_eq!(extra_out, "extra");
+
+    let result = new_protoc()
+        .with_plugin("rust-ppbc", &extra_plugin, extra_out_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called")
+        });
+    assert!(matches!(result, Err(ErrorKind::PluginNameConflict(name)) if name == "rust-ppbc"));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_large_stderr_does_not_deadlock() {
+    // A fake protoc which prints 1 MB into the stderr before running the real protoc.
+    let (_script_dir, fake_protoc) =
+        fake_protoc("#!/bin/sh\nyes 'noise' | head -c 1048576 >&2\nexec protoc \"$@\"\n");
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("noisy.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package noisy;").unwrap();
+
+    let warnings = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_with_warnings(Duration::from_secs(10), |req| {
+            Ok(test_call_wrapper_inner(req, "noisy.rs", "// noisy"))
+        })
+        .unwrap();
+    assert!(warnings.is_empty());
+    assert!(out_dir.path().join("noisy.rs").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_plugin_never_connects() {
+    // A fake protoc which never invokes the plugin.
+    let (_script_dir, fake_protoc) = fake_protoc("#!/bin/sh\nexec sleep 10\n");
+
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file("unused.proto")
+        .run(Duration::from_millis(200), |_| {
+            panic!("the closure must not be called");
+        });
+    assert!(
+        matches!(
+            result,
+            Err(ErrorKind::Timeout {
+                phase: Phase::AwaitingPluginHandshake,
+                ..
+            })
+        ),
+        "{:?}",
+        result
+    );
+}
+
+#[cfg(unix)]
+#[test]
+fn test_timeout_has_stderr() {
+    // A fake protoc which prints something and never invokes the plugin.
+    let (_script_dir, fake_protoc) =
+        fake_protoc("#!/bin/sh\necho 'still parsing' >&2\nexec sleep 10\n");
+
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file("unused.proto")
+        .run(Duration::from_millis(500), |_| {
+            panic!("the closure must not be called");
+        });
+    match result {
+        Err(ErrorKind::Timeout {
+            phase: Phase::AwaitingPluginHandshake,
+            elapsed,
+            stderr,
+            ..
+        }) => {
+            assert!(elapsed >= Duration::from_millis(500));
+            assert_eq!(stderr, "still parsing\n");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_timeout_running_closure() {
+    let proto_dir = tempdir().unwrap();
+    let proto_file = NamedTempFile::new_in(proto_dir.path()).unwrap();
+    proto_file
+        .as_file()
+        .write_all(b"syntax = \"proto3\"; package empty;")
+        .unwrap();
+
+    let result = Protoc::new()
+        .protoc_path("protoc")
+        .proto_file(proto_file.path())
+        .proto_path(proto_dir.path())
+        .run_to_memory(Duration::from_secs(1), |req| {
+            ::std::thread::sleep(Duration::from_millis(1500));
+            Ok(test_call_wrapper_inner(req, "slow.rs", "// slow"))
+        });
+    match result {
+        Err(ErrorKind::Timeout {
+            phase: Phase::RunningClosure,
+            elapsed,
+            ..
+        }) => assert!(elapsed >= Duration::from_millis(1500), "{:?}", elapsed),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_timeout_whole_run() {
+    // A fake protoc which is slow both before and after running the plugin, each within the
+    // timeout but not together.
+    let (_script_dir, fake_protoc) =
+        fake_protoc("#!/bin/sh\nsleep 0.6\nprotoc \"$@\"\nsleep 0.6\n");
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("whole.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package whole;").unwrap();
+
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_to_memory(Duration::from_secs(1), |req| {
+            Ok(test_call_wrapper_inner(req, "whole.rs", "// whole"))
+        });
+    match result {
+        Err(ErrorKind::Timeout {
+            phase: Phase::AwaitingProtocExit,
+            elapsed,
+            ..
+        }) => assert!(elapsed >= Duration::from_secs(1), "{:?}", elapsed),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_plugin_not_invoked() {
+    // A fake protoc which succeeds without invoking the plugin.
+    let (_script_dir, fake_protoc) = fake_protoc("#!/bin/sh\nexit 0\n");
+
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file("unused.proto")
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(result, Err(ErrorKind::PluginNotInvoked)),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_create_out_dir() {
+    let out_root = tempdir().unwrap();
+    let out_dir = out_root.path().join("nested").join("out");
+    let out_file_name = "empty_test.rs";
+    let out_file_content = "This\nis\na\ntest";
+    let proto_dir = tempdir().unwrap();
+    let proto_file = NamedTempFile::new_in(proto_dir.path()).unwrap();
+    proto_file
+        .as_file()
+        .write_all(b"syntax = \"proto3\"; package empty;")
+        .unwrap();
+
+    Protoc::new()
+        .protoc_path("protoc")
+        .out_dir(&out_dir)
+        .proto_file(proto_file.path())
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.join(out_file_name)).unwrap();
+    assert_eq!(actual_out, out_file_content);
+}
+
+#[test]
+fn test_create_out_dir_disabled() {
+    let out_root = tempdir().unwrap();
+    let out_dir = out_root.path().join("missing");
+
+    let result = Protoc::new()
+        .protoc_path("protoc")
+        .out_dir(&out_dir)
+        .create_out_dir(false)
+        .proto_file("unused.proto")
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    match result {
+        Err(ErrorKind::OutDirNotFound(path)) => assert_eq!(path, out_dir),
+        other => panic!("unexpected result: {:?}", other),
+    }
+    assert!(!out_dir.exists());
+}
+
+#[test]
+fn test_create_out_dir_fails() {
+    // A regular file can't be the parent of the output directory.
+    let out_root = tempdir().unwrap();
+    let blocker = out_root.path().join("file");
+    ::std::fs::write(&blocker, "").unwrap();
+    let out_dir = blocker.join("out");
+
+    let result = Protoc::new()
+        .protoc_path("protoc")
+        .out_dir(&out_dir)
+        .proto_file("unused.proto")
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    match result {
+        Err(ErrorKind::OutDirCreateError { path, .. }) => assert_eq!(path, out_dir),
+        other => panic!("unexpected result: {:?}", other),
+    }
+}
+
+#[test]
+fn test_on_event() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("events.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package events;").unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_clone = Arc::clone(&events);
+    protoc
+        .clone()
+        .on_event(move |event| events_clone.lock().unwrap().push(event))
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "events.rs", "// events"))
+        })
+        .unwrap();
+    let events = ::std::mem::take(&mut *events.lock().unwrap());
+    assert_eq!(events.len(), 7, "{:?}", events);
+    assert_eq!(events[0], RunEvent::Spawned);
+    assert_eq!(events[1], RunEvent::PluginConnected);
+    assert!(matches!(
+        events[2],
+        RunEvent::RequestReceived { bytes, proto_files: 1 } if bytes > 0
+    ));
+    assert_eq!(events[3], RunEvent::ClosureStarted);
+    assert!(matches!(events[4], RunEvent::ClosureFinished { .. }));
+    assert!(matches!(events[5], RunEvent::ResponseSent { bytes } if bytes > 0));
+    assert!(matches!(events[6], RunEvent::ProtocExited(status) if status.success()));
+
+    // On errors, the events up to the failure point are reported.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_clone = Arc::clone(&events);
+    let result = protoc
+        .on_event(move |event| events_clone.lock().unwrap().push(event))
+        .run(Duration::from_secs(3), |_| Err("failed".to_string()));
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(matches!(result, Err(ErrorKind::CallbackError(_))));
+    let events = events.lock().unwrap();
+    assert_eq!(events.len(), 5, "{:?}", events);
+    assert!(matches!(
+        events.last(),
+        Some(RunEvent::ClosureFinished { .. })
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_on_event_protoc_output() {
+    // A fake protoc which prints to the both streams and fails.
+    let (_script_dir, fake_protoc) = fake_protoc("#!/bin/sh\necho out\necho err >&2\nexit 1\n");
+
+    // The outputs are only reported to the handler, and still attached to the error.
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let events_clone = Arc::clone(&events);
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file("unused.proto")
+        .on_event(move |event| events_clone.lock().unwrap().push(event))
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    match result {
+        Err(ErrorKind::ProtocProcessError(info)) => {
+            assert_eq!(info.stdout, "out\n");
+            assert_eq!(info.stderr, "err\n");
+        }
+        other => panic!("unexpected result: {:?}", other),
+    }
+    let events = events.lock().unwrap();
+    assert!(events.contains(&RunEvent::ProtocStdout("out\n".to_string())));
+    assert!(events.contains(&RunEvent::ProtocStderr("err\n".to_string())));
+}
+
+#[test]
+fn test_request_guard() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("guarded.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package guarded;").unwrap();
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path());
+    let guard_with_limit = |limit: usize| {
+        move |info: &RequestInfo| {
+            assert!(info.byte_len > 0);
+            if info.proto_file_count > limit {
+                Err(format!("too many proto files: {}", info.proto_file_count))
+            } else {
+                Ok(())
+            }
+        }
+    };
+
+    let called = Arc::new(Mutex::new(false));
+    let called_clone = Arc::clone(&called);
+    let result =
+        protoc
+            .clone()
+            .request_guard(guard_with_limit(0))
+            .run(Duration::from_secs(3), move |req| {
+                *called_clone.lock().unwrap() = true;
+                Ok(test_call_wrapper_inner(req, "guarded.rs", "// guarded"))
+            });
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(&result, Err(ErrorKind::Rejected(msg)) if msg == "too many proto files: 1"),
+        "{:?}",
+        result
+    );
+    assert!(!*called.lock().unwrap());
+
+    protoc
+        .request_guard(guard_with_limit(1))
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "guarded.rs", "// guarded"))
+        })
+        .unwrap();
+    assert!(out_dir.path().join("guarded.rs").exists());
+}
+
+#[test]
+fn test_verify_output() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("verified.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package verified;").unwrap();
+    let verified = Arc::new(Mutex::new(Vec::new()));
+    let verified_clone = Arc::clone(&verified);
+    let protoc = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .verify_output(move |path, content| {
+            verified_clone.lock().unwrap().push(path.to_path_buf());
+            match content.ends_with(b"\n") {
+                true => Ok(()),
+                false => Err("no trailing newline".to_string()),
+            }
+        });
+
+    protoc
+        .clone()
+        .run(Duration::from_secs(3), |_| {
+            Ok(ResponseBuilder::new()
+                .file("a.rs", "// a\n// @@protoc_insertion_point(x)\n")
+                .file("b.rs", "// b\n")
+                .file_with_insertion("a.rs", "x", "// inserted\n")
+                .build_bytes())
+        })
+        .unwrap();
+    assert_eq!(
+        *verified.lock().unwrap(),
+        [out_dir.path().join("a.rs"), out_dir.path().join("b.rs")]
+    );
+
+    verified.lock().unwrap().clear();
+    let result = protoc.run(Duration::from_secs(3), |_| {
+        Ok(ResponseBuilder::new()
+            .file("c.rs", "// c")
+            .file("d.rs", "// d\n")
+            .build_bytes())
+    });
+    assert!(
+        matches!(&result, Err(ErrorKind::VerificationFailed { file, message })
+            if *file == out_dir.path().join("c.rs") && message == "no trailing newline"),
+        "{:?}",
+        result
+    );
+    // Stops at the first failure, and removes the rejected outputs.
+    assert_eq!(*verified.lock().unwrap(), [out_dir.path().join("c.rs")]);
+    assert!(!out_dir.path().join("c.rs").exists());
+    assert!(!out_dir.path().join("d.rs").exists());
+    assert!(out_dir.path().join("a.rs").exists());
+}
+
+#[test]
+fn test_reproducible() {
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("reproducible.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\"; package reproducible; message A { int32 a = 1; }",
+    )
+    .unwrap();
+
+    let run = || {
+        let out_dir = tempdir().unwrap();
+        Protoc::new()
+            .out_dir(out_dir.path())
+            .proto_file(&proto_file)
+            .proto_path(proto_dir.path())
+            .reproducible(true)
+            .run(Duration::from_secs(3), |req| {
+                Ok(test_call_wrapper_inner(
+                    req,
+                    "reproducible.rs",
+                    "// reproducible",
+                ))
+            })
+            .unwrap();
+        let output = ::std::fs::read(out_dir.path().join("reproducible.rs")).unwrap();
+        let descriptor_set = Protoc::new()
+            .proto_file(&proto_file)
+            .proto_path(proto_dir.path())
+            .include_source_info(true)
+            .reproducible(true)
+            .compile_descriptor_set(Duration::from_secs(3))
+            .unwrap();
+        (output, descriptor_set)
+    };
+    assert_eq!(run(), run());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_reproducible_sets_source_date_epoch() {
+    // A fake protoc which records SOURCE_DATE_EPOCH and then runs the real protoc.
+    let (script_dir, fake_protoc) = fake_protoc(
+        "#!/bin/sh\necho \"$SOURCE_DATE_EPOCH\" > \"$(dirname \"$0\")/env.txt\"\nexec protoc \"$@\"\n",
+    );
+    let env_file = script_dir.path().join("env.txt");
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("input.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package input;").unwrap();
+
+    Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .reproducible(true)
+        .compile_descriptor_set(Duration::from_secs(3))
+        .unwrap();
+    let expected = ::std::env::var("SOURCE_DATE_EPOCH").unwrap_or_else(|_| "0".to_string());
+    assert_eq!(
+        ::std::fs::read_to_string(&env_file).unwrap().trim(),
+        expected
+    );
+}
+
+#[test]
+fn test_run_cancellable() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("cancelled.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package cancelled;").unwrap();
+
+    let token = CancellationToken::new();
+    let canceller = {
+        let token = token.clone();
+        ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        })
+    };
+    let result = Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_cancellable(Duration::from_secs(10), token, |req| {
+            // Still sleeping when cancelled.
+            ::std::thread::sleep(Duration::from_millis(300));
+            Ok(test_call_wrapper_inner(req, "cancelled.rs", "// cancelled"))
+        });
+    canceller.join().unwrap();
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(matches!(result, Err(ErrorKind::Cancelled)));
+    assert!(!out_dir.path().join("cancelled.rs").exists());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_run_cancellable_while_waiting_for_plugin() {
+    use ::std::time::Instant;
+
+    // A fake protoc which never invokes the plugin.
+    let (_script_dir, fake_protoc) = fake_protoc("#!/bin/sh\nexec sleep 10\n");
+
+    let token = CancellationToken::new();
+    let canceller = {
+        let token = token.clone();
+        ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(100));
+            token.cancel();
+        })
+    };
+    let started = Instant::now();
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file("unused.proto")
+        .run_cancellable(Duration::from_secs(10), token, |_| {
+            panic!("the closure must not be called");
+        });
+    canceller.join().unwrap();
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(matches!(result, Err(ErrorKind::Cancelled)));
+    assert!(started.elapsed() < Duration::from_secs(5));
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn test_run_cancellable_kills_process_group() {
+    // A fake protoc which spawns a long-running child, in place of a plugin, and waits for it.
+    let (script_dir, fake_protoc) = fake_protoc(
+        "#!/bin/sh
+sleep 10 &
+echo $! > \"$(dirname \"$0\")/pid.txt\"
+wait
+",
+    );
+    let pid_file = script_dir.path().join("pid.txt");
+
+    let token = CancellationToken::new();
+    let canceller = {
+        let token = token.clone();
+        ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(200));
+            token.cancel();
+        })
+    };
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file("unused.proto")
+        .run_cancellable(Duration::from_secs(10), token, |_| {
+            panic!("the closure must not be called");
+        });
+    canceller.join().unwrap();
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(matches!(result, Err(ErrorKind::Cancelled)));
+
+    // The child of protoc has been killed too. It's either reaped, or a zombie until it's reaped
+    // by its new parent.
+    let pid = ::std::fs::read_to_string(&pid_file).unwrap();
+    let stat = ::std::fs::read_to_string(format!("/proc/{}/stat", pid.trim()));
+    if let Ok(stat) = stat {
+        let state = stat.rsplit(')').next().unwrap().split_whitespace().next();
+        assert_eq!(state, Some("Z"), "{}", stat);
+    }
+}
+
+#[cfg(unix)]
+#[test]
+fn test_retry_policy() {
+    // A fake protoc which silently fails the first invocation, and runs the real protoc after that.
+    let (script_dir, fake_protoc) = fake_protoc(
+        "#!/bin/sh\ncount_file=\"$(dirname \"$0\")/count.txt\"\necho >> \"$count_file\"\nif [ $(wc -l < \"$count_file\") -eq 1 ]; then exit 1; fi\nexec protoc \"$@\"\n",
+    );
+    let count_file = script_dir.path().join("count.txt");
+    let invocations = || {
+        ::std::fs::read_to_string(&count_file)
+            .unwrap()
+            .lines()
+            .count()
+    };
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("input.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package input;").unwrap();
+    let out_dir = tempdir().unwrap();
+    let protoc = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .retry_policy(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(10),
+        });
+
+    protoc
+        .clone()
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "retried.rs", "// retried"))
+        })
+        .unwrap();
+    assert_eq!(invocations(), 2);
+    assert!(out_dir.path().join("retried.rs").exists());
+
+    // The closure errors are never retried.
+    let result = protoc.run(Duration::from_secs(3), |_| Err("failed".to_string()));
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(matches!(result, Err(ErrorKind::CallbackError(_))));
+    assert_eq!(invocations(), 3);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_retry_policy_gives_up() {
+    // A fake protoc which always fails silently.
+    let (_script_dir, fake_protoc) = fake_protoc("#!/bin/sh\nexit 1\n");
+
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .proto_file("unused.proto")
+        .retry_policy(RetryPolicy {
+            max_attempts: 2,
+            backoff: Duration::from_millis(10),
+        })
+        .run(Duration::from_secs(3), |_| {
+            panic!("the closure must not be called");
+        });
+    let Err(ErrorKind::RetryError { attempts, error }) = result else {
+        panic!("unexpected result: {:?}", result);
+    };
+    assert_eq!(attempts, 2);
+    assert!(matches!(*error, ErrorKind::ProtocProcessError(_)));
+}
+
 fn test_call_wrapper_inner(
     req_bytes: &[u8],
     out_file_name: &str,
@@ -63,18 +3012,30 @@ fn test_call_wrapper_inner(
 ) -> Vec<u8> {
     let req = CodeGeneratorRequest::from_bytes(req_bytes).unwrap();
     // Check that we received one proto file
-    assert_eq!(req.proto_file_count, 1);
+    assert_eq!(req.proto_file.len(), 1);
 
     // Create response with one file
     let file = File {
         name: out_file_name.to_string(),
-        content: out_file_content.to_string(),
+        content: out_file_content.into(),
+        ..Default::default()
     };
 
     let mut res = CodeGeneratorResponse::default();
     res.files.push(file);
 
-    let mut res_bytes = Vec::new();
-    res.to_bytes(&mut res_bytes).unwrap();
-    res_bytes
+    res.to_bytes().unwrap()
+}
+
+/// Writes the given shell script as a fake `protoc` into a new temp dir, and returns the temp
+/// dir and the script's path.
+#[cfg(unix)]
+fn fake_protoc(script: &str) -> (::tempfile::TempDir, ::std::path::PathBuf) {
+    use ::std::os::unix::fs::PermissionsExt;
+
+    let script_dir = tempdir().unwrap();
+    let fake_protoc = script_dir.path().join("protoc");
+    ::std::fs::write(&fake_protoc, script).unwrap();
+    ::std::fs::set_permissions(&fake_protoc, ::std::fs::Permissions::from_mode(0o755)).unwrap();
+    (script_dir, fake_protoc)
 }
@@ -0,0 +1,153 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for the declarative configuration of `Protoc` (`serde` feature).
+
+#![cfg(feature = "serde")]
+
+use ::protoc_plugin_by_closure::plugin_proto::ResponseBuilder;
+use ::protoc_plugin_by_closure::{ErrorFormat, Protoc, ProtocConfig, RetryPolicy, Transport};
+use ::std::path::Path;
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+fn configured() -> Protoc {
+    Protoc::new()
+        .plugin_name("custom")
+        .with_plugin("other", "bin/protoc-gen-other", "gen/other")
+        .out_dir("gen")
+        .create_out_dir(false)
+        .proto_file("foo.proto")
+        .proto_dir_recursive("protos")
+        .proto_path("include")
+        .include_imports(true)
+        .dependency_out("gen/deps.d")
+        .max_request_size(1024)
+        .transport(Transport::IpcChannel)
+        .protoc_parse_timeout(Duration::from_millis(1500))
+        .error_format(ErrorFormat::Msvs)
+        .direct_dependencies(["bar.proto"])
+        .retry_policy(RetryPolicy {
+            max_attempts: 3,
+            backoff: Duration::from_millis(20),
+        })
+        .reproducible(true)
+        .arg("--experimental_allow_proto3_optional")
+}
+
+#[test]
+fn test_config_round_trip() {
+    let config = configured().to_config();
+    assert_eq!(config.plugin_name, "custom");
+    assert_eq!(config.plugins[0].name, "other");
+    assert_eq!(config.protoc_parse_timeout_ms, Some(1500));
+    assert_eq!(config.retry_max_attempts, 3);
+    assert_eq!(Protoc::from_config(config.clone()).to_config(), config);
+
+    let toml = ::toml::to_string(&config).unwrap();
+    assert!(toml.contains("error_format = \"msvs\""), "{}", toml);
+    assert_eq!(::toml::from_str::<ProtocConfig>(&toml).unwrap(), config);
+
+    let json = ::serde_json::to_string(&config).unwrap();
+    assert_eq!(
+        ::serde_json::from_str::<ProtocConfig>(&json).unwrap(),
+        config
+    );
+}
+
+#[test]
+fn test_config_rounds_up_sub_millisecond_durations() {
+    let config = Protoc::new()
+        .protoc_parse_timeout(Duration::from_micros(1))
+        .retry_policy(RetryPolicy {
+            max_attempts: 2,
+            backoff: Duration::from_micros(1500),
+        })
+        .to_config();
+    assert_eq!(config.protoc_parse_timeout_ms, Some(1));
+    assert_eq!(config.retry_backoff_ms, 2);
+}
+
+#[test]
+fn test_config_defaults() {
+    let config: ProtocConfig = ::toml::from_str("proto_files = [\"foo.proto\"]").unwrap();
+    assert_eq!(config, Protoc::new().proto_file("foo.proto").to_config());
+    assert_eq!(ProtocConfig::default(), Protoc::new().to_config());
+
+    let result = ::toml::from_str::<ProtocConfig>("proto_file = [\"foo.proto\"]");
+    assert!(result.is_err(), "{:?}", result);
+}
+
+#[test]
+fn test_config_resolve_paths() {
+    let config = configured()
+        .proto_file("/abs/bar.proto")
+        .to_config()
+        .resolve_paths("/base");
+    assert_eq!(config.protoc_path, Path::new("protoc"));
+    assert_eq!(config.out_dir.as_deref(), Some(Path::new("/base/gen")));
+    assert_eq!(
+        config.proto_files,
+        [Path::new("/base/foo.proto"), Path::new("/abs/bar.proto")]
+    );
+    assert_eq!(config.proto_dirs_recursive, [Path::new("/base/protos")]);
+    // `proto_dir_recursive` adds the proto path as well.
+    assert_eq!(
+        config.proto_paths,
+        [Path::new("/base/protos"), Path::new("/base/include")]
+    );
+    assert_eq!(
+        config.plugins[0].path,
+        Path::new("/base/bin/protoc-gen-other")
+    );
+
+    let config = ProtocConfig {
+        protoc_path: "tools/protoc".into(),
+        ..Default::default()
+    };
+    assert_eq!(
+        config.resolve_paths("/base").protoc_path,
+        Path::new("/base/tools/protoc")
+    );
+}
+
+#[test]
+fn test_run_from_config() {
+    let package_dir = tempdir().unwrap();
+    ::std::fs::create_dir(package_dir.path().join("proto")).unwrap();
+    ::std::fs::write(
+        package_dir.path().join("proto/config.proto"),
+        "syntax = \"proto3\"; message Config {}",
+    )
+    .unwrap();
+    let codegen_toml = r#"
+        proto_files = ["proto/config.proto"]
+        proto_paths = ["proto"]
+        out_dir = "gen"
+        fatal_warnings = true
+    "#;
+
+    let config: ProtocConfig = ::toml::from_str(codegen_toml).unwrap();
+    Protoc::from_config(config.resolve_paths(package_dir.path()))
+        .run(Duration::from_secs(3), |_| {
+            Ok(ResponseBuilder::new()
+                .file("config.rs", "// config\n")
+                .build_bytes())
+        })
+        .unwrap();
+    assert_eq!(
+        ::std::fs::read_to_string(package_dir.path().join("gen/config.rs")).unwrap(),
+        "// config\n"
+    );
+}
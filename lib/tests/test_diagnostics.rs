@@ -0,0 +1,276 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for parsing `protoc`'s diagnostics.
+
+use ::protoc_plugin_by_closure::{
+    Diagnostic, ErrorFormat, ErrorKind, Protoc, Severity, parse_protoc_diagnostics,
+};
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+use Severity::{Error, Raw, Warning};
+
+/// The `protoc` outputs, and the expected severity, file, line, column and message of each
+/// diagnostic.
+#[allow(clippy::type_complexity)]
+const SAMPLES: &[(&str, &[(Severity, &str, Option<u32>, Option<u32>, &str)])] = &[
+    (
+        "syntax.proto:2:25: Expected \";\".\n",
+        &[(Error, "syntax.proto", Some(2), Some(25), "Expected \";\".")],
+    ),
+    (
+        "syntax.proto(2) : error in column=25: Expected \";\".\n",
+        &[(Error, "syntax.proto", Some(2), Some(25), "Expected \";\".")],
+    ),
+    (
+        "undef.proto:2:13: \"Missing\" is not defined.\n",
+        &[(
+            Error,
+            "undef.proto",
+            Some(2),
+            Some(13),
+            "\"Missing\" is not defined.",
+        )],
+    ),
+    (
+        "unused.proto:2:1: warning: Import base.proto is unused.\n",
+        &[(
+            Warning,
+            "unused.proto",
+            Some(2),
+            Some(1),
+            "Import base.proto is unused.",
+        )],
+    ),
+    (
+        "unused.proto(2) : warning in column=1: warning: Import base.proto is unused.\n",
+        &[(
+            Warning,
+            "unused.proto",
+            Some(2),
+            Some(1),
+            "Import base.proto is unused.",
+        )],
+    ),
+    (
+        "nope.proto: File not found.\n\
+         badimport.proto:2:1: Import \"nope.proto\" was not found or had errors.\n",
+        &[
+            (Error, "nope.proto", None, None, "File not found."),
+            (
+                Error,
+                "badimport.proto",
+                Some(2),
+                Some(1),
+                "Import \"nope.proto\" was not found or had errors.",
+            ),
+        ],
+    ),
+    (
+        "reserved.proto(3) : error in column=22: Field \"a\" uses reserved number 1.\n\
+         reserved.proto(3) : error in column=22: Suggested field numbers for p.I: 2\n",
+        &[
+            (
+                Error,
+                "reserved.proto",
+                Some(3),
+                Some(22),
+                "Field \"a\" uses reserved number 1.",
+            ),
+            (
+                Error,
+                "reserved.proto",
+                Some(3),
+                Some(22),
+                "Suggested field numbers for p.I: 2",
+            ),
+        ],
+    ),
+    (
+        "C:\\protos\\win.proto:7:3: Expected type name.\n",
+        &[(
+            Error,
+            "C:\\protos\\win.proto",
+            Some(7),
+            Some(3),
+            "Expected type name.",
+        )],
+    ),
+    (
+        "edition.proto:1:11: Unknown edition \"2099\".\n",
+        &[(
+            Error,
+            "edition.proto",
+            Some(1),
+            Some(11),
+            "Unknown edition \"2099\".",
+        )],
+    ),
+    (
+        "sub: warning: directory does not exist.\n",
+        &[(Warning, "sub", None, None, "directory does not exist.")],
+    ),
+    (
+        "/tmp/pd/base.proto: File does not reside within any path specified using \
+         --proto_path (or -I).\n",
+        &[(
+            Error,
+            "/tmp/pd/base.proto",
+            None,
+            None,
+            "File does not reside within any path specified using --proto_path (or -I).",
+        )],
+    ),
+    (
+        "Could not make proto path relative: missing.proto: No such file or directory\n",
+        &[(
+            Raw,
+            "",
+            None,
+            None,
+            "Could not make proto path relative: missing.proto: No such file or directory",
+        )],
+    ),
+    (
+        "protoc-gen-foo: program not found or is not executable\n\
+         Please specify a program using absolute path or make sure the program is available \
+         in your PATH system variable\n\
+         --foo_out: protoc-gen-foo: Plugin failed with status code 1.\n",
+        &[
+            (
+                Raw,
+                "",
+                None,
+                None,
+                "protoc-gen-foo: program not found or is not executable",
+            ),
+            (
+                Raw,
+                "",
+                None,
+                None,
+                "Please specify a program using absolute path or make sure the program is \
+                 available in your PATH system variable",
+            ),
+            (
+                Raw,
+                "",
+                None,
+                None,
+                "--foo_out: protoc-gen-foo: Plugin failed with status code 1.",
+            ),
+        ],
+    ),
+];
+
+#[test]
+fn test_parse_protoc_diagnostics() {
+    for (stderr, expected) in SAMPLES {
+        let diagnostics = parse_protoc_diagnostics(stderr);
+        let actual = diagnostics
+            .iter()
+            .map(|d| {
+                (
+                    d.severity,
+                    d.file.as_str(),
+                    d.line,
+                    d.column,
+                    d.message.as_str(),
+                )
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(actual, *expected, "{}", stderr);
+        assert!(diagnostics.iter().all(|d| d.notes.is_empty()), "{}", stderr);
+    }
+}
+
+#[test]
+fn test_parse_protoc_diagnostics_notes() {
+    let diagnostics = parse_protoc_diagnostics(
+        "a.proto:1:1: warning: first\n  detail\na.proto:1:1: note: see here\n\n  orphan\nb.proto:2:2: second\n",
+    );
+    assert_eq!(
+        diagnostics,
+        [
+            Diagnostic {
+                severity: Warning,
+                file: "a.proto".to_string(),
+                line: Some(1),
+                column: Some(1),
+                message: "first".to_string(),
+                notes: vec![
+                    "  detail".to_string(),
+                    "a.proto:1:1: note: see here".to_string()
+                ],
+            },
+            Diagnostic {
+                severity: Raw,
+                file: String::new(),
+                line: None,
+                column: None,
+                message: "  orphan".to_string(),
+                notes: Vec::new(),
+            },
+            Diagnostic {
+                severity: Error,
+                file: "b.proto".to_string(),
+                line: Some(2),
+                column: Some(2),
+                message: "second".to_string(),
+                notes: Vec::new(),
+            },
+        ]
+    );
+    assert_eq!(diagnostics[2].to_string(), "b.proto:2:2: second");
+    assert_eq!(diagnostics[1].to_string(), "  orphan");
+}
+
+#[test]
+fn test_error_diagnostics() {
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("broken.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\";\nmessage Broken { int32 a = 1 }\n",
+    )
+    .unwrap();
+
+    for format in [ErrorFormat::Gcc, ErrorFormat::Msvs] {
+        let error = Protoc::new()
+            .proto_file(&proto_file)
+            .proto_path(proto_dir.path())
+            .error_format(format)
+            .compile_descriptor_set(Duration::from_secs(3))
+            .unwrap_err();
+        assert!(
+            matches!(error, ErrorKind::ProtocProcessError(_)),
+            "{:?}",
+            error
+        );
+        let diagnostics = error.diagnostics();
+        assert_eq!(diagnostics.len(), 1, "{:?}", diagnostics);
+        assert_eq!(diagnostics[0].severity, Error);
+        assert!(
+            diagnostics[0].file.ends_with("broken.proto"),
+            "{:?}",
+            diagnostics
+        );
+        assert_eq!(
+            (diagnostics[0].line, diagnostics[0].column),
+            (Some(2), Some(30))
+        );
+    }
+    assert_eq!(ErrorKind::NoInput.diagnostics(), []);
+}
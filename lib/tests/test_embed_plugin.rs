@@ -0,0 +1,98 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for the `embed-plugin` feature. The cache directory is set by an env var, which is
+//! process-wide, so they are in a separate test binary with a single test.
+
+#![cfg(feature = "embed-plugin")]
+
+use ::protoc_plugin_by_closure::plugin_proto::ResponseBuilder;
+use ::protoc_plugin_by_closure::{Protoc, extracted_plugin_path};
+use ::std::env;
+use ::std::time::{Duration, SystemTime};
+use ::tempfile::tempdir;
+
+const VAR_NAME: &str = "PROTOC_PLUGIN_BY_CLOSURE_CACHE_DIR";
+
+#[test]
+fn test_embed_plugin() {
+    let cache_dir = tempdir().unwrap();
+    unsafe { env::set_var(VAR_NAME, cache_dir.path()) };
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; message Main {}").unwrap();
+    let protoc = Protoc::new()
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .out_dir(out_dir.path());
+    let run = || {
+        protoc
+            .clone()
+            .run(Duration::from_secs(3), |_| {
+                Ok(ResponseBuilder::new()
+                    .file("main.rs", b"// main")
+                    .build_bytes())
+            })
+            .unwrap();
+    };
+
+    // The first run extracts the plugin binary into the cache directory.
+    run();
+    let plugin = extracted_plugin_path().unwrap();
+    assert!(plugin.starts_with(cache_dir.path().join("protoc-plugin-by-closure")));
+    assert!(plugin.is_file());
+    assert_eq!(
+        ::std::fs::read(out_dir.path().join("main.rs")).unwrap(),
+        b"// main"
+    );
+
+    // The second run reuses it.
+    let modified = |path| -> SystemTime { ::std::fs::metadata(path).unwrap().modified().unwrap() };
+    let first_modified = modified(&plugin);
+    run();
+    assert_eq!(modified(&plugin), first_modified);
+
+    // A tampered file is not run, but extracted again.
+    let extracted = ::std::fs::read(&plugin).unwrap();
+    ::std::fs::write(&plugin, b"#!/bin/sh\nexit 1\n").unwrap();
+    run();
+    assert_eq!(::std::fs::read(&plugin).unwrap(), extracted);
+
+    // The cache dir must be private to this user.
+    #[cfg(unix)]
+    {
+        use ::std::os::unix::fs::PermissionsExt;
+        let root = cache_dir.path().join("protoc-plugin-by-closure");
+        let mode = |path| ::std::fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(&root), 0o700);
+        ::std::fs::set_permissions(&root, ::std::fs::Permissions::from_mode(0o777)).unwrap();
+        let result = extracted_plugin_path();
+        assert!(
+            matches!(
+                &result,
+                Err(::protoc_plugin_by_closure::ErrorKind::PluginExtractError { .. })
+            ),
+            "{:?}",
+            result
+        );
+        ::std::fs::set_permissions(&root, ::std::fs::Permissions::from_mode(0o700)).unwrap();
+    }
+
+    // It's extracted again after the cache is cleaned.
+    ::std::fs::remove_dir_all(cache_dir.path().join("protoc-plugin-by-closure")).unwrap();
+    run();
+    assert!(plugin.is_file());
+    unsafe { env::remove_var(VAR_NAME) };
+}
@@ -0,0 +1,85 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ::protoc_plugin_by_closure::plugin_proto::{CodeGeneratorRequest, CodeGeneratorResponse, File};
+use ::protoc_plugin_by_closure::{IpcServer, Transport, plugin_path};
+use ::std::io::{Read, Write};
+use ::std::process::{Command, Stdio};
+use ::std::time::Duration;
+
+/// The size of the synthetic `proto_file` entry, about the size of the largest real requests.
+const LARGE_SIZE: usize = 50 * 1024 * 1024;
+
+/// Passes a synthetic 50 MB request and a 50 MB response through the real plugin binary, and
+/// checks that both arrive intact. Run with
+/// `cargo test --release --test test_large_request -- --ignored`.
+#[test]
+#[ignore = "slow in the debug builds"]
+fn test_large_request() {
+    large_round_trip(Transport::IpcChannel);
+}
+
+/// Same as [`test_large_request`], but over the Unix domain socket, where both sides read
+/// the length-prefixed frames.
+#[cfg(unix)]
+#[test]
+#[ignore = "slow in the debug builds"]
+fn test_large_request_unix_socket() {
+    large_round_trip(Transport::UnixSocket);
+}
+
+fn large_round_trip(transport: Transport) {
+    let (init_key, server) = IpcServer::start_with_transport(transport).unwrap();
+    let req = CodeGeneratorRequest {
+        file_to_generate: vec!["large.proto".to_string()],
+        parameter: Some(init_key),
+        proto_file: vec![vec![b'x'; LARGE_SIZE]],
+        ..Default::default()
+    };
+    let req_bytes = req.to_bytes().unwrap();
+    let req_len = req_bytes.len();
+    let res = CodeGeneratorResponse {
+        files: vec![File {
+            name: "large.txt".to_string(),
+            content: vec![b'y'; LARGE_SIZE],
+            ..Default::default()
+        }],
+        ..Default::default()
+    };
+    let res_bytes = res.to_bytes().unwrap();
+
+    let mut plugin = Command::new(plugin_path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = plugin.stdin.take().unwrap();
+    let writer = ::std::thread::spawn(move || stdin.write_all(&req_bytes));
+    server
+        .serve(Duration::from_secs(30), |req| {
+            assert_eq!(req.len(), req_len);
+            Ok(res_bytes.clone())
+        })
+        .unwrap();
+    writer.join().unwrap().unwrap();
+    let mut stdout = Vec::new();
+    plugin
+        .stdout
+        .take()
+        .unwrap()
+        .read_to_end(&mut stdout)
+        .unwrap();
+    assert!(plugin.wait().unwrap().success());
+    assert_eq!(stdout, res_bytes);
+}
@@ -0,0 +1,112 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for the manifest of the generated files (`serde` feature).
+
+#![cfg(feature = "serde")]
+
+#[cfg(feature = "on-memory")]
+use ::protoc_plugin_by_closure::ProtocOnMemory;
+use ::protoc_plugin_by_closure::plugin_proto::{CodeGeneratorResponse, File};
+use ::protoc_plugin_by_closure::{FileKind, Manifest, ManifestFile, Protoc};
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+fn response() -> Vec<u8> {
+    let file = |content: &str, insertion_point: Option<&str>| File {
+        name: "out.rs".to_string(),
+        insertion_point: insertion_point.map(str::to_string),
+        content: content.as_bytes().to_vec(),
+        ..Default::default()
+    };
+    CodeGeneratorResponse {
+        files: vec![
+            file("let x = 1;\n", Some("x")),
+            file("// @@protoc_insertion_point(x)\n", None),
+        ],
+        ..Default::default()
+    }
+    .to_bytes()
+    .unwrap()
+}
+
+#[test]
+fn test_manifest_out() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; message Main {}").unwrap();
+    let manifest_path = out_dir.path().join("manifest.json");
+
+    let output = Protoc::new()
+        .out_dir(out_dir.path().join("gen"))
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .manifest_out(&manifest_path)
+        .run_with_output(Duration::from_secs(3), |_| Ok(response()))
+        .unwrap();
+
+    let manifest: Manifest =
+        ::serde_json::from_slice(&::std::fs::read(&manifest_path).unwrap()).unwrap();
+    assert_eq!(manifest, output.manifest().unwrap());
+    assert_eq!(manifest.crate_version, env!("CARGO_PKG_VERSION"));
+    assert!(manifest.protoc_version.is_some());
+    // The insertion is listed after the file it inserts into, as protoc applied it. The file's
+    // hash is of the final content on disk.
+    let written = ::std::fs::read(out_dir.path().join("gen/out.rs")).unwrap();
+    assert_eq!(written.len(), 42);
+    assert_eq!(
+        manifest.files,
+        vec![
+            ManifestFile {
+                name: "out.rs".to_string(),
+                kind: FileKind::New,
+                size: 42,
+                sha256: "efd37d190dd3ee5a9ee2e183362dec0043fee8651ad09d2492310db13bfe10e7"
+                    .to_string(),
+            },
+            ManifestFile {
+                name: "out.rs".to_string(),
+                kind: FileKind::Insertion {
+                    point: "x".to_string(),
+                    target: "out.rs".to_string(),
+                },
+                size: 11,
+                sha256: "4c2e6a8978be608645aa7a4732a66dff996b24a6f5be1703b2fa8a2c1b198baa"
+                    .to_string(),
+            },
+        ]
+    );
+}
+
+#[cfg(feature = "on-memory")]
+#[test]
+fn test_on_memory_manifest_out() {
+    let dir = tempdir().unwrap();
+    let manifest_path = dir.path().join("manifest.json");
+
+    ProtocOnMemory::new()
+        .add_file("main.proto", "syntax = \"proto3\"; message Main {}")
+        .manifest_out(&manifest_path)
+        .run(Duration::from_secs(3), |_| Ok(response()))
+        .unwrap();
+
+    let manifest: Manifest =
+        ::serde_json::from_slice(&::std::fs::read(&manifest_path).unwrap()).unwrap();
+    assert_eq!(manifest.files.len(), 2);
+    assert_eq!(
+        manifest.files[0].sha256,
+        "efd37d190dd3ee5a9ee2e183362dec0043fee8651ad09d2492310db13bfe10e7"
+    );
+}
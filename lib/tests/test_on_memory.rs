@@ -12,12 +12,17 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-mod compiler_plugin;
+#![cfg(feature = "on-memory")]
 
-use ::protoc_plugin_by_closure::ProtocOnMemory;
+mod descriptor;
+
+use ::protoc_plugin_by_closure::plugin_proto::{CodeGeneratorRequest, CodeGeneratorResponse, File};
+use ::protoc_plugin_by_closure::{ErrorKind, ProtocOnMemory, RunEvent, plugin_path};
+use ::std::sync::{Arc, Mutex};
 use ::std::time::Duration;
+use ::tempfile::tempdir;
 
-use self::compiler_plugin::{CodeGeneratorRequest, CodeGeneratorResponse, File};
+use self::descriptor::FileDescriptorSet;
 
 #[test]
 fn test_on_memory() {
@@ -46,6 +51,607 @@ package empty;
     assert_eq!(result_content, out_file_content);
 }
 
+#[test]
+fn test_on_memory_generate_only() {
+    let mut file_to_generate = Vec::new();
+    let mut proto_file_count = 0;
+
+    let result_files = ProtocOnMemory::new()
+        .add_file(
+            "base.proto",
+            "syntax = \"proto3\"; package base; message Base {}",
+        )
+        .add_file(
+            "user.proto",
+            "syntax = \"proto3\"; package user; import \"base.proto\"; message User { base.Base b = 1; }",
+        )
+        .add_file(
+            "unrelated.proto",
+            "syntax = \"proto3\"; package unrelated; message Unrelated {}",
+        )
+        .generate_only(["user.proto"])
+        .run(Duration::from_secs(3), |req_bytes| {
+            let req = CodeGeneratorRequest::from_bytes(req_bytes).unwrap();
+            file_to_generate = req.file_to_generate;
+            proto_file_count = req.proto_file.len();
+            Ok(Vec::new())
+        })
+        .unwrap();
+
+    assert!(result_files.is_empty());
+    assert_eq!(file_to_generate, vec!["user.proto".to_string()]);
+    // The requested file and its import, but not the unrelated file.
+    assert_eq!(proto_file_count, 2);
+}
+
+#[test]
+fn test_on_memory_generate_only_unknown_file() {
+    let result = ProtocOnMemory::new()
+        .add_file("user.proto", "syntax = \"proto3\"; package user;")
+        .generate_only(["usr.proto"])
+        .run(Duration::from_secs(3), |_| Ok(Vec::new()));
+    assert!(
+        matches!(&result, Err(ErrorKind::UnknownGenerateOnlyFileError(name)) if name == "usr.proto"),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_on_memory_compile_descriptor_set() {
+    let descriptor_set = ProtocOnMemory::new()
+        .add_file("a.proto", "syntax = \"proto3\"; package a; message A {}")
+        .add_file("b/b.proto", "syntax = \"proto3\"; package b; message B {}")
+        .compile_descriptor_set(Duration::from_secs(3))
+        .unwrap();
+    let set = FileDescriptorSet::from_bytes(&descriptor_set).unwrap();
+    assert_eq!(set.file_count, 2);
+}
+
+#[test]
+fn test_on_memory_check() {
+    let diagnostics = ProtocOnMemory::new()
+        .add_file("a.proto", "syntax = \"proto3\"; package a; message A {}")
+        .check(Duration::from_secs(3))
+        .unwrap();
+    assert_eq!(diagnostics, vec![]);
+
+    let result = ProtocOnMemory::new()
+        .add_file("a.proto", "syntax = \"proto3\"; package a; message A {}")
+        .add_file("b.proto", "syntax = \"proto3\";\nimport \"c.proto\";\n")
+        .check(Duration::from_secs(3));
+    let Err(ErrorKind::CheckError(diagnostics)) = &result else {
+        panic!("{:?}", result);
+    };
+    assert!(
+        diagnostics.iter().any(|d| d.file.ends_with("b.proto")),
+        "{:?}",
+        diagnostics
+    );
+}
+
+#[test]
+fn test_on_memory_strip_parameter() {
+    let run = |strip_parameter: bool| {
+        let mut request = Vec::new();
+        ProtocOnMemory::new()
+            .add_file(
+                "stable/names.proto",
+                "syntax = \"proto3\"; message Stable {}",
+            )
+            .strip_parameter(strip_parameter)
+            .run(Duration::from_secs(3), |req| {
+                request = req.to_vec();
+                Ok(Vec::new())
+            })
+            .unwrap();
+        request
+    };
+
+    let request = run(true);
+    assert_eq!(run(true), request);
+    let parsed = CodeGeneratorRequest::from_bytes(&request).unwrap();
+    assert_eq!(parsed.parameter, None);
+    assert_eq!(parsed.file_to_generate, ["stable/names.proto"]);
+    // Without it, the requests differ by the key to connect to the plugin binary.
+    assert_ne!(run(false), run(false));
+}
+
+#[test]
+fn test_on_memory_add_files() {
+    // Each file imports the previous one.
+    let files = (0..5).map(|i| {
+        let import = match i {
+            0 => String::new(),
+            _ => format!("import \"p{}.proto\";", i - 1),
+        };
+        (
+            format!("p{}.proto", i),
+            format!("syntax = \"proto3\"; {} message M{} {{}}", import, i),
+        )
+    });
+    let descriptor_set = ProtocOnMemory::new()
+        .add_files(files.clone())
+        .compile_descriptor_set(Duration::from_secs(3))
+        .unwrap();
+    let set = FileDescriptorSet::from_bytes(&descriptor_set).unwrap();
+    assert_eq!(set.file_count, 5);
+
+    let result = ProtocOnMemory::new()
+        .add_files(files)
+        .add_files([("p2.proto", b"syntax = \"proto3\";".to_vec())])
+        .compile_descriptor_set(Duration::from_secs(3));
+    assert!(
+        matches!(&result, Err(ErrorKind::DuplicateInputFileError(name)) if name == "p2.proto"),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_on_memory_out_dir() {
+    let out_dir = tempdir().unwrap();
+    let out_file_name = "nested/dir/write_through.rs";
+    let out_file_content = "// write through";
+    ::std::fs::create_dir_all(out_dir.path().join("nested/dir")).unwrap();
+    ::std::fs::write(out_dir.path().join(out_file_name), "// stale").unwrap();
+
+    let result_files = ProtocOnMemory::new()
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .out_dir(out_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(
+                req,
+                out_file_name,
+                out_file_content,
+            ))
+        })
+        .unwrap();
+
+    assert_eq!(
+        result_files,
+        vec![(out_file_name.to_string(), out_file_content.to_string())]
+    );
+    let on_disk = ::std::fs::read_to_string(out_dir.path().join(out_file_name)).unwrap();
+    assert_eq!(on_disk, out_file_content);
+}
+
+#[test]
+fn test_on_memory_out_dir_atomic() {
+    let out_dir = tempdir().unwrap();
+    // Writing b.rs fails after a.rs is written, since a directory is there.
+    ::std::fs::create_dir(out_dir.path().join("b.rs")).unwrap();
+
+    let result = ProtocOnMemory::new()
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .out_dir(out_dir.path())
+        .run(Duration::from_secs(3), |_| {
+            let mut res = CodeGeneratorResponse::default();
+            for name in ["a.rs", "b.rs"] {
+                res.files.push(File {
+                    name: name.to_string(),
+                    content: b"// complete".to_vec(),
+                    ..Default::default()
+                });
+            }
+            Ok(res.to_bytes().unwrap())
+        });
+    assert!(matches!(result, Err(ErrorKind::IoError(_))), "{:?}", result);
+
+    // a.rs is complete, and no temp file is left behind.
+    assert_eq!(
+        ::std::fs::read_to_string(out_dir.path().join("a.rs")).unwrap(),
+        "// complete"
+    );
+    assert!(out_dir.path().join("b.rs").is_dir());
+    let mut names = ::std::fs::read_dir(out_dir.path())
+        .unwrap()
+        .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+        .collect::<Vec<_>>();
+    names.sort();
+    assert_eq!(names, ["a.rs", "b.rs"]);
+
+    // The written file has the same permissions as the normally written one.
+    #[cfg(unix)]
+    {
+        use ::std::os::unix::fs::PermissionsExt;
+        let normal = out_dir.path().join("normal.rs");
+        ::std::fs::write(&normal, "").unwrap();
+        let mode = |name| {
+            ::std::fs::metadata(out_dir.path().join(name))
+                .unwrap()
+                .permissions()
+                .mode()
+        };
+        assert_eq!(mode("a.rs"), mode("normal.rs"));
+    }
+}
+
+#[test]
+fn test_on_memory_out_dir_rejects_escaping_name() {
+    let out_dir = tempdir().unwrap();
+
+    let result = ProtocOnMemory::new()
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .out_dir(out_dir.path().join("sub"))
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "../escaped.rs", "// escaped"))
+        });
+
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(
+            &result,
+            Err(ErrorKind::UnsafeOutputFileNameError(name)) if name == "../escaped.rs"
+        ),
+        "{:?}",
+        result
+    );
+    assert!(!out_dir.path().join("escaped.rs").exists());
+}
+
+#[test]
+fn test_on_memory_no_input() {
+    let result = ProtocOnMemory::new().run(Duration::from_secs(3), |_| {
+        panic!("the closure must not be called")
+    });
+    assert!(matches!(result, Err(ErrorKind::NoInput)));
+}
+
+#[test]
+fn test_on_memory_preserves_response_order() {
+    let names = ["c.rs", "b.rs", "a.rs"];
+
+    let result_files = ProtocOnMemory::new()
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .run(Duration::from_secs(3), |_| {
+            Ok(reverse_alphabetical_response())
+        })
+        .unwrap();
+    let result_names = result_files
+        .iter()
+        .map(|(name, _)| name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(result_names, names);
+
+    let result_map = ProtocOnMemory::new()
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .run_map(Duration::from_secs(3), |_| {
+            Ok(reverse_alphabetical_response())
+        })
+        .unwrap();
+    let result_names = result_map.keys().map(String::as_str).collect::<Vec<_>>();
+    assert_eq!(result_names, ["a.rs", "b.rs", "c.rs"]);
+    assert_eq!(result_map["b.rs"], b"// b.rs");
+}
+
+#[test]
+fn test_on_memory_normalize_newlines() {
+    let crlf_content = "line 1\r\nline 2\r\n";
+    for (normalize_newlines, expected) in [(false, crlf_content), (true, "line 1\nline 2\n")] {
+        let result_files = ProtocOnMemory::new()
+            .add_file("input.proto", "syntax = \"proto3\"; package input;")
+            .normalize_newlines(normalize_newlines)
+            .run(Duration::from_secs(3), |req| {
+                Ok(test_call_wrapper_inner(req, "crlf.rs", crlf_content))
+            })
+            .unwrap();
+        assert_eq!(
+            result_files,
+            vec![("crlf.rs".to_string(), expected.to_string())]
+        );
+    }
+}
+
+#[test]
+fn test_on_memory_run_map_rejects_duplicate_names() {
+    let file = File {
+        name: "dup.rs".to_string(),
+        content: "// dup".into(),
+        ..Default::default()
+    };
+    let mut res = CodeGeneratorResponse::default();
+    res.files.push(file.clone());
+    res.files.push(file);
+    let res_bytes = res.to_bytes().unwrap();
+
+    let result = ProtocOnMemory::new()
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .run_map(Duration::from_secs(3), |_| Ok(res_bytes));
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(matches!(
+        result,
+        Err(ErrorKind::DuplicateOutputFileError(name)) if name == "dup.rs"
+    ));
+}
+
+#[cfg(unix)]
+#[test]
+fn test_on_memory_keep_temp() {
+    for keep_temp in [false, true] {
+        let script_dir = tempdir().unwrap();
+        let (fake_protoc, args_file) = recording_protoc(script_dir.path());
+
+        let kept = Arc::new(Mutex::new(None));
+        let kept_clone = Arc::clone(&kept);
+        ProtocOnMemory::new()
+            .protoc_path(&fake_protoc)
+            .add_file("input.proto", "syntax = \"proto3\"; package input;")
+            .keep_temp(keep_temp)
+            .on_event(move |event| {
+                if let RunEvent::TempDirKept(path) = event {
+                    *kept_clone.lock().unwrap() = Some(path);
+                }
+            })
+            .run(Duration::from_secs(3), |req| {
+                Ok(test_call_wrapper_inner(req, "kept.rs", "// kept"))
+            })
+            .unwrap();
+
+        let args = ::std::fs::read_to_string(&args_file).unwrap();
+        let proto_dir = args
+            .lines()
+            .find_map(|arg| arg.strip_prefix("--proto_path="))
+            .map(::std::path::Path::new)
+            .unwrap();
+        let temp_dir = proto_dir.parent().unwrap();
+        assert_eq!(proto_dir.join("input.proto").exists(), keep_temp);
+        assert_eq!(temp_dir.join("out/kept.rs").exists(), keep_temp);
+        assert_eq!(temp_dir.exists(), keep_temp);
+        assert_eq!(
+            kept.lock().unwrap().as_deref(),
+            keep_temp.then_some(temp_dir)
+        );
+        if keep_temp {
+            ::std::fs::remove_dir_all(temp_dir).unwrap();
+        }
+    }
+}
+
+#[test]
+fn test_on_memory_plugin_path() {
+    let plugin_dir = tempdir().unwrap();
+    let plugin = plugin_dir.path().join("plugin");
+    ::std::fs::copy(plugin_path(), &plugin).unwrap();
+    let protoc =
+        ProtocOnMemory::new().add_file("input.proto", "syntax = \"proto3\"; package input;");
+
+    let files = protoc
+        .clone()
+        .plugin_path(&plugin)
+        .run(Duration::from_secs(3), |req| {
+            Ok(test_call_wrapper_inner(req, "override.rs", "// override"))
+        })
+        .unwrap();
+    assert_eq!(
+        files,
+        vec![("override.rs".to_string(), "// override".to_string())]
+    );
+
+    let missing = plugin_dir.path().join("missing");
+    let result = protoc
+        .plugin_path(&missing)
+        .run(Duration::from_secs(3), |_| unreachable!());
+    assert!(
+        matches!(&result, Err(ErrorKind::PluginNotFound(path)) if path == &missing),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_on_memory_temp_dir() {
+    let root_dir = tempdir().unwrap();
+    // Created if missing.
+    let base_dir = root_dir.path().join("scratch");
+    let is_empty = || ::std::fs::read_dir(&base_dir).unwrap().next().is_none();
+
+    let files = ProtocOnMemory::new()
+        .protoc_path("protoc")
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .temp_dir(&base_dir)
+        .run(Duration::from_secs(3), |req| {
+            // The inputs are staged under the given directory while running.
+            assert!(!is_empty());
+            Ok(test_call_wrapper_inner(req, "staged.rs", "// staged"))
+        })
+        .unwrap();
+
+    assert_eq!(
+        files,
+        vec![("staged.rs".to_string(), "// staged".to_string())]
+    );
+    assert!(is_empty());
+}
+
+#[cfg(unix)]
+#[test]
+fn test_on_memory_cleanup_on_panic() {
+    let script_dir = tempdir().unwrap();
+    let (fake_protoc, args_file) = recording_protoc(script_dir.path());
+
+    let result = ::std::panic::catch_unwind(::std::panic::AssertUnwindSafe(|| {
+        ProtocOnMemory::new()
+            .protoc_path(&fake_protoc)
+            .add_file("input.proto", "syntax = \"proto3\"; package input;")
+            .run(Duration::from_secs(3), |_| panic!("the closure panicked"))
+    }));
+    assert!(result.is_err());
+
+    // protoc is killed and reaped, so even a zombie process must not be left.
+    let pid = ::std::fs::read_to_string(script_dir.path().join("pid.txt")).unwrap();
+    let alive = ::std::process::Command::new("kill")
+        .args(["-0", pid.trim()])
+        .stderr(::std::process::Stdio::null())
+        .status()
+        .unwrap()
+        .success();
+    assert!(!alive);
+
+    let args = ::std::fs::read_to_string(&args_file).unwrap();
+    let proto_dir = args
+        .lines()
+        .find_map(|arg| arg.strip_prefix("--proto_path="))
+        .map(::std::path::Path::new)
+        .unwrap();
+    assert!(!proto_dir.parent().unwrap().exists());
+}
+
+#[test]
+fn test_on_memory_run_raw() {
+    let res_bytes = ProtocOnMemory::new()
+        .add_file("raw.proto", "syntax = \"proto3\"; package raw;")
+        .run_raw(Duration::from_secs(3), |_| {
+            Ok(reverse_alphabetical_response())
+        })
+        .unwrap();
+
+    // The closure's response has no insertion points, so it's sent to protoc as it is.
+    assert_eq!(res_bytes, reverse_alphabetical_response());
+    let res = CodeGeneratorResponse::from_bytes(&res_bytes).unwrap();
+    let names = res
+        .files
+        .iter()
+        .map(|f| f.name.as_str())
+        .collect::<Vec<_>>();
+    assert_eq!(names, ["c.rs", "b.rs", "a.rs"]);
+}
+
+#[test]
+fn test_on_memory_generator_reported_error() {
+    let result = ProtocOnMemory::new()
+        .add_file("failing.proto", "syntax = \"proto3\"; package failing;")
+        .run(Duration::from_secs(3), |_| {
+            let res = CodeGeneratorResponse {
+                error: Some("unsupported option: foo".to_string()),
+                ..Default::default()
+            };
+            Ok(res.to_bytes().unwrap())
+        });
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(
+            &result,
+            Err(ErrorKind::GeneratorReportedError(message)) if message == "unsupported option: foo"
+        ),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_on_memory_empty_response() {
+    let files = ProtocOnMemory::new()
+        .add_file("input.proto", "syntax = \"proto3\"; package input;")
+        .run(Duration::from_secs(3), |_| {
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+    assert_eq!(files, vec![]);
+}
+
+#[test]
+fn test_on_memory_run_with_warnings() {
+    let output = ProtocOnMemory::new()
+        .add_file("base.proto", "syntax = \"proto3\"; message Base {}")
+        // The import is unused, which protoc warns about.
+        .add_file(
+            "user.proto",
+            "syntax = \"proto3\"; import \"base.proto\"; message User {}",
+        )
+        .generate_only(["user.proto"])
+        .run_with_warnings(Duration::from_secs(3), |_| {
+            let mut res = CodeGeneratorResponse::default();
+            res.files.push(File {
+                name: "user.txt".to_string(),
+                content: b"user".to_vec(),
+                ..Default::default()
+            });
+            Ok(res.to_bytes().unwrap())
+        })
+        .unwrap();
+
+    assert_eq!(output.files, [("user.txt".to_string(), "user".to_string())]);
+    assert_eq!(output.warnings.len(), 1);
+    assert!(
+        output.warnings[0].message.contains("base.proto"),
+        "{:?}",
+        output.warnings
+    );
+    assert!(!output.stderr.contains("warning:"), "{}", output.stderr);
+}
+
+#[cfg(unix)]
+#[test]
+fn test_on_memory_run_with_warnings_raw_stderr() {
+    use ::std::os::unix::fs::PermissionsExt;
+
+    // A fake protoc which prints an error in the msvs format, which is kept as it is, and a
+    // warning with an indented line belonging to it, which are dropped.
+    let script_dir = tempdir().unwrap();
+    let script = script_dir.path().join("protoc");
+    ::std::fs::write(
+        &script,
+        "#!/bin/sh\n\
+         echo 'other.proto(2) : error in column=25: Expected \";\".' >&2\n\
+         echo 'base.proto:1:1: warning: Something is deprecated.' >&2\n\
+         echo '  Something else' >&2\n\
+         exec protoc \"$@\"\n",
+    )
+    .unwrap();
+    ::std::fs::set_permissions(&script, ::std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let output = ProtocOnMemory::new()
+        .protoc_path(&script)
+        .add_file("base.proto", "syntax = \"proto3\"; message Base {}")
+        .run_with_warnings(Duration::from_secs(3), |_| {
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+
+    assert_eq!(output.warnings.len(), 1, "{:?}", output.warnings);
+    assert_eq!(
+        output.stderr,
+        "other.proto(2) : error in column=25: Expected \";\".\n"
+    );
+}
+
+/// Creates a fake protoc script which records its pid into `pid.txt` and its arguments (one per
+/// line) into a file in the same dir, and then runs the real protoc.
+/// Returns the paths to the script and the recorded args file.
+#[cfg(unix)]
+fn recording_protoc(dir: &::std::path::Path) -> (::std::path::PathBuf, ::std::path::PathBuf) {
+    use ::std::os::unix::fs::PermissionsExt;
+
+    let script = dir.join("protoc");
+    let args_file = dir.join("args.txt");
+    ::std::fs::write(
+        &script,
+        format!(
+            "#!/bin/sh\necho $$ > '{}'\nprintf '%s\\n' \"$@\" > '{}'\nexec protoc \"$@\"\n",
+            dir.join("pid.txt").display(),
+            args_file.display()
+        ),
+    )
+    .unwrap();
+    ::std::fs::set_permissions(&script, ::std::fs::Permissions::from_mode(0o755)).unwrap();
+    (script, args_file)
+}
+
+/// Creates an encoded `CodeGeneratorResponse` with the files `c.rs`, `b.rs` and `a.rs`
+/// in this order.
+fn reverse_alphabetical_response() -> Vec<u8> {
+    let mut res = CodeGeneratorResponse::default();
+    for name in ["c.rs", "b.rs", "a.rs"] {
+        res.files.push(File {
+            name: name.to_string(),
+            content: format!("// {}", name).into_bytes(),
+            ..Default::default()
+        });
+    }
+    res.to_bytes().unwrap()
+}
+
 fn test_call_wrapper_inner(
     req_bytes: &[u8],
     out_file_name: &str,
@@ -53,18 +659,17 @@ fn test_call_wrapper_inner(
 ) -> Vec<u8> {
     let req = CodeGeneratorRequest::from_bytes(req_bytes).unwrap();
     // Check that we received one proto file
-    assert_eq!(req.proto_file_count, 1);
+    assert_eq!(req.proto_file.len(), 1);
 
     // Create response with one file
     let file = File {
         name: out_file_name.to_string(),
-        content: out_file_content.to_string(),
+        content: out_file_content.into(),
+        ..Default::default()
     };
 
     let mut res = CodeGeneratorResponse::default();
     res.files.push(file);
 
-    let mut res_bytes = Vec::new();
-    res.to_bytes(&mut res_bytes).unwrap();
-    res_bytes
+    res.to_bytes().unwrap()
 }
@@ -0,0 +1,83 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests that `ProtocOnMemory` doesn't copy the input file contents, counting the large
+//! allocations with a global allocator, so they are in a separate test binary with a single
+//! test.
+
+#![cfg(feature = "on-memory")]
+
+use ::protoc_plugin_by_closure::ProtocOnMemory;
+use ::protoc_plugin_by_closure::plugin_proto::{CodeGeneratorRequest, CodeGeneratorResponse};
+use ::std::alloc::{GlobalAlloc, Layout, System};
+use ::std::sync::atomic::{AtomicUsize, Ordering};
+use ::std::time::Duration;
+
+/// The size of the large input file.
+const LARGE_FILE_LEN: usize = 16 * 1024 * 1024;
+
+/// The number of the allocations at least half as large as the large input file.
+static LARGE_ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        if layout.size() >= LARGE_FILE_LEN / 2 {
+            LARGE_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        unsafe { System.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { System.dealloc(ptr, layout) }
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        if new_size >= LARGE_FILE_LEN / 2 {
+            LARGE_ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        }
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[test]
+fn test_on_memory_no_copy() {
+    // A large comment, which is not in the request since only main.proto is generated.
+    let mut large = String::with_capacity(LARGE_FILE_LEN);
+    large.push_str("syntax = \"proto3\"; package large; message Large {}\n");
+    while large.len() + 64 < LARGE_FILE_LEN {
+        large.push_str(&format!("// {:060}\n", large.len()));
+    }
+    let large_bytes = large.clone().into_bytes();
+    let allocations = LARGE_ALLOCATIONS.load(Ordering::Relaxed);
+
+    // The owned contents are moved in and written into the temp dir, which is the only copy.
+    ProtocOnMemory::new()
+        .add_file("large.proto", large)
+        .add_file_bytes("large_bytes.proto", large_bytes)
+        .add_file(
+            "main.proto",
+            "syntax = \"proto3\"; import \"large.proto\"; message Main { large.Large large = 1; }",
+        )
+        .generate_only(["main.proto"])
+        .run(Duration::from_secs(10), |req| {
+            let req = CodeGeneratorRequest::from_bytes(req).map_err(|e| e.to_string())?;
+            assert!(req.proto_file.iter().all(|file| file.len() < 1024));
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+    assert_eq!(LARGE_ALLOCATIONS.load(Ordering::Relaxed), allocations);
+}
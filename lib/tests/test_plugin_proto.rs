@@ -0,0 +1,397 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#[cfg(feature = "on-memory")]
+use ::protoc_plugin_by_closure::ProtocOnMemory;
+use ::protoc_plugin_by_closure::plugin_proto::{
+    Annotation, CodeGeneratorRequest, CodeGeneratorResponse, FEATURE_PROTO3_OPTIONAL,
+    FEATURE_SUPPORTS_EDITIONS, File, FileDescriptorLite, GeneratedCodeInfo, ResponseBuilder,
+    Version,
+};
+use ::protoc_plugin_by_closure::{ErrorKind, Protoc};
+use ::std::sync::Mutex;
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+/// A tiny xorshift PRNG, so that the round-trip tests are reproducible without any deps.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+    fn below(&mut self, n: u64) -> u64 {
+        self.next() % n
+    }
+    fn bytes(&mut self) -> Vec<u8> {
+        (0..self.below(40)).map(|_| self.next() as u8).collect()
+    }
+    fn string(&mut self) -> String {
+        const CHARS: &[char] = &['a', 'Z', '0', '.', '/', ' ', '\n', 'é', '日', '🦀'];
+        (0..self.below(20))
+            .map(|_| CHARS[self.below(CHARS.len() as u64) as usize])
+            .collect()
+    }
+    fn option<T>(&mut self, f: impl FnOnce(&mut Self) -> T) -> Option<T> {
+        (self.below(2) == 0).then(|| f(self))
+    }
+}
+
+#[test]
+fn test_request_round_trip() {
+    let mut rng = Rng(0x9e37_79b9_7f4a_7c15);
+    for _ in 0..500 {
+        let request = CodeGeneratorRequest {
+            file_to_generate: (0..rng.below(4)).map(|_| rng.string()).collect(),
+            parameter: rng.option(Rng::string),
+            compiler_version: rng.option(|rng| Version {
+                major: rng.option(|rng| rng.next() as i32),
+                minor: rng.option(|rng| rng.next() as i32),
+                patch: rng.option(|rng| rng.next() as i32),
+                suffix: rng.option(Rng::string),
+            }),
+            proto_file: (0..rng.below(4)).map(|_| rng.bytes()).collect(),
+        };
+        let bytes = request.to_bytes().unwrap();
+        assert_eq!(CodeGeneratorRequest::from_bytes(&bytes).unwrap(), request);
+    }
+}
+
+#[test]
+fn test_response_round_trip() {
+    let mut rng = Rng(0x2545_f491_4f6c_dd1d);
+    for _ in 0..500 {
+        let response = CodeGeneratorResponse {
+            error: rng.option(Rng::string),
+            supported_features: rng.option(Rng::next),
+            minimum_edition: rng.option(|rng| rng.next() as i32),
+            maximum_edition: rng.option(|rng| rng.next() as i32),
+            files: (0..rng.below(4))
+                .map(|_| File {
+                    name: rng.string(),
+                    // An empty insertion point is parsed as `None`.
+                    insertion_point: rng.option(Rng::string).filter(|p| !p.is_empty()),
+                    content: rng.bytes(),
+                    generated_code_info: rng.option(|rng| GeneratedCodeInfo {
+                        annotation: (0..rng.below(3))
+                            .map(|_| Annotation {
+                                path: (0..rng.below(5)).map(|_| rng.next() as i32).collect(),
+                                source_file: rng.option(Rng::string),
+                                begin: rng.option(|rng| rng.next() as i32),
+                                end: rng.option(|rng| rng.next() as i32),
+                                semantic: rng.option(|rng| rng.below(3) as i32),
+                            })
+                            .collect(),
+                    }),
+                })
+                .collect(),
+        };
+        let bytes = response.to_bytes().unwrap();
+        assert_eq!(CodeGeneratorResponse::from_bytes(&bytes).unwrap(), response);
+    }
+}
+
+#[test]
+fn test_response_wire_format() {
+    let response = CodeGeneratorResponse {
+        error: Some("e".to_string()),
+        supported_features: Some(3),
+        minimum_edition: Some(998),
+        maximum_edition: Some(1000),
+        files: vec![File {
+            name: "a".to_string(),
+            insertion_point: Some("p".to_string()),
+            content: b"c".to_vec(),
+            generated_code_info: None,
+        }],
+    };
+    assert_eq!(
+        response.to_bytes().unwrap(),
+        [
+            0x0a, 1, b'e', // error = 1
+            0x10, 3, // supported_features = 2
+            0x18, 0xe6, 0x07, // minimum_edition = 3
+            0x20, 0xe8, 0x07, // maximum_edition = 4
+            0x7a, 9, // file = 15
+            0x0a, 1, b'a', // name = 1
+            0x12, 1, b'p', // insertion_point = 2
+            0x7a, 1, b'c', // content = 15
+        ]
+    );
+}
+
+#[test]
+fn test_response_invalid_file_name() {
+    let bytes = [
+        0x7a, 3, // file = 15
+        0x0a, 1, b'a', // name = 1
+        0x7a, 4, // file = 15
+        0x0a, 2, 0xff, b'b', // name = 1, not UTF-8
+    ];
+    let result = CodeGeneratorResponse::from_bytes(&bytes);
+    assert!(
+        matches!(
+            &result,
+            Err(ErrorKind::InvalidFileName { index: 1, bytes }) if bytes == &[0xff, b'b']
+        ),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_request_malformed() {
+    let bytes = [
+        0x12, 1, b'a', // parameter = 2
+        0x7a, 10, 1, 2, // proto_file = 15, truncated
+    ];
+    let result = CodeGeneratorRequest::from_bytes(&bytes);
+    assert!(
+        matches!(
+            &result,
+            Err(ErrorKind::MalformedMessage {
+                offset: 3,
+                last_field_number: Some(2),
+                ..
+            })
+        ),
+        "{:?}",
+        result
+    );
+    assert!(
+        result
+            .unwrap_err()
+            .to_string()
+            .starts_with("MalformedMessage: corrupt protobuf at byte 3 after field 2: ")
+    );
+
+    // The offset of an error in a nested message is in the outer message's bytes.
+    let bytes = [
+        0x12, 1, b'a', // parameter = 2
+        0x1a, 4, // compiler_version = 3
+        0x08, 1, // major = 1
+        0x10, 0x80, // minor = 2, truncated
+    ];
+    let result = CodeGeneratorRequest::from_bytes(&bytes);
+    assert!(
+        matches!(
+            &result,
+            Err(ErrorKind::MalformedMessage {
+                offset: 7,
+                last_field_number: Some(1),
+                ..
+            })
+        ),
+        "{:?}",
+        result
+    );
+}
+
+#[test]
+fn test_request_from_protoc() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("main.proto");
+    ::std::fs::write(
+        &proto_file,
+        "syntax = \"proto3\"; import \"dep.proto\"; package main;",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("dep.proto"),
+        "syntax = \"proto3\"; package dep;",
+    )
+    .unwrap();
+
+    let request = Mutex::new(None);
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |req| {
+            *request.lock().unwrap() = Some(CodeGeneratorRequest::from_bytes(req).unwrap());
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+
+    let request = request.into_inner().unwrap().unwrap();
+    assert_eq!(request.file_to_generate, ["main.proto"]);
+    assert_eq!(
+        request.proto_file_names().unwrap(),
+        ["dep.proto", "main.proto"]
+    );
+    let version = request.compiler_version.unwrap();
+    assert!(version.major.is_some() && version.minor.is_some());
+}
+
+#[cfg(feature = "on-memory")]
+#[test]
+fn test_file_descriptor_lite() {
+    let files = Mutex::new(Vec::new());
+    ProtocOnMemory::new()
+        .add_file(
+            "dep.proto",
+            "syntax = \"proto3\"; package dep; enum Color { COLOR_UNSPECIFIED = 0; }",
+        )
+        .add_file(
+            "main.proto",
+            "syntax = \"proto2\"; package my.main; import \"dep.proto\"; \
+             message A { optional dep.Color color = 1; } message B {}",
+        )
+        .run(Duration::from_secs(3), |req| {
+            let request = CodeGeneratorRequest::from_bytes(req).unwrap();
+            *files.lock().unwrap() = request.proto_file_descriptors().unwrap();
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+
+    let files = files.into_inner().unwrap();
+    assert_eq!(files.len(), 2);
+    assert_eq!(files[0].name, "dep.proto");
+    assert_eq!(files[0].package, "dep");
+    assert!(files[0].dependency.is_empty());
+    assert_eq!(files[0].enum_type_names, ["Color"]);
+    assert_eq!(files[0].syntax.as_deref(), Some("proto3"));
+    assert_eq!(files[1].name, "main.proto");
+    assert_eq!(files[1].package, "my.main");
+    assert_eq!(files[1].dependency, ["dep.proto"]);
+    assert_eq!(files[1].message_type_names, ["A", "B"]);
+    assert_eq!(files[1].syntax, None);
+    assert_eq!(files[1].edition, None);
+}
+
+#[test]
+fn test_file_descriptor_lite_skips_groups() {
+    let bytes = [
+        0x0a, 1, b'a', // name = 1
+        0x9b, 0x01, // start group 19
+        0x08, 1, // varint field 1 in the group
+        0xa3, 0x01, // start nested group 20
+        0x12, 1, b'x', // package = 2 in the nested group, which must be ignored
+        0xa4, 0x01, // end nested group 20
+        0x9c, 0x01, // end group 19
+        0x12, 1, b'p', // package = 2
+    ];
+    let file = FileDescriptorLite::from_bytes(&bytes).unwrap();
+    assert_eq!(file.name, "a");
+    assert_eq!(file.package, "p");
+
+    // An unterminated group is an error rather than being silently ignored.
+    assert!(FileDescriptorLite::from_bytes(&bytes[..12]).is_err());
+    // So is an end group tag without its start.
+    assert!(FileDescriptorLite::from_bytes(&[0x9c, 0x01]).is_err());
+}
+
+#[test]
+fn test_generated_code_info() {
+    let file = File {
+        name: "a.rs".to_string(),
+        content: b"struct A;\nstruct B;\n".to_vec(),
+        generated_code_info: Some(GeneratedCodeInfo {
+            annotation: vec![
+                Annotation {
+                    path: vec![4, 0],
+                    source_file: Some("a.proto".to_string()),
+                    begin: Some(7),
+                    end: Some(8),
+                    semantic: None,
+                },
+                Annotation {
+                    path: vec![4, 1],
+                    source_file: Some("a.proto".to_string()),
+                    begin: Some(17),
+                    end: Some(18),
+                    semantic: Some(1),
+                },
+            ],
+        }),
+        ..Default::default()
+    };
+    let bytes = file.to_bytes().unwrap();
+    assert_eq!(File::from_bytes(&bytes).unwrap(), file);
+
+    // The path is written packed, but the non-packed encoding is accepted as well.
+    let packed = [0x0a, 2, 4, 1];
+    let non_packed = [0x08, 4, 0x08, 1];
+    for bytes in [&packed[..], &non_packed[..]] {
+        assert_eq!(Annotation::from_bytes(bytes).unwrap().path, [4, 1]);
+    }
+    let annotation = Annotation {
+        path: vec![4, 1],
+        ..Default::default()
+    };
+    assert_eq!(annotation.to_bytes().unwrap(), packed);
+}
+
+#[test]
+fn test_response_builder() {
+    assert!(ResponseBuilder::new().build_bytes().is_empty());
+
+    assert_eq!(
+        ResponseBuilder::new()
+            .file("a", "x")
+            .file_with_insertion("a", "p", "y")
+            .support_proto3_optional()
+            .build_bytes(),
+        [
+            0x10, 1, // supported_features = 2
+            0x7a, 6, // file = 15
+            0x0a, 1, b'a', // name = 1
+            0x7a, 1, b'x', // content = 15
+            0x7a, 9, // file = 15
+            0x0a, 1, b'a', // name = 1
+            0x12, 1, b'p', // insertion_point = 2
+            0x7a, 1, b'y', // content = 15
+        ]
+    );
+
+    let response = ResponseBuilder::new()
+        .error("e")
+        .file("ignored", "")
+        .build();
+    // The files are dropped, since protoc ignores them.
+    assert_eq!(
+        response.to_bytes().unwrap(),
+        [
+            0x0a, 1, b'e', // error = 1
+        ]
+    );
+    assert_eq!(
+        response,
+        CodeGeneratorResponse {
+            error: Some("e".to_string()),
+            ..Default::default()
+        }
+    );
+
+    let response = ResponseBuilder::new()
+        .support_proto3_optional()
+        .support_proto3_optional()
+        .build();
+    assert_eq!(response.supported_features, Some(FEATURE_PROTO3_OPTIONAL));
+
+    let response = ResponseBuilder::new()
+        .supported_features(FEATURE_PROTO3_OPTIONAL)
+        .support_editions(998, 1000)
+        .build();
+    assert_eq!(
+        response.supported_features,
+        Some(FEATURE_PROTO3_OPTIONAL | FEATURE_SUPPORTS_EDITIONS)
+    );
+    assert_eq!(response.minimum_edition, Some(998));
+    assert_eq!(response.maximum_edition, Some(1000));
+}
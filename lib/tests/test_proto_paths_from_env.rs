@@ -0,0 +1,67 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for reading the proto paths from an env var, which is process-wide, so they are in
+//! a separate test binary with a single test.
+
+mod descriptor;
+
+use ::protoc_plugin_by_closure::{ErrorKind, Protoc};
+use ::std::env;
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+use self::descriptor::FileDescriptorSet;
+
+const VAR_NAME: &str = "PROTOC_PLUGIN_BY_CLOSURE_TEST_PROTO_INCLUDE";
+
+#[test]
+fn test_proto_paths_from_env() {
+    // main.proto in the first dir imports dep.proto in the second dir.
+    let main_dir = tempdir().unwrap();
+    let dep_dir = tempdir().unwrap();
+    let main_proto = main_dir.path().join("main.proto");
+    ::std::fs::write(
+        &main_proto,
+        "syntax = \"proto3\"; import \"dep.proto\"; message Main { Dep dep = 1; }",
+    )
+    .unwrap();
+    ::std::fs::write(
+        dep_dir.path().join("dep.proto"),
+        "syntax = \"proto3\"; message Dep {}",
+    )
+    .unwrap();
+
+    // Without the env var, nothing is added and dep.proto is not found.
+    unsafe { env::remove_var(VAR_NAME) };
+    let result = Protoc::new()
+        .proto_file(&main_proto)
+        .proto_path(main_dir.path())
+        .proto_paths_from_env(VAR_NAME)
+        .compile_descriptor_set(Duration::from_secs(3));
+    assert!(matches!(result, Err(ErrorKind::ProtocProcessError(_))));
+
+    let paths = env::join_paths([main_dir.path(), dep_dir.path()]).unwrap();
+    unsafe { env::set_var(VAR_NAME, paths) };
+    let descriptor_set = Protoc::new()
+        .proto_file(&main_proto)
+        .proto_paths_from_env(VAR_NAME)
+        .include_imports(true)
+        .compile_descriptor_set(Duration::from_secs(3))
+        .unwrap();
+    unsafe { env::remove_var(VAR_NAME) };
+
+    let descriptor_set = FileDescriptorSet::from_bytes(&descriptor_set).unwrap();
+    assert_eq!(descriptor_set.file_count, 2);
+}
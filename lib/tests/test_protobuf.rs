@@ -0,0 +1,104 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+#![cfg(feature = "protobuf")]
+
+use ::protobuf::plugin::CodeGeneratorResponse;
+use ::protobuf::plugin::code_generator_response::File;
+use ::protoc_plugin_by_closure::Protoc;
+#[cfg(feature = "on-memory")]
+use ::protoc_plugin_by_closure::plugin_proto::FEATURE_PROTO3_OPTIONAL;
+#[cfg(feature = "on-memory")]
+use ::protoc_plugin_by_closure::{ErrorKind, ProtocOnMemory};
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+#[test]
+fn test_run_protobuf() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("parsed.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package parsed;").unwrap();
+
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run_protobuf(Duration::from_secs(3), |req| {
+            assert_eq!(req.proto_file[0].package(), "parsed");
+            assert_eq!(req.parameter, None);
+            Ok(CodeGeneratorResponse {
+                file: vec![File {
+                    name: Some("parsed.rs".to_string()),
+                    content: Some(format!("// {}", req.file_to_generate[0])),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        })
+        .unwrap();
+
+    let actual_out = ::std::fs::read_to_string(out_dir.path().join("parsed.rs")).unwrap();
+    assert_eq!(actual_out, "// parsed.proto");
+}
+
+#[cfg(feature = "on-memory")]
+#[test]
+fn test_on_memory_run_protobuf() {
+    let result_files = ProtocOnMemory::new()
+        .add_file("on_memory.proto", "syntax = \"proto3\"; package on_memory;")
+        .run_protobuf(Duration::from_secs(3), |req| {
+            Ok(CodeGeneratorResponse {
+                file: vec![File {
+                    name: Some("on_memory.rs".to_string()),
+                    content: Some(format!("// {}", req.proto_file[0].package())),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            })
+        })
+        .unwrap();
+
+    assert_eq!(
+        result_files,
+        vec![("on_memory.rs".to_string(), "// on_memory".to_string())]
+    );
+}
+
+#[cfg(feature = "on-memory")]
+#[test]
+fn test_run_protobuf_supported_features() {
+    let protoc = ProtocOnMemory::new().add_file(
+        "optional.proto",
+        "syntax = \"proto3\"; message M { optional int32 f = 1; }",
+    );
+
+    // protoc rejects the proto3 optional fields unless the plugin supports them.
+    let result = protoc.clone().run_protobuf(Duration::from_secs(3), |_| {
+        Ok(CodeGeneratorResponse::default())
+    });
+    assert!(
+        matches!(result, Err(ErrorKind::ProtocProcessError(_))),
+        "{:?}",
+        result
+    );
+
+    let result_files = protoc
+        .supported_features(FEATURE_PROTO3_OPTIONAL)
+        .run_protobuf(Duration::from_secs(3), |_| {
+            Ok(CodeGeneratorResponse::default())
+        })
+        .unwrap();
+    assert_eq!(result_files, vec![]);
+}
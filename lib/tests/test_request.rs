@@ -0,0 +1,72 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ::protoc_plugin_by_closure::{Protoc, request};
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+#[test]
+fn test_proto_file_names() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    ::std::fs::create_dir(proto_dir.path().join("sub")).unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("first.proto"),
+        "syntax = \"proto3\"; package first;",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("sub/second.proto"),
+        "syntax = \"proto3\"; package second;",
+    )
+    .unwrap();
+    ::std::fs::write(
+        proto_dir.path().join("third.proto"),
+        "syntax = \"proto3\"; package third;",
+    )
+    .unwrap();
+
+    let mut names = Vec::new();
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_path(proto_dir.path())
+        .proto_files(
+            ["third.proto", "first.proto", "sub/second.proto"].map(|n| proto_dir.path().join(n)),
+        )
+        .run(Duration::from_secs(3), |req| {
+            names = request::proto_file_names(req).unwrap();
+            Ok(Vec::new())
+        })
+        .unwrap();
+
+    assert_eq!(names, ["third.proto", "first.proto", "sub/second.proto"]);
+}
+
+#[test]
+fn test_parse_parameters() {
+    assert_eq!(
+        request::parse_parameters("a=1,b,c=,d=2"),
+        [
+            ("a".to_string(), Some("1".to_string())),
+            ("b".to_string(), None),
+            ("c".to_string(), Some(String::new())),
+            ("d".to_string(), Some("2".to_string())),
+        ]
+    );
+    assert_eq!(
+        request::parse_parameters(",x=y=z,,"),
+        [("x".to_string(), Some("y=z".to_string()))]
+    );
+    assert!(request::parse_parameters("").is_empty());
+}
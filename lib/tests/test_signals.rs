@@ -0,0 +1,78 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// Signals are process global, so the test is in its own test binary.
+#![cfg(unix)]
+
+use ::protoc_plugin_by_closure::{ErrorKind, Protoc};
+use ::std::os::unix::fs::PermissionsExt;
+use ::std::process::Command;
+use ::std::thread;
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+#[test]
+fn test_handle_signals() {
+    let script_dir = tempdir().unwrap();
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("signal.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package signal;").unwrap();
+
+    // A fake protoc which records its pid and then runs the real protoc.
+    let fake_protoc = script_dir.path().join("protoc");
+    let pid_file = script_dir.path().join("pid.txt");
+    ::std::fs::write(
+        &fake_protoc,
+        format!(
+            "#!/bin/sh\necho $$ > '{}'\nexec protoc \"$@\"\n",
+            pid_file.display()
+        ),
+    )
+    .unwrap();
+    ::std::fs::set_permissions(&fake_protoc, ::std::fs::Permissions::from_mode(0o755)).unwrap();
+
+    let result = Protoc::new()
+        .protoc_path(&fake_protoc)
+        .handle_signals(true)
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(10), |_| {
+            // Interrupt this process in the middle of the slow closure.
+            let status = Command::new("kill")
+                .arg("-INT")
+                .arg(::std::process::id().to_string())
+                .status()
+                .unwrap();
+            assert!(status.success());
+            thread::sleep(Duration::from_millis(200));
+            Ok(Vec::new())
+        });
+    let result = result.map_err(ErrorKind::into_inner);
+    assert!(
+        matches!(result, Err(ErrorKind::Interrupted)),
+        "{:?}",
+        result
+    );
+
+    // The protoc process has been killed and reaped.
+    let pid = ::std::fs::read_to_string(&pid_file).unwrap();
+    let status = Command::new("kill")
+        .arg("-0")
+        .arg(pid.trim())
+        .status()
+        .unwrap();
+    assert!(!status.success());
+}
@@ -0,0 +1,46 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for the default transport detection, which depends on the process-wide env vars,
+//! so they are in a separate test binary with a single test.
+
+use ::protoc_plugin_by_closure::{Protoc, Transport};
+use ::std::env;
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+#[test]
+fn test_transport_detect() {
+    unsafe { env::remove_var("CARGO_CFG_TARGET_OS") };
+    assert_eq!(Transport::detect(), Transport::IpcChannel);
+
+    unsafe { env::set_var("CARGO_CFG_TARGET_OS", "linux") };
+    #[cfg(unix)]
+    assert_eq!(Transport::detect(), Transport::UnixSocket);
+    #[cfg(not(unix))]
+    assert_eq!(Transport::detect(), Transport::IpcChannel);
+
+    // The detected transport works as a build script would use it.
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("detected.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package detected;").unwrap();
+    Protoc::new()
+        .out_dir(out_dir.path())
+        .proto_file(&proto_file)
+        .proto_path(proto_dir.path())
+        .run(Duration::from_secs(3), |_| Ok(Vec::new()))
+        .unwrap();
+    unsafe { env::remove_var("CARGO_CFG_TARGET_OS") };
+}
@@ -0,0 +1,53 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use ::protoc_plugin_by_closure::{ErrorKind, Protoc, Transport};
+use ::std::env;
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+// The env var is process global, so all the cases are in a single test.
+#[test]
+fn test_plugin_version_mismatch() {
+    let out_dir = tempdir().unwrap();
+    let proto_dir = tempdir().unwrap();
+    let proto_file = proto_dir.path().join("version.proto");
+    ::std::fs::write(&proto_file, "syntax = \"proto3\"; package version;").unwrap();
+
+    // Simulates a stale plugin binary. The env var is inherited through protoc.
+    unsafe { env::set_var("PROTOC_PLUGIN_BY_CLOSURE_PROTOCOL_VERSION_OVERRIDE", "999") };
+    let mut transports = vec![Transport::IpcChannel];
+    #[cfg(unix)]
+    transports.push(Transport::UnixSocket);
+    for transport in transports {
+        let result = Protoc::new()
+            .transport(transport)
+            .out_dir(out_dir.path())
+            .proto_file(&proto_file)
+            .proto_path(proto_dir.path())
+            .run(Duration::from_secs(3), |_| {
+                panic!("the closure must not be called")
+            });
+        let result = result.map_err(ErrorKind::into_inner);
+        assert!(
+            matches!(
+                result,
+                Err(ErrorKind::PluginVersionMismatch { lib, bin: 999 }) if lib != 999
+            ),
+            "{:?}",
+            result
+        );
+    }
+    unsafe { env::remove_var("PROTOC_PLUGIN_BY_CLOSURE_PROTOCOL_VERSION_OVERRIDE") };
+}
@@ -0,0 +1,59 @@
+// Copyright 2021 Google LLC
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//      http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Tests for the `well-known-types` feature.
+
+#![cfg(feature = "well-known-types")]
+
+use ::protoc_plugin_by_closure::plugin_proto::{CodeGeneratorRequest, CodeGeneratorResponse};
+use ::protoc_plugin_by_closure::{ErrorKind, Protoc, ProtocOnMemory};
+use ::std::time::Duration;
+use ::tempfile::tempdir;
+
+#[test]
+fn test_well_known_types() {
+    // A copy of protoc without its bundled include dir next to it, which protoc would find by
+    // itself otherwise.
+    let protoc_dir = tempdir().unwrap();
+    let protoc_path = protoc_dir.path().join("protoc");
+    ::std::fs::copy(Protoc::new().resolved_protoc_path().unwrap(), &protoc_path).unwrap();
+    let protoc = ProtocOnMemory::new().protoc_path(&protoc_path).add_file(
+        "main.proto",
+        "syntax = \"proto3\"; import \"google/protobuf/timestamp.proto\"; \
+         message Main { google.protobuf.Timestamp time = 1; }",
+    );
+
+    let result = protoc
+        .clone()
+        .run(Duration::from_secs(3), |_| unreachable!());
+    assert!(
+        matches!(&result, Err(ErrorKind::ProtocProcessError(_))),
+        "{:?}",
+        result
+    );
+
+    let files = protoc
+        .with_well_known_types()
+        .run(Duration::from_secs(3), |req| {
+            let req = CodeGeneratorRequest::from_bytes(req).unwrap();
+            assert_eq!(req.file_to_generate, ["main.proto"]);
+            assert_eq!(
+                req.proto_file_names().unwrap(),
+                ["google/protobuf/timestamp.proto", "main.proto"]
+            );
+            Ok(CodeGeneratorResponse::default().to_bytes().unwrap())
+        })
+        .unwrap();
+    assert_eq!(files, vec![]);
+}